@@ -0,0 +1,76 @@
+// Times camera picks against a BVH over a million triangle height field.
+// Run with `cargo run --release --example bvh_pick`
+extern crate vulkan_renderer;
+
+use std::time::Instant;
+use vulkan_renderer::bvh::TriangleBvh;
+use vulkan_renderer::camera::Camera;
+use vulkan_renderer::math::{Perspective, Point, Vec3};
+
+// 708 * 708 quads * 2 is just over a million triangles
+const GRID: usize = 708;
+const PICKS: usize = 10000;
+
+fn height(x: f32, z: f32) -> f32 {
+    (x * 0.05).sin() * 4.0 + (z * 0.07).cos() * 3.0 + (x * z * 0.001).sin()
+}
+
+fn main() {
+    let mut vertices = Vec::with_capacity((GRID + 1) * (GRID + 1));
+    for z in 0..GRID + 1 {
+        for x in 0..GRID + 1 {
+            let (fx, fz) = (x as f32 - GRID as f32 * 0.5, z as f32 - GRID as f32 * 0.5);
+            vertices.push(Point::new(fx, height(fx, fz), fz));
+        }
+    }
+    let mut indices = Vec::with_capacity(GRID * GRID * 6);
+    for z in 0..GRID {
+        for x in 0..GRID {
+            let i = (z * (GRID + 1) + x) as u32;
+            let row = (GRID + 1) as u32;
+            indices.extend_from_slice(&[i, i + row, i + 1, i + 1, i + row, i + row + 1]);
+        }
+    }
+
+    let start = Instant::now();
+    let bvh = TriangleBvh::from_indexed(&vertices, &indices);
+    let elapsed = start.elapsed();
+    println!(
+        "built bvh over {} triangles ({} nodes) in {:.1}ms",
+        bvh.triangles().len(),
+        bvh.bvh().node_count(),
+        elapsed.as_secs() as f64 * 1000.0 + elapsed.subsec_nanos() as f64 / 1e6
+    );
+
+    let camera: Camera<Perspective> = Camera::new(
+        Point::new(0.0, 60.0, -(GRID as f32) * 0.5),
+        Point::new(0.0, 0.0, 0.0),
+        Vec3::new(0.0, 1.0, 0.0),
+        Perspective::new(1.777778, 1.0, 0.1, 5000.0),
+    );
+
+    // A cheap deterministic sweep over the screen instead of a random number generator
+    let mut hits = 0;
+    let mut worst = 0.0f64;
+    let start = Instant::now();
+    for i in 0..PICKS {
+        let x = ((i * 7919) % PICKS) as f32 / PICKS as f32 * 2.0 - 1.0;
+        let y = ((i * 104729) % PICKS) as f32 / PICKS as f32 * 2.0 - 1.0;
+        let pick_start = Instant::now();
+        let ray = camera.screen_to_world_space(x, y);
+        if bvh.closest_hit(&ray).is_some() {
+            hits += 1;
+        }
+        let pick = pick_start.elapsed();
+        worst = worst.max(pick.as_secs() as f64 * 1000.0 + pick.subsec_nanos() as f64 / 1e6);
+    }
+    let elapsed = start.elapsed();
+    let total = elapsed.as_secs() as f64 * 1000.0 + elapsed.subsec_nanos() as f64 / 1e6;
+    println!(
+        "{} picks, {} hits, {:.4}ms average, {:.4}ms worst",
+        PICKS,
+        hits,
+        total / PICKS as f64,
+        worst
+    );
+}
@@ -0,0 +1,685 @@
+use math;
+use math::{Isometry, Mat4, Point, Quaternion, Rotation, Vec3};
+use nalgebra::{Matrix3, Translation3};
+use ray::{Ray3, RayHit};
+use std::f32;
+
+// An axis aligned bounding box. An empty box has min > max and contains nothing
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Aabb {
+    pub min: Point,
+    pub max: Point,
+}
+
+impl Aabb {
+    pub fn new(min: Point, max: Point) -> Self {
+        Self { min, max }
+    }
+    pub fn empty() -> Self {
+        Self {
+            min: Point::new(f32::MAX, f32::MAX, f32::MAX),
+            max: Point::new(f32::MIN, f32::MIN, f32::MIN),
+        }
+    }
+    pub fn from_points<'a, I: IntoIterator<Item = &'a Point>>(points: I) -> Self {
+        let mut aabb = Self::empty();
+        for point in points {
+            aabb.expand_to_point(point);
+        }
+        aabb
+    }
+    pub fn from_center_extents(center: Point, half_extents: Vec3) -> Self {
+        Self {
+            min: center - half_extents,
+            max: center + half_extents,
+        }
+    }
+    pub fn is_empty(&self) -> bool {
+        self.min.x > self.max.x || self.min.y > self.max.y || self.min.z > self.max.z
+    }
+    pub fn expand_to_point(&mut self, point: &Point) {
+        for axis in 0..3 {
+            self.min[axis] = self.min[axis].min(point[axis]);
+            self.max[axis] = self.max[axis].max(point[axis]);
+        }
+    }
+    pub fn merge(&mut self, other: &Aabb) {
+        if other.is_empty() {
+            return;
+        }
+        self.expand_to_point(&other.min);
+        self.expand_to_point(&other.max);
+    }
+    pub fn merged(&self, other: &Aabb) -> Aabb {
+        let mut aabb = *self;
+        aabb.merge(other);
+        aabb
+    }
+    pub fn center(&self) -> Point {
+        Point::from_coordinates((self.min.coords + self.max.coords) * 0.5)
+    }
+    pub fn half_extents(&self) -> Vec3 {
+        (self.max - self.min) * 0.5
+    }
+    pub fn size(&self) -> Vec3 {
+        self.max - self.min
+    }
+    pub fn surface_area(&self) -> f32 {
+        if self.is_empty() {
+            return 0.0;
+        }
+        let size = self.size();
+        2.0 * (size.x * size.y + size.y * size.z + size.z * size.x)
+    }
+    // The axis the box is longest along
+    pub fn largest_axis(&self) -> usize {
+        let size = self.size();
+        if size.x >= size.y && size.x >= size.z {
+            0
+        } else if size.y >= size.z {
+            1
+        } else {
+            2
+        }
+    }
+    // The corners ordered by the bits of the index, bit 0 picks max x, bit 1 max y, bit 2 max z
+    pub fn corners(&self) -> [Point; 8] {
+        let mut corners = [self.min; 8];
+        for (i, corner) in corners.iter_mut().enumerate() {
+            for axis in 0..3 {
+                if i & (1 << axis) != 0 {
+                    corner[axis] = self.max[axis];
+                }
+            }
+        }
+        corners
+    }
+    pub fn contains_point(&self, point: &Point) -> bool {
+        (0..3).all(|axis| point[axis] >= self.min[axis] && point[axis] <= self.max[axis])
+    }
+    pub fn contains_aabb(&self, other: &Aabb) -> bool {
+        self.contains_point(&other.min) && self.contains_point(&other.max)
+    }
+    pub fn intersects_aabb(&self, other: &Aabb) -> bool {
+        (0..3).all(|axis| self.min[axis] <= other.max[axis] && self.max[axis] >= other.min[axis])
+    }
+    pub fn intersects_sphere(&self, sphere: &Sphere) -> bool {
+        self.distance_squared(&sphere.center) <= sphere.radius * sphere.radius
+    }
+    // Zero for points inside the box
+    pub fn distance_squared(&self, point: &Point) -> f32 {
+        let mut distance = 0.0;
+        for axis in 0..3 {
+            let v = point[axis];
+            if v < self.min[axis] {
+                distance += (self.min[axis] - v) * (self.min[axis] - v);
+            } else if v > self.max[axis] {
+                distance += (v - self.max[axis]) * (v - self.max[axis]);
+            }
+        }
+        distance
+    }
+    // The box enclosing this one after it has been transformed
+    pub fn transform(&self, isometry: &Isometry) -> Aabb {
+        self.transform_matrix(&isometry.to_homogeneous())
+    }
+    pub fn transform_matrix(&self, matrix: &Mat4) -> Aabb {
+        if self.is_empty() {
+            return *self;
+        }
+        let corners = self.corners();
+        let transformed: Vec<Point> = corners
+            .iter()
+            .map(|corner| math::transform_point(matrix, corner))
+            .collect();
+        Aabb::from_points(transformed.iter())
+    }
+    pub fn intersect_ray(&self, ray: &Ray3) -> Option<RayHit> {
+        ray.intersect_aabb(self.min, self.max)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Sphere {
+    pub center: Point,
+    pub radius: f32,
+}
+
+impl Sphere {
+    pub fn new(center: Point, radius: f32) -> Self {
+        Self { center, radius }
+    }
+    // Ritter's bounding sphere, within a few percent of the minimal sphere
+    pub fn from_points(points: &[Point]) -> Self {
+        if points.is_empty() {
+            return Self::new(Point::origin(), 0.0);
+        }
+        let furthest_from = |from: &Point| {
+            let mut best = points[0];
+            let mut best_distance = -1.0;
+            for point in points {
+                let distance = (point - from).norm_squared();
+                if distance > best_distance {
+                    best_distance = distance;
+                    best = *point;
+                }
+            }
+            best
+        };
+        let a = furthest_from(&points[0]);
+        let b = furthest_from(&a);
+        let mut sphere = Self::new(
+            Point::from_coordinates((a.coords + b.coords) * 0.5),
+            (b - a).norm() * 0.5,
+        );
+        for point in points {
+            sphere.expand_to_point(point);
+        }
+        sphere
+    }
+    pub fn from_aabb(aabb: &Aabb) -> Self {
+        Self::new(aabb.center(), aabb.half_extents().norm())
+    }
+    pub fn expand_to_point(&mut self, point: &Point) {
+        let offset = point - self.center;
+        let distance = offset.norm();
+        if distance <= self.radius {
+            return;
+        }
+        let radius = (self.radius + distance) * 0.5;
+        self.center += offset * ((radius - self.radius) / distance);
+        self.radius = radius;
+    }
+    pub fn merge(&mut self, other: &Sphere) {
+        let offset = other.center - self.center;
+        let distance = offset.norm();
+        if distance + other.radius <= self.radius {
+            return;
+        }
+        if distance + self.radius <= other.radius {
+            *self = *other;
+            return;
+        }
+        let radius = (self.radius + distance + other.radius) * 0.5;
+        self.center += offset * ((radius - self.radius) / distance);
+        self.radius = radius;
+    }
+    pub fn merged(&self, other: &Sphere) -> Sphere {
+        let mut sphere = *self;
+        sphere.merge(other);
+        sphere
+    }
+    pub fn contains_point(&self, point: &Point) -> bool {
+        (point - self.center).norm_squared() <= self.radius * self.radius
+    }
+    pub fn intersects_sphere(&self, other: &Sphere) -> bool {
+        let radius = self.radius + other.radius;
+        (other.center - self.center).norm_squared() <= radius * radius
+    }
+    pub fn intersects_aabb(&self, aabb: &Aabb) -> bool {
+        aabb.intersects_sphere(self)
+    }
+    pub fn to_aabb(&self) -> Aabb {
+        let extents = Vec3::new(self.radius, self.radius, self.radius);
+        Aabb::from_center_extents(self.center, extents)
+    }
+    pub fn transform(&self, isometry: &Isometry) -> Sphere {
+        Self::new(isometry * self.center, self.radius)
+    }
+    // The radius is scaled by the largest axis scale of the matrix
+    pub fn transform_matrix(&self, matrix: &Mat4) -> Sphere {
+        let scale = (0..3)
+            .map(|axis| {
+                let mut unit = Vec3::zeros();
+                unit[axis] = 1.0;
+                math::transform_vector(matrix, &unit).norm()
+            })
+            .fold(0.0f32, f32::max);
+        Self::new(math::transform_point(matrix, &self.center), self.radius * scale)
+    }
+    pub fn intersect_ray(&self, ray: &Ray3) -> Option<RayHit> {
+        ray.intersect_sphere(self.center, self.radius)
+    }
+}
+
+// An oriented bounding box, `axes` are unit length and perpendicular
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Obb {
+    pub center: Point,
+    pub axes: [Vec3; 3],
+    pub half_extents: Vec3,
+}
+
+impl Obb {
+    pub fn new(center: Point, axes: [Vec3; 3], half_extents: Vec3) -> Self {
+        Self {
+            center,
+            axes,
+            half_extents,
+        }
+    }
+    pub fn from_aabb(aabb: &Aabb) -> Self {
+        Self::new(
+            aabb.center(),
+            [Vec3::x(), Vec3::y(), Vec3::z()],
+            aabb.half_extents(),
+        )
+    }
+    // Fits the box to the principal axes of the points' covariance
+    pub fn from_points(points: &[Point]) -> Self {
+        if points.is_empty() {
+            return Self::from_aabb(&Aabb::new(Point::origin(), Point::origin()));
+        }
+        let count = points.len() as f32;
+        let mean = points.iter().fold(Vec3::zeros(), |sum, p| sum + p.coords) / count;
+        let mut covariance = Matrix3::zeros();
+        for point in points {
+            let d = point.coords - mean;
+            covariance += d * d.transpose();
+        }
+        covariance /= count;
+        let eigenvectors = covariance.symmetric_eigen().eigenvectors;
+        let x = eigenvectors.column(0).normalize();
+        let y = eigenvectors.column(1).normalize();
+        // Rebuilding z keeps the basis right handed
+        let z = math::cross(x, y).normalize();
+        let y = math::cross(z, x);
+        let axes = [x, y, z];
+
+        let mut min = Vec3::repeat(f32::MAX);
+        let mut max = Vec3::repeat(f32::MIN);
+        for point in points {
+            let d = point.coords - mean;
+            for axis in 0..3 {
+                let v = d.dot(&axes[axis]);
+                min[axis] = min[axis].min(v);
+                max[axis] = max[axis].max(v);
+            }
+        }
+        let local_center = (min + max) * 0.5;
+        let center = mean + axes[0] * local_center.x + axes[1] * local_center.y
+            + axes[2] * local_center.z;
+        Self::new(Point::from_coordinates(center), axes, (max - min) * 0.5)
+    }
+    // The local to world isometry, local space has the box centered at the origin
+    pub fn isometry(&self) -> Isometry {
+        let basis = Matrix3::from_columns(&self.axes);
+        let rotation = Quaternion::from_rotation_matrix(&Rotation::from_matrix_unchecked(basis));
+        Isometry::from_parts(Translation3::from_vector(self.center.coords), rotation)
+    }
+    pub fn corners(&self) -> [Point; 8] {
+        let mut corners = [self.center; 8];
+        for (i, corner) in corners.iter_mut().enumerate() {
+            for axis in 0..3 {
+                let sign = if i & (1 << axis) != 0 { 1.0 } else { -1.0 };
+                *corner += self.axes[axis] * self.half_extents[axis] * sign;
+            }
+        }
+        corners
+    }
+    pub fn to_aabb(&self) -> Aabb {
+        Aabb::from_points(self.corners().iter())
+    }
+    pub fn contains_point(&self, point: &Point) -> bool {
+        let d = point - self.center;
+        (0..3).all(|axis| d.dot(&self.axes[axis]).abs() <= self.half_extents[axis])
+    }
+    // Separating axis test over the face normals of both boxes and their edge cross products
+    pub fn intersects_obb(&self, other: &Obb) -> bool {
+        let offset = other.center - self.center;
+        let projected_radius = |obb: &Obb, axis: &Vec3| {
+            (0..3)
+                .map(|i| obb.half_extents[i] * obb.axes[i].dot(axis).abs())
+                .sum::<f32>()
+        };
+        let separated = |axis: Vec3| {
+            if axis.norm_squared() < 1e-10 {
+                return false;
+            }
+            let distance = offset.dot(&axis).abs();
+            distance > projected_radius(self, &axis) + projected_radius(other, &axis)
+        };
+        for i in 0..3 {
+            if separated(self.axes[i]) || separated(other.axes[i]) {
+                return false;
+            }
+        }
+        for i in 0..3 {
+            for j in 0..3 {
+                if separated(math::cross(self.axes[i], other.axes[j])) {
+                    return false;
+                }
+            }
+        }
+        true
+    }
+    pub fn intersects_aabb(&self, aabb: &Aabb) -> bool {
+        self.intersects_obb(&Obb::from_aabb(aabb))
+    }
+    pub fn intersects_sphere(&self, sphere: &Sphere) -> bool {
+        let d = sphere.center - self.center;
+        let mut closest = self.center;
+        for axis in 0..3 {
+            let v = d.dot(&self.axes[axis])
+                .max(-self.half_extents[axis])
+                .min(self.half_extents[axis]);
+            closest += self.axes[axis] * v;
+        }
+        sphere.contains_point(&closest)
+    }
+    pub fn transform(&self, isometry: &Isometry) -> Obb {
+        Self::new(
+            isometry * self.center,
+            [
+                isometry * self.axes[0],
+                isometry * self.axes[1],
+                isometry * self.axes[2],
+            ],
+            self.half_extents,
+        )
+    }
+    // Scale is folded into the half extents. Shearing matrices give an approximate box
+    pub fn transform_matrix(&self, matrix: &Mat4) -> Obb {
+        let mut axes = self.axes;
+        let mut half_extents = self.half_extents;
+        for axis in 0..3 {
+            let transformed = math::transform_vector(matrix, &self.axes[axis]);
+            let scale = transformed.norm();
+            half_extents[axis] *= scale;
+            if scale > 0.0 {
+                axes[axis] = transformed / scale;
+            }
+        }
+        Self::new(math::transform_point(matrix, &self.center), axes, half_extents)
+    }
+    pub fn intersect_ray(&self, ray: &Ray3) -> Option<RayHit> {
+        let isometry = self.isometry();
+        let local = ray.transform(&isometry.inverse());
+        let extents = self.half_extents;
+        let hit = local.intersect_aabb(
+            Point::from_coordinates(-extents),
+            Point::from_coordinates(extents),
+        )?;
+        Some(RayHit {
+            distance: hit.distance,
+            point: isometry * hit.point,
+            normal: isometry * hit.normal,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::f32::consts::{FRAC_PI_2, FRAC_PI_4};
+
+    // A small linear congruential generator so the points are the same every run
+    struct Lcg(u32);
+
+    impl Lcg {
+        fn next(&mut self) -> f32 {
+            self.0 = self.0.wrapping_mul(1_664_525).wrapping_add(1_013_904_223);
+            (self.0 >> 8) as f32 / (1 << 24) as f32
+        }
+        fn range(&mut self, min: f32, max: f32) -> f32 {
+            min + self.next() * (max - min)
+        }
+        fn point(&mut self, extent: f32) -> Point {
+            Point::new(
+                self.range(-extent, extent),
+                self.range(-extent, extent),
+                self.range(-extent, extent),
+            )
+        }
+    }
+
+    fn point(x: f32, y: f32, z: f32) -> Point {
+        Point::new(x, y, z)
+    }
+    fn vec(x: f32, y: f32, z: f32) -> Vec3 {
+        Vec3::new(x, y, z)
+    }
+    fn assert_near(a: Point, b: Point) {
+        assert!((a - b).norm() < 1e-4, "{:?} != {:?}", a, b);
+    }
+    fn unit_cube() -> Aabb {
+        Aabb::new(point(-1.0, -1.0, -1.0), point(1.0, 1.0, 1.0))
+    }
+    // Rays from all around, aimed near the origin
+    fn random_rays(rng: &mut Lcg, count: usize) -> Vec<Ray3> {
+        (0..count)
+            .map(|_| {
+                let origin = rng.point(6.0);
+                Ray3::new(origin, rng.point(2.0) - origin)
+            })
+            .collect()
+    }
+
+    #[test]
+    fn aabb_from_points_is_tight() {
+        let points = [point(1.0, -2.0, 0.5), point(-3.0, 4.0, 0.0), point(0.0, 0.0, 2.0)];
+        let aabb = Aabb::from_points(points.iter());
+        assert_eq!(aabb.min, point(-3.0, -2.0, 0.0));
+        assert_eq!(aabb.max, point(1.0, 4.0, 2.0));
+        assert!(points.iter().all(|p| aabb.contains_point(p)));
+        assert!(Aabb::from_points([].iter()).is_empty());
+    }
+
+    #[test]
+    fn ritter_sphere_encloses_every_point() {
+        let mut rng = Lcg(7);
+        for count in 1..40 {
+            let points: Vec<Point> = (0..count).map(|_| rng.point(10.0)).collect();
+            let sphere = Sphere::from_points(&points);
+            for p in &points {
+                assert!((p - sphere.center).norm() <= sphere.radius * (1.0 + 1e-5) + 1e-5);
+            }
+            // Never worse than the sphere around the bounding box
+            let aabb_sphere = Sphere::from_aabb(&Aabb::from_points(points.iter()));
+            assert!(sphere.radius <= aabb_sphere.radius + 1e-4);
+        }
+        let sphere = Sphere::from_points(&[]);
+        assert_eq!(sphere.radius, 0.0);
+    }
+
+    #[test]
+    fn obb_from_points_encloses_every_point() {
+        let mut rng = Lcg(11);
+        // A long thin box rotated off the world axes
+        let rotation =
+            Rotation::from_axis_angle(&Vec3::y_axis(), 0.6) * Rotation::from_axis_angle(&Vec3::z_axis(), 0.3);
+        let points: Vec<Point> = (0..200)
+            .map(|_| {
+                let local = vec(rng.range(-8.0, 8.0), rng.range(-1.0, 1.0), rng.range(-0.5, 0.5));
+                Point::from_coordinates(rotation * local + vec(3.0, -2.0, 1.0))
+            })
+            .collect();
+        let obb = Obb::from_points(&points);
+        let grown = Obb::new(obb.center, obb.axes, obb.half_extents + Vec3::repeat(1e-4));
+        assert!(points.iter().all(|p| grown.contains_point(p)));
+        // The longest axis follows the points rather than the world
+        let longest = (0..3)
+            .max_by(|&a, &b| obb.half_extents[a].partial_cmp(&obb.half_extents[b]).unwrap())
+            .unwrap();
+        assert!(obb.axes[longest].dot(&(rotation * Vec3::x())).abs() > 0.99);
+    }
+
+    #[test]
+    fn aabb_merge_ignores_empty_boxes() {
+        let a = Aabb::new(point(0.0, 0.0, 0.0), point(1.0, 1.0, 1.0));
+        let b = Aabb::new(point(-2.0, 0.5, 3.0), point(-1.0, 2.0, 4.0));
+        let merged = a.merged(&b);
+        assert_eq!(merged.min, point(-2.0, 0.0, 0.0));
+        assert_eq!(merged.max, point(1.0, 2.0, 4.0));
+        assert!(merged.contains_aabb(&a) && merged.contains_aabb(&b));
+        assert_eq!(a.merged(&Aabb::empty()), a);
+        assert_eq!(Aabb::empty().merged(&a), a);
+    }
+
+    #[test]
+    fn sphere_merge_encloses_both() {
+        let a = Sphere::new(point(0.0, 0.0, 0.0), 1.0);
+        let b = Sphere::new(point(4.0, 0.0, 0.0), 2.0);
+        let merged = a.merged(&b);
+        assert_near(merged.center, point(2.5, 0.0, 0.0));
+        assert!((merged.radius - 3.5).abs() < 1e-5);
+        // One inside the other keeps the outer one
+        let inner = Sphere::new(point(0.5, 0.0, 0.0), 0.25);
+        assert_eq!(a.merged(&inner), a);
+        assert_eq!(inner.merged(&a), a);
+    }
+
+    #[test]
+    fn aabb_transform_encloses_the_rotated_box() {
+        let aabb = Aabb::new(point(0.0, 0.0, 0.0), point(2.0, 1.0, 1.0));
+        let isometry = Isometry::new(vec(1.0, 0.0, 0.0), Vec3::y() * FRAC_PI_2);
+        let transformed = aabb.transform(&isometry);
+        // x goes to -z
+        assert_near(transformed.min, point(1.0, 0.0, -2.0));
+        assert_near(transformed.max, point(2.0, 1.0, 0.0));
+        // Rotating by 45 degrees grows the box to fit the corners
+        let isometry = Isometry::new(Vec3::zeros(), Vec3::z() * FRAC_PI_4);
+        let transformed = unit_cube().transform(&isometry);
+        let half = 2.0f32.sqrt();
+        assert_near(transformed.max, point(half, half, 1.0));
+        for corner in unit_cube().corners().iter() {
+            assert!(Aabb::new(transformed.min - Vec3::repeat(1e-5), transformed.max + Vec3::repeat(1e-5))
+                .contains_point(&(isometry * corner)));
+        }
+        let scale = Mat4::new_nonuniform_scaling(&vec(2.0, 3.0, 0.5));
+        let scaled = unit_cube().transform_matrix(&scale);
+        assert_near(scaled.max, point(2.0, 3.0, 0.5));
+        assert!(Aabb::empty().transform(&isometry).is_empty());
+    }
+
+    #[test]
+    fn sphere_and_obb_transforms() {
+        let sphere = Sphere::new(point(1.0, 0.0, 0.0), 2.0);
+        let isometry = Isometry::new(vec(0.0, 5.0, 0.0), Vec3::z() * FRAC_PI_2);
+        let moved = sphere.transform(&isometry);
+        assert_near(moved.center, point(0.0, 6.0, 0.0));
+        assert_eq!(moved.radius, 2.0);
+        // The largest scale bounds the stretched sphere
+        let scaled = sphere.transform_matrix(&Mat4::new_nonuniform_scaling(&vec(1.0, 3.0, 2.0)));
+        assert!((scaled.radius - 6.0).abs() < 1e-5);
+
+        let obb = Obb::from_aabb(&Aabb::new(point(0.0, 0.0, 0.0), point(2.0, 1.0, 4.0)));
+        let moved = obb.transform(&isometry);
+        let mut expected: Vec<Point> = obb.corners().iter().map(|c| isometry * c).collect();
+        for corner in moved.corners().iter() {
+            let index = expected.iter().position(|e| (e - corner).norm() < 1e-4).unwrap();
+            expected.remove(index);
+        }
+        let matrix = isometry.to_homogeneous() * Mat4::new_scaling(2.0);
+        let scaled = obb.transform_matrix(&matrix);
+        assert!((scaled.half_extents - obb.half_extents * 2.0).norm() < 1e-5);
+        assert_near(scaled.center, isometry * point(2.0, 1.0, 4.0));
+    }
+
+    #[test]
+    fn aabb_and_sphere_containment() {
+        let aabb = unit_cube();
+        assert!(aabb.contains_point(&point(1.0, -1.0, 0.0)));
+        assert!(!aabb.contains_point(&point(1.01, 0.0, 0.0)));
+        assert!(aabb.contains_aabb(&Aabb::new(point(0.0, 0.0, 0.0), point(1.0, 1.0, 1.0))));
+        assert!(!aabb.contains_aabb(&Aabb::new(point(0.0, 0.0, 0.0), point(1.5, 1.0, 1.0))));
+        // Touching faces intersect, a gap does not
+        assert!(aabb.intersects_aabb(&Aabb::new(point(1.0, 0.0, 0.0), point(2.0, 1.0, 1.0))));
+        assert!(!aabb.intersects_aabb(&Aabb::new(point(1.1, 0.0, 0.0), point(2.0, 1.0, 1.0))));
+        // A sphere off a corner is measured to the corner, not the faces
+        let corner_sphere = Sphere::new(point(2.0, 2.0, 2.0), 1.7);
+        assert!(!aabb.intersects_sphere(&corner_sphere));
+        assert!(aabb.intersects_sphere(&Sphere::new(point(2.0, 2.0, 2.0), 1.75)));
+        assert!(corner_sphere.intersects_sphere(&Sphere::new(point(0.0, 0.0, 0.0), 1.8)));
+        assert!(!corner_sphere.intersects_sphere(&Sphere::new(point(0.0, 0.0, 0.0), 1.7)));
+        assert!(corner_sphere.contains_point(&point(1.0, 2.0, 2.0)));
+    }
+
+    #[test]
+    fn obb_containment_and_sphere_overlap() {
+        let rotation = Rotation::from_axis_angle(&Vec3::z_axis(), FRAC_PI_4);
+        let axes = [rotation * Vec3::x(), rotation * Vec3::y(), Vec3::z()];
+        let obb = Obb::new(point(0.0, 0.0, 0.0), axes, vec(2.0, 0.5, 1.0));
+        assert!(obb.contains_point(&point(1.4, 1.4, 0.0)));
+        // Inside the world aligned box around it but outside the rotated one
+        assert!(!obb.contains_point(&point(1.4, -1.4, 0.0)));
+        assert!(obb.to_aabb().contains_point(&point(1.4, -1.4, 0.0)));
+        assert!(obb.intersects_sphere(&Sphere::new(point(1.4, -1.4, 0.0), 1.5)));
+        assert!(!obb.intersects_sphere(&Sphere::new(point(1.4, -1.4, 0.0), 1.4)));
+        assert!(obb.intersects_aabb(&Aabb::new(point(1.0, 1.0, -1.0), point(3.0, 3.0, 1.0))));
+        assert!(!obb.intersects_aabb(&Aabb::new(point(1.0, -3.0, -1.0), point(3.0, -1.0, 1.0))));
+    }
+
+    #[test]
+    fn obbs_separated_only_on_an_edge_axis() {
+        // `b` turns an edge towards the edge of the unit cube along z at x = y = 1.
+        // The face normals of both boxes overlap, only the cross product of the
+        // two edges separates them
+        let n = vec(1.0, 1.0, 0.0).normalize();
+        let edge = vec(1.0, -1.0, 0.0).normalize();
+        let axes = [edge, (n + Vec3::z()).normalize(), (n - Vec3::z()).normalize()];
+        let a = Obb::from_aabb(&unit_cube());
+        // Each box reaches sqrt(2) along `n`
+        let reach = 2.0 * 2.0f32.sqrt();
+        let b = Obb::new(Point::from_coordinates(n * (reach + 0.07)), axes, Vec3::repeat(1.0));
+        let face_separated = |axis: &Vec3| {
+            let radius = |obb: &Obb| {
+                (0..3)
+                    .map(|i| obb.half_extents[i] * obb.axes[i].dot(axis).abs())
+                    .sum::<f32>()
+            };
+            (b.center - a.center).dot(axis).abs() > radius(&a) + radius(&b)
+        };
+        assert!(!a.axes.iter().chain(b.axes.iter()).any(|axis| face_separated(axis)));
+        assert!(!a.intersects_obb(&b) && !b.intersects_obb(&a));
+
+        let b = Obb::new(Point::from_coordinates(n * (reach - 0.07)), axes, Vec3::repeat(1.0));
+        assert!(a.intersects_obb(&b) && b.intersects_obb(&a));
+        assert!(a.intersects_obb(&a));
+    }
+
+    #[test]
+    fn ray_queries_match_ray3() {
+        let mut rng = Lcg(3);
+        let aabb = Aabb::new(point(-1.0, -0.5, -2.0), point(1.5, 0.5, 1.0));
+        let sphere = Sphere::new(point(0.5, -0.5, 0.0), 1.5);
+        let rotation =
+            Quaternion::from_axis_angle(&Vec3::x_axis(), 0.7) * Quaternion::from_axis_angle(&Vec3::y_axis(), -0.4);
+        let isometry = Isometry::from_parts(Translation3::new(0.5, 0.0, -1.0), rotation);
+        let obb = Obb::from_aabb(&aabb).transform(&isometry);
+        let (mut aabb_hits, mut obb_hits) = (0, 0);
+        for ray in random_rays(&mut rng, 500) {
+            let expected = ray.intersect_aabb(aabb.min, aabb.max);
+            let hit = aabb.intersect_ray(&ray);
+            assert_eq!(hit.is_some(), expected.is_some());
+            if let (Some(hit), Some(expected)) = (hit, expected) {
+                assert_eq!(hit.distance, expected.distance);
+                aabb_hits += 1;
+            }
+            // An unrotated box gives the same hit as the slab test
+            match (Obb::from_aabb(&aabb).intersect_ray(&ray), expected) {
+                (Some(hit), Some(expected)) => {
+                    assert!((hit.distance - expected.distance).abs() < 1e-4);
+                    assert!((hit.normal - expected.normal).norm() < 1e-4);
+                }
+                (None, None) => {}
+                (hit, expected) => panic!("{:?} != {:?}", hit, expected),
+            }
+            // A rotated box is the slab test in its own space
+            let local = ray.transform(&isometry.inverse());
+            match (obb.intersect_ray(&ray), local.intersect_aabb(aabb.min, aabb.max)) {
+                (Some(hit), Some(expected)) => {
+                    assert!((hit.distance - expected.distance).abs() < 1e-4);
+                    assert_near(hit.point, isometry * expected.point);
+                    assert_near(hit.point, ray.point_at(hit.distance));
+                    obb_hits += 1;
+                }
+                (None, None) => {}
+                (hit, expected) => panic!("{:?} != {:?}", hit, expected),
+            }
+            let expected = ray.intersect_sphere(sphere.center, sphere.radius);
+            assert_eq!(sphere.intersect_ray(&ray).map(|h| h.distance), expected.map(|h| h.distance));
+        }
+        assert!(aabb_hits > 50 && obb_hits > 50);
+    }
+}
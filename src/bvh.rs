@@ -0,0 +1,648 @@
+use bounds::Aabb;
+use math::Point;
+use ray::{Ray3, RayHit};
+use std::f32;
+
+// Number of buckets centroids are binned into when evaluating split candidates
+const SAH_BINS: usize = 16;
+// Relative cost of visiting a node compared to intersecting a primitive
+const TRAVERSAL_COST: f32 = 1.0;
+const INTERSECTION_COST: f32 = 1.0;
+const MAX_LEAF_SIZE: usize = 4;
+
+#[derive(Debug, Clone, Copy)]
+struct Node {
+    bounds: Aabb,
+    // For interior nodes the index of the left child, the right child follows it.
+    // For leaves the first entry in `Bvh::indices`
+    first: u32,
+    // Zero for interior nodes
+    count: u32,
+}
+
+impl Node {
+    fn is_leaf(&self) -> bool {
+        self.count > 0
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct BvhHit {
+    pub primitive: usize,
+    pub hit: RayHit,
+}
+
+// The inverse direction is precomputed once per query for the slab tests
+struct RayData {
+    origin: Point,
+    inv_dir: [f32; 3],
+}
+
+impl RayData {
+    fn new(ray: &Ray3) -> Self {
+        let dir = ray.dir();
+        Self {
+            origin: ray.origin(),
+            inv_dir: [1.0 / dir.x, 1.0 / dir.y, 1.0 / dir.z],
+        }
+    }
+    // The entry distance into the box if it is entered before `max_distance`
+    fn entry(&self, aabb: &Aabb, max_distance: f32) -> Option<f32> {
+        let mut t_min = 0.0f32;
+        let mut t_max = max_distance;
+        for axis in 0..3 {
+            let inv = self.inv_dir[axis];
+            let mut near = (aabb.min[axis] - self.origin[axis]) * inv;
+            let mut far = (aabb.max[axis] - self.origin[axis]) * inv;
+            if near > far {
+                ::std::mem::swap(&mut near, &mut far);
+            }
+            // NaNs from 0 * inf fail these comparisons and leave the range untouched
+            if near > t_min {
+                t_min = near;
+            }
+            if far < t_max {
+                t_max = far;
+            }
+            if t_min > t_max {
+                return None;
+            }
+        }
+        Some(t_min)
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Bin {
+    bounds: Aabb,
+    count: usize,
+}
+
+// A bounding volume hierarchy over abstract primitives, built with a binned
+// surface area heuristic. The primitives themselves are owned by the caller,
+// see `TriangleBvh` and `ObjectBvh`
+#[derive(Debug, Clone)]
+pub struct Bvh {
+    nodes: Vec<Node>,
+    indices: Vec<u32>,
+}
+
+impl Bvh {
+    pub fn build(bounds: &[Aabb]) -> Self {
+        let centroids: Vec<Point> = bounds.iter().map(|b| b.center()).collect();
+        let mut bvh = Self {
+            nodes: Vec::with_capacity(bounds.len() * 2),
+            indices: (0..bounds.len() as u32).collect(),
+        };
+        let mut root_bounds = Aabb::empty();
+        for aabb in bounds {
+            root_bounds.merge(aabb);
+        }
+        bvh.nodes.push(Node {
+            bounds: root_bounds,
+            first: 0,
+            count: bounds.len() as u32,
+        });
+        // An explicit stack, degenerate input can make the tree as deep as there
+        // are primitives
+        let mut pending = Vec::new();
+        if !bounds.is_empty() {
+            pending.push(0);
+        }
+        while let Some(node_index) = pending.pop() {
+            if let Some(left) = bvh.split(node_index, bounds, &centroids) {
+                pending.push(left + 1);
+                pending.push(left);
+            }
+        }
+        bvh
+    }
+    // Turns a leaf into an interior node if the SAH says it is worth it and
+    // returns the index of its left child
+    fn split(&mut self, node_index: usize, bounds: &[Aabb], centroids: &[Point]) -> Option<usize> {
+        let node = self.nodes[node_index];
+        let first = node.first as usize;
+        let count = node.count as usize;
+        if count <= MAX_LEAF_SIZE {
+            return None;
+        }
+
+        let centroid_bounds = Aabb::from_points(
+            self.indices[first..first + count]
+                .iter()
+                .map(|&i| &centroids[i as usize]),
+        );
+        let axis = centroid_bounds.largest_axis();
+        let min = centroid_bounds.min[axis];
+        let extent = centroid_bounds.max[axis] - min;
+        if extent <= 0.0 {
+            return None;
+        }
+        let bin_of = |centroid: &Point| {
+            let bin = ((centroid[axis] - min) / extent * SAH_BINS as f32) as usize;
+            bin.min(SAH_BINS - 1)
+        };
+
+        let mut bins = [Bin {
+            bounds: Aabb::empty(),
+            count: 0,
+        }; SAH_BINS];
+        for &i in self.indices[first..first + count].iter() {
+            let bin = &mut bins[bin_of(&centroids[i as usize])];
+            bin.bounds.merge(&bounds[i as usize]);
+            bin.count += 1;
+        }
+
+        // Sweep from both ends to get the cost of splitting after every bin
+        let mut left_area = [0.0f32; SAH_BINS - 1];
+        let mut left_count = [0usize; SAH_BINS - 1];
+        let mut accumulated = Aabb::empty();
+        let mut total = 0;
+        for i in 0..SAH_BINS - 1 {
+            accumulated.merge(&bins[i].bounds);
+            total += bins[i].count;
+            left_area[i] = accumulated.surface_area();
+            left_count[i] = total;
+        }
+        let mut best_split = 0;
+        let mut best_cost = f32::MAX;
+        let mut accumulated = Aabb::empty();
+        let mut total = 0;
+        for i in (1..SAH_BINS).rev() {
+            accumulated.merge(&bins[i].bounds);
+            total += bins[i].count;
+            let cost = left_area[i - 1] * left_count[i - 1] as f32 + accumulated.surface_area() * total as f32;
+            if cost < best_cost {
+                best_cost = cost;
+                best_split = i;
+            }
+        }
+
+        let leaf_cost = count as f32 * INTERSECTION_COST;
+        let split_cost =
+            TRAVERSAL_COST + INTERSECTION_COST * best_cost / node.bounds.surface_area().max(f32::EPSILON);
+        if split_cost >= leaf_cost {
+            return None;
+        }
+
+        // Partition the indices in place around the chosen bin
+        let mut i = first;
+        let mut j = first + count;
+        while i < j {
+            if bin_of(&centroids[self.indices[i] as usize]) < best_split {
+                i += 1;
+            } else {
+                j -= 1;
+                self.indices.swap(i, j);
+            }
+        }
+        let left_count = i - first;
+        if left_count == 0 || left_count == count {
+            return None;
+        }
+
+        let child_bounds = |range: &[u32]| {
+            let mut aabb = Aabb::empty();
+            for &index in range {
+                aabb.merge(&bounds[index as usize]);
+            }
+            aabb
+        };
+        let left = Node {
+            bounds: child_bounds(&self.indices[first..i]),
+            first: first as u32,
+            count: left_count as u32,
+        };
+        let right = Node {
+            bounds: child_bounds(&self.indices[i..first + count]),
+            first: i as u32,
+            count: (count - left_count) as u32,
+        };
+        let left_index = self.nodes.len();
+        self.nodes.push(left);
+        self.nodes.push(right);
+        self.nodes[node_index].first = left_index as u32;
+        self.nodes[node_index].count = 0;
+        Some(left_index)
+    }
+    pub fn node_count(&self) -> usize {
+        self.nodes.len()
+    }
+    pub fn bounds(&self) -> Aabb {
+        self.nodes[0].bounds
+    }
+    // Recomputes every node's bounds from the primitives' new bounds without
+    // changing the tree. Quality degrades if the primitives move far apart
+    pub fn refit(&mut self, bounds: &[Aabb]) {
+        if self.indices.is_empty() {
+            return;
+        }
+        // Children are always stored after their parents
+        for node_index in (0..self.nodes.len()).rev() {
+            let node = self.nodes[node_index];
+            let mut aabb = Aabb::empty();
+            if node.is_leaf() {
+                let first = node.first as usize;
+                for &i in self.indices[first..first + node.count as usize].iter() {
+                    aabb.merge(&bounds[i as usize]);
+                }
+            } else {
+                let left = node.first as usize;
+                aabb.merge(&self.nodes[left].bounds);
+                aabb.merge(&self.nodes[left + 1].bounds);
+            }
+            self.nodes[node_index].bounds = aabb;
+        }
+    }
+    // Visits nodes front to back, `intersect` is called with primitive indices and
+    // returns the hit distance. Stops at the first hit if `any_hit` is set
+    fn traverse<F>(&self, ray: &Ray3, max_distance: f32, any_hit: bool, mut intersect: F) -> Option<BvhHit>
+    where
+        F: FnMut(usize) -> Option<RayHit>,
+    {
+        if self.indices.is_empty() {
+            return None;
+        }
+        let data = RayData::new(ray);
+        let mut closest: Option<BvhHit> = None;
+        let mut max_distance = max_distance;
+        let mut stack = Vec::with_capacity(64);
+        if data.entry(&self.nodes[0].bounds, max_distance).is_some() {
+            stack.push(0usize);
+        }
+        while let Some(node_index) = stack.pop() {
+            let node = &self.nodes[node_index];
+            if node.is_leaf() {
+                let first = node.first as usize;
+                for &i in self.indices[first..first + node.count as usize].iter() {
+                    if let Some(hit) = intersect(i as usize) {
+                        if hit.distance < max_distance {
+                            max_distance = hit.distance;
+                            closest = Some(BvhHit {
+                                primitive: i as usize,
+                                hit,
+                            });
+                            if any_hit {
+                                return closest;
+                            }
+                        }
+                    }
+                }
+                continue;
+            }
+            let left = node.first as usize;
+            let right = left + 1;
+            let left_entry = data.entry(&self.nodes[left].bounds, max_distance);
+            let right_entry = data.entry(&self.nodes[right].bounds, max_distance);
+            // Push the further child first so the nearer one is visited next
+            match (left_entry, right_entry) {
+                (Some(l), Some(r)) => {
+                    if l < r {
+                        stack.push(right);
+                        stack.push(left);
+                    } else {
+                        stack.push(left);
+                        stack.push(right);
+                    }
+                }
+                (Some(_), None) => stack.push(left),
+                (None, Some(_)) => stack.push(right),
+                (None, None) => (),
+            }
+        }
+        closest
+    }
+    pub fn closest_hit<F>(&self, ray: &Ray3, max_distance: f32, intersect: F) -> Option<BvhHit>
+    where
+        F: FnMut(usize) -> Option<RayHit>,
+    {
+        self.traverse(ray, max_distance, false, intersect)
+    }
+    // Returns whichever hit closer than `max_distance` is found first, cheaper
+    // than `closest_hit` for occlusion queries
+    pub fn any_hit<F>(&self, ray: &Ray3, max_distance: f32, intersect: F) -> Option<BvhHit>
+    where
+        F: FnMut(usize) -> Option<RayHit>,
+    {
+        self.traverse(ray, max_distance, true, intersect)
+    }
+}
+
+fn triangle_bounds(triangle: &[Point; 3]) -> Aabb {
+    Aabb::from_points(triangle.iter())
+}
+
+// A BVH over a triangle soup, primitive indices in hits are triangle indices
+pub struct TriangleBvh {
+    bvh: Bvh,
+    triangles: Vec<[Point; 3]>,
+}
+
+impl TriangleBvh {
+    pub fn new(triangles: Vec<[Point; 3]>) -> Self {
+        let bounds: Vec<Aabb> = triangles.iter().map(triangle_bounds).collect();
+        Self {
+            bvh: Bvh::build(&bounds),
+            triangles,
+        }
+    }
+    // Builds from an indexed triangle list, three indices per triangle
+    pub fn from_indexed(vertices: &[Point], indices: &[u32]) -> Self {
+        let triangles = indices
+            .chunks(3)
+            .filter(|tri| tri.len() == 3)
+            .map(|tri| {
+                [
+                    vertices[tri[0] as usize],
+                    vertices[tri[1] as usize],
+                    vertices[tri[2] as usize],
+                ]
+            })
+            .collect();
+        Self::new(triangles)
+    }
+    pub fn bvh(&self) -> &Bvh {
+        &self.bvh
+    }
+    pub fn triangles(&self) -> &[[Point; 3]] {
+        &self.triangles
+    }
+    // Replaces the triangle positions (same count and order) and refits the tree
+    pub fn refit(&mut self, triangles: Vec<[Point; 3]>) {
+        assert_eq!(triangles.len(), self.triangles.len());
+        self.triangles = triangles;
+        let bounds: Vec<Aabb> = self.triangles.iter().map(triangle_bounds).collect();
+        self.bvh.refit(&bounds);
+    }
+    pub fn closest_hit(&self, ray: &Ray3) -> Option<BvhHit> {
+        let triangles = &self.triangles;
+        self.bvh.closest_hit(ray, f32::MAX, |i| {
+            let triangle = &triangles[i];
+            ray.intersect_triangle(triangle[0], triangle[1], triangle[2])
+        })
+    }
+    pub fn any_hit(&self, ray: &Ray3, max_distance: f32) -> Option<BvhHit> {
+        let triangles = &self.triangles;
+        self.bvh.any_hit(ray, max_distance, |i| {
+            let triangle = &triangles[i];
+            ray.intersect_triangle(triangle[0], triangle[1], triangle[2])
+        })
+    }
+}
+
+// A BVH over object bounds. Hits are against the bounds only, use
+// `closest_hit_with` to refine them against the objects' own geometry
+pub struct ObjectBvh {
+    bvh: Bvh,
+    bounds: Vec<Aabb>,
+}
+
+impl ObjectBvh {
+    pub fn new(bounds: Vec<Aabb>) -> Self {
+        Self {
+            bvh: Bvh::build(&bounds),
+            bounds,
+        }
+    }
+    pub fn bvh(&self) -> &Bvh {
+        &self.bvh
+    }
+    pub fn bounds(&self) -> &[Aabb] {
+        &self.bounds
+    }
+    pub fn refit(&mut self, bounds: Vec<Aabb>) {
+        assert_eq!(bounds.len(), self.bounds.len());
+        self.bounds = bounds;
+        self.bvh.refit(&self.bounds);
+    }
+    pub fn closest_hit(&self, ray: &Ray3) -> Option<BvhHit> {
+        let bounds = &self.bounds;
+        self.bvh.closest_hit(ray, f32::MAX, |i| bounds[i].intersect_ray(ray))
+    }
+    pub fn any_hit(&self, ray: &Ray3, max_distance: f32) -> Option<BvhHit> {
+        let bounds = &self.bounds;
+        self.bvh.any_hit(ray, max_distance, |i| bounds[i].intersect_ray(ray))
+    }
+    // `intersect` is given an object index and the ray and tests the object's geometry
+    pub fn closest_hit_with<F>(&self, ray: &Ray3, mut intersect: F) -> Option<BvhHit>
+    where
+        F: FnMut(usize, &Ray3) -> Option<RayHit>,
+    {
+        let bounds = &self.bounds;
+        self.bvh.closest_hit(ray, f32::MAX, |i| {
+            bounds[i].intersect_ray(ray)?;
+            intersect(i, ray)
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use math::Vec3;
+
+    // A small linear congruential generator so the meshes are the same every run
+    struct Lcg(u32);
+
+    impl Lcg {
+        fn next(&mut self) -> f32 {
+            self.0 = self.0.wrapping_mul(1_664_525).wrapping_add(1_013_904_223);
+            (self.0 >> 8) as f32 / (1 << 24) as f32
+        }
+        fn range(&mut self, min: f32, max: f32) -> f32 {
+            min + self.next() * (max - min)
+        }
+        fn point(&mut self, extent: f32) -> Point {
+            Point::new(
+                self.range(-extent, extent),
+                self.range(-extent, extent),
+                self.range(-extent, extent),
+            )
+        }
+    }
+
+    fn random_triangles(rng: &mut Lcg, count: usize) -> Vec<[Point; 3]> {
+        (0..count)
+            .map(|_| {
+                let center = rng.point(20.0);
+                [
+                    center + rng.point(1.0).coords,
+                    center + rng.point(1.0).coords,
+                    center + rng.point(1.0).coords,
+                ]
+            })
+            .collect()
+    }
+
+    fn random_rays(rng: &mut Lcg, count: usize) -> Vec<Ray3> {
+        (0..count)
+            .map(|_| {
+                let origin = rng.point(30.0);
+                // Aim near the middle so most rays go through the mesh
+                let target = rng.point(10.0);
+                Ray3::new(origin, target - origin)
+            })
+            .collect()
+    }
+
+    fn brute_force(triangles: &[[Point; 3]], ray: &Ray3) -> Option<BvhHit> {
+        let mut closest: Option<BvhHit> = None;
+        for (primitive, triangle) in triangles.iter().enumerate() {
+            if let Some(hit) = ray.intersect_triangle(triangle[0], triangle[1], triangle[2]) {
+                if closest.map_or(true, |closest| hit.distance < closest.hit.distance) {
+                    closest = Some(BvhHit { primitive, hit });
+                }
+            }
+        }
+        closest
+    }
+
+    fn assert_same_hit(triangles: &[[Point; 3]], ray: &Ray3, bvh: Option<BvhHit>, expected: Option<BvhHit>) {
+        match (bvh, expected) {
+            (None, None) => {}
+            (Some(bvh), Some(expected)) => {
+                // Overlapping triangles can tie, either one is correct then
+                assert_eq!(bvh.hit.distance, expected.hit.distance);
+                let triangle = &triangles[bvh.primitive];
+                let direct = ray.intersect_triangle(triangle[0], triangle[1], triangle[2]).unwrap();
+                assert_eq!(direct.distance, expected.hit.distance);
+            }
+            (bvh, expected) => panic!("bvh hit {:?}, brute force hit {:?}", bvh, expected),
+        }
+    }
+
+    fn depth(bvh: &Bvh) -> usize {
+        let mut deepest = 0;
+        let mut pending = vec![(0, 1)];
+        while let Some((node_index, depth)) = pending.pop() {
+            let node = bvh.nodes[node_index];
+            deepest = deepest.max(depth);
+            if !node.is_leaf() {
+                pending.push((node.first as usize, depth + 1));
+                pending.push((node.first as usize + 1, depth + 1));
+            }
+        }
+        deepest
+    }
+
+    #[test]
+    fn closest_hit_matches_brute_force() {
+        let mut rng = Lcg(1);
+        let triangles = random_triangles(&mut rng, 2000);
+        let bvh = TriangleBvh::new(triangles.clone());
+        let mut hits = 0;
+        for ray in random_rays(&mut rng, 500) {
+            let expected = brute_force(&triangles, &ray);
+            hits += expected.is_some() as usize;
+            assert_same_hit(&triangles, &ray, bvh.closest_hit(&ray), expected);
+        }
+        // Otherwise the comparison says little
+        assert!(hits > 100, "only {} rays hit", hits);
+    }
+
+    #[test]
+    fn any_hit_matches_brute_force() {
+        let mut rng = Lcg(2);
+        let triangles = random_triangles(&mut rng, 2000);
+        let bvh = TriangleBvh::new(triangles.clone());
+        for ray in random_rays(&mut rng, 500) {
+            let max_distance = rng.range(5.0, 40.0);
+            let expected = brute_force(&triangles, &ray).filter(|hit| hit.hit.distance <= max_distance);
+            match bvh.any_hit(&ray, max_distance) {
+                Some(hit) => {
+                    assert!(expected.is_some());
+                    assert!(hit.hit.distance <= max_distance);
+                    let triangle = &triangles[hit.primitive];
+                    let direct = ray.intersect_triangle(triangle[0], triangle[1], triangle[2]).unwrap();
+                    assert_eq!(direct.distance, hit.hit.distance);
+                }
+                None => assert!(expected.is_none()),
+            }
+        }
+    }
+
+    #[test]
+    fn closest_hit_matches_brute_force_after_refit() {
+        let mut rng = Lcg(3);
+        let triangles = random_triangles(&mut rng, 1000);
+        let mut bvh = TriangleBvh::new(triangles);
+        let offset = Vec3::new(3.0, -2.0, 1.0);
+        let moved: Vec<[Point; 3]> = random_triangles(&mut rng, 1000)
+            .iter()
+            .map(|t| [t[0] + offset, t[1] + offset, t[2] + offset])
+            .collect();
+        bvh.refit(moved.clone());
+        for ray in random_rays(&mut rng, 300) {
+            assert_same_hit(&moved, &ray, bvh.closest_hit(&ray), brute_force(&moved, &ray));
+        }
+    }
+
+    #[test]
+    fn indexed_height_field_matches_brute_force() {
+        const GRID: usize = 32;
+        let mut vertices = Vec::new();
+        for z in 0..GRID + 1 {
+            for x in 0..GRID + 1 {
+                let (fx, fz) = (x as f32, z as f32);
+                vertices.push(Point::new(fx, (fx * 0.3).sin() + (fz * 0.2).cos(), fz));
+            }
+        }
+        let row = (GRID + 1) as u32;
+        let mut indices = Vec::new();
+        for z in 0..GRID {
+            for x in 0..GRID {
+                let i = z as u32 * row + x as u32;
+                indices.extend_from_slice(&[i, i + row, i + 1, i + 1, i + row, i + row + 1]);
+            }
+        }
+        let bvh = TriangleBvh::from_indexed(&vertices, &indices);
+        assert_eq!(bvh.triangles().len(), GRID * GRID * 2);
+        let mut rng = Lcg(4);
+        for _ in 0..500 {
+            let origin = Point::new(rng.range(-5.0, 37.0), 10.0, rng.range(-5.0, 37.0));
+            let target = Point::new(rng.range(0.0, 32.0), 0.0, rng.range(0.0, 32.0));
+            let ray = Ray3::new(origin, target - origin);
+            assert_same_hit(
+                bvh.triangles(),
+                &ray,
+                bvh.closest_hit(&ray),
+                brute_force(bvh.triangles(), &ray),
+            );
+        }
+    }
+
+    #[test]
+    fn identical_triangles_stay_in_one_leaf() {
+        let triangle = [
+            Point::new(0.0, 0.0, 0.0),
+            Point::new(1.0, 0.0, 0.0),
+            Point::new(0.0, 1.0, 0.0),
+        ];
+        let bvh = TriangleBvh::new(vec![triangle; 1000]);
+        assert_eq!(bvh.bvh().node_count(), 1);
+        let ray = Ray3::new(Point::new(0.25, 0.25, 1.0), Vec3::new(0.0, 0.0, -1.0));
+        assert_eq!(bvh.closest_hit(&ray).unwrap().hit.distance, 1.0);
+    }
+
+    #[test]
+    fn exponential_spacing_builds_a_deep_tree() {
+        // Every split only peels the last few primitives off the end so the tree
+        // ends up far deeper than the log2 of the count
+        let bounds: Vec<Aabb> = (0..800)
+            .map(|i| {
+                let x = 1.1f32.powi(i);
+                Aabb::new(Point::new(x, 0.0, 0.0), Point::new(x * 1.01, 1.0, 1.0))
+            })
+            .collect();
+        let bvh = ObjectBvh::new(bounds.clone());
+        let depth = depth(bvh.bvh());
+        assert!(depth > 20, "depth {}", depth);
+        for (i, aabb) in bounds.iter().enumerate().step_by(37) {
+            let center = aabb.center();
+            let ray = Ray3::new(Point::new(center.x, 5.0, 0.5), Vec3::new(0.0, -1.0, 0.0));
+            let hit = bvh.closest_hit(&ray).unwrap();
+            assert_eq!(hit.primitive, i);
+            assert_eq!(hit.hit.distance, 4.0);
+        }
+    }
+}
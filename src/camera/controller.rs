@@ -0,0 +1,549 @@
+use camera::Camera;
+use math;
+use math::{Projection, Vec3};
+use std::collections::HashSet;
+use std::f32::consts::PI;
+use winit::{DeviceEvent, ElementState, Event, KeyboardInput, MouseButton, MouseScrollDelta,
+            VirtualKeyCode, WindowEvent};
+
+// Just short of straight up or down so the look direction never lines up with `up`
+const MAX_PITCH: f32 = PI * 0.5 - 0.01;
+
+pub trait CameraController {
+    // Feed every winit event through here before calling `update`
+    fn handle_event(&mut self, event: &Event);
+    // Applies the input gathered since the last update, `dt` is in seconds
+    fn update<T: Projection>(&mut self, camera: &mut Camera<T>, dt: f32);
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct KeyBindings {
+    pub forward: VirtualKeyCode,
+    pub back: VirtualKeyCode,
+    pub left: VirtualKeyCode,
+    pub right: VirtualKeyCode,
+    pub up: VirtualKeyCode,
+    pub down: VirtualKeyCode,
+    pub fast: VirtualKeyCode,
+    // Only used by the fly controller
+    pub roll_left: VirtualKeyCode,
+    pub roll_right: VirtualKeyCode,
+}
+
+impl Default for KeyBindings {
+    fn default() -> Self {
+        Self {
+            forward: VirtualKeyCode::W,
+            back: VirtualKeyCode::S,
+            left: VirtualKeyCode::A,
+            right: VirtualKeyCode::D,
+            up: VirtualKeyCode::Space,
+            down: VirtualKeyCode::LShift,
+            fast: VirtualKeyCode::LControl,
+            roll_left: VirtualKeyCode::Q,
+            roll_right: VirtualKeyCode::E,
+        }
+    }
+}
+
+// The raw input accumulated between updates
+#[derive(Debug, Default)]
+pub struct InputState {
+    keys: HashSet<VirtualKeyCode>,
+    buttons: HashSet<MouseButton>,
+    cursor: Option<(f32, f32)>,
+    // Cursor movement while a button is held, in pixels
+    drag: (f32, f32),
+    // Raw mouse motion reported by the device
+    motion: (f32, f32),
+    scroll: f32,
+}
+
+impl InputState {
+    pub fn handle_event(&mut self, event: &Event) {
+        match *event {
+            Event::WindowEvent { ref event, .. } => match *event {
+                WindowEvent::KeyboardInput {
+                    input: KeyboardInput {
+                        state,
+                        virtual_keycode: Some(key),
+                        ..
+                    },
+                    ..
+                } => match state {
+                    ElementState::Pressed => {
+                        self.keys.insert(key);
+                    }
+                    ElementState::Released => {
+                        self.keys.remove(&key);
+                    }
+                },
+                WindowEvent::MouseInput { state, button, .. } => match state {
+                    ElementState::Pressed => {
+                        self.buttons.insert(button);
+                    }
+                    ElementState::Released => {
+                        self.buttons.remove(&button);
+                    }
+                },
+                WindowEvent::CursorMoved { position, .. } => {
+                    let position = (position.0 as f32, position.1 as f32);
+                    if let Some(last) = self.cursor {
+                        if !self.buttons.is_empty() {
+                            self.drag.0 += position.0 - last.0;
+                            self.drag.1 += position.1 - last.1;
+                        }
+                    }
+                    self.cursor = Some(position);
+                }
+                WindowEvent::CursorLeft { .. } => {
+                    self.cursor = None;
+                }
+                WindowEvent::MouseWheel { delta, .. } => match delta {
+                    MouseScrollDelta::LineDelta(_, y) => self.scroll += y,
+                    // Roughly one line per 20 pixels
+                    MouseScrollDelta::PixelDelta(_, y) => self.scroll += y / 20.0,
+                },
+                WindowEvent::Focused(false) => {
+                    self.keys.clear();
+                    self.buttons.clear();
+                }
+                _ => (),
+            },
+            Event::DeviceEvent {
+                event: DeviceEvent::MouseMotion { delta },
+                ..
+            } => {
+                self.motion.0 += delta.0 as f32;
+                self.motion.1 += delta.1 as f32;
+            }
+            _ => (),
+        }
+    }
+    pub fn is_key_down(&self, key: VirtualKeyCode) -> bool {
+        self.keys.contains(&key)
+    }
+    pub fn is_button_down(&self, button: MouseButton) -> bool {
+        self.buttons.contains(&button)
+    }
+    // Returns the cursor drag, mouse motion and scroll since the last call and resets them
+    pub fn take_deltas(&mut self) -> ((f32, f32), (f32, f32), f32) {
+        let deltas = (self.drag, self.motion, self.scroll);
+        self.drag = (0.0, 0.0);
+        self.motion = (0.0, 0.0);
+        self.scroll = 0.0;
+        deltas
+    }
+    // -1, 0 or 1 depending on which of the two keys are held
+    fn axis(&self, positive: VirtualKeyCode, negative: VirtualKeyCode) -> f32 {
+        let mut value = 0.0;
+        if self.is_key_down(positive) {
+            value += 1.0;
+        }
+        if self.is_key_down(negative) {
+            value -= 1.0;
+        }
+        value
+    }
+}
+
+// Yaw and pitch angles relative to an up direction
+#[derive(Debug, Clone, Copy)]
+struct YawPitch {
+    yaw: f32,
+    pitch: f32,
+}
+
+impl YawPitch {
+    fn from_dir(dir: Vec3, up: Vec3) -> Self {
+        let (forward, right) = math::perpendicular_basis(up);
+        let dir = dir.normalize();
+        let pitch = dir.dot(&up).max(-1.0).min(1.0).asin();
+        let yaw = dir.dot(&right).atan2(dir.dot(&forward));
+        Self {
+            yaw,
+            pitch: pitch.max(-MAX_PITCH).min(MAX_PITCH),
+        }
+    }
+    // Reuses the angles a controller left the camera with, or derives them from
+    // `dir` again if something else has turned the camera since
+    fn resume(last: Option<(YawPitch, Vec3)>, dir: Vec3, up: Vec3) -> Self {
+        let dir = dir.normalize();
+        match last {
+            Some((angles, last_dir)) if (last_dir - dir).norm() < 1e-4 => angles,
+            _ => Self::from_dir(dir, up),
+        }
+    }
+    fn rotate(&mut self, yaw: f32, pitch: f32) {
+        self.yaw = (self.yaw + yaw) % (2.0 * PI);
+        self.pitch = (self.pitch + pitch).max(-MAX_PITCH).min(MAX_PITCH);
+    }
+    fn dir(&self, up: Vec3) -> Vec3 {
+        let (forward, right) = math::perpendicular_basis(up);
+        let horizontal = forward * self.yaw.cos() + right * self.yaw.sin();
+        horizontal * self.pitch.cos() + up * self.pitch.sin()
+    }
+    // The look direction flattened onto the plane perpendicular to `up`
+    fn horizontal_dir(&self, up: Vec3) -> Vec3 {
+        let (forward, right) = math::perpendicular_basis(up);
+        forward * self.yaw.cos() + right * self.yaw.sin()
+    }
+}
+
+// Rotates the eye around the target while dragging with the left mouse button,
+// pans with the middle or right button and zooms with the scroll wheel
+pub struct OrbitController {
+    input: InputState,
+    // The angles from the last update and the direction they gave the camera
+    angles: Option<(YawPitch, Vec3)>,
+    // Radians per pixel dragged
+    pub rotate_speed: f32,
+    // Fraction of the distance to the target moved per pixel dragged
+    pub pan_speed: f32,
+    // Fraction of the distance to the target moved per scroll line
+    pub zoom_speed: f32,
+    pub min_distance: f32,
+    pub max_distance: f32,
+}
+
+impl OrbitController {
+    pub fn new() -> Self {
+        Self {
+            input: InputState::default(),
+            angles: None,
+            rotate_speed: 0.005,
+            pan_speed: 0.001,
+            zoom_speed: 0.1,
+            min_distance: 0.01,
+            max_distance: 10000.0,
+        }
+    }
+}
+
+impl Default for OrbitController {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl CameraController for OrbitController {
+    fn handle_event(&mut self, event: &Event) {
+        self.input.handle_event(event);
+    }
+    fn update<T: Projection>(&mut self, camera: &mut Camera<T>, _dt: f32) {
+        let (drag, _, scroll) = self.input.take_deltas();
+        let up = camera.up_dir().unwrap();
+        let target = camera.target();
+        let offset = camera.eye() - target;
+        let mut distance = offset.norm();
+        let mut angles = YawPitch::resume(self.angles, offset, up);
+
+        if self.input.is_button_down(MouseButton::Left) {
+            angles.rotate(-drag.0 * self.rotate_speed, drag.1 * self.rotate_speed);
+        } else if self.input.is_button_down(MouseButton::Middle)
+            || self.input.is_button_down(MouseButton::Right)
+        {
+            let right = camera.right_dir().unwrap();
+            let pan_up = math::cross(right, camera.look_dir().unwrap());
+            let pan = (right * -drag.0 + pan_up * drag.1) * self.pan_speed * distance;
+            camera.move_eye_and_target_by(pan);
+        }
+
+        distance *= (1.0 - self.zoom_speed).powf(scroll);
+        distance = distance.max(self.min_distance).min(self.max_distance);
+
+        let target = camera.target();
+        camera.move_eye_to(target + angles.dir(up) * distance);
+        self.angles = Some((angles, (camera.eye() - target).normalize()));
+    }
+}
+
+// Mouse look with clamped pitch and movement restricted to the plane perpendicular to `up`
+pub struct FirstPersonController {
+    input: InputState,
+    // The angles from the last update and the direction they gave the camera
+    angles: Option<(YawPitch, Vec3)>,
+    pub bindings: KeyBindings,
+    // Radians per unit of mouse motion
+    pub look_speed: f32,
+    // World units per second
+    pub move_speed: f32,
+    pub fast_multiplier: f32,
+}
+
+impl FirstPersonController {
+    pub fn new() -> Self {
+        Self {
+            input: InputState::default(),
+            angles: None,
+            bindings: KeyBindings::default(),
+            look_speed: 0.002,
+            move_speed: 5.0,
+            fast_multiplier: 4.0,
+        }
+    }
+}
+
+impl Default for FirstPersonController {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl CameraController for FirstPersonController {
+    fn handle_event(&mut self, event: &Event) {
+        self.input.handle_event(event);
+    }
+    fn update<T: Projection>(&mut self, camera: &mut Camera<T>, dt: f32) {
+        let (_, motion, _) = self.input.take_deltas();
+        let up = camera.up_dir().unwrap();
+        let mut angles = YawPitch::resume(self.angles, camera.look_dir().unwrap(), up);
+        angles.rotate(motion.0 * self.look_speed, -motion.1 * self.look_speed);
+
+        let forward = angles.horizontal_dir(up);
+        let right = math::cross(forward, up);
+        let b = self.bindings;
+        let mut movement = forward * self.input.axis(b.forward, b.back)
+            + right * self.input.axis(b.right, b.left)
+            + up * self.input.axis(b.up, b.down);
+        if movement.norm() > 0.0 {
+            movement = movement.normalize();
+        }
+        let mut speed = self.move_speed * dt;
+        if self.input.is_key_down(b.fast) {
+            speed *= self.fast_multiplier;
+        }
+
+        camera.move_eye_by(movement * speed);
+        let eye = camera.eye();
+        camera.look_at(eye + angles.dir(up));
+        self.angles = Some((angles, camera.look_dir().unwrap()));
+    }
+}
+
+// Like the first person controller but moves along the look direction, so
+// looking up and moving forward climbs. The roll keys tilt the view around the
+// look direction, mouse look and movement stay relative to the world's up
+pub struct FlyController {
+    input: InputState,
+    // The angles from the last update and the direction they gave the camera
+    angles: Option<(YawPitch, Vec3)>,
+    pub bindings: KeyBindings,
+    pub look_speed: f32,
+    pub move_speed: f32,
+    pub fast_multiplier: f32,
+    // Radians per second
+    pub roll_speed: f32,
+}
+
+impl FlyController {
+    pub fn new() -> Self {
+        Self {
+            input: InputState::default(),
+            angles: None,
+            bindings: KeyBindings::default(),
+            look_speed: 0.002,
+            move_speed: 10.0,
+            fast_multiplier: 4.0,
+            roll_speed: 1.5,
+        }
+    }
+}
+
+impl Default for FlyController {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl CameraController for FlyController {
+    fn handle_event(&mut self, event: &Event) {
+        self.input.handle_event(event);
+    }
+    fn update<T: Projection>(&mut self, camera: &mut Camera<T>, dt: f32) {
+        let (_, motion, scroll) = self.input.take_deltas();
+        let up = camera.up_dir().unwrap();
+        let mut angles = YawPitch::resume(self.angles, camera.look_dir().unwrap(), up);
+        angles.rotate(motion.0 * self.look_speed, -motion.1 * self.look_speed);
+        // Scrolling adjusts the flying speed
+        self.move_speed = (self.move_speed * 1.1f32.powf(scroll)).max(0.01);
+
+        let forward = angles.dir(up);
+        let right = math::cross(forward, up).normalize();
+        let b = self.bindings;
+        let mut movement = forward * self.input.axis(b.forward, b.back)
+            + right * self.input.axis(b.right, b.left)
+            + up * self.input.axis(b.up, b.down);
+        if movement.norm() > 0.0 {
+            movement = movement.normalize();
+        }
+        let mut speed = self.move_speed * dt;
+        if self.input.is_key_down(b.fast) {
+            speed *= self.fast_multiplier;
+        }
+
+        camera.move_eye_by(movement * speed);
+        let eye = camera.eye();
+        camera.look_at(eye + forward);
+        camera.roll_by(self.input.axis(b.roll_right, b.roll_left) * self.roll_speed * dt);
+        self.angles = Some((angles, camera.look_dir().unwrap()));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use math::{Perspective, Point};
+
+    fn camera() -> Camera<Perspective> {
+        Camera::new(
+            Point::new(0.0, 0.0, 5.0),
+            Point::new(0.0, 0.0, 0.0),
+            Vec3::y(),
+            Perspective::new(1.0, 1.0, 0.1, 100.0),
+        )
+    }
+
+    #[test]
+    fn orbit_follows_a_camera_moved_elsewhere() {
+        let mut camera = camera();
+        let mut orbit = OrbitController::default();
+        orbit.update(&mut camera, 0.016);
+        assert!((camera.eye() - Point::new(0.0, 0.0, 5.0)).norm() < 1e-4);
+
+        camera.move_eye_to(Point::new(3.0, 4.0, 0.0));
+        orbit.update(&mut camera, 0.016);
+        assert!((camera.eye() - Point::new(3.0, 4.0, 0.0)).norm() < 1e-4);
+    }
+
+    #[test]
+    fn first_person_and_fly_follow_a_camera_turned_elsewhere() {
+        let mut camera = camera();
+        let mut first_person = FirstPersonController::default();
+        let mut fly = FlyController::default();
+        first_person.update(&mut camera, 0.016);
+        fly.update(&mut camera, 0.016);
+
+        camera.look_at(Point::new(5.0, 2.0, 5.0));
+        let look = camera.look_dir().unwrap();
+        first_person.update(&mut camera, 0.016);
+        assert!((camera.look_dir().unwrap() - look).norm() < 1e-4);
+        fly.update(&mut camera, 0.016);
+        assert!((camera.look_dir().unwrap() - look).norm() < 1e-4);
+    }
+
+    #[test]
+    fn orbit_drag_keeps_the_distance_to_the_target() {
+        let mut camera = camera();
+        let mut orbit = OrbitController::default();
+        orbit.input.buttons.insert(MouseButton::Left);
+        for &drag in [(120.0, 0.0), (0.0, 80.0), (-300.0, -200.0)].iter() {
+            let eye = camera.eye();
+            orbit.input.drag = drag;
+            orbit.update(&mut camera, 0.016);
+            assert!((camera.eye() - eye).norm() > 0.1);
+            assert!(((camera.eye() - camera.target()).norm() - 5.0).abs() < 1e-4);
+            assert!((camera.look_dir().unwrap() - (camera.target() - camera.eye()).normalize()).norm() < 1e-4);
+        }
+        // Dragging far up stops short of looking straight down
+        orbit.input.drag = (0.0, 10000.0);
+        orbit.update(&mut camera, 0.016);
+        assert!(camera.look_dir().unwrap().dot(&Vec3::y()).abs() < 1.0 - 1e-5);
+    }
+
+    #[test]
+    fn orbit_zoom_clamps_the_distance() {
+        let mut camera = camera();
+        let mut orbit = OrbitController::default();
+        orbit.min_distance = 1.0;
+        orbit.max_distance = 20.0;
+        orbit.input.scroll = 1.0;
+        orbit.update(&mut camera, 0.016);
+        assert!(((camera.eye() - camera.target()).norm() - 4.5).abs() < 1e-4);
+        orbit.input.scroll = 100.0;
+        orbit.update(&mut camera, 0.016);
+        assert!(((camera.eye() - camera.target()).norm() - 1.0).abs() < 1e-4);
+        orbit.input.scroll = -100.0;
+        orbit.update(&mut camera, 0.016);
+        assert!(((camera.eye() - camera.target()).norm() - 20.0).abs() < 1e-3);
+        // Zooming moves along the same direction
+        assert!((camera.look_dir().unwrap() - -Vec3::z()).norm() < 1e-4);
+    }
+
+    #[test]
+    fn first_person_pitch_stops_short_of_the_poles() {
+        for &motion in [-1e5, 1e5].iter() {
+            let mut camera = camera();
+            let mut first_person = FirstPersonController::default();
+            first_person.input.motion = (0.0, motion);
+            first_person.update(&mut camera, 0.016);
+            let look = camera.look_dir().unwrap();
+            let pitch = look.dot(&Vec3::y()).asin();
+            assert!((pitch.abs() - MAX_PITCH).abs() < 1e-3, "{}", pitch);
+            // Moving the mouse up looks up
+            assert_eq!(pitch < 0.0, motion > 0.0);
+            assert!(camera.camera_up().unwrap().dot(&Vec3::y()) > 0.0);
+        }
+    }
+
+    #[test]
+    fn first_person_moves_in_the_yaw_plane() {
+        let mut camera = camera();
+        let mut first_person = FirstPersonController::default();
+        // Look down and to the side, walking forward must not sink
+        first_person.input.motion = (300.0, 200.0);
+        first_person.update(&mut camera, 0.016);
+        let look = camera.look_dir().unwrap();
+        assert!(look.y < -0.3);
+        let keys = [
+            (VirtualKeyCode::W, 1.0, 0.0),
+            (VirtualKeyCode::S, -1.0, 0.0),
+            (VirtualKeyCode::D, 0.0, 1.0),
+            (VirtualKeyCode::A, 0.0, -1.0),
+        ];
+        let forward = Vec3::new(look.x, 0.0, look.z).normalize();
+        let right = math::cross(forward, Vec3::y());
+        for &(key, ahead, side) in keys.iter() {
+            let eye = camera.eye();
+            first_person.input.keys.insert(key);
+            first_person.update(&mut camera, 0.5);
+            first_person.input.keys.remove(&key);
+            let moved = camera.eye() - eye;
+            let expected = (forward * ahead + right * side) * first_person.move_speed * 0.5;
+            assert!((moved - expected).norm() < 1e-3, "{:?}: {:?} != {:?}", key, moved, expected);
+            // The view is untouched by walking
+            assert!((camera.look_dir().unwrap() - look).norm() < 1e-4);
+        }
+        first_person.input.keys.insert(VirtualKeyCode::Space);
+        let eye = camera.eye();
+        first_person.update(&mut camera, 0.5);
+        assert!((camera.eye() - eye - Vec3::y() * first_person.move_speed * 0.5).norm() < 1e-3);
+    }
+
+    #[test]
+    fn fly_roll_tilts_the_camera_up() {
+        let mut camera = camera();
+        let mut fly = FlyController::default();
+        fly.input.keys.insert(VirtualKeyCode::E);
+        fly.update(&mut camera, 0.5);
+        let roll = fly.roll_speed * 0.5;
+        assert!((camera.roll() - roll).abs() < 1e-5);
+        // Rolling right tips the top of the view to the right, the look direction stays
+        let up = camera.camera_up().unwrap();
+        assert!((up - Vec3::new(roll.sin(), roll.cos(), 0.0)).norm() < 1e-4);
+        assert!((camera.look_dir().unwrap() - -Vec3::z()).norm() < 1e-4);
+
+        fly.input.keys.clear();
+        fly.input.keys.insert(VirtualKeyCode::Q);
+        fly.update(&mut camera, 0.5);
+        assert!(camera.roll().abs() < 1e-5);
+        assert!((camera.camera_up().unwrap() - Vec3::y()).norm() < 1e-4);
+        // Turning the mouse keeps the roll
+        fly.input.keys.clear();
+        camera.set_roll(0.4);
+        fly.input.motion = (200.0, 0.0);
+        fly.update(&mut camera, 0.016);
+        assert!((camera.roll() - 0.4).abs() < 1e-5);
+        assert!((camera.look_dir().unwrap() - -Vec3::z()).norm() > 0.1);
+    }
+}
@@ -0,0 +1,459 @@
+use math;
+use math::{ClipSpace, Isometry, Mat4, Orthographic, Perspective, Point, Projection, Quaternion,
+           ReverseZPerspective, Rotation, Vec3};
+use nalgebra::{Translation3, Unit};
+use ray::Ray3;
+use std::f32::consts::PI;
+
+pub mod controller;
+pub mod path;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Visibility {
+    Visible,
+    // In front of the camera but outside the view volume
+    OffScreen,
+    BehindCamera,
+}
+
+// A world space point projected onto the screen. `pixel` is measured from the
+// top left corner of the viewport and is mirrored for points behind the camera
+#[derive(Debug, Clone, Copy)]
+pub struct ScreenPoint {
+    pub ndc: Point,
+    pub pixel: [f32; 2],
+    pub visibility: Visibility,
+}
+
+// Anything shorter than this is treated as a zero length vector
+const EPSILON: f32 = 1e-6;
+
+pub struct Camera<T: Projection> {
+    eye: Point,
+    target: Point,
+    // The world's up direction, the camera's own up direction comes from `frame`
+    up: Unit<Vec3>,
+    projection: T,
+    clip_space: ClipSpace,
+    // Camera to world rotation before roll is applied. Looks down -z with y up
+    frame: Quaternion,
+    // Rotation around the look direction in radians
+    roll: f32,
+}
+
+impl<T: Projection> Camera<T> {
+    pub fn new(eye: Point, target: Point, up: Vec3, projection: T) -> Self {
+        let mut camera = Self {
+            eye,
+            target,
+            up: Unit::new_normalize(up),
+            projection,
+            clip_space: ClipSpace::Vulkan,
+            frame: Quaternion::identity(),
+            roll: 0.0,
+        };
+        camera.reorient(None);
+        camera
+    }
+    // Rebuilds `frame` from the look direction and world up. When the two are
+    // parallel `right_hint` (or the previous right direction) is used instead, and
+    // when it is given the frame never flips relative to it, so pitching over the
+    // poles turns the camera upside down rather than snapping it around
+    fn reorient(&mut self, right_hint: Option<Vec3>) {
+        let look = self.target - self.eye;
+        if look.norm() < EPSILON {
+            return;
+        }
+        let look = look.normalize();
+        let mut right = math::cross(look, self.up.unwrap());
+        if right.norm() < EPSILON {
+            let hint = right_hint.unwrap_or_else(|| self.frame * Vec3::x());
+            right = hint - look * hint.dot(&look);
+            if right.norm() < EPSILON {
+                right = math::perpendicular_basis(look).1;
+            }
+        } else if let Some(hint) = right_hint {
+            if right.dot(&hint) < 0.0 {
+                right = -right;
+            }
+        }
+        self.frame = math::look_rotation(look, right);
+    }
+    // Keeps the camera's current orientation while the look direction changes
+    fn reorient_continuous(&mut self) {
+        let right = self.frame * Vec3::x();
+        self.reorient(Some(right));
+    }
+    // The clip space that `view_projection` outputs to and `screen_to_world_space` expects,
+    // defaults to Vulkan's conventions
+    pub fn set_clip_space(&mut self, clip_space: ClipSpace) {
+        self.clip_space = clip_space;
+    }
+    pub fn clip_space(&self) -> ClipSpace {
+        self.clip_space
+    }
+    pub fn eye(&self) -> Point {
+        self.eye
+    }
+    pub fn target(&self) -> Point {
+        self.target
+    }
+    pub fn move_eye_to(&mut self, new_pos: Point) {
+        self.eye = new_pos;
+        self.reorient_continuous();
+    }
+    pub fn move_eye_by(&mut self, vec: Vec3) {
+        self.eye += vec;
+        self.reorient_continuous();
+    }
+    pub fn move_target_by(&mut self, vec: Vec3) {
+        self.target += vec;
+        self.reorient_continuous();
+    }
+    // The look direction is unchanged so the orientation is kept as is
+    pub fn move_eye_and_target_by(&mut self, vec: Vec3) {
+        self.eye += vec;
+        self.target += vec;
+    }
+    // Rotates target around the world's up direction
+    pub fn rotate_target_around_up(&mut self, angle: f32) {
+        self.target -= self.eye.coords;
+        let rotation = Rotation::from_axis_angle(&self.up, angle);
+        self.target = rotation * self.target + self.eye.coords;
+        let right = rotation * (self.frame * Vec3::x());
+        self.reorient(Some(right));
+    }
+    // Rotates the target around the right direction
+    pub fn rotate_target_around_right(&mut self, angle: f32) {
+        self.target -= self.eye.coords;
+        let rotation = Rotation::from_axis_angle(&self.right_dir(), angle);
+        self.target = rotation * self.target + self.eye.coords;
+        self.reorient_continuous();
+    }
+    // The world's up direction given when the camera was created
+    pub fn up_dir(&self) -> Unit<Vec3> {
+        self.up
+    }
+    // The camera's own up direction, perpendicular to the look and right directions
+    pub fn camera_up(&self) -> Unit<Vec3> {
+        Unit::new_normalize(self.orientation() * Vec3::y())
+    }
+    // The camera's right direction, rolled along with `camera_up`. Comes from
+    // `frame` so it is well defined even when looking along the world's up direction
+    pub fn right_dir(&self) -> Unit<Vec3> {
+        Unit::new_normalize(self.orientation() * Vec3::x())
+    }
+    // The normalised eye to target vector 
+    pub fn look_dir(&self) -> Unit<Vec3> {
+        Unit::new_normalize(self.orientation() * -Vec3::z())
+    }
+    pub fn look_at(&mut self, new_look: Point) {
+        self.target = new_look;
+        self.reorient_continuous();
+    }
+    // The camera to world rotation, the camera looks down -z with y up
+    pub fn orientation(&self) -> Quaternion {
+        let forward = Unit::new_normalize(Vec3::new(0.0, 0.0, -1.0));
+        self.frame * Quaternion::from_axis_angle(&forward, self.roll)
+    }
+    // Points the camera along `orientation`, keeping the eye and the distance to the target
+    pub fn set_orientation(&mut self, orientation: Quaternion) {
+        let distance = (self.target - self.eye).norm().max(EPSILON);
+        let forward = orientation * -Vec3::z();
+        let right = orientation * Vec3::x();
+        self.target = self.eye + forward * distance;
+        self.reorient(Some(right));
+        // Whatever rotation is left around the look direction becomes roll
+        let base_up = self.frame * Vec3::y();
+        let new_up = orientation * Vec3::y();
+        self.roll = math::cross(base_up, new_up).dot(&forward).atan2(base_up.dot(&new_up));
+    }
+    // Applies `rotation` (in world space) on top of the current orientation
+    pub fn rotate_by(&mut self, rotation: Quaternion) {
+        let orientation = rotation * self.orientation();
+        self.set_orientation(orientation);
+    }
+    pub fn roll(&self) -> f32 {
+        self.roll
+    }
+    pub fn set_roll(&mut self, roll: f32) {
+        self.roll = roll;
+    }
+    pub fn roll_by(&mut self, angle: f32) {
+        self.roll += angle;
+    }
+    // Yaw around and pitch towards the world's up direction, with roll around the look direction
+    pub fn yaw_pitch_roll(&self) -> (f32, f32, f32) {
+        let up = self.up.unwrap();
+        let look = self.look_dir().unwrap();
+        let pitch = look.dot(&up).max(-1.0).min(1.0).asin();
+        // Fall back to the camera's up direction when looking straight up or down
+        let horizontal = if pitch.abs() > PI * 0.5 - 1e-3 {
+            let camera_up = self.frame * Vec3::y();
+            -camera_up * pitch.signum()
+        } else {
+            look
+        };
+        let (forward, right) = math::perpendicular_basis(up);
+        let yaw = horizontal.dot(&right).atan2(horizontal.dot(&forward));
+        (yaw, pitch, self.roll)
+    }
+    pub fn set_yaw_pitch_roll(&mut self, yaw: f32, pitch: f32, roll: f32) {
+        let up = self.up.unwrap();
+        let (forward, right) = math::perpendicular_basis(up);
+        let horizontal = forward * yaw.cos() + right * yaw.sin();
+        let look = horizontal * pitch.cos() + up * pitch.sin();
+        let distance = (self.target - self.eye).norm().max(EPSILON);
+        self.target = self.eye + look * distance;
+        self.reorient(Some(math::cross(horizontal, up)));
+        self.roll = roll;
+    }
+    pub fn look_at_matrix(&self) -> Isometry {
+        Isometry::from_parts(Translation3::from_vector(self.eye.coords), self.orientation()).inverse()
+    }
+    pub fn view_matrix(&self) -> Mat4 {
+        self.look_at_matrix().to_homogeneous()
+    }
+    // The projection matrix converted into the camera's clip space
+    pub fn projection_matrix(&self) -> Mat4 {
+        ClipSpace::conversion(self.projection.clip_space(), self.clip_space) * self.projection.matrix()
+    }
+    // The matrix representing the projection matrix multiplied by the view matrix
+    pub fn view_projection(&self) -> Mat4 {
+        self.projection_matrix() * self.view_matrix()
+    }
+    pub fn set_znear(&mut self, znear: f32) {
+        self.projection.set_znear(znear);
+    }
+    pub fn set_zfar(&mut self, zfar: f32) {
+        self.projection.set_zfar(zfar);
+    }
+    pub fn projection_ref(&self) -> &T {
+        &self.projection
+    }
+    // The normalised device depth of the near and far planes in the camera's clip space
+    pub fn depth_range(&self) -> (f32, f32) {
+        let (near, far) = self.clip_space.depth_range();
+        if self.projection.reversed_depth() {
+            return (far, near);
+        }
+        (near, far)
+    }
+    // Unprojects a point given in normalised device coordinates of the camera's clip space
+    pub fn unproject(&self, point: Point) -> Point {
+        let point = ClipSpace::convert_ndc(point, self.clip_space, self.projection.clip_space());
+        let point = self.projection.unproject(point);
+        self.look_at_matrix().inverse() * point
+    }
+    // Converts a position in pixels from the top left of the viewport into
+    // normalised device coordinates of the camera's clip space
+    pub fn pixel_to_ndc(&self, pixel: [f32; 2], viewport: [u32; 2]) -> [f32; 2] {
+        let x = pixel[0] / viewport[0] as f32 * 2.0 - 1.0;
+        let y = pixel[1] / viewport[1] as f32 * 2.0 - 1.0;
+        match self.clip_space {
+            ClipSpace::OpenGl => [x, -y],
+            ClipSpace::Vulkan => [x, y],
+        }
+    }
+    pub fn ndc_to_pixel(&self, ndc: [f32; 2], viewport: [u32; 2]) -> [f32; 2] {
+        let y = match self.clip_space {
+            ClipSpace::OpenGl => -ndc[1],
+            ClipSpace::Vulkan => ndc[1],
+        };
+        [
+            (ndc[0] + 1.0) * 0.5 * viewport[0] as f32,
+            (y + 1.0) * 0.5 * viewport[1] as f32,
+        ]
+    }
+    pub fn world_to_screen(&self, point: Point, viewport: [u32; 2]) -> ScreenPoint {
+        let view_point = self.look_at_matrix() * point;
+        let clip = self.view_projection() * point.to_homogeneous();
+        let w = if clip.w.abs() < EPSILON { EPSILON } else { clip.w };
+        let ndc = Point::new(clip.x / w, clip.y / w, clip.z / w);
+        let (near, far) = self.depth_range();
+        let (min_depth, max_depth) = (near.min(far), near.max(far));
+
+        // The camera looks down -z in view space
+        let visibility = if view_point.z >= 0.0 {
+            Visibility::BehindCamera
+        } else if ndc.x < -1.0 || ndc.x > 1.0 || ndc.y < -1.0 || ndc.y > 1.0 || ndc.z < min_depth
+            || ndc.z > max_depth
+        {
+            Visibility::OffScreen
+        } else {
+            Visibility::Visible
+        };
+
+        ScreenPoint {
+            ndc,
+            pixel: self.ndc_to_pixel([ndc.x, ndc.y], viewport),
+            visibility,
+        }
+    }
+    // Like `screen_to_world_space` but takes a position in pixels from the top
+    // left of the viewport, such as a mouse position
+    pub fn pixel_to_world_space(&self, pixel: [f32; 2], viewport: [u32; 2]) -> Ray3 {
+        let ndc = self.pixel_to_ndc(pixel, viewport);
+        self.screen_to_world_space(ndc[0], ndc[1])
+    }
+    // Note: x and y must range from -1 to 1 and follow the camera's clip space, so
+    // with Vulkan conventions y = -1 is the top of the screen.
+    // The ray starts on the near plane
+    pub fn screen_to_world_space(&self, x: f32, y: f32) -> Ray3 {
+        let (near, far) = self.depth_range();
+        let start = self.unproject(Point::new(x, y, near));
+        let end = self.unproject(Point::new(x, y, (near + far) * 0.5));
+        Ray3::new(start, end - start)
+    }
+}
+
+impl Default for Camera<Perspective> {
+    fn default() -> Self {
+        Self::new(
+            Point::new(0.0, 0.0, 0.0),
+            Point::new(0.0, 0.0, 1.0),
+            Vec3::new(0.0, 1.0, 0.0),
+            Perspective::new(1.777778, PI * 0.5, 0.1, 1000.0),
+        )
+    }
+}
+
+impl Default for Camera<Orthographic> {
+    fn default() -> Self {
+        Self::new(
+            Point::new(0.0, 0.0, 0.0),
+            Point::new(0.0, 0.0, 1.0),
+            Vec3::new(0.0, 1.0, 0.0),
+            Orthographic::new(-1.0, 1.0, -1.0, 1.0, 0.1, 1000.0),
+        )
+    }
+}
+
+impl Camera<Perspective> {
+    pub fn set_fovy(&mut self, new_fovy: f32) {
+        self.projection.set_fovy(new_fovy);
+    }
+    pub fn set_aspect(&mut self, new_aspect: f32) {
+        self.projection.set_aspect(new_aspect);
+    }
+    pub fn get_fovy(&self) -> f32 {
+        self.projection.fovy()
+    }
+    pub fn get_aspect(&self) -> f32 {
+        self.projection.aspect()
+    }
+}
+
+impl Default for Camera<ReverseZPerspective> {
+    fn default() -> Self {
+        Self::new(
+            Point::new(0.0, 0.0, 0.0),
+            Point::new(0.0, 0.0, 1.0),
+            Vec3::new(0.0, 1.0, 0.0),
+            ReverseZPerspective::infinite(1.777778, PI * 0.5, 0.1),
+        )
+    }
+}
+
+impl Camera<ReverseZPerspective> {
+    pub fn set_fovy(&mut self, new_fovy: f32) {
+        self.projection.set_fovy(new_fovy);
+    }
+    pub fn set_aspect(&mut self, new_aspect: f32) {
+        self.projection.set_aspect(new_aspect);
+    }
+    pub fn get_fovy(&self) -> f32 {
+        self.projection.fovy()
+    }
+    pub fn get_aspect(&self) -> f32 {
+        self.projection.aspect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn camera(target: Point) -> Camera<Perspective> {
+        Camera::new(Point::new(0.0, 0.0, 0.0), target, Vec3::y(), Perspective::new(1.0, 1.0, 0.1, 100.0))
+    }
+
+    // Right, camera up and look form a right handed orthonormal basis
+    fn assert_basis(camera: &Camera<Perspective>) {
+        let (right, up, look) = (
+            camera.right_dir().unwrap(),
+            camera.camera_up().unwrap(),
+            camera.look_dir().unwrap(),
+        );
+        for v in [right, up, look].iter() {
+            assert!(v.iter().all(|c| c.is_finite()), "{:?}", v);
+            assert!((v.norm() - 1.0).abs() < 1e-4);
+        }
+        assert!(right.dot(&up).abs() < 1e-4 && right.dot(&look).abs() < 1e-4 && up.dot(&look).abs() < 1e-4);
+        assert!((math::cross(right, up) + look).norm() < 1e-4);
+    }
+
+    #[test]
+    fn looking_straight_up_or_down_is_well_defined() {
+        for &y in [5.0, -5.0].iter() {
+            let camera = camera(Point::new(0.0, y, 0.0));
+            assert_basis(&camera);
+            assert!((camera.look_dir().unwrap() - Vec3::y() * y.signum()).norm() < 1e-4);
+            // The world up is unchanged
+            assert_eq!(camera.up_dir().unwrap(), Vec3::y());
+            let (yaw, pitch, roll) = camera.yaw_pitch_roll();
+            assert!(yaw.is_finite() && roll == 0.0);
+            assert!((pitch - PI * 0.5 * y.signum()).abs() < 1e-3);
+        }
+    }
+
+    #[test]
+    fn pitching_onto_a_pole_keeps_the_right_direction() {
+        let mut camera = camera(Point::new(0.0, 0.0, -5.0));
+        let right = camera.right_dir().unwrap();
+        camera.rotate_target_around_right(PI * 0.5);
+        assert_basis(&camera);
+        assert!((camera.look_dir().unwrap() - Vec3::y()).norm() < 1e-4);
+        assert!((camera.right_dir().unwrap() - right).norm() < 1e-4);
+    }
+
+    #[test]
+    fn crossing_a_pole_turns_the_camera_upside_down() {
+        let mut camera = camera(Point::new(0.0, 0.0, -5.0));
+        let mut right = camera.right_dir().unwrap();
+        // Steps that land exactly on the pole and then carry on over it
+        for _ in 0..12 {
+            camera.rotate_target_around_right(PI / 12.0);
+            assert_basis(&camera);
+            let new_right = camera.right_dir().unwrap();
+            assert!(new_right.dot(&right) > 0.99, "right flipped from {:?} to {:?}", right, new_right);
+            right = new_right;
+        }
+        // Half a turn later the camera looks back along +z, upside down
+        assert!((camera.look_dir().unwrap() - Vec3::z()).norm() < 1e-3);
+        assert!((camera.camera_up().unwrap() + Vec3::y()).norm() < 1e-3);
+        assert_eq!(camera.up_dir().unwrap(), Vec3::y());
+    }
+
+    #[test]
+    fn yaw_at_a_pole_keeps_the_basis() {
+        let mut camera = camera(Point::new(0.0, 5.0, 0.0));
+        camera.rotate_target_around_up(0.7);
+        assert_basis(&camera);
+        camera.set_yaw_pitch_roll(0.3, -PI * 0.5, 0.2);
+        assert_basis(&camera);
+        assert!((camera.look_dir().unwrap() + Vec3::y()).norm() < 1e-4);
+        assert!((camera.roll() - 0.2).abs() < 1e-6);
+    }
+
+    #[test]
+    fn orientation_round_trips_through_a_pole() {
+        let mut camera = camera(Point::new(0.0, 0.0, -5.0));
+        let pole = Quaternion::from_axis_angle(&Vec3::x_axis(), PI * 0.5);
+        camera.set_orientation(pole);
+        assert_basis(&camera);
+        assert!(camera.orientation().angle_to(&pole) < 1e-3);
+        camera.rotate_by(Quaternion::from_axis_angle(&Vec3::y_axis(), 0.4));
+        assert_basis(&camera);
+    }
+}
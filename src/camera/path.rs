@@ -0,0 +1,466 @@
+use camera::Camera;
+use math;
+use math::{Perspective, Point, Quaternion, Vec3};
+use nalgebra::Unit;
+use std::f32::consts::PI;
+use std::error::Error;
+use std::fmt;
+use std::fs::File;
+use std::io;
+use std::io::{BufRead, BufReader, Read, Write};
+use std::path::Path;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Keyframe {
+    // Seconds from the start of the path
+    pub time: f32,
+    pub eye: Point,
+    pub target: Point,
+    pub up: Vec3,
+    pub fovy: f32,
+}
+
+impl Keyframe {
+    pub fn new(time: f32, eye: Point, target: Point, up: Vec3, fovy: f32) -> Self {
+        Self {
+            time,
+            eye,
+            target,
+            up,
+            fovy,
+        }
+    }
+    // The camera to world rotation looking from eye to target, same convention as
+    // `Camera::orientation`. With the eye on the target it looks horizontally
+    fn orientation(&self) -> Quaternion {
+        let look = self.target - self.eye;
+        let look = if look.norm() < 1e-6 {
+            math::perpendicular_basis(self.up).0
+        } else {
+            look.normalize()
+        };
+        let mut right = math::cross(look, self.up);
+        if right.norm() < 1e-6 {
+            right = math::perpendicular_basis(look).1;
+        }
+        math::look_rotation(look, right)
+    }
+}
+
+// Interpolates the shorter way round, `q` and `-q` are the same rotation. Falls
+// back to a normalised lerp where slerp is undefined
+fn slerp_shortest(q0: &Quaternion, q1: &Quaternion, t: f32) -> Quaternion {
+    let q1 = if q0.coords.dot(&q1.coords) < 0.0 {
+        Quaternion::new_unchecked(-q1.unwrap())
+    } else {
+        *q1
+    };
+    q0.try_slerp(&q1, t, 1e-6).unwrap_or_else(|| q0.nlerp(&q1, t))
+}
+
+// Turns the unit vector `from` towards `to` by the fraction `t` of the angle
+// between them. Opposite vectors turn around `axis`, or any perpendicular when
+// `axis` lines up with them
+fn slerp_dir(from: Vec3, to: Vec3, t: f32, axis: Vec3) -> Vec3 {
+    if let Some(rotation) = Quaternion::scaled_rotation_between(&from, &to, t) {
+        return rotation * from;
+    }
+    let mut axis = axis - from * axis.dot(&from);
+    if axis.norm() < 1e-6 {
+        axis = math::perpendicular_basis(from).0;
+    }
+    Quaternion::from_axis_angle(&Unit::new_normalize(axis), PI * t) * from
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Interpolation {
+    CatmullRom,
+    // Cardinal spline tangents scaled by (1 - tension), a tension of 0 matches
+    // Catmull-Rom and 1 stops at every keyframe
+    Hermite { tension: f32 },
+}
+
+impl Interpolation {
+    fn tangent_scale(&self) -> f32 {
+        match *self {
+            Interpolation::CatmullRom => 1.0,
+            Interpolation::Hermite { tension } => 1.0 - tension,
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum PathError {
+    Io(io::Error),
+    Parse { line: usize, message: String },
+}
+
+impl fmt::Display for PathError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            PathError::Io(ref err) => write!(f, "failed to read camera path: {}", err),
+            PathError::Parse { line, ref message } => write!(f, "camera path line {}: {}", line, message),
+        }
+    }
+}
+
+impl Error for PathError {
+    fn description(&self) -> &str {
+        match *self {
+            PathError::Io(_) => "failed to read camera path",
+            PathError::Parse { .. } => "malformed camera path",
+        }
+    }
+}
+
+impl From<io::Error> for PathError {
+    fn from(err: io::Error) -> Self {
+        PathError::Io(err)
+    }
+}
+
+// Keyframes sorted by time. Positions follow a spline through the eyes, the
+// orientation and up direction are slerped and the eye to target distance and
+// fovy are lerped
+//
+// The text format has one keyframe per line,
+//     time eye.x eye.y eye.z target.x target.y target.z up.x up.y up.z fovy
+// optionally preceded by `interpolation catmull_rom` or `interpolation hermite <tension>`
+// and `clip <znear> <zfar>`. Blank lines and lines starting with '#' are ignored
+#[derive(Debug, Clone)]
+pub struct CameraPath {
+    keyframes: Vec<Keyframe>,
+    pub interpolation: Interpolation,
+    pub znear: f32,
+    pub zfar: f32,
+}
+
+impl CameraPath {
+    pub fn new(interpolation: Interpolation) -> Self {
+        Self {
+            keyframes: Vec::new(),
+            interpolation,
+            znear: 0.1,
+            zfar: 1000.0,
+        }
+    }
+    // Keeps the keyframes sorted, a keyframe at an existing time is inserted after it
+    pub fn add_keyframe(&mut self, keyframe: Keyframe) {
+        let index = self.keyframes
+            .iter()
+            .position(|k| k.time > keyframe.time)
+            .unwrap_or(self.keyframes.len());
+        self.keyframes.insert(index, keyframe);
+    }
+    pub fn keyframes(&self) -> &[Keyframe] {
+        &self.keyframes
+    }
+    pub fn duration(&self) -> f32 {
+        match (self.keyframes.first(), self.keyframes.last()) {
+            (Some(first), Some(last)) => last.time - first.time,
+            _ => 0.0,
+        }
+    }
+    // The eye velocity at keyframe `i` in world units per second
+    fn velocity(&self, i: usize) -> Vec3 {
+        let last = self.keyframes.len() - 1;
+        let prev = &self.keyframes[if i == 0 { 0 } else { i - 1 }];
+        let next = &self.keyframes[if i == last { last } else { i + 1 }];
+        let dt = next.time - prev.time;
+        if dt <= 0.0 {
+            return Vec3::zeros();
+        }
+        (next.eye - prev.eye) / dt * self.interpolation.tangent_scale()
+    }
+    // Returns None for an empty path, times outside the path are clamped to its ends
+    pub fn sample(&self, time: f32, aspect: f32) -> Option<Camera<Perspective>> {
+        let first = self.keyframes.first()?;
+        let last = self.keyframes.last()?;
+        if time <= first.time || self.keyframes.len() == 1 {
+            return Some(self.camera_at(first, aspect));
+        }
+        if time >= last.time {
+            return Some(self.camera_at(last, aspect));
+        }
+
+        let i = self.keyframes
+            .iter()
+            .rposition(|k| k.time <= time)
+            .unwrap_or(0);
+        let (k0, k1) = (&self.keyframes[i], &self.keyframes[i + 1]);
+        let span = k1.time - k0.time;
+        if span <= 0.0 {
+            return Some(self.camera_at(k1, aspect));
+        }
+        let u = (time - k0.time) / span;
+
+        // Cubic hermite basis
+        let u2 = u * u;
+        let u3 = u2 * u;
+        let h00 = 2.0 * u3 - 3.0 * u2 + 1.0;
+        let h10 = u3 - 2.0 * u2 + u;
+        let h01 = -2.0 * u3 + 3.0 * u2;
+        let h11 = u3 - u2;
+        let m0 = self.velocity(i) * span;
+        let m1 = self.velocity(i + 1) * span;
+        let eye = k0.eye.coords * h00 + m0 * h10 + k1.eye.coords * h01 + m1 * h11;
+        let eye = Point::from_coordinates(eye);
+
+        let orientation = slerp_shortest(&k0.orientation(), &k1.orientation(), u);
+        let d0 = (k0.target - k0.eye).norm();
+        let d1 = (k1.target - k1.eye).norm();
+        let distance = d0 + (d1 - d0) * u;
+        // Opposite ups roll around the look direction
+        let up = slerp_dir(k0.up.normalize(), k1.up.normalize(), u, orientation * Vec3::z());
+        let fovy = k0.fovy + (k1.fovy - k0.fovy) * u;
+        Some(self.camera(eye, orientation, distance, up, fovy, aspect))
+    }
+    fn camera_at(&self, keyframe: &Keyframe, aspect: f32) -> Camera<Perspective> {
+        let distance = (keyframe.target - keyframe.eye).norm();
+        self.camera(keyframe.eye, keyframe.orientation(), distance, keyframe.up, keyframe.fovy, aspect)
+    }
+    // Keyframes and the samples between them are built the same way, so the ends
+    // of the path line up with its middle
+    fn camera(
+        &self,
+        eye: Point,
+        orientation: Quaternion,
+        distance: f32,
+        up: Vec3,
+        fovy: f32,
+        aspect: f32,
+    ) -> Camera<Perspective> {
+        // A target on the eye would leave the camera without a look direction
+        let target = eye + orientation * -Vec3::z() * distance.max(1e-3);
+        let mut camera = Camera::new(
+            eye,
+            target,
+            up,
+            Perspective::new(aspect, fovy, self.znear, self.zfar),
+        );
+        camera.set_orientation(orientation);
+        camera
+    }
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Self, PathError> {
+        Self::read(File::open(path)?)
+    }
+    pub fn save<P: AsRef<Path>>(&self, path: P) -> Result<(), PathError> {
+        self.write(File::create(path)?)
+    }
+    pub fn read<R: Read>(reader: R) -> Result<Self, PathError> {
+        let mut path = CameraPath::new(Interpolation::CatmullRom);
+        for (index, line) in BufReader::new(reader).lines().enumerate() {
+            let line = line?;
+            let line_number = index + 1;
+            let error = |message: &str| PathError::Parse {
+                line: line_number,
+                message: message.to_string(),
+            };
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let mut words = line.split_whitespace();
+            match line.split_whitespace().next() {
+                Some("interpolation") => {
+                    words.next();
+                    path.interpolation = match words.next() {
+                        Some("catmull_rom") => Interpolation::CatmullRom,
+                        Some("hermite") => {
+                            let tension = words
+                                .next()
+                                .ok_or_else(|| error("missing hermite tension"))?
+                                .parse::<f32>()
+                                .map_err(|_| error("invalid hermite tension"))?;
+                            Interpolation::Hermite { tension }
+                        }
+                        _ => return Err(error("unknown interpolation")),
+                    };
+                    continue;
+                }
+                Some("clip") => {
+                    words.next();
+                    let mut planes = [0.0f32; 2];
+                    for plane in planes.iter_mut() {
+                        *plane = words
+                            .next()
+                            .ok_or_else(|| error("expected znear and zfar"))?
+                            .parse()
+                            .map_err(|_| error("invalid number"))?;
+                    }
+                    if words.next().is_some() || !(planes[0] > 0.0 && planes[1] > planes[0]) {
+                        return Err(error("invalid clip planes"));
+                    }
+                    path.znear = planes[0];
+                    path.zfar = planes[1];
+                    continue;
+                }
+                _ => {}
+            }
+            let mut values = [0.0f32; 11];
+            for value in values.iter_mut() {
+                *value = words
+                    .next()
+                    .ok_or_else(|| error("expected 11 values"))?
+                    .parse()
+                    .map_err(|_| error("invalid number"))?;
+            }
+            if words.next().is_some() {
+                return Err(error("expected 11 values"));
+            }
+            let v = values;
+            path.add_keyframe(Keyframe::new(
+                v[0],
+                Point::new(v[1], v[2], v[3]),
+                Point::new(v[4], v[5], v[6]),
+                Vec3::new(v[7], v[8], v[9]),
+                v[10],
+            ));
+        }
+        Ok(path)
+    }
+    pub fn write<W: Write>(&self, mut writer: W) -> Result<(), PathError> {
+        writeln!(writer, "# time eye.x eye.y eye.z target.x target.y target.z up.x up.y up.z fovy")?;
+        match self.interpolation {
+            Interpolation::CatmullRom => writeln!(writer, "interpolation catmull_rom")?,
+            Interpolation::Hermite { tension } => writeln!(writer, "interpolation hermite {}", tension)?,
+        }
+        writeln!(writer, "clip {} {}", self.znear, self.zfar)?;
+        for k in self.keyframes.iter() {
+            writeln!(
+                writer,
+                "{} {} {} {} {} {} {} {} {} {} {}",
+                k.time,
+                k.eye.x,
+                k.eye.y,
+                k.eye.z,
+                k.target.x,
+                k.target.y,
+                k.target.z,
+                k.up.x,
+                k.up.y,
+                k.up.z,
+                k.fovy
+            )?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn keyframe(time: f32, eye: Point, target: Point) -> Keyframe {
+        Keyframe::new(time, eye, target, Vec3::y(), 1.0)
+    }
+
+    #[test]
+    fn slerp_takes_the_short_way_round() {
+        let q0 = Quaternion::from_axis_angle(&Vec3::y_axis(), 0.0);
+        let q1 = Quaternion::from_axis_angle(&Vec3::y_axis(), 1.0);
+        let flipped = Quaternion::new_unchecked(-q1.unwrap());
+        let half = slerp_shortest(&q0, &flipped, 0.5);
+        assert!(half.angle_to(&Quaternion::from_axis_angle(&Vec3::y_axis(), 0.5)) < 1e-4);
+    }
+
+    #[test]
+    fn slerp_between_opposite_signs_of_one_rotation_stays_put() {
+        let q = Quaternion::from_axis_angle(&Vec3::x_axis(), 0.3);
+        let flipped = Quaternion::new_unchecked(-q.unwrap());
+        for &t in [0.0, 0.5, 1.0].iter() {
+            assert!(slerp_shortest(&q, &flipped, t).angle_to(&q) < 1e-4);
+        }
+    }
+
+    #[test]
+    fn half_turn_between_keyframes_is_well_defined() {
+        let mut path = CameraPath::new(Interpolation::CatmullRom);
+        let eye = Point::new(0.0, 0.0, 0.0);
+        path.add_keyframe(keyframe(0.0, eye, Point::new(0.0, 0.0, -1.0)));
+        path.add_keyframe(keyframe(1.0, eye, Point::new(0.0, 0.0, 1.0)));
+        for i in 0..=10 {
+            let camera = path.sample(i as f32 / 10.0, 1.0).unwrap();
+            let look = camera.look_dir().unwrap();
+            assert!(look.iter().all(|c| c.is_finite()));
+            let expected = PI * i as f32 / 10.0;
+            assert!((look.dot(&-Vec3::z()).max(-1.0).min(1.0).acos() - expected).abs() < 1e-2);
+        }
+    }
+
+    #[test]
+    fn eye_on_target_does_not_produce_nan() {
+        let mut path = CameraPath::new(Interpolation::CatmullRom);
+        let eye = Point::new(1.0, 2.0, 3.0);
+        path.add_keyframe(keyframe(0.0, eye, eye));
+        path.add_keyframe(keyframe(1.0, Point::new(2.0, 2.0, 3.0), Point::new(2.0, 2.0, 0.0)));
+        let orientation = path.keyframes()[0].orientation();
+        assert!(orientation.coords.iter().all(|c| c.is_finite()));
+        let camera = path.sample(0.5, 1.0).unwrap();
+        assert!(camera.view_projection().iter().all(|c| c.is_finite()));
+    }
+
+    #[test]
+    fn opposite_ups_roll_between_keyframes() {
+        let mut path = CameraPath::new(Interpolation::CatmullRom);
+        let (eye, target) = (Point::new(0.0, 0.0, 0.0), Point::new(0.0, 0.0, -1.0));
+        path.add_keyframe(keyframe(0.0, eye, target));
+        path.add_keyframe(Keyframe::new(1.0, eye, target, -Vec3::y(), 1.0));
+        for i in 0..=10 {
+            let camera = path.sample(i as f32 / 10.0, 1.0).unwrap();
+            let up = camera.up_dir().unwrap();
+            assert!(up.iter().all(|c| c.is_finite()));
+            assert!(camera.view_projection().iter().all(|c| c.is_finite()));
+            // Always at a right angle to the look direction, half way it is sideways
+            assert!(up.z.abs() < 1e-4);
+            let angle = up.dot(&Vec3::y()).max(-1.0).min(1.0).acos();
+            assert!((angle - PI * i as f32 / 10.0).abs() < 1e-2, "{} at {}", angle, i);
+        }
+    }
+
+    #[test]
+    fn ends_of_the_path_use_the_keyframe_orientation() {
+        let mut path = CameraPath::new(Interpolation::CatmullRom);
+        // With the eye on the target only the keyframe's orientation says where it looks
+        let eye = Point::new(1.0, 2.0, 3.0);
+        let start = Keyframe::new(0.0, eye, eye, Vec3::x(), 1.0);
+        let end = Keyframe::new(2.0, Point::new(4.0, 2.0, 3.0), Point::new(4.0, 5.0, 3.0), Vec3::z(), 1.0);
+        path.add_keyframe(start);
+        path.add_keyframe(end);
+        for &(time, keyframe) in [(-1.0, start), (0.0, start), (2.0, end), (3.0, end)].iter() {
+            let camera = path.sample(time, 1.0).unwrap();
+            assert!(camera.orientation().angle_to(&keyframe.orientation()) < 1e-4, "at {}", time);
+            assert_eq!(camera.eye(), keyframe.eye);
+        }
+        // Just inside the path the samples agree with the ends
+        let near_start = path.sample(1e-3, 1.0).unwrap();
+        assert!(near_start.orientation().angle_to(&start.orientation()) < 1e-2);
+        let near_end = path.sample(2.0 - 1e-3, 1.0).unwrap();
+        assert!(near_end.orientation().angle_to(&end.orientation()) < 1e-2);
+    }
+
+    #[test]
+    fn save_and_load_round_trip() {
+        let mut path = CameraPath::new(Interpolation::Hermite { tension: 0.25 });
+        path.znear = 0.5;
+        path.zfar = 250.0;
+        path.add_keyframe(keyframe(0.0, Point::new(0.0, 1.0, 2.0), Point::new(0.0, 0.0, 0.0)));
+        path.add_keyframe(keyframe(2.5, Point::new(3.0, 1.0, 2.0), Point::new(0.0, 0.5, 0.0)));
+        let mut text = Vec::new();
+        path.write(&mut text).unwrap();
+        let loaded = CameraPath::read(&text[..]).unwrap();
+        assert_eq!(loaded.interpolation, path.interpolation);
+        assert_eq!((loaded.znear, loaded.zfar), (0.5, 250.0));
+        assert_eq!(loaded.keyframes(), path.keyframes());
+    }
+
+    #[test]
+    fn invalid_clip_planes_are_rejected() {
+        for text in ["clip 0.1".as_bytes(), b"clip 10 1", b"clip 0 10", b"clip 0.1 10 20"].iter() {
+            match CameraPath::read(*text) {
+                Err(PathError::Parse { line: 1, .. }) => {}
+                result => panic!("expected a parse error, got {:?}", result),
+            }
+        }
+    }
+}
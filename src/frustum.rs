@@ -0,0 +1,284 @@
+use camera::Camera;
+use math::{ClipSpace, Mat4, Point, Projection, Vec3};
+use nalgebra::Vector4;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Containment {
+    Inside,
+    Intersecting,
+    Outside,
+}
+
+// A plane with a unit normal, points with a positive signed distance are in front of it
+#[derive(Debug, Clone, Copy)]
+pub struct Plane {
+    pub normal: Vec3,
+    pub d: f32,
+}
+
+impl Plane {
+    // Builds a plane from the coefficients of `ax + by + cz + d = 0`, normalising them.
+    // A plane at infinity (zero normal) is kept with a zero normal and a d of +-1
+    pub fn from_coefficients(a: f32, b: f32, c: f32, d: f32) -> Self {
+        let normal = Vec3::new(a, b, c);
+        let len = normal.norm();
+        if len < 1e-6 {
+            return Self {
+                normal: Vec3::zeros(),
+                d: d.signum(),
+            };
+        }
+        Self {
+            normal: normal / len,
+            d: d / len,
+        }
+    }
+    pub fn signed_distance(&self, point: &Point) -> f32 {
+        self.normal.dot(&point.coords) + self.d
+    }
+}
+
+pub const LEFT: usize = 0;
+pub const RIGHT: usize = 1;
+pub const BOTTOM: usize = 2;
+pub const TOP: usize = 3;
+pub const NEAR: usize = 4;
+pub const FAR: usize = 5;
+
+pub struct Frustum {
+    // Indexed by LEFT, RIGHT, BOTTOM, TOP, NEAR and FAR, normals point inwards
+    planes: [Plane; 6],
+    // Near plane corners followed by the far plane corners, each in the order
+    // (-x, -y), (x, -y), (x, y), (-x, y) in OpenGL normalised device coordinates
+    corners: [Point; 8],
+}
+
+impl Frustum {
+    pub fn from_camera<T: Projection>(camera: &Camera<T>) -> Self {
+        let reversed_depth = camera.projection_ref().reversed_depth();
+        Self::from_matrix(&camera.view_projection(), camera.clip_space(), reversed_depth)
+    }
+    // Extracts the planes from a view projection matrix that outputs to `clip_space`.
+    // With an infinite far plane the far corners are infinite
+    pub fn from_matrix(matrix: &Mat4, clip_space: ClipSpace, reversed_depth: bool) -> Self {
+        let matrix = &(clip_space.to_opengl() * matrix);
+        let row = |i: usize| Vector4::new(matrix[(i, 0)], matrix[(i, 1)], matrix[(i, 2)], matrix[(i, 3)]);
+        let (x, y, z, w) = (row(0), row(1), row(2), row(3));
+        let plane = |v: Vector4<f32>| Plane::from_coefficients(v.x, v.y, v.z, v.w);
+        let mut planes = [
+            plane(w + x),
+            plane(w - x),
+            plane(w + y),
+            plane(w - y),
+            plane(w + z),
+            plane(w - z),
+        ];
+        let near = if reversed_depth { 1.0 } else { -1.0 };
+        if reversed_depth {
+            planes.swap(NEAR, FAR);
+        }
+
+        let inverse = matrix.try_inverse().unwrap_or_else(Mat4::identity);
+        let corner = |x: f32, y: f32, z: f32| {
+            let v = inverse * Vector4::new(x, y, z, 1.0);
+            Point::new(v.x / v.w, v.y / v.w, v.z / v.w)
+        };
+        let corners = [
+            corner(-1.0, -1.0, near),
+            corner(1.0, -1.0, near),
+            corner(1.0, 1.0, near),
+            corner(-1.0, 1.0, near),
+            corner(-1.0, -1.0, -near),
+            corner(1.0, -1.0, -near),
+            corner(1.0, 1.0, -near),
+            corner(-1.0, 1.0, -near),
+        ];
+
+        Self { planes, corners }
+    }
+    pub fn planes(&self) -> &[Plane; 6] {
+        &self.planes
+    }
+    pub fn plane(&self, index: usize) -> &Plane {
+        &self.planes[index]
+    }
+    pub fn corners(&self) -> &[Point; 8] {
+        &self.corners
+    }
+    // Points lying exactly on a plane are considered inside
+    pub fn contains_point(&self, point: &Point) -> Containment {
+        for plane in self.planes.iter() {
+            if plane.signed_distance(point) < 0.0 {
+                return Containment::Outside;
+            }
+        }
+        Containment::Inside
+    }
+    pub fn contains_sphere(&self, center: &Point, radius: f32) -> Containment {
+        let mut result = Containment::Inside;
+        for plane in self.planes.iter() {
+            let distance = plane.signed_distance(center);
+            if distance < -radius {
+                return Containment::Outside;
+            }
+            if distance < radius {
+                result = Containment::Intersecting;
+            }
+        }
+        result
+    }
+    // Tests the box corner furthest along each plane normal (the positive vertex)
+    // for rejection and the nearest (the negative vertex) for intersection
+    pub fn contains_aabb(&self, min: &Point, max: &Point) -> Containment {
+        let mut result = Containment::Inside;
+        for plane in self.planes.iter() {
+            let mut positive = *min;
+            let mut negative = *max;
+            for axis in 0..3 {
+                if plane.normal[axis] >= 0.0 {
+                    positive[axis] = max[axis];
+                    negative[axis] = min[axis];
+                }
+            }
+            if plane.signed_distance(&positive) < 0.0 {
+                return Containment::Outside;
+            }
+            if plane.signed_distance(&negative) < 0.0 {
+                result = Containment::Intersecting;
+            }
+        }
+        result
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use math::{Orthographic, Perspective, ReverseZPerspective};
+    use std::f32::consts::PI;
+
+    const CLIP_SPACES: [ClipSpace; 2] = [ClipSpace::OpenGl, ClipSpace::Vulkan];
+
+    fn point(x: f32, y: f32, z: f32) -> Point {
+        Point::new(x, y, z)
+    }
+
+    // Looks down -z from the origin with the near plane at 1 and the far plane at 10
+    fn frustum<T: Projection>(projection: T, clip_space: ClipSpace) -> Frustum {
+        let mut camera = Camera::new(point(0.0, 0.0, 0.0), point(0.0, 0.0, -1.0), Vec3::y(), projection);
+        camera.set_clip_space(clip_space);
+        Frustum::from_camera(&camera)
+    }
+    // 90 degrees wide, so the side planes are at |x| = -z and |y| = -z
+    fn perspectives() -> Vec<Frustum> {
+        let mut frustums = Vec::new();
+        for &clip_space in CLIP_SPACES.iter() {
+            frustums.push(frustum(Perspective::new(1.0, PI * 0.5, 1.0, 10.0), clip_space));
+            frustums.push(frustum(ReverseZPerspective::new(1.0, PI * 0.5, 1.0, 10.0), clip_space));
+        }
+        frustums
+    }
+
+    fn assert_plane_through(frustum: &Frustum, index: usize, point: Point) {
+        let distance = frustum.plane(index).signed_distance(&point);
+        assert!(distance.abs() < 1e-4, "plane {} is {} away from {:?}", index, distance, point);
+    }
+
+    #[test]
+    fn planes_and_corners_match_the_projection() {
+        for frustum in perspectives() {
+            assert_plane_through(&frustum, NEAR, point(0.3, -0.2, -1.0));
+            assert_plane_through(&frustum, FAR, point(-2.0, 4.0, -10.0));
+            assert_plane_through(&frustum, LEFT, point(-5.0, 1.0, -5.0));
+            assert_plane_through(&frustum, RIGHT, point(5.0, 1.0, -5.0));
+            assert_plane_through(&frustum, BOTTOM, point(1.0, -5.0, -5.0));
+            assert_plane_through(&frustum, TOP, point(1.0, 5.0, -5.0));
+            // Normals point inwards
+            assert!(frustum.plane(NEAR).normal.z < 0.0);
+            assert!(frustum.plane(FAR).normal.z > 0.0);
+            for (i, corner) in frustum.corners().iter().enumerate() {
+                let z = if i < 4 { -1.0 } else { -10.0 };
+                assert!((corner.z - z).abs() < 1e-3, "corner {} at {:?}", i, corner);
+                assert!((corner.x.abs() + z).abs() < 1e-3 && (corner.y.abs() + z).abs() < 1e-3);
+            }
+            let near = frustum.corners();
+            assert!(near[0].x < 0.0 && near[1].x > 0.0 && near[2].x > 0.0 && near[3].x < 0.0);
+        }
+    }
+
+    #[test]
+    fn reversed_depth_swaps_near_and_far() {
+        for &clip_space in CLIP_SPACES.iter() {
+            let standard = frustum(Perspective::new(1.0, PI * 0.5, 1.0, 10.0), clip_space);
+            let reversed = frustum(ReverseZPerspective::new(1.0, PI * 0.5, 1.0, 10.0), clip_space);
+            for &index in [NEAR, FAR].iter() {
+                let (a, b) = (standard.plane(index), reversed.plane(index));
+                assert!((a.normal - b.normal).norm() < 1e-4 && (a.d - b.d).abs() < 1e-3);
+            }
+        }
+    }
+
+    #[test]
+    fn points() {
+        for frustum in perspectives() {
+            assert_eq!(frustum.contains_point(&point(0.0, 0.0, -5.0)), Containment::Inside);
+            assert_eq!(frustum.contains_point(&point(4.9, -4.9, -5.0)), Containment::Inside);
+            assert_eq!(frustum.contains_point(&point(0.0, 0.0, 5.0)), Containment::Outside);
+            assert_eq!(frustum.contains_point(&point(0.0, 0.0, -0.5)), Containment::Outside);
+            assert_eq!(frustum.contains_point(&point(0.0, 0.0, -11.0)), Containment::Outside);
+            assert_eq!(frustum.contains_point(&point(5.1, 0.0, -5.0)), Containment::Outside);
+            assert_eq!(frustum.contains_point(&point(0.0, -5.1, -5.0)), Containment::Outside);
+        }
+    }
+
+    #[test]
+    fn spheres() {
+        for frustum in perspectives() {
+            assert_eq!(frustum.contains_sphere(&point(0.0, 0.0, -5.0), 1.0), Containment::Inside);
+            assert_eq!(frustum.contains_sphere(&point(5.0, 0.0, -5.0), 0.5), Containment::Intersecting);
+            assert_eq!(frustum.contains_sphere(&point(0.0, 0.0, -1.0), 0.5), Containment::Intersecting);
+            assert_eq!(frustum.contains_sphere(&point(0.0, 0.0, -10.2), 0.5), Containment::Intersecting);
+            assert_eq!(frustum.contains_sphere(&point(20.0, 0.0, -5.0), 1.0), Containment::Outside);
+            assert_eq!(frustum.contains_sphere(&point(0.0, 0.0, 2.0), 1.0), Containment::Outside);
+            assert_eq!(frustum.contains_sphere(&point(0.0, 0.0, -12.0), 1.0), Containment::Outside);
+        }
+    }
+
+    #[test]
+    fn aabbs() {
+        for frustum in perspectives() {
+            let contains = |min: [f32; 3], max: [f32; 3]| {
+                frustum.contains_aabb(&point(min[0], min[1], min[2]), &point(max[0], max[1], max[2]))
+            };
+            assert_eq!(contains([-1.0, -1.0, -6.0], [1.0, 1.0, -4.0]), Containment::Inside);
+            assert_eq!(contains([-1.0, -1.0, -11.0], [1.0, 1.0, -9.0]), Containment::Intersecting);
+            assert_eq!(contains([4.0, -1.0, -6.0], [6.0, 1.0, -4.0]), Containment::Intersecting);
+            // Encloses the whole frustum
+            assert_eq!(contains([-20.0, -20.0, -20.0], [20.0, 20.0, 20.0]), Containment::Intersecting);
+            assert_eq!(contains([-1.0, -1.0, 1.0], [1.0, 1.0, 3.0]), Containment::Outside);
+            assert_eq!(contains([8.0, -1.0, -6.0], [9.0, 1.0, -4.0]), Containment::Outside);
+            assert_eq!(contains([-1.0, -1.0, -30.0], [1.0, 1.0, -20.0]), Containment::Outside);
+        }
+    }
+
+    #[test]
+    fn orthographic() {
+        for &clip_space in CLIP_SPACES.iter() {
+            let frustum = frustum(Orthographic::new(-2.0, 2.0, -1.0, 1.0, 1.0, 10.0), clip_space);
+            assert_plane_through(&frustum, NEAR, point(0.0, 0.0, -1.0));
+            assert_plane_through(&frustum, FAR, point(0.0, 0.0, -10.0));
+            assert_plane_through(&frustum, RIGHT, point(2.0, 0.0, -5.0));
+            assert_plane_through(&frustum, TOP, point(0.0, 1.0, -5.0));
+            assert_eq!(frustum.contains_point(&point(1.9, 0.9, -9.0)), Containment::Inside);
+            assert_eq!(frustum.contains_point(&point(2.1, 0.0, -5.0)), Containment::Outside);
+            assert_eq!(frustum.contains_sphere(&point(0.0, 0.0, -5.0), 0.5), Containment::Inside);
+            assert_eq!(frustum.contains_sphere(&point(0.0, 1.0, -5.0), 0.5), Containment::Intersecting);
+            assert_eq!(frustum.contains_sphere(&point(0.0, 3.0, -5.0), 0.5), Containment::Outside);
+            let max = point(0.5, 0.5, 0.5);
+            assert_eq!(frustum.contains_aabb(&point(-0.5, -0.5, -0.5), &max), Containment::Outside);
+            assert_eq!(frustum.contains_aabb(&point(-0.5, -0.5, -2.0), &max), Containment::Intersecting);
+            let max = point(0.5, 0.5, -2.0);
+            assert_eq!(frustum.contains_aabb(&point(-0.5, -0.5, -3.0), &max), Containment::Inside);
+        }
+    }
+}
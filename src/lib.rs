@@ -5,12 +5,20 @@ extern crate vulkano;
 #[macro_use]
 extern crate vulkano_shader_derive;
 extern crate vulkano_win;
+extern crate vk_sys as vk;
 extern crate winit;
 extern crate nalgebra;
+extern crate png;
+#[macro_use]
+extern crate log;
+extern crate rayon;
 
 
 pub mod ray;
 pub mod camera; 
 pub mod math;
+pub mod frustum;
+pub mod bounds;
+pub mod bvh;
 pub mod renderer;
 
@@ -1,10 +1,12 @@
-use nalgebra::{Vector3, Perspective3, Matrix4, Point3, Unit, Orthographic3, Isometry3, Rotation3};
+use nalgebra::{Vector3, Vector4, Perspective3, Matrix3, Matrix4, Point3, Unit, Orthographic3, Isometry3, Rotation3,
+               UnitQuaternion};
 
 
 pub type Isometry = Isometry3<f32>;
 pub type Orthographic = Orthographic3<f32>;
 pub type Perspective = Perspective3<f32>;
 pub type Rotation = Rotation3<f32>;
+pub type Quaternion = UnitQuaternion<f32>;
 pub type Vec3 = Vector3<f32>;
 pub type Mat4 = Matrix4<f32>;
 pub type Point = Point3<f32>;
@@ -47,8 +49,185 @@ impl Projection for Orthographic {
     }
 }
 
+// A perspective projection outputting to Vulkan clip space with the depth range
+// reversed, the near plane maps to a depth of 1 and the far plane to 0. Without a
+// far plane depth approaches 0 at infinity
+#[derive(Debug, Clone, Copy)]
+pub struct ReverseZPerspective {
+    aspect: f32,
+    fovy: f32,
+    znear: f32,
+    zfar: Option<f32>,
+    matrix: Mat4,
+    inverse: Mat4,
+}
+
+impl ReverseZPerspective {
+    pub fn new(aspect: f32, fovy: f32, znear: f32, zfar: f32) -> Self {
+        Self::build(aspect, fovy, znear, Some(zfar))
+    }
+    pub fn infinite(aspect: f32, fovy: f32, znear: f32) -> Self {
+        Self::build(aspect, fovy, znear, None)
+    }
+    fn build(aspect: f32, fovy: f32, znear: f32, zfar: Option<f32>) -> Self {
+        let mut projection = Self {
+            aspect,
+            fovy,
+            znear,
+            zfar,
+            matrix: Mat4::identity(),
+            inverse: Mat4::identity(),
+        };
+        projection.update_matrix();
+        projection
+    }
+    fn update_matrix(&mut self) {
+        let f = 1.0 / (self.fovy * 0.5).tan();
+        let (a, b) = match self.zfar {
+            Some(zfar) => {
+                let range = zfar - self.znear;
+                (self.znear / range, self.znear * zfar / range)
+            }
+            None => (0.0, self.znear),
+        };
+        self.matrix = Mat4::new(
+            f / self.aspect, 0.0, 0.0, 0.0,
+            0.0, -f, 0.0, 0.0,
+            0.0, 0.0, a, b,
+            0.0, 0.0, -1.0, 0.0,
+        );
+        self.inverse = self.matrix.try_inverse().unwrap_or_else(Mat4::identity);
+    }
+    pub fn is_infinite(&self) -> bool {
+        self.zfar.is_none()
+    }
+    pub fn set_infinite(&mut self) {
+        self.zfar = None;
+        self.update_matrix();
+    }
+    pub fn fovy(&self) -> f32 {
+        self.fovy
+    }
+    pub fn aspect(&self) -> f32 {
+        self.aspect
+    }
+    pub fn set_fovy(&mut self, fovy: f32) {
+        self.fovy = fovy;
+        self.update_matrix();
+    }
+    pub fn set_aspect(&mut self, aspect: f32) {
+        self.aspect = aspect;
+        self.update_matrix();
+    }
+}
+
+impl Projection for ReverseZPerspective {
+    fn matrix(&self) -> &Mat4 {
+        &self.matrix
+    }
+    fn clip_space(&self) -> ClipSpace {
+        ClipSpace::Vulkan
+    }
+    fn reversed_depth(&self) -> bool {
+        true
+    }
+    // An infinite zfar removes the far plane
+    fn set_znear_zfar(&mut self, znear: f32, zfar: f32) {
+        self.znear = znear;
+        self.zfar = if zfar.is_finite() { Some(zfar) } else { None };
+        self.update_matrix();
+    }
+    fn unproject(&self, point: Point) -> Point {
+        let v = self.inverse * Vector4::new(point.x, point.y, point.z, 1.0);
+        Point::new(v.x / v.w, v.y / v.w, v.z / v.w)
+    }
+    fn get_znear(&self) -> f32 {
+        self.znear
+    }
+    fn get_zfar(&self) -> f32 {
+        self.zfar.unwrap_or(::std::f32::INFINITY)
+    }
+}
+
+// The conventions of the clip space a projection matrix outputs to
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ClipSpace {
+    // Y up in normalised device coordinates, depth ranging from -1 to 1
+    OpenGl,
+    // Y down in normalised device coordinates, depth ranging from 0 to 1
+    Vulkan,
+}
+
+impl ClipSpace {
+    // The matrix taking OpenGL clip coordinates into this clip space
+    pub fn from_opengl(&self) -> Mat4 {
+        match *self {
+            ClipSpace::OpenGl => Mat4::identity(),
+            ClipSpace::Vulkan => Mat4::new(
+                1.0, 0.0, 0.0, 0.0,
+                0.0, -1.0, 0.0, 0.0,
+                0.0, 0.0, 0.5, 0.5,
+                0.0, 0.0, 0.0, 1.0,
+            ),
+        }
+    }
+    // The matrix taking clip coordinates in this clip space into OpenGL clip coordinates
+    pub fn to_opengl(&self) -> Mat4 {
+        match *self {
+            ClipSpace::OpenGl => Mat4::identity(),
+            ClipSpace::Vulkan => Mat4::new(
+                1.0, 0.0, 0.0, 0.0,
+                0.0, -1.0, 0.0, 0.0,
+                0.0, 0.0, 2.0, -1.0,
+                0.0, 0.0, 0.0, 1.0,
+            ),
+        }
+    }
+    // The matrix converting clip coordinates in `from` into clip coordinates in `to`
+    pub fn conversion(from: ClipSpace, to: ClipSpace) -> Mat4 {
+        if from == to {
+            return Mat4::identity();
+        }
+        to.from_opengl() * from.to_opengl()
+    }
+    // Converts a point in normalised device coordinates from `from` into `to`
+    pub fn convert_ndc(point: Point, from: ClipSpace, to: ClipSpace) -> Point {
+        let point = match from {
+            ClipSpace::OpenGl => point,
+            ClipSpace::Vulkan => Point::new(point.x, -point.y, point.z * 2.0 - 1.0),
+        };
+        match to {
+            ClipSpace::OpenGl => point,
+            ClipSpace::Vulkan => Point::new(point.x, -point.y, (point.z + 1.0) * 0.5),
+        }
+    }
+    // The normalised device depth of the near and far planes
+    pub fn depth_range(&self) -> (f32, f32) {
+        match *self {
+            ClipSpace::OpenGl => (-1.0, 1.0),
+            ClipSpace::Vulkan => (0.0, 1.0),
+        }
+    }
+}
+
 pub trait Projection {
     fn matrix(&self) -> &Mat4;
+    // The clip space `matrix` outputs to and `unproject` expects its input in
+    fn clip_space(&self) -> ClipSpace {
+        ClipSpace::OpenGl
+    }
+    // Whether the near plane maps to the far end of the clip space's depth range
+    fn reversed_depth(&self) -> bool {
+        false
+    }
+    // The normalised device depth of the near and far planes
+    fn depth_range(&self) -> (f32, f32) {
+        let (near, far) = self.clip_space().depth_range();
+        if self.reversed_depth() {
+            return (far, near);
+        }
+        (near, far)
+    }
     fn set_znear_zfar(&mut self, znear: f32, zfar: f32);
     fn get_znear(&self) -> f32;
     fn get_zfar(&self) -> f32;
@@ -72,4 +251,39 @@ pub fn cross(a: Vec3, b: Vec3) -> Vec3 {
     let z = a.x * b.y - a.y * b.x;
 
     Vec3::new(x, y, z)
-}
\ No newline at end of file
+}
+
+// Two unit vectors perpendicular to `up` and each other, forward and forward x up.
+// Used as the zero yaw frame when measuring angles around `up`
+pub fn perpendicular_basis(up: Vec3) -> (Vec3, Vec3) {
+    let reference = if up.x.abs() < 0.9 {
+        Vec3::new(1.0, 0.0, 0.0)
+    } else {
+        Vec3::new(0.0, 0.0, 1.0)
+    };
+    let forward = (reference - up * reference.dot(&up)).normalize();
+    let right = cross(forward, up);
+    (forward, right)
+}
+
+// The rotation taking -z to `look` and x to `right`, the two must be perpendicular.
+// This is the camera to world rotation of a camera looking along `look`
+pub fn look_rotation(look: Vec3, right: Vec3) -> Quaternion {
+    let look = look.normalize();
+    let right = right.normalize();
+    let up = cross(right, look);
+    let basis = Matrix3::from_columns(&[right, up, -look]);
+    Quaternion::from_rotation_matrix(&Rotation::from_matrix_unchecked(basis))
+}
+
+// Transforms a point by a homogeneous matrix, dividing through by w
+pub fn transform_point(matrix: &Mat4, point: &Point) -> Point {
+    let v = matrix * point.to_homogeneous();
+    Point::new(v.x / v.w, v.y / v.w, v.z / v.w)
+}
+
+// Transforms a direction by a homogeneous matrix, ignoring translation
+pub fn transform_vector(matrix: &Mat4, vector: &Vec3) -> Vec3 {
+    let v = matrix * Vector4::new(vector.x, vector.y, vector.z, 0.0);
+    Vec3::new(v.x, v.y, v.z)
+}
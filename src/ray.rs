@@ -1,15 +1,302 @@
-use math::{Point, Vec3};
+use math;
+use math::{Isometry, Point, Vec3};
 
+// Anything closer to zero than this is treated as parallel / degenerate
+const EPSILON: f32 = 1e-6;
+
+#[derive(Debug, Clone, Copy)]
 pub struct Ray3 {
     origin: Point,
     dir: Vec3,
 }
 
+// The result of a successful intersection query. `distance` is measured along
+// the (normalised) ray direction and `normal` always faces back towards the ray
+#[derive(Debug, Clone, Copy)]
+pub struct RayHit {
+    pub distance: f32,
+    pub point: Point,
+    pub normal: Vec3,
+}
+
 impl Ray3 {
+    // Note: dir is normalised so hit distances are in world units
     pub fn new(origin: Point, dir: Vec3) -> Self {
         Self {
             origin,
-            dir
+            dir: dir.normalize(),
+        }
+    }
+    pub fn origin(&self) -> Point {
+        self.origin
+    }
+    pub fn dir(&self) -> Vec3 {
+        self.dir
+    }
+    pub fn point_at(&self, t: f32) -> Point {
+        self.origin + self.dir * t
+    }
+    pub fn transform(&self, isometry: &Isometry) -> Ray3 {
+        Self {
+            origin: isometry * self.origin,
+            dir: isometry * self.dir,
+        }
+    }
+    fn hit(&self, distance: f32, normal: Vec3) -> RayHit {
+        let normal = if normal.dot(&self.dir) > 0.0 { -normal } else { normal };
+        RayHit {
+            distance,
+            point: self.point_at(distance),
+            normal,
+        }
+    }
+    // Returns None if the ray is parallel to the plane or the plane is behind the ray
+    pub fn intersect_plane(&self, point_on_plane: Point, normal: Vec3) -> Option<RayHit> {
+        let normal = normal.normalize();
+        let denom = normal.dot(&self.dir);
+        if denom.abs() < EPSILON {
+            return None;
+        }
+        let t = normal.dot(&(point_on_plane - self.origin)) / denom;
+        if t < 0.0 {
+            return None;
+        }
+        Some(self.hit(t, normal))
+    }
+    // If the origin is inside the sphere the exit point is returned. A ray that
+    // grazes the sphere counts as a hit
+    pub fn intersect_sphere(&self, center: Point, radius: f32) -> Option<RayHit> {
+        let oc = self.origin - center;
+        let b = oc.dot(&self.dir);
+        let c = oc.dot(&oc) - radius * radius;
+        let discriminant = b * b - c;
+        if discriminant < 0.0 {
+            return None;
         }
+        let root = discriminant.sqrt();
+        let mut t = -b - root;
+        if t < 0.0 {
+            t = -b + root;
+        }
+        if t < 0.0 {
+            return None;
+        }
+        let normal = (self.point_at(t) - center) / radius;
+        Some(self.hit(t, normal))
+    }
+    // Slab test against an axis aligned box. Axes the ray is parallel to are
+    // handled separately so no infinities or NaNs leak into the result
+    pub fn intersect_aabb(&self, min: Point, max: Point) -> Option<RayHit> {
+        let mut t_min = 0.0f32;
+        let mut t_max = ::std::f32::MAX;
+        let mut normal = Vec3::zeros();
+        let mut exit_normal = Vec3::zeros();
+        for axis in 0..3 {
+            let origin = self.origin[axis];
+            let dir = self.dir[axis];
+            if dir.abs() < EPSILON {
+                if origin < min[axis] || origin > max[axis] {
+                    return None;
+                }
+                continue;
+            }
+            let inv = 1.0 / dir;
+            let mut near = (min[axis] - origin) * inv;
+            let mut far = (max[axis] - origin) * inv;
+            let mut axis_normal = Vec3::zeros();
+            axis_normal[axis] = -dir.signum();
+            if near > far {
+                ::std::mem::swap(&mut near, &mut far);
+            }
+            if near > t_min {
+                t_min = near;
+                normal = axis_normal;
+            }
+            if far < t_max {
+                t_max = far;
+                exit_normal = -axis_normal;
+            }
+            if t_min > t_max {
+                return None;
+            }
+        }
+        // Origin inside the box, report the exit point instead
+        if normal == Vec3::zeros() {
+            return Some(self.hit(t_max, exit_normal));
+        }
+        Some(self.hit(t_min, normal))
+    }
+    // Möller–Trumbore, both windings are considered front facing
+    pub fn intersect_triangle(&self, a: Point, b: Point, c: Point) -> Option<RayHit> {
+        let edge1 = b - a;
+        let edge2 = c - a;
+        let p = math::cross(self.dir, edge2);
+        let det = edge1.dot(&p);
+        if det.abs() < EPSILON {
+            return None;
+        }
+        let inv_det = 1.0 / det;
+        let s = self.origin - a;
+        let u = s.dot(&p) * inv_det;
+        if u < 0.0 || u > 1.0 {
+            return None;
+        }
+        let q = math::cross(s, edge1);
+        let v = self.dir.dot(&q) * inv_det;
+        if v < 0.0 || u + v > 1.0 {
+            return None;
+        }
+        let t = edge2.dot(&q) * inv_det;
+        if t < EPSILON {
+            return None;
+        }
+        let normal = math::cross(edge1, edge2).normalize();
+        Some(self.hit(t, normal))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn point(x: f32, y: f32, z: f32) -> Point {
+        Point::new(x, y, z)
     }
-}
\ No newline at end of file
+    fn vec(x: f32, y: f32, z: f32) -> Vec3 {
+        Vec3::new(x, y, z)
+    }
+
+    #[test]
+    fn plane_hit_faces_the_ray() {
+        let ray = Ray3::new(point(0.0, 5.0, 0.0), vec(0.0, -2.0, 0.0));
+        let hit = ray.intersect_plane(point(3.0, 1.0, -2.0), vec(0.0, 1.0, 0.0)).unwrap();
+        assert_eq!(hit.distance, 4.0);
+        assert_eq!(hit.point, point(0.0, 1.0, 0.0));
+        assert_eq!(hit.normal, vec(0.0, 1.0, 0.0));
+        // Same plane from below, the normal is flipped towards the ray
+        let ray = Ray3::new(point(0.0, -1.0, 0.0), vec(0.0, 1.0, 0.0));
+        let hit = ray.intersect_plane(point(0.0, 1.0, 0.0), vec(0.0, 1.0, 0.0)).unwrap();
+        assert_eq!(hit.normal, vec(0.0, -1.0, 0.0));
+    }
+
+    #[test]
+    fn ray_parallel_to_plane_misses() {
+        let ray = Ray3::new(point(0.0, 2.0, 0.0), vec(1.0, 0.0, 0.0));
+        assert!(ray.intersect_plane(point(0.0, 0.0, 0.0), vec(0.0, 1.0, 0.0)).is_none());
+        // Lying in the plane is parallel too
+        let ray = Ray3::new(point(0.0, 0.0, 0.0), vec(1.0, 0.0, 1.0));
+        assert!(ray.intersect_plane(point(0.0, 0.0, 0.0), vec(0.0, 1.0, 0.0)).is_none());
+    }
+
+    #[test]
+    fn plane_behind_the_origin_misses() {
+        let ray = Ray3::new(point(0.0, 5.0, 0.0), vec(0.0, 1.0, 0.0));
+        assert!(ray.intersect_plane(point(0.0, 1.0, 0.0), vec(0.0, 1.0, 0.0)).is_none());
+    }
+
+    #[test]
+    fn sphere_hit_from_outside_returns_entry() {
+        let ray = Ray3::new(point(-5.0, 0.0, 0.0), vec(1.0, 0.0, 0.0));
+        let hit = ray.intersect_sphere(point(0.0, 0.0, 0.0), 1.0).unwrap();
+        assert_eq!(hit.distance, 4.0);
+        assert_eq!(hit.normal, vec(-1.0, 0.0, 0.0));
+    }
+
+    #[test]
+    fn ray_grazing_sphere_hits_tangentially() {
+        let ray = Ray3::new(point(-5.0, 1.0, 0.0), vec(1.0, 0.0, 0.0));
+        let hit = ray.intersect_sphere(point(0.0, 0.0, 0.0), 1.0).unwrap();
+        assert_eq!(hit.distance, 5.0);
+        assert_eq!(hit.point, point(0.0, 1.0, 0.0));
+        assert_eq!(hit.normal, vec(0.0, 1.0, 0.0));
+        let ray = Ray3::new(point(-5.0, 1.001, 0.0), vec(1.0, 0.0, 0.0));
+        assert!(ray.intersect_sphere(point(0.0, 0.0, 0.0), 1.0).is_none());
+    }
+
+    #[test]
+    fn origin_inside_sphere_returns_exit() {
+        let ray = Ray3::new(point(0.0, 0.0, 0.0), vec(0.0, 0.0, 1.0));
+        let hit = ray.intersect_sphere(point(0.0, 0.0, 0.0), 2.0).unwrap();
+        assert_eq!(hit.distance, 2.0);
+        assert_eq!(hit.point, point(0.0, 0.0, 2.0));
+        assert_eq!(hit.normal, vec(0.0, 0.0, -1.0));
+    }
+
+    #[test]
+    fn sphere_behind_the_origin_misses() {
+        let ray = Ray3::new(point(5.0, 0.0, 0.0), vec(1.0, 0.0, 0.0));
+        assert!(ray.intersect_sphere(point(0.0, 0.0, 0.0), 1.0).is_none());
+    }
+
+    #[test]
+    fn aabb_hit_reports_entry_face() {
+        let ray = Ray3::new(point(0.5, 5.0, -0.5), vec(0.0, -1.0, 0.0));
+        let hit = ray.intersect_aabb(point(-1.0, -1.0, -1.0), point(1.0, 1.0, 1.0)).unwrap();
+        assert_eq!(hit.distance, 4.0);
+        assert_eq!(hit.point, point(0.5, 1.0, -0.5));
+        assert_eq!(hit.normal, vec(0.0, 1.0, 0.0));
+    }
+
+    #[test]
+    fn ray_along_aabb_edge_hits() {
+        let (min, max) = (point(-1.0, -1.0, -1.0), point(1.0, 1.0, 1.0));
+        let ray = Ray3::new(point(-5.0, 1.0, 1.0), vec(1.0, 0.0, 0.0));
+        let hit = ray.intersect_aabb(min, max).unwrap();
+        assert_eq!(hit.distance, 4.0);
+        assert_eq!(hit.normal, vec(-1.0, 0.0, 0.0));
+        // Parallel to the box just past the edge
+        let ray = Ray3::new(point(-5.0, 1.001, 1.0), vec(1.0, 0.0, 0.0));
+        assert!(ray.intersect_aabb(min, max).is_none());
+    }
+
+    #[test]
+    fn origin_inside_aabb_returns_exit() {
+        let ray = Ray3::new(point(0.0, 0.0, 0.0), vec(1.0, 0.0, 0.0));
+        let hit = ray.intersect_aabb(point(-1.0, -1.0, -1.0), point(1.0, 1.0, 1.0)).unwrap();
+        assert_eq!(hit.distance, 1.0);
+        assert_eq!(hit.point, point(1.0, 0.0, 0.0));
+        assert_eq!(hit.normal, vec(-1.0, 0.0, 0.0));
+    }
+
+    #[test]
+    fn aabb_behind_the_origin_misses() {
+        let ray = Ray3::new(point(5.0, 0.0, 0.0), vec(1.0, 0.0, 0.0));
+        assert!(ray.intersect_aabb(point(-1.0, -1.0, -1.0), point(1.0, 1.0, 1.0)).is_none());
+    }
+
+    #[test]
+    fn triangle_hit_for_both_windings() {
+        let (a, b, c) = (point(0.0, 0.0, 0.0), point(1.0, 0.0, 0.0), point(0.0, 1.0, 0.0));
+        let ray = Ray3::new(point(0.25, 0.25, 1.0), vec(0.0, 0.0, -1.0));
+        for hit in [ray.intersect_triangle(a, b, c), ray.intersect_triangle(a, c, b)].iter() {
+            let hit = hit.unwrap();
+            assert_eq!(hit.distance, 1.0);
+            assert_eq!(hit.normal, vec(0.0, 0.0, 1.0));
+        }
+    }
+
+    #[test]
+    fn ray_parallel_to_triangle_misses() {
+        let (a, b, c) = (point(0.0, 0.0, 0.0), point(1.0, 0.0, 0.0), point(0.0, 1.0, 0.0));
+        let ray = Ray3::new(point(-1.0, 0.25, 0.0), vec(1.0, 0.0, 0.0));
+        assert!(ray.intersect_triangle(a, b, c).is_none());
+        let ray = Ray3::new(point(-1.0, 0.25, 1.0), vec(1.0, 0.0, 0.0));
+        assert!(ray.intersect_triangle(a, b, c).is_none());
+    }
+
+    #[test]
+    fn ray_through_triangle_edge_hits() {
+        let (a, b, c) = (point(0.0, 0.0, 0.0), point(1.0, 0.0, 0.0), point(0.0, 1.0, 0.0));
+        let ray = Ray3::new(point(0.5, 0.0, 1.0), vec(0.0, 0.0, -1.0));
+        assert_eq!(ray.intersect_triangle(a, b, c).unwrap().distance, 1.0);
+        let ray = Ray3::new(point(0.5, -0.001, 1.0), vec(0.0, 0.0, -1.0));
+        assert!(ray.intersect_triangle(a, b, c).is_none());
+    }
+
+    #[test]
+    fn triangle_behind_the_origin_misses() {
+        let ray = Ray3::new(point(0.25, 0.25, 1.0), vec(0.0, 0.0, 1.0));
+        let hit = ray.intersect_triangle(point(0.0, 0.0, 0.0), point(1.0, 0.0, 0.0), point(0.0, 1.0, 0.0));
+        assert!(hit.is_none());
+    }
+}
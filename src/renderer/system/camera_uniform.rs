@@ -0,0 +1,103 @@
+use camera::Camera;
+use math::{Isometry, Mat4, Projection};
+use std::sync::Arc;
+use vulkano::{buffer::{cpu_pool::CpuBufferPoolSubbuffer, CpuBufferPool},
+              device::Device,
+              memory::pool::StdMemoryPool};
+
+// Column major, matching the layout of a glsl mat4
+pub fn mat4_to_array(matrix: &Mat4) -> [[f32; 4]; 4] {
+    let mut array = [[0.0; 4]; 4];
+    for column in 0..4 {
+        for row in 0..4 {
+            array[column][row] = matrix[(row, column)];
+        }
+    }
+    array
+}
+
+// Per frame camera data, laid out to match the `CameraData` std140 uniform block
+// declared by the shaders
+#[derive(Debug, Clone, Copy)]
+#[repr(C)]
+pub struct CameraUniform {
+    pub view: [[f32; 4]; 4],
+    pub projection: [[f32; 4]; 4],
+    pub view_projection: [[f32; 4]; 4],
+    pub inverse_view: [[f32; 4]; 4],
+    pub inverse_projection: [[f32; 4]; 4],
+    pub inverse_view_projection: [[f32; 4]; 4],
+    // w is unused
+    pub position: [f32; 4],
+}
+
+impl CameraUniform {
+    pub fn from_camera<T: Projection>(camera: &Camera<T>) -> Self {
+        let view = camera.view_matrix();
+        let projection = camera.projection_matrix();
+        let view_projection = projection * view;
+        let inverse = |matrix: &Mat4| matrix.try_inverse().unwrap_or_else(Mat4::identity);
+        let eye = camera.eye();
+        Self {
+            view: mat4_to_array(&view),
+            projection: mat4_to_array(&projection),
+            view_projection: mat4_to_array(&view_projection),
+            inverse_view: mat4_to_array(&inverse(&view)),
+            inverse_projection: mat4_to_array(&inverse(&projection)),
+            inverse_view_projection: mat4_to_array(&inverse(&view_projection)),
+            position: [eye.x, eye.y, eye.z, 1.0],
+        }
+    }
+}
+
+// Per object transforms passed as push constants, 128 bytes so it fits in the
+// minimum push constant size every device supports
+#[derive(Debug, Clone, Copy)]
+#[repr(C)]
+pub struct ModelTransform {
+    pub model: [[f32; 4]; 4],
+    // The inverse transpose of the model matrix, only the upper 3x3 is used
+    pub normal: [[f32; 4]; 4],
+}
+
+impl ModelTransform {
+    pub fn identity() -> Self {
+        Self::from_matrix(&Mat4::identity())
+    }
+    pub fn from_matrix(model: &Mat4) -> Self {
+        let normal = model
+            .try_inverse()
+            .map(|inverse| inverse.transpose())
+            .unwrap_or_else(Mat4::identity);
+        Self {
+            model: mat4_to_array(model),
+            normal: mat4_to_array(&normal),
+        }
+    }
+    // Isometries have no scale so the rotation doubles as the normal matrix
+    pub fn from_isometry(isometry: &Isometry) -> Self {
+        Self {
+            model: mat4_to_array(&isometry.to_homogeneous()),
+            normal: mat4_to_array(&isometry.rotation.to_homogeneous()),
+        }
+    }
+}
+
+pub type CameraBuffer = CpuBufferPoolSubbuffer<CameraUniform, Arc<StdMemoryPool>>;
+
+// Hands out a fresh uniform buffer every frame so the previous frame's data can
+// still be in use by the GPU
+pub struct CameraUniformPool {
+    pool: CpuBufferPool<CameraUniform>,
+}
+
+impl CameraUniformPool {
+    pub fn new(device: Arc<Device>) -> Self {
+        Self {
+            pool: CpuBufferPool::uniform_buffer(device),
+        }
+    }
+    pub fn next<T: Projection>(&self, camera: &Camera<T>) -> CameraBuffer {
+        self.pool.next(CameraUniform::from_camera(camera))
+    }
+}
@@ -0,0 +1,704 @@
+use camera::Camera;
+use math;
+use math::{Mat4, Point, Projection, Vec3};
+use renderer::system::camera_uniform::CameraBuffer;
+use renderer::system::compute_system::ComputeSystem;
+use renderer::system::error::RendererError;
+use renderer::system::frames::FramesInFlight;
+use renderer::system::gbuffer::{GBuffer, DEPTH, DIFFUSE, NORMALS, SPECULAR};
+use renderer::system::lighting_system::{additive_blend, fullscreen_triangle, LightingVertex};
+use std::f32;
+use std::sync::Arc;
+use vulkano::{buffer::{BufferUsage, CpuAccessibleBuffer, DeviceLocalBuffer},
+              command_buffer::{AutoCommandBuffer, AutoCommandBufferBuilder, DynamicState},
+              descriptor::descriptor_set::{DescriptorSet, PersistentDescriptorSet},
+              device::Queue,
+              framebuffer::{RenderPassAbstract, Subpass},
+              pipeline::{ComputePipeline, GraphicsPipeline, GraphicsPipelineAbstract}};
+
+// Must match MAX_LIGHTS in the shaders
+pub const MAX_LIGHTS_PER_CLUSTER: usize = 64;
+// Must match local_size_x of the culling shader
+const CULL_LOCAL_SIZE: u32 = 64;
+
+// A point light as laid out in the light storage buffer
+#[derive(Debug, Clone, Copy)]
+#[repr(C)]
+pub struct ClusterLight {
+    pub position_radius: [f32; 4],
+    // w is unused
+    pub color: [f32; 4],
+}
+
+impl ClusterLight {
+    pub fn new(position: Point, radius: f32, color: [f32; 3]) -> Self {
+        Self {
+            position_radius: [position.x, position.y, position.z, radius],
+            color: [color[0], color[1], color[2], 1.0],
+        }
+    }
+}
+
+// Splits the view frustum into screen space tiles and exponentially spaced depth
+// slices between `znear` and `zfar`. Anything beyond `zfar` falls into the last
+// slice, which reaches out to the camera's far plane, so an infinite projection
+// can still be clustered
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ClusterGrid {
+    pub tiles_x: u32,
+    pub tiles_y: u32,
+    pub slices: u32,
+    pub znear: f32,
+    pub zfar: f32,
+}
+
+impl ClusterGrid {
+    pub fn new(tiles_x: u32, tiles_y: u32, slices: u32, znear: f32, zfar: f32) -> Self {
+        Self {
+            tiles_x,
+            tiles_y,
+            slices,
+            znear,
+            zfar,
+        }
+    }
+    pub fn cluster_count(&self) -> usize {
+        (self.tiles_x * self.tiles_y * self.slices) as usize
+    }
+    pub fn cluster_index(&self, x: u32, y: u32, slice: u32) -> usize {
+        ((slice * self.tiles_y + y) * self.tiles_x + x) as usize
+    }
+    // The view space distance of the near side of `slice`
+    pub fn slice_depth(&self, slice: u32) -> f32 {
+        self.znear * (self.zfar / self.znear).powf(slice as f32 / self.slices as f32)
+    }
+    pub fn slice_of_depth(&self, depth: f32) -> u32 {
+        let slice = (depth / self.znear).ln() / (self.zfar / self.znear).ln() * self.slices as f32;
+        (slice.max(0.0) as u32).min(self.slices - 1)
+    }
+    // Where the last slice ends, the camera's far plane when that is beyond `zfar`
+    // and infinity for an infinite projection
+    pub fn last_slice_far<T: Projection>(&self, camera: &Camera<T>) -> f32 {
+        camera.projection_ref().get_zfar().max(self.zfar)
+    }
+    fn push_constants<T: Projection>(&self, camera: &Camera<T>, light_count: u32) -> ([u32; 4], [f32; 4]) {
+        let (near, far) = camera.depth_range();
+        (
+            [self.tiles_x, self.tiles_y, self.slices, light_count],
+            [self.znear, self.zfar, near, (near + far) * 0.5],
+        )
+    }
+    // The view space bounds of one cluster. Each corner of the tile is unprojected
+    // at two depths and the line through them is cut at the slice's near and far
+    // depths, which works for orthographic as well as perspective projections.
+    // `last_slice_far` comes from `last_slice_far`
+    pub fn cluster_bounds(
+        &self,
+        inverse_projection: &Mat4,
+        ndc_depths: (f32, f32),
+        last_slice_far: f32,
+        x: u32,
+        y: u32,
+        slice: u32,
+    ) -> (Vec3, Vec3) {
+        let ndc_min = [
+            x as f32 / self.tiles_x as f32 * 2.0 - 1.0,
+            y as f32 / self.tiles_y as f32 * 2.0 - 1.0,
+        ];
+        let ndc_max = [
+            (x + 1) as f32 / self.tiles_x as f32 * 2.0 - 1.0,
+            (y + 1) as f32 / self.tiles_y as f32 * 2.0 - 1.0,
+        ];
+        let mut depths = [self.slice_depth(slice), self.slice_depth(slice + 1)];
+        if slice + 1 == self.slices {
+            depths[1] = depths[1].max(last_slice_far);
+        }
+        let mut min = Vec3::repeat(f32::MAX);
+        let mut max = Vec3::repeat(f32::MIN);
+        for &cx in [ndc_min[0], ndc_max[0]].iter() {
+            for &cy in [ndc_min[1], ndc_max[1]].iter() {
+                let a = math::transform_point(inverse_projection, &Point::new(cx, cy, ndc_depths.0)).coords;
+                let b = math::transform_point(inverse_projection, &Point::new(cx, cy, ndc_depths.1)).coords;
+                for &depth in depths.iter() {
+                    let p = point_at_depth(a, b, depth);
+                    for axis in 0..3 {
+                        min[axis] = min[axis].min(p[axis]);
+                        max[axis] = max[axis].max(p[axis]);
+                    }
+                }
+            }
+        }
+        (min, max)
+    }
+}
+
+// The point at view space `depth` on the line through `a` and `b`. An infinite
+// depth gives infinite coordinates along every axis the line moves in
+fn point_at_depth(a: Vec3, b: Vec3, depth: f32) -> Vec3 {
+    let d = b - a;
+    if depth.is_infinite() {
+        let away = if d.z > 0.0 { -d } else { d };
+        let mut p = Vec3::new(0.0, 0.0, -depth);
+        for axis in 0..2 {
+            p[axis] = if away[axis] == 0.0 { a[axis] } else { away[axis].signum() * f32::INFINITY };
+        }
+        return p;
+    }
+    a + d * ((-depth - a.z) / d.z)
+}
+
+// Light indices per cluster, laid out exactly like the GPU buffers: `counts` has
+// one entry per cluster and `indices` holds MAX_LIGHTS_PER_CLUSTER slots per cluster
+#[derive(Debug, Clone, PartialEq)]
+pub struct ClusterAssignment {
+    pub counts: Vec<u32>,
+    pub indices: Vec<u32>,
+}
+
+impl ClusterAssignment {
+    pub fn lights(&self, cluster: usize) -> &[u32] {
+        let start = cluster * MAX_LIGHTS_PER_CLUSTER;
+        &self.indices[start..start + self.counts[cluster] as usize]
+    }
+}
+
+// The CPU reference of the culling shader, producing the same assignment
+pub fn assign_lights<T: Projection>(grid: &ClusterGrid, camera: &Camera<T>, lights: &[ClusterLight]) -> ClusterAssignment {
+    let inverse_projection = camera
+        .projection_matrix()
+        .try_inverse()
+        .unwrap_or_else(Mat4::identity);
+    let view = camera.view_matrix();
+    let (near, far) = camera.depth_range();
+    let ndc_depths = (near, (near + far) * 0.5);
+    let last_slice_far = grid.last_slice_far(camera);
+    let view_lights: Vec<(Point, f32)> = lights
+        .iter()
+        .map(|light| {
+            let p = light.position_radius;
+            (math::transform_point(&view, &Point::new(p[0], p[1], p[2])), p[3])
+        })
+        .collect();
+
+    let mut assignment = ClusterAssignment {
+        counts: vec![0; grid.cluster_count()],
+        indices: vec![0; grid.cluster_count() * MAX_LIGHTS_PER_CLUSTER],
+    };
+    for slice in 0..grid.slices {
+        for y in 0..grid.tiles_y {
+            for x in 0..grid.tiles_x {
+                let cluster = grid.cluster_index(x, y, slice);
+                let (min, max) = grid.cluster_bounds(&inverse_projection, ndc_depths, last_slice_far, x, y, slice);
+                let mut count = 0;
+                for (i, &(center, radius)) in view_lights.iter().enumerate() {
+                    if count == MAX_LIGHTS_PER_CLUSTER {
+                        break;
+                    }
+                    let mut distance = 0.0;
+                    for axis in 0..3 {
+                        let d = center[axis] - center[axis].max(min[axis]).min(max[axis]);
+                        distance += d * d;
+                    }
+                    if distance <= radius * radius {
+                        assignment.indices[cluster * MAX_LIGHTS_PER_CLUSTER + count] = i as u32;
+                        count += 1;
+                    }
+                }
+                assignment.counts[cluster] = count as u32;
+            }
+        }
+    }
+    assignment
+}
+
+// The storage buffers the culling pass writes and the lighting pass reads
+pub struct ClusterBuffers {
+    pub lights: Arc<CpuAccessibleBuffer<[ClusterLight]>>,
+    pub light_count: u32,
+    pub counts: Arc<DeviceLocalBuffer<[u32]>>,
+    pub indices: Arc<DeviceLocalBuffer<[u32]>>,
+}
+
+// Assigns lights to clusters on the GPU with a compute pass, run it before the
+// render pass and draw with `ClusteredLightingSystem` in the lighting subpass.
+// Like a `UniformRing` every frame in flight has its own cluster buffers, so one
+// frame can be culled while the GPU still shades an earlier one
+pub struct LightCullingSystem {
+    compute: ComputeSystem,
+    grid: ClusterGrid,
+    // Counts and indices for each slot of `FramesInFlight`
+    buffers: Vec<(Arc<DeviceLocalBuffer<[u32]>>, Arc<DeviceLocalBuffer<[u32]>>)>,
+}
+
+impl LightCullingSystem {
+    pub fn new(queue: Arc<Queue>, grid: ClusterGrid, frames: &FramesInFlight) -> Result<Self, RendererError> {
+        let shader = cull_cs::Shader::load(queue.device().clone()).map_err(RendererError::ShaderLoad)?;
+        let pipeline = Arc::new(
+            ComputePipeline::new(queue.device().clone(), &shader.main_entry_point(), &())?,
+        );
+        // Transfer source so the assignment can be read back for debugging
+        let usage = BufferUsage {
+            storage_buffer: true,
+            transfer_source: true,
+            ..BufferUsage::none()
+        };
+        let mut buffers = Vec::with_capacity(frames.count());
+        for _ in 0..frames.count() {
+            let counts = DeviceLocalBuffer::array(
+                queue.device().clone(),
+                grid.cluster_count(),
+                usage,
+                Some(queue.family()),
+            )?;
+            let indices = DeviceLocalBuffer::array(
+                queue.device().clone(),
+                grid.cluster_count() * MAX_LIGHTS_PER_CLUSTER,
+                usage,
+                Some(queue.family()),
+            )?;
+            buffers.push((counts, indices));
+        }
+        Ok(Self {
+            compute: ComputeSystem::new(queue, pipeline),
+            grid,
+            buffers,
+        })
+    }
+    pub fn grid(&self) -> &ClusterGrid {
+        &self.grid
+    }
+    // Uploads `lights` and returns the culling command buffer along with the buffers
+    // to hand to the lighting pass. Execute the command buffer before the frame,
+    // `slot` must come from `FramesInFlight::begin` for the frame being recorded
+    pub fn cull<T: Projection>(
+        &self,
+        slot: usize,
+        camera: &Camera<T>,
+        camera_buffer: CameraBuffer,
+        lights: &[ClusterLight],
+    ) -> Result<(AutoCommandBuffer, ClusterBuffers), RendererError> {
+        let light_count = lights.len() as u32;
+        // Zero sized buffers are not allowed, the shader never reads the padding light
+        let padding = [ClusterLight::new(Point::origin(), 0.0, [0.0; 3])];
+        let uploaded = if lights.is_empty() { &padding[..] } else { lights };
+        let light_buffer = CpuAccessibleBuffer::from_iter(
+            self.compute.queue().device().clone(),
+            BufferUsage::all(),
+            uploaded.iter().cloned(),
+        )?;
+
+        let (ref counts, ref indices) = self.buffers[slot];
+        let set = Arc::new(
+            PersistentDescriptorSet::start(self.compute.pipeline().clone(), 0)
+                .add_buffer(camera_buffer)?
+                .add_buffer(light_buffer.clone())?
+                .add_buffer(counts.clone())?
+                .add_buffer(indices.clone())?
+                .build()?,
+        );
+        let (grid, depth) = self.grid.push_constants(camera, light_count);
+        let push_constants = cull_cs::ty::PushConstants {
+            grid,
+            depth,
+            last_slice_far: self.grid.last_slice_far(camera),
+        };
+        let groups = ComputeSystem::group_count(self.grid.cluster_count() as u32, CULL_LOCAL_SIZE);
+        let command_buffer = self.compute
+            .dispatch(self.compute.command_buffer_builder()?, [groups, 1, 1], set, push_constants)?
+            .build()?;
+
+        Ok((
+            command_buffer,
+            ClusterBuffers {
+                lights: light_buffer,
+                light_count,
+                counts: counts.clone(),
+                indices: indices.clone(),
+            },
+        ))
+    }
+}
+
+// Shades every pixel once with only the lights assigned to its cluster
+pub struct ClusteredLightingSystem {
+    queue: Arc<Queue>,
+    vertex_buffer: Arc<CpuAccessibleBuffer<[LightingVertex]>>,
+    pipeline: Arc<GraphicsPipelineAbstract + Send + Sync>,
+}
+
+impl ClusteredLightingSystem {
+    pub fn new<R>(queue: Arc<Queue>, subpass: Subpass<R>) -> Result<Self, RendererError>
+    where
+        R: RenderPassAbstract + Send + Sync + 'static,
+    {
+        let vs = clustered_vs::Shader::load(queue.device().clone()).map_err(RendererError::ShaderLoad)?;
+        let fs = clustered_fs::Shader::load(queue.device().clone()).map_err(RendererError::ShaderLoad)?;
+        let pipeline = Arc::new(GraphicsPipeline::start()
+            .vertex_input_single_buffer::<LightingVertex>()
+            .vertex_shader(vs.main_entry_point(), ())
+            .triangle_list()
+            .viewports_dynamic_scissors_irrelevant(1)
+            .fragment_shader(fs.main_entry_point(), ())
+            .blend_collective(additive_blend())
+            .render_pass(subpass)
+            .build(queue.device().clone())?) as Arc<_>;
+        Ok(Self {
+            vertex_buffer: fullscreen_triangle(&queue)?,
+            queue,
+            pipeline,
+        })
+    }
+    pub fn draw<T: Projection>(
+        &self,
+        dynamic_state: DynamicState,
+        gbuffer: &GBuffer,
+        camera: &Camera<T>,
+        camera_buffer: CameraBuffer,
+        grid: &ClusterGrid,
+        clusters: &ClusterBuffers,
+    ) -> Result<AutoCommandBuffer, RendererError> {
+        let gbuffer_set = Arc::new(
+            PersistentDescriptorSet::start(self.pipeline.clone(), 0)
+                .add_image(gbuffer.require(DIFFUSE)?)?
+                .add_image(gbuffer.require(SPECULAR)?)?
+                .add_image(gbuffer.require(NORMALS)?)?
+                .add_image(gbuffer.require(DEPTH)?)?
+                .build()?,
+        ) as Arc<DescriptorSet + Send + Sync>;
+        let camera_set = Arc::new(
+            PersistentDescriptorSet::start(self.pipeline.clone(), 1)
+                .add_buffer(camera_buffer)?
+                .build()?,
+        ) as Arc<DescriptorSet + Send + Sync>;
+        let cluster_set = Arc::new(
+            PersistentDescriptorSet::start(self.pipeline.clone(), 2)
+                .add_buffer(clusters.lights.clone())?
+                .add_buffer(clusters.counts.clone())?
+                .add_buffer(clusters.indices.clone())?
+                .build()?,
+        ) as Arc<DescriptorSet + Send + Sync>;
+        let (grid, depth) = grid.push_constants(camera, clusters.light_count);
+        let push_constants = clustered_fs::ty::PushConstants { grid, depth };
+
+        Ok(AutoCommandBufferBuilder::secondary_graphics(
+            self.queue.device().clone(),
+            self.queue.family(),
+            self.pipeline.clone().subpass(),
+        )?
+            .draw(
+                self.pipeline.clone(),
+                dynamic_state,
+                self.vertex_buffer.clone(),
+                (gbuffer_set, camera_set, cluster_set),
+                push_constants,
+            )?
+            .build()?)
+    }
+}
+
+mod cull_cs {
+    #[derive(VulkanoShader)]
+    #[allow(dead_code)]
+    #[ty = "compute"]
+    #[src = "
+#version 450
+#define MAX_LIGHTS 64
+layout(local_size_x = 64) in;
+
+layout(set = 0, binding = 0) uniform CameraData {
+    mat4 view;
+    mat4 projection;
+    mat4 view_projection;
+    mat4 inverse_view;
+    mat4 inverse_projection;
+    mat4 inverse_view_projection;
+    vec4 position;
+} camera;
+
+struct Light {
+    vec4 position_radius;
+    vec4 color;
+};
+layout(set = 0, binding = 1) readonly buffer Lights {
+    Light lights[];
+};
+layout(set = 0, binding = 2) writeonly buffer ClusterCounts {
+    uint counts[];
+};
+layout(set = 0, binding = 3) writeonly buffer ClusterIndices {
+    uint indices[];
+};
+
+// grid: tiles x, tiles y, depth slices, light count
+// depth: cluster znear, cluster zfar, ndc depth of the near plane, a second ndc depth
+// last_slice_far: where the last slice ends, infinite for an infinite projection
+layout(push_constant) uniform PushConstants {
+    uvec4 grid;
+    vec4 depth;
+    float last_slice_far;
+} params;
+
+vec3 unproject(vec2 ndc, float depth) {
+    vec4 p = camera.inverse_projection * vec4(ndc, depth, 1.0);
+    return p.xyz / p.w;
+}
+
+// Same as `point_at_depth` in cluster.rs
+vec3 point_at_depth(vec3 a, vec3 b, float depth) {
+    vec3 d = b - a;
+    if (isinf(depth)) {
+        vec3 away = d.z > 0.0 ? -d : d;
+        float inf = uintBitsToFloat(0x7F800000u);
+        return vec3(away.x == 0.0 ? a.x : sign(away.x) * inf, away.y == 0.0 ? a.y : sign(away.y) * inf, -inf);
+    }
+    return a + d * ((-depth - a.z) / d.z);
+}
+
+float slice_depth(uint slice) {
+    return params.depth.x * pow(params.depth.y / params.depth.x, float(slice) / float(params.grid.z));
+}
+
+void main() {
+    uint index = gl_GlobalInvocationID.x;
+    if (index >= params.grid.x * params.grid.y * params.grid.z) {
+        return;
+    }
+    uint x = index % params.grid.x;
+    uint y = (index / params.grid.x) % params.grid.y;
+    uint slice = index / (params.grid.x * params.grid.y);
+
+    vec2 ndc_min = vec2(x, y) / vec2(params.grid.xy) * 2.0 - 1.0;
+    vec2 ndc_max = vec2(x + 1, y + 1) / vec2(params.grid.xy) * 2.0 - 1.0;
+    float depths[2] = float[2](slice_depth(slice), slice_depth(slice + 1));
+    if (slice + 1 == params.grid.z) {
+        depths[1] = max(depths[1], params.last_slice_far);
+    }
+    vec3 bounds_min = vec3(3.402823e38);
+    vec3 bounds_max = vec3(-3.402823e38);
+    for (int corner = 0; corner < 4; corner++) {
+        vec2 ndc = vec2((corner & 1) == 0 ? ndc_min.x : ndc_max.x, (corner & 2) == 0 ? ndc_min.y : ndc_max.y);
+        vec3 a = unproject(ndc, params.depth.z);
+        vec3 b = unproject(ndc, params.depth.w);
+        for (int i = 0; i < 2; i++) {
+            vec3 p = point_at_depth(a, b, depths[i]);
+            bounds_min = min(bounds_min, p);
+            bounds_max = max(bounds_max, p);
+        }
+    }
+
+    uint count = 0;
+    for (uint i = 0; i < params.grid.w && count < MAX_LIGHTS; i++) {
+        vec4 light = lights[i].position_radius;
+        vec3 center = (camera.view * vec4(light.xyz, 1.0)).xyz;
+        vec3 offset = center - clamp(center, bounds_min, bounds_max);
+        if (dot(offset, offset) <= light.w * light.w) {
+            indices[index * MAX_LIGHTS + count] = i;
+            count++;
+        }
+    }
+    counts[index] = count;
+}
+"]
+    struct Dummy;
+}
+
+mod clustered_vs {
+    #[derive(VulkanoShader)]
+    #[allow(dead_code)]
+    #[ty = "vertex"]
+    #[src = "
+#version 450
+layout(location = 0) in vec2 position;
+layout(location = 0) out vec2 v_ndc;
+
+void main() {
+    v_ndc = position;
+    gl_Position = vec4(position, 0.0, 1.0);
+}
+"]
+    struct Dummy;
+}
+
+mod clustered_fs {
+    #[derive(VulkanoShader)]
+    #[allow(dead_code)]
+    #[ty = "fragment"]
+    #[src = "
+#version 450
+#define MAX_LIGHTS 64
+layout(input_attachment_index = 0, set = 0, binding = 0) uniform subpassInput u_diffuse;
+layout(input_attachment_index = 1, set = 0, binding = 1) uniform subpassInput u_specular;
+layout(input_attachment_index = 2, set = 0, binding = 2) uniform subpassInput u_normals;
+layout(input_attachment_index = 3, set = 0, binding = 3) uniform subpassInput u_depth;
+
+layout(set = 1, binding = 0) uniform CameraData {
+    mat4 view;
+    mat4 projection;
+    mat4 view_projection;
+    mat4 inverse_view;
+    mat4 inverse_projection;
+    mat4 inverse_view_projection;
+    vec4 position;
+} camera;
+
+struct Light {
+    vec4 position_radius;
+    vec4 color;
+};
+layout(set = 2, binding = 0) readonly buffer Lights {
+    Light lights[];
+};
+layout(set = 2, binding = 1) readonly buffer ClusterCounts {
+    uint counts[];
+};
+layout(set = 2, binding = 2) readonly buffer ClusterIndices {
+    uint indices[];
+};
+
+layout(push_constant) uniform PushConstants {
+    uvec4 grid;
+    vec4 depth;
+} params;
+
+layout(location = 0) in vec2 v_ndc;
+layout(location = 0) out vec4 f_color;
+
+const float SHININESS = 32.0;
+
+void main() {
+    vec4 world = camera.inverse_view_projection * vec4(v_ndc, subpassLoad(u_depth).x, 1.0);
+    vec3 position = world.xyz / world.w;
+    // Nothing was drawn where the normal is still the zero it was cleared to
+    vec3 packed_normal = subpassLoad(u_normals).xyz;
+    if (dot(packed_normal, packed_normal) < 1e-8) {
+        discard;
+    }
+    vec3 normal = normalize(packed_normal);
+    vec3 diffuse = subpassLoad(u_diffuse).rgb;
+    float specular = subpassLoad(u_specular).x;
+    vec3 to_eye = normalize(camera.position.xyz - position);
+
+    float view_depth = -(camera.view * vec4(position, 1.0)).z;
+    float slice_f = log(view_depth / params.depth.x) / log(params.depth.y / params.depth.x) * float(params.grid.z);
+    uint slice = min(uint(max(slice_f, 0.0)), params.grid.z - 1);
+    uvec2 tile = min(uvec2((v_ndc * 0.5 + 0.5) * vec2(params.grid.xy)), params.grid.xy - 1);
+    uint cluster = (slice * params.grid.y + tile.y) * params.grid.x + tile.x;
+
+    vec3 result = vec3(0.0);
+    uint count = counts[cluster];
+    for (uint i = 0; i < count; i++) {
+        Light light = lights[indices[cluster * MAX_LIGHTS + i]];
+        vec3 offset = light.position_radius.xyz - position;
+        float distance = length(offset);
+        float window = clamp(1.0 - pow(distance / light.position_radius.w, 4.0), 0.0, 1.0);
+        float attenuation = window * window / (distance * distance + 1.0);
+        vec3 to_light = offset / distance;
+        vec3 halfway = normalize(to_light + to_eye);
+        float lambert = max(dot(normal, to_light), 0.0);
+        float highlight = lambert > 0.0 ? pow(max(dot(normal, halfway), 0.0), SHININESS) : 0.0;
+        result += (diffuse * lambert + specular * highlight) * light.color.rgb * attenuation;
+    }
+    f_color = vec4(result, 1.0);
+}
+"]
+    struct Dummy;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use math::{ClipSpace, Perspective, ReverseZPerspective};
+    use std::f32::consts::FRAC_PI_2;
+
+    // Slices end at 0.5, 0.89, 1.58, ... 28.1 and 50
+    fn grid() -> ClusterGrid {
+        ClusterGrid::new(4, 4, 8, 0.5, 50.0)
+    }
+    // At the origin looking down -z, so view and world space are the same
+    fn camera<T: Projection>(projection: T) -> Camera<T> {
+        let mut camera = Camera::new(Point::origin(), Point::new(0.0, 0.0, -1.0), Vec3::y(), projection);
+        camera.set_clip_space(ClipSpace::Vulkan);
+        camera
+    }
+    // The cluster a point is shaded with, the same lookup as the lighting shader
+    fn cluster_of<T: Projection>(grid: &ClusterGrid, camera: &Camera<T>, point: Point) -> usize {
+        let ndc = math::transform_point(&camera.view_projection(), &point);
+        let tile = |v: f32, tiles: u32| (((v * 0.5 + 0.5) * tiles as f32) as u32).min(tiles - 1);
+        let slice = grid.slice_of_depth(-point.z);
+        grid.cluster_index(tile(ndc.x, grid.tiles_x), tile(ndc.y, grid.tiles_y), slice)
+    }
+    fn clusters_with(grid: &ClusterGrid, assignment: &ClusterAssignment, light: u32) -> Vec<usize> {
+        (0..grid.cluster_count())
+            .filter(|&cluster| assignment.lights(cluster).contains(&light))
+            .collect()
+    }
+
+    #[test]
+    fn small_light_lands_in_one_cluster() {
+        let grid = grid();
+        let camera = camera(Perspective::new(1.0, FRAC_PI_2, 0.5, 200.0));
+        // Well inside a tile and the slice from 8.9 to 15.8
+        let position = Point::new(2.5, 1.25, -10.0);
+        let lights = [ClusterLight::new(position, 0.1, [1.0; 3])];
+        let assignment = assign_lights(&grid, &camera, &lights);
+        let cluster = cluster_of(&grid, &camera, position);
+        assert_eq!(cluster / 16, 5);
+        assert_eq!(clusters_with(&grid, &assignment, 0), vec![cluster]);
+        assert_eq!(assignment.counts.iter().sum::<u32>(), 1);
+
+        // A larger light spills into the neighbouring clusters
+        let lights = [ClusterLight::new(position, 3.0, [1.0; 3])];
+        let assignment = assign_lights(&grid, &camera, &lights);
+        let clusters = clusters_with(&grid, &assignment, 0);
+        assert!(clusters.len() > 1 && clusters.contains(&cluster));
+    }
+
+    #[test]
+    fn last_slice_reaches_the_camera_far_plane() {
+        let grid = grid();
+        let camera = camera(Perspective::new(1.0, FRAC_PI_2, 0.5, 200.0));
+        assert!((grid.last_slice_far(&camera) - 200.0).abs() < 1e-2);
+        // Past the grid's zfar but in front of the camera's far plane
+        let beyond_grid = Point::new(25.0, 12.5, -100.0);
+        // Past the camera's far plane, nothing there is ever shaded
+        let beyond_camera = Point::new(25.0, 12.5, -300.0);
+        let lights = [
+            ClusterLight::new(beyond_grid, 1.0, [1.0; 3]),
+            ClusterLight::new(beyond_camera, 1.0, [1.0; 3]),
+        ];
+        let assignment = assign_lights(&grid, &camera, &lights);
+        let cluster = cluster_of(&grid, &camera, beyond_grid);
+        assert_eq!(cluster / 16, grid.slices as usize - 1);
+        // The boxes of the deep last slice are loose enough to reach the next tile
+        let clusters = clusters_with(&grid, &assignment, 0);
+        assert!(clusters.contains(&cluster));
+        assert!(clusters.iter().all(|&cluster| cluster / 16 == grid.slices as usize - 1));
+        assert!(clusters_with(&grid, &assignment, 1).is_empty());
+    }
+
+    #[test]
+    fn last_slice_of_an_infinite_projection_is_unbounded() {
+        let grid = grid();
+        let camera = camera(ReverseZPerspective::infinite(1.0, FRAC_PI_2, 0.5));
+        assert!(grid.last_slice_far(&camera).is_infinite());
+        let far_away = Point::new(250.0, 125.0, -1000.0);
+        let lights = [
+            ClusterLight::new(Point::new(2.5, 1.25, -10.0), 0.1, [1.0; 3]),
+            ClusterLight::new(far_away, 1.0, [1.0; 3]),
+        ];
+        let assignment = assign_lights(&grid, &camera, &lights);
+        assert_eq!(clusters_with(&grid, &assignment, 0).len(), 1);
+        // The unbounded boxes of neighbouring tiles overlap, but only in the last slice
+        let clusters = clusters_with(&grid, &assignment, 1);
+        assert!(clusters.contains(&cluster_of(&grid, &camera, far_away)));
+        assert!(clusters.iter().all(|&cluster| cluster / 16 == grid.slices as usize - 1));
+
+        let inverse_projection = camera.projection_matrix().try_inverse().unwrap();
+        let (near, far) = camera.depth_range();
+        let ndc_depths = (near, (near + far) * 0.5);
+        let (min, max) = grid.cluster_bounds(&inverse_projection, ndc_depths, f32::INFINITY, 2, 2, 7);
+        assert!(min.iter().chain(max.iter()).all(|c| !c.is_nan()));
+        assert_eq!((min.z, max.x), (f32::NEG_INFINITY, f32::INFINITY));
+        assert!(min.x.abs() < 1e-3 && max.z < 0.0);
+    }
+}
@@ -0,0 +1,47 @@
+use renderer::system::error::RendererError;
+use std::sync::Arc;
+use vulkano::{command_buffer::AutoCommandBufferBuilder,
+              descriptor::descriptor_set::DescriptorSetsCollection,
+              device::Queue,
+              pipeline::ComputePipelineAbstract};
+
+// The compute counterpart of `DrawSystem`, owns a compute pipeline and records
+// dispatches of it into primary command buffers that run outside the render pass
+pub struct ComputeSystem {
+    queue: Arc<Queue>,
+    pipeline: Arc<ComputePipelineAbstract + Send + Sync>,
+}
+
+impl ComputeSystem {
+    pub fn new(queue: Arc<Queue>, pipeline: Arc<ComputePipelineAbstract + Send + Sync>) -> Self {
+        Self { queue, pipeline }
+    }
+    pub fn queue(&self) -> &Arc<Queue> {
+        &self.queue
+    }
+    pub fn pipeline(&self) -> &Arc<ComputePipelineAbstract + Send + Sync> {
+        &self.pipeline
+    }
+    pub fn command_buffer_builder(&self) -> Result<AutoCommandBufferBuilder, RendererError> {
+        Ok(AutoCommandBufferBuilder::primary_one_time_submit(
+            self.queue.device().clone(),
+            self.queue.family(),
+        )?)
+    }
+    // The number of work groups needed to cover `invocations` with groups of `local_size`
+    pub fn group_count(invocations: u32, local_size: u32) -> u32 {
+        (invocations + local_size - 1) / local_size
+    }
+    pub fn dispatch<S, Pc>(
+        &self,
+        builder: AutoCommandBufferBuilder,
+        groups: [u32; 3],
+        sets: S,
+        push_constants: Pc,
+    ) -> Result<AutoCommandBufferBuilder, RendererError>
+    where
+        S: DescriptorSetsCollection,
+    {
+        Ok(builder.dispatch(groups, self.pipeline.clone(), sets, push_constants)?)
+    }
+}
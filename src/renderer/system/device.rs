@@ -0,0 +1,343 @@
+use renderer::system::gbuffer::GBufferBuilder;
+use std::error::Error;
+use std::fmt;
+use std::sync::Arc;
+use vulkano::{device::{Device, DeviceCreationError, DeviceExtensions, Queue},
+              format::{Format, FormatTy},
+              instance::{self,
+                         debug::{DebugCallback, Message, MessageTypes},
+                         Features, Instance, InstanceCreationError, InstanceExtensions, PhysicalDevice,
+                         PhysicalDeviceType, QueueFamily},
+              swapchain::Surface};
+use vulkano_win;
+
+// Tried in order, the first one installed is enabled
+const VALIDATION_LAYERS: [&str; 2] = ["VK_LAYER_KHRONOS_validation", "VK_LAYER_LUNARG_standard_validation"];
+
+pub struct InstanceBuilder {
+    extensions: InstanceExtensions,
+    validation: bool,
+    messages: MessageTypes,
+}
+
+// Keeps the debug callback alive for as long as the instance is in use
+pub struct RendererInstance {
+    pub instance: Arc<Instance>,
+    debug_callback: Option<DebugCallback>,
+}
+
+impl RendererInstance {
+    pub fn has_debug_callback(&self) -> bool {
+        self.debug_callback.is_some()
+    }
+}
+
+impl Default for InstanceBuilder {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl InstanceBuilder {
+    // No extensions, enough for headless rendering
+    pub fn new() -> Self {
+        Self {
+            extensions: InstanceExtensions::none(),
+            validation: false,
+            messages: MessageTypes {
+                error: true,
+                warning: true,
+                performance_warning: true,
+                information: false,
+                debug: false,
+            },
+        }
+    }
+    // The extensions needed to create window surfaces
+    pub fn windowed() -> Self {
+        Self::new().extensions(vulkano_win::required_extensions())
+    }
+    pub fn extensions(mut self, extensions: InstanceExtensions) -> Self {
+        self.extensions = self.extensions.union(&extensions);
+        self
+    }
+    // Enables the validation layer if one is installed and routes its reports into
+    // the `log` crate
+    pub fn validation(mut self, enabled: bool) -> Self {
+        self.validation = enabled;
+        self
+    }
+    // Which reports reach the log, errors and warnings by default
+    pub fn debug_messages(mut self, messages: MessageTypes) -> Self {
+        self.messages = messages;
+        self
+    }
+    pub fn build(self) -> Result<RendererInstance, InstanceCreationError> {
+        let mut extensions = self.extensions;
+        let mut layers = Vec::new();
+        if self.validation {
+            let available: Vec<String> = instance::layers_list()
+                .map(|list| list.map(|layer| layer.name().to_string()).collect())
+                .unwrap_or_else(|_| Vec::new());
+            match VALIDATION_LAYERS.iter().find(|&&name| available.iter().any(|a| a == name)) {
+                Some(&name) => {
+                    layers.push(name);
+                    extensions.ext_debug_report = true;
+                }
+                None => warn!("Validation was requested but no validation layer is installed"),
+            }
+        }
+        let instance = Instance::new(None, &extensions, layers.iter())?;
+        let debug_callback = if extensions.ext_debug_report {
+            DebugCallback::new(&instance, self.messages, log_message).ok()
+        } else {
+            None
+        };
+        Ok(RendererInstance {
+            instance,
+            debug_callback,
+        })
+    }
+}
+
+fn log_message(message: &Message) {
+    if message.ty.error {
+        error!("[{}] {}", message.layer_prefix, message.description);
+    } else if message.ty.warning || message.ty.performance_warning {
+        warn!("[{}] {}", message.layer_prefix, message.description);
+    } else if message.ty.information {
+        info!("[{}] {}", message.layer_prefix, message.description);
+    } else {
+        debug!("[{}] {}", message.layer_prefix, message.description);
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FormatUse {
+    ColorAttachment,
+    DepthStencilAttachment,
+    Sampled,
+    Storage,
+}
+
+#[derive(Debug)]
+pub enum DeviceSelectionError {
+    NoSuitableDevice,
+    Creation(DeviceCreationError),
+}
+
+impl fmt::Display for DeviceSelectionError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            DeviceSelectionError::NoSuitableDevice => write!(f, "no physical device meets the requirements"),
+            DeviceSelectionError::Creation(ref err) => write!(f, "failed to create device: {}", err),
+        }
+    }
+}
+
+impl Error for DeviceSelectionError {
+    fn description(&self) -> &str {
+        match *self {
+            DeviceSelectionError::NoSuitableDevice => "no suitable physical device",
+            DeviceSelectionError::Creation(_) => "failed to create device",
+        }
+    }
+}
+
+impl From<DeviceCreationError> for DeviceSelectionError {
+    fn from(err: DeviceCreationError) -> Self {
+        DeviceSelectionError::Creation(err)
+    }
+}
+
+// Compute and transfer use their own families when the device has them, otherwise
+// they share the graphics queue
+#[derive(Clone)]
+pub struct Queues {
+    pub graphics: Arc<Queue>,
+    pub compute: Arc<Queue>,
+    pub transfer: Arc<Queue>,
+}
+
+pub struct SelectedDevice {
+    pub device: Arc<Device>,
+    pub queues: Queues,
+}
+
+#[derive(Debug, Clone)]
+pub struct DeviceSelector {
+    features: Features,
+    extensions: DeviceExtensions,
+    formats: Vec<(Format, FormatUse)>,
+}
+
+struct QueueChoice<'a> {
+    graphics: QueueFamily<'a>,
+    compute: QueueFamily<'a>,
+    transfer: QueueFamily<'a>,
+}
+
+impl Default for DeviceSelector {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl DeviceSelector {
+    pub fn new() -> Self {
+        Self {
+            features: Features::none(),
+            extensions: DeviceExtensions::none(),
+            formats: Vec::new(),
+        }
+    }
+    // Requires every attachment format of the gbuffer to be renderable
+    pub fn for_gbuffer(gbuffer: &GBufferBuilder) -> Self {
+        let mut selector = Self::new();
+        for attachment in gbuffer.attachments() {
+            let usage = match attachment.format.ty() {
+                FormatTy::Depth | FormatTy::Stencil | FormatTy::DepthStencil => FormatUse::DepthStencilAttachment,
+                _ => FormatUse::ColorAttachment,
+            };
+            selector = selector.require_format(attachment.format, usage);
+        }
+        selector
+    }
+    pub fn features(mut self, features: Features) -> Self {
+        self.features = features;
+        self
+    }
+    pub fn extensions(mut self, extensions: DeviceExtensions) -> Self {
+        self.extensions = extensions;
+        self
+    }
+    pub fn require_format(mut self, format: Format, usage: FormatUse) -> Self {
+        self.formats.push((format, usage));
+        self
+    }
+
+    // None if the device cannot be used, otherwise higher is better. Discrete GPUs
+    // win over integrated ones, and those over software rasterisers
+    pub fn score(&self, physical: PhysicalDevice) -> Option<u32> {
+        self.score_with(physical, &|_| true)
+    }
+
+    pub fn select(&self, instance: &Arc<Instance>) -> Result<SelectedDevice, DeviceSelectionError> {
+        self.select_with(instance, self.extensions, &|_| true)
+    }
+
+    // Only considers devices with a graphics queue that can present to `surface`,
+    // and enables the swapchain extension
+    pub fn select_for_surface<W>(
+        &self,
+        instance: &Arc<Instance>,
+        surface: &Arc<Surface<W>>,
+    ) -> Result<SelectedDevice, DeviceSelectionError> {
+        let extensions = DeviceExtensions {
+            khr_swapchain: true,
+            ..self.extensions
+        };
+        self.select_with(instance, extensions, &|family| surface.is_supported(family).unwrap_or(false))
+    }
+
+    fn score_with(&self, physical: PhysicalDevice, presents: &Fn(QueueFamily) -> bool) -> Option<u32> {
+        if !physical.supported_features().superset_of(&self.features) {
+            return None;
+        }
+        for &(format, usage) in &self.formats {
+            let features = format.properties(physical).optimal_tiling_features;
+            let supported = match usage {
+                FormatUse::ColorAttachment => features.color_attachment,
+                FormatUse::DepthStencilAttachment => features.depth_stencil_attachment,
+                FormatUse::Sampled => features.sampled_image,
+                FormatUse::Storage => features.storage_image,
+            };
+            if !supported {
+                return None;
+            }
+        }
+        let queues = choose_queues(physical, presents)?;
+        let mut score = match physical.ty() {
+            PhysicalDeviceType::DiscreteGpu => 1000,
+            PhysicalDeviceType::IntegratedGpu => 500,
+            PhysicalDeviceType::VirtualGpu => 250,
+            PhysicalDeviceType::Cpu => 100,
+            PhysicalDeviceType::Other => 50,
+        };
+        if queues.compute.id() != queues.graphics.id() {
+            score += 10;
+        }
+        if queues.transfer.id() != queues.compute.id() {
+            score += 5;
+        }
+        Some(score)
+    }
+
+    fn select_with(
+        &self,
+        instance: &Arc<Instance>,
+        extensions: DeviceExtensions,
+        presents: &Fn(QueueFamily) -> bool,
+    ) -> Result<SelectedDevice, DeviceSelectionError> {
+        let physical = PhysicalDevice::enumerate(instance)
+            .filter_map(|physical| self.score_with(physical, presents).map(|score| (score, physical)))
+            .fold(None, |best: Option<(u32, PhysicalDevice)>, (score, physical)| match best {
+                Some((best_score, _)) if best_score >= score => best,
+                _ => Some((score, physical)),
+            })
+            .map(|(_, physical)| physical)
+            .ok_or(DeviceSelectionError::NoSuitableDevice)?;
+        info!("Using {} ({:?})", physical.name(), physical.ty());
+
+        let choice = choose_queues(physical, presents).unwrap();
+        let mut families: Vec<QueueFamily> = Vec::with_capacity(3);
+        for &family in [choice.graphics, choice.compute, choice.transfer].iter() {
+            if !families.iter().any(|f| f.id() == family.id()) {
+                families.push(family);
+            }
+        }
+        let (device, queues) = Device::new(
+            physical,
+            &self.features,
+            &extensions,
+            families.iter().map(|&family| (family, 1.0)),
+        )?;
+        let queues: Vec<Arc<Queue>> = queues.collect();
+        let queue_of = |family: QueueFamily| {
+            queues
+                .iter()
+                .find(|q| q.family().id() == family.id())
+                .unwrap()
+                .clone()
+        };
+        Ok(SelectedDevice {
+            queues: Queues {
+                graphics: queue_of(choice.graphics),
+                compute: queue_of(choice.compute),
+                transfer: queue_of(choice.transfer),
+            },
+            device,
+        })
+    }
+}
+
+// Prefers a compute family without graphics for async compute, and a transfer
+// family with neither for uploads
+fn choose_queues<'a>(physical: PhysicalDevice<'a>, presents: &Fn(QueueFamily) -> bool) -> Option<QueueChoice<'a>> {
+    let graphics = physical
+        .queue_families()
+        .find(|&q| q.supports_graphics() && presents(q))?;
+    let compute = physical
+        .queue_families()
+        .find(|&q| q.supports_compute() && !q.supports_graphics())
+        .unwrap_or(graphics);
+    let transfer = physical
+        .queue_families()
+        .find(|&q| q.supports_transfers() && !q.supports_graphics() && !q.supports_compute())
+        .unwrap_or(compute);
+    Some(QueueChoice {
+        graphics,
+        compute,
+        transfer,
+    })
+}
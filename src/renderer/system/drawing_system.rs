@@ -1,9 +1,14 @@
+use renderer::system::camera_uniform::{CameraBuffer, ModelTransform};
+use renderer::system::error::RendererError;
+use renderer::system::render_system::DepthMode;
 use std::sync::Arc;
-use vulkano::{command_buffer::pool::standard::StandardCommandPoolBuilder,
-              command_buffer::{AutoCommandBufferBuilder},
+use vulkano::{buffer::{BufferAccess, TypedBufferAccess},
+              command_buffer::pool::standard::StandardCommandPoolBuilder,
+              command_buffer::{AutoCommandBufferBuilder, DynamicState},
+              descriptor::descriptor_set::{DescriptorSet, PersistentDescriptorSet},
               device::Queue,
               framebuffer::{RenderPassAbstract, Subpass},
-              pipeline::{GraphicsPipelineAbstract, GraphicsPipeline}};
+              pipeline::{depth_stencil::DepthStencil, GraphicsPipelineAbstract, GraphicsPipeline}};
 
 pub struct DrawSystem {
     queue: Arc<Queue>,
@@ -17,42 +22,81 @@ impl DrawSystem {
             pipeline
         }
     }
-    pub fn command_buffer_builder(&self) -> AutoCommandBufferBuilder<StandardCommandPoolBuilder> {
-        AutoCommandBufferBuilder::secondary_graphics(
+    pub fn command_buffer_builder(&self) -> Result<AutoCommandBufferBuilder<StandardCommandPoolBuilder>, RendererError> {
+        Ok(AutoCommandBufferBuilder::secondary_graphics(
             self.queue.device().clone(),
             self.queue.family(),
             self.pipeline.clone().subpass(),
-        ).unwrap()
+        )?)
     }
-    pub fn new_geometry_draw<R>(queue: Arc<Queue>, subpass: Subpass<R>) -> Self 
+    // The descriptor set binding the per frame camera uniform, shared by every draw in a frame
+    pub fn camera_set(&self, camera: CameraBuffer) -> Result<Arc<DescriptorSet + Send + Sync>, RendererError> {
+        Ok(Arc::new(
+            PersistentDescriptorSet::start(self.pipeline.clone(), 0)
+                .add_buffer(camera)?
+                .build()?,
+        ))
+    }
+    // Records a draw of `vertices` transformed by `transform` into a builder from `command_buffer_builder`
+    pub fn draw<V>(
+        &self,
+        builder: AutoCommandBufferBuilder<StandardCommandPoolBuilder>,
+        dynamic_state: DynamicState,
+        vertices: V,
+        camera_set: Arc<DescriptorSet + Send + Sync>,
+        transform: ModelTransform,
+    ) -> Result<AutoCommandBufferBuilder<StandardCommandPoolBuilder>, RendererError>
+    where
+        V: BufferAccess + TypedBufferAccess<Content = [Vertex]> + Send + Sync + 'static,
+    {
+        let push_constants = vs::ty::PushConstants {
+            model: transform.model,
+            normal: transform.normal,
+        };
+        Ok(builder.draw(
+            self.pipeline.clone(),
+            dynamic_state,
+            vertices,
+            camera_set,
+            push_constants,
+        )?)
+    }
+    pub fn new_geometry_draw<R>(queue: Arc<Queue>, subpass: Subpass<R>) -> Result<Self, RendererError>
+    where R: RenderPassAbstract + Send + Sync + 'static
+    {
+        Self::new_geometry_draw_with_depth(queue, subpass, DepthMode::Standard)
+    }
+    pub fn new_geometry_draw_with_depth<R>(queue: Arc<Queue>, subpass: Subpass<R>, depth_mode: DepthMode) -> Result<Self, RendererError>
     where R: RenderPassAbstract + Send + Sync + 'static
     {
-        let vs = vs::Shader::load(queue.device().clone()).expect("Failed to load vertex shader");
-        let fs = fs::Shader::load(queue.device().clone()).expect("Failed to load fragment shader");
+        let vs = vs::Shader::load(queue.device().clone()).map_err(RendererError::ShaderLoad)?;
+        let fs = fs::Shader::load(queue.device().clone()).map_err(RendererError::ShaderLoad)?;
         let pipeline = Arc::new(GraphicsPipeline::start()
         .vertex_input_single_buffer::<Vertex>()
         .vertex_shader(vs.main_entry_point(), ())
         .triangle_list()
         .viewports_dynamic_scissors_irrelevant(1)
         .fragment_shader(fs.main_entry_point(), ())
-        .depth_stencil_simple_depth()
+        .depth_stencil(DepthStencil {
+            depth_compare: depth_mode.compare_op(),
+            .. DepthStencil::simple_depth_test()
+        })
         .render_pass(subpass)
-        .build(queue.device().clone())
-        .unwrap()) as Arc<_>;
+        .build(queue.device().clone())?) as Arc<_>;
 
-        Self {
+        Ok(Self {
             queue,
             pipeline
-        }
+        })
     }
 }
 
 #[derive(Debug, Clone)]
-struct Vertex {
-    position: [f32; 3],
-    normal: [f32; 3],
-    colour: [f32; 3],
-    specular: f32,
+pub struct Vertex {
+    pub position: [f32; 3],
+    pub normal: [f32; 3],
+    pub colour: [f32; 3],
+    pub specular: f32,
 }
 impl_vertex!(Vertex, position, normal, colour, specular);
 
@@ -70,9 +114,26 @@ layout(location = 3) in float specular;
 layout(location = 0) out vec3 v_normal;
 layout(location = 1) out vec3 v_colour;
 layout(location = 2) out float v_specular;
+
+layout(set = 0, binding = 0) uniform CameraData {
+    mat4 view;
+    mat4 projection;
+    mat4 view_projection;
+    mat4 inverse_view;
+    mat4 inverse_projection;
+    mat4 inverse_view_projection;
+    vec4 position;
+} camera;
+
+layout(push_constant) uniform PushConstants {
+    mat4 model;
+    mat4 normal;
+} object;
+
 void main() {
+    gl_Position = camera.view_projection * object.model * vec4(position, 1.0);
     v_colour = colour;
-    v_normal = normal;
+    v_normal = normalize(mat3(object.normal) * normal);
     v_specular = specular;
 }
 "]
@@ -96,7 +157,7 @@ layout(location = 2) out vec3 f_normals;
 void main() {
     f_colour = v_colour;
     f_specular = v_specular;
-    f_normals = v_normal; 
+    f_normals = normalize(v_normal);
 }
 "]
     struct Dummy;
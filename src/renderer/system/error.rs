@@ -0,0 +1,213 @@
+use renderer::system::device::DeviceSelectionError;
+use renderer::system::render_graph::RenderGraphError;
+use std::error::Error;
+use std::fmt;
+use vulkano::{buffer::cpu_access::{ReadLockError, WriteLockError},
+              command_buffer::{AutoCommandBufferBuilderContextError, BeginRenderPassError, BuildError,
+                               CommandBufferExecError, CopyBufferImageError, DispatchError, DrawError,
+                               ExecuteCommandsError},
+              descriptor::descriptor_set::{PersistentDescriptorSetBuildError, PersistentDescriptorSetError},
+              device::DeviceCreationError,
+              format::Format,
+              framebuffer::FramebufferCreationError,
+              image::ImageCreationError,
+              instance::InstanceCreationError,
+              memory::DeviceMemoryAllocError,
+              pipeline::{ComputePipelineCreationError, GraphicsPipelineCreationError},
+              query::QueryPoolCreationError,
+              swapchain::{AcquireError, SwapchainCreationError},
+              sync::FlushError,
+              OomError};
+
+pub type BoxedError = Box<Error + Send + Sync>;
+
+// Everything that can go wrong while creating or running the renderer. Vulkano
+// errors are grouped by what was being done when they happened
+#[derive(Debug)]
+pub enum RendererError {
+    // Out of host or device memory, or an image or buffer could not be created
+    Allocation(BoxedError),
+    Framebuffer(FramebufferCreationError),
+    // Recording, building or submitting a command buffer
+    CommandBuffer(BoxedError),
+    Pipeline(BoxedError),
+    ShaderLoad(OomError),
+    DescriptorSet(BoxedError),
+    Swapchain(BoxedError),
+    // Creating the instance or device, or picking a physical device
+    Device(BoxedError),
+    RenderGraph(RenderGraphError),
+    // Images can not have a zero sized dimension
+    InvalidDimensions([u32; 2]),
+    UnsupportedFormat(Format),
+    // A render graph attachment the gbuffer lacks or has in another format
+    AttachmentMismatch(String),
+    // Returned by a frame after one of its steps failed
+    FrameAborted,
+    // Everything created from the device is unusable, see `DeviceRecovery`
+    DeviceLost,
+    // The window surface is gone and the swapchain has to be created on a new one
+    SurfaceLost,
+    // The queue family can not write timestamps, so there is nothing to profile
+    TimestampsUnsupported,
+}
+
+impl RendererError {
+    pub fn is_device_lost(&self) -> bool {
+        match *self {
+            RendererError::DeviceLost => true,
+            _ => false,
+        }
+    }
+    pub fn is_surface_lost(&self) -> bool {
+        match *self {
+            RendererError::SurfaceLost => true,
+            _ => false,
+        }
+    }
+    // Formats the device can not use for an image are reported as unsupported
+    // rather than as a failed allocation
+    pub fn from_image_creation(err: ImageCreationError, format: Format) -> Self {
+        match err {
+            ImageCreationError::FormatNotSupported | ImageCreationError::UnsupportedUsage => {
+                RendererError::UnsupportedFormat(format)
+            }
+            err => err.into(),
+        }
+    }
+}
+
+impl fmt::Display for RendererError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            RendererError::Allocation(ref err) => write!(f, "allocation failed: {}", err),
+            RendererError::Framebuffer(ref err) => write!(f, "failed to create framebuffer: {}", err),
+            RendererError::CommandBuffer(ref err) => write!(f, "command buffer error: {}", err),
+            RendererError::Pipeline(ref err) => write!(f, "failed to create pipeline: {}", err),
+            RendererError::ShaderLoad(ref err) => write!(f, "failed to load shader: {}", err),
+            RendererError::DescriptorSet(ref err) => write!(f, "failed to create descriptor set: {}", err),
+            RendererError::Swapchain(ref err) => write!(f, "swapchain error: {}", err),
+            RendererError::Device(ref err) => write!(f, "failed to create device: {}", err),
+            RendererError::RenderGraph(ref err) => write!(f, "{}", err),
+            RendererError::InvalidDimensions(dims) => write!(f, "invalid image dimensions {}x{}", dims[0], dims[1]),
+            RendererError::UnsupportedFormat(format) => write!(f, "format {:?} is not supported", format),
+            RendererError::AttachmentMismatch(ref name) => {
+                write!(f, "gbuffer attachment '{}' is missing or does not match the render graph", name)
+            }
+            RendererError::FrameAborted => write!(f, "the frame was aborted by an earlier error"),
+            RendererError::DeviceLost => write!(f, "the device was lost"),
+            RendererError::SurfaceLost => write!(f, "the surface was lost"),
+            RendererError::TimestampsUnsupported => write!(f, "the device does not support timestamp queries"),
+        }
+    }
+}
+
+impl Error for RendererError {
+    fn description(&self) -> &str {
+        match *self {
+            RendererError::Allocation(_) => "allocation failed",
+            RendererError::Framebuffer(_) => "failed to create framebuffer",
+            RendererError::CommandBuffer(_) => "command buffer error",
+            RendererError::Pipeline(_) => "failed to create pipeline",
+            RendererError::ShaderLoad(_) => "failed to load shader",
+            RendererError::DescriptorSet(_) => "failed to create descriptor set",
+            RendererError::Swapchain(_) => "swapchain error",
+            RendererError::Device(_) => "failed to create device",
+            RendererError::RenderGraph(_) => "invalid render graph",
+            RendererError::InvalidDimensions(_) => "invalid image dimensions",
+            RendererError::UnsupportedFormat(_) => "unsupported format",
+            RendererError::AttachmentMismatch(_) => "gbuffer does not match render graph",
+            RendererError::FrameAborted => "frame aborted",
+            RendererError::DeviceLost => "device lost",
+            RendererError::SurfaceLost => "surface lost",
+            RendererError::TimestampsUnsupported => "timestamps unsupported",
+        }
+    }
+}
+
+macro_rules! renderer_error_from {
+    ($($err:ty => $variant:ident),* $(,)*) => {
+        $(
+            impl From<$err> for RendererError {
+                fn from(err: $err) -> Self {
+                    RendererError::$variant(Box::new(err))
+                }
+            }
+        )*
+    };
+}
+
+renderer_error_from! {
+    OomError => Allocation,
+    DeviceMemoryAllocError => Allocation,
+    QueryPoolCreationError => Allocation,
+    ImageCreationError => Allocation,
+    BeginRenderPassError => CommandBuffer,
+    AutoCommandBufferBuilderContextError => CommandBuffer,
+    BuildError => CommandBuffer,
+    ExecuteCommandsError => CommandBuffer,
+    CommandBufferExecError => CommandBuffer,
+    DrawError => CommandBuffer,
+    DispatchError => CommandBuffer,
+    CopyBufferImageError => CommandBuffer,
+    // Written while a submitted command buffer still reads it
+    WriteLockError => CommandBuffer,
+    // Read while a submitted command buffer still writes it
+    ReadLockError => CommandBuffer,
+    GraphicsPipelineCreationError => Pipeline,
+    ComputePipelineCreationError => Pipeline,
+    PersistentDescriptorSetError => DescriptorSet,
+    PersistentDescriptorSetBuildError => DescriptorSet,
+    InstanceCreationError => Device,
+}
+
+impl From<FramebufferCreationError> for RendererError {
+    fn from(err: FramebufferCreationError) -> Self {
+        RendererError::Framebuffer(err)
+    }
+}
+
+impl From<RenderGraphError> for RendererError {
+    fn from(err: RenderGraphError) -> Self {
+        RendererError::RenderGraph(err)
+    }
+}
+
+impl From<FlushError> for RendererError {
+    fn from(err: FlushError) -> Self {
+        match err {
+            FlushError::DeviceLost => RendererError::DeviceLost,
+            FlushError::SurfaceLost => RendererError::SurfaceLost,
+            err => RendererError::CommandBuffer(Box::new(err)),
+        }
+    }
+}
+
+impl From<SwapchainCreationError> for RendererError {
+    fn from(err: SwapchainCreationError) -> Self {
+        match err {
+            SwapchainCreationError::DeviceLost => RendererError::DeviceLost,
+            SwapchainCreationError::SurfaceLost => RendererError::SurfaceLost,
+            err => RendererError::Swapchain(Box::new(err)),
+        }
+    }
+}
+
+impl From<AcquireError> for RendererError {
+    fn from(err: AcquireError) -> Self {
+        match err {
+            AcquireError::DeviceLost => RendererError::DeviceLost,
+            AcquireError::SurfaceLost => RendererError::SurfaceLost,
+            err => RendererError::Swapchain(Box::new(err)),
+        }
+    }
+}
+
+impl From<DeviceSelectionError> for RendererError {
+    fn from(err: DeviceSelectionError) -> Self {
+        match err {
+            DeviceSelectionError::Creation(DeviceCreationError::DeviceLost) => RendererError::DeviceLost,
+            err => RendererError::Device(Box::new(err)),
+        }
+    }
+}
@@ -0,0 +1,142 @@
+use renderer::system::error::RendererError;
+use std::sync::Arc;
+use std::time::Duration;
+use vulkano::{buffer::{BufferUsage, CpuAccessibleBuffer},
+              device::Device,
+              memory::Content,
+              sync::{now, FenceSignalFuture, FlushError, GpuFuture}};
+
+// Lets the CPU record up to `count` frames ahead of the GPU. Every frame in flight
+// has a slot with its own fence, `begin` waits on the fence of the frame that last
+// used the slot and `end` submits the new frame with it. Anything written once per
+// frame, such as a `UniformRing`, can be indexed by the slot and overwritten after
+// `begin` without racing the GPU. Frames share the render system's gbuffer, so
+// each must start from `previous_frame` to run after the one before it on the GPU
+pub struct FramesInFlight {
+    fences: Vec<Option<Arc<FenceSignalFuture<Box<GpuFuture>>>>>,
+    slot: usize,
+    frame: u64,
+    recording: bool,
+}
+
+impl FramesInFlight {
+    // Two frames is enough to keep the GPU busy while the next one is recorded
+    pub fn new(count: usize) -> Self {
+        assert!(count > 0, "Need at least one frame in flight");
+        Self {
+            fences: (0..count).map(|_| None).collect(),
+            slot: 0,
+            frame: 0,
+            recording: false,
+        }
+    }
+    pub fn count(&self) -> usize {
+        self.fences.len()
+    }
+    // The slot of the frame being recorded
+    pub fn slot(&self) -> usize {
+        self.slot
+    }
+    // The number of frames ended so far
+    pub fn frame_number(&self) -> u64 {
+        self.frame
+    }
+
+    // The number of submitted frames the GPU has not finished yet
+    pub fn in_flight(&self) -> usize {
+        self.fences
+            .iter()
+            .filter_map(|f| f.as_ref())
+            .filter(|fence| fence.wait(Some(Duration::from_secs(0))).is_err())
+            .count()
+    }
+
+    // Waits until the GPU has finished the frame submitted `count` frames ago, then
+    // returns the slot to record into. The wait is on the CPU, the frame itself
+    // starts from `previous_frame`
+    pub fn begin(&mut self) -> Result<usize, RendererError> {
+        assert!(!self.recording, "FramesInFlight::begin called twice without end");
+        for fence in self.fences.iter_mut().filter_map(|f| f.as_mut()) {
+            fence.cleanup_finished();
+        }
+        if let Some(fence) = self.fences[self.slot].take() {
+            fence.wait(None)?;
+        }
+        self.recording = true;
+        Ok(self.slot)
+    }
+
+    // Flushes `future`, usually the one `Frame` finished with or a swapchain
+    // present, and signals the slot's fence when it completes. The slot is moved on
+    // even if flushing fails so the next frame can begin
+    pub fn end<F>(&mut self, future: F) -> Result<(), FlushError>
+    where
+        F: GpuFuture + 'static,
+    {
+        assert!(self.recording, "FramesInFlight::end called without begin");
+        let result = (Box::new(future) as Box<GpuFuture>).then_signal_fence_and_flush();
+        self.recording = false;
+        self.frame += 1;
+        let slot = self.slot;
+        self.slot = (self.slot + 1) % self.fences.len();
+        self.fences[slot] = Some(Arc::new(result?));
+        Ok(())
+    }
+
+    // The future to start the next frame from, joined with a swapchain acquire if
+    // there is one. It is the last submitted frame if that may still be running,
+    // which orders the frames' use of shared images on the GPU without the CPU
+    // waiting for it
+    pub fn previous_frame(&self, device: Arc<Device>) -> Box<GpuFuture> {
+        let count = self.fences.len();
+        let previous = (self.slot + count - 1) % count;
+        match self.fences[previous] {
+            Some(ref fence) if self.frame > 0 => Box::new(fence.clone()),
+            _ => Box::new(now(device)),
+        }
+    }
+
+    // Blocks until every submitted frame has finished, for example before resources
+    // shared between frames are replaced
+    pub fn wait_idle(&mut self) -> Result<(), RendererError> {
+        for fence in self.fences.iter_mut() {
+            if let Some(fence) = fence.take() {
+                fence.wait(None)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+// One buffer per frame in flight, so the data of a frame can be written while the
+// GPU still reads the buffers of earlier frames
+pub struct UniformRing<T: 'static> {
+    buffers: Vec<Arc<CpuAccessibleBuffer<T>>>,
+}
+
+impl<T> UniformRing<T>
+where
+    T: Content + Copy + Send + Sync + 'static,
+{
+    pub fn new(device: Arc<Device>, frames: &FramesInFlight, initial: T) -> Result<Self, RendererError> {
+        let mut buffers = Vec::with_capacity(frames.count());
+        for _ in 0..frames.count() {
+            buffers.push(CpuAccessibleBuffer::from_data(
+                device.clone(),
+                BufferUsage::uniform_buffer(),
+                initial,
+            )?);
+        }
+        Ok(Self { buffers })
+    }
+    // Overwrites the buffer of `slot`, which must come from `FramesInFlight::begin`
+    // for the frame being recorded
+    pub fn write(&self, slot: usize, data: T) -> Result<Arc<CpuAccessibleBuffer<T>>, RendererError> {
+        let buffer = &self.buffers[slot];
+        *buffer.write()? = data;
+        Ok(buffer.clone())
+    }
+    pub fn get(&self, slot: usize) -> &Arc<CpuAccessibleBuffer<T>> {
+        &self.buffers[slot]
+    }
+}
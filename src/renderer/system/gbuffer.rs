@@ -1,94 +1,210 @@
+use renderer::system::error::RendererError;
+use renderer::system::render_system::DepthMode;
+use std::ops::Index;
 use std::sync::Arc;
-use vulkano::{command_buffer::{AutoCommandBufferBuilder, CommandBuffer},
-              device::Queue,
-              format::Format,
-              framebuffer::{Framebuffer, FramebufferAbstract,
-                            RenderPassAbstract, Subpass},
-              image::{AttachmentImage, ImageAccess, ImageUsage, ImageViewAccess},
-              sync::GpuFuture};
+use vulkano::{device::Queue,
+              format::{ClearValue, Format},
+              image::{AttachmentImage, ImageUsage}};
 
+// The attachment names of the default deferred gbuffer
+pub const DIFFUSE: &str = "diffuse";
+pub const SPECULAR: &str = "specular";
+pub const NORMALS: &str = "normals";
+pub const DEPTH: &str = "depth";
+
+#[derive(Debug, Clone)]
+pub struct GBufferAttachment {
+    pub name: String,
+    pub format: Format,
+    pub usage: ImageUsage,
+    pub clear_value: ClearValue,
+}
+
+// A set of named images that share their dimensions and are rebuilt together
 pub struct GBuffer {
-    pub diffuse: Arc<AttachmentImage>,
-    pub specular: Arc<AttachmentImage>,
-    pub normal: Arc<AttachmentImage>,
-    pub depth: Arc<AttachmentImage>,
-    pub builder: GBufferBuilder,
+    images: Vec<Arc<AttachmentImage>>,
+    dims: [u32; 2],
+    builder: GBufferBuilder,
 }
 
 impl GBuffer {
     #[inline]
     pub fn dims(&self) -> [u32; 2] {
-        ImageAccess::dimensions(&self.diffuse).width_height()
+        self.dims
+    }
+    pub fn len(&self) -> usize {
+        self.images.len()
+    }
+    pub fn is_empty(&self) -> bool {
+        self.images.is_empty()
+    }
+    pub fn builder(&self) -> &GBufferBuilder {
+        &self.builder
+    }
+    pub fn attachment(&self, name: &str) -> Option<Arc<AttachmentImage>> {
+        self.builder.index(name).map(|i| self.images[i].clone())
+    }
+    // Like `attachment`, for callers that return a `RendererError` anyway
+    pub fn require(&self, name: &str) -> Result<Arc<AttachmentImage>, RendererError> {
+        self.attachment(name)
+            .ok_or_else(|| RendererError::AttachmentMismatch(name.to_string()))
+    }
+    pub fn image(&self, index: usize) -> Option<&Arc<AttachmentImage>> {
+        self.images.get(index)
+    }
+    pub fn clear_value(&self, name: &str) -> Option<ClearValue> {
+        self.builder.get(name).map(|a| a.clear_value)
+    }
+    // Clear values do not affect the images so nothing is rebuilt. A `RenderSystem`
+    // clears with the values of its render graph, see `RenderSystem::set_clear_value`
+    pub fn set_clear_value(&mut self, name: &str, clear_value: ClearValue) -> bool {
+        self.builder.set_clear_value(name, clear_value)
     }
-    pub fn rebuild_with_dims(&mut self, queue: Arc<Queue>, dims: [u32; 2]) {
-        *self = self.builder.build_with_dims(queue, dims);
+    // Left unchanged if rebuilding fails
+    pub fn rebuild_with_dims(&mut self, queue: Arc<Queue>, dims: [u32; 2]) -> Result<(), RendererError> {
+        *self = self.builder.build_with_dims(queue, dims)?;
+        Ok(())
+    }
+    // Replaces the attachments, keeping the current dimensions
+    pub fn rebuild_with_builder(&mut self, queue: Arc<Queue>, builder: GBufferBuilder) -> Result<(), RendererError> {
+        *self = builder.build_with_dims(queue, self.dims)?;
+        Ok(())
     }
 }
-#[derive(Copy, Clone)]
+
+// Panics if there is no attachment called `name`, use `attachment` or `require`
+// where that is not a bug
+impl<'a> Index<&'a str> for GBuffer {
+    type Output = Arc<AttachmentImage>;
+    fn index(&self, name: &'a str) -> &Arc<AttachmentImage> {
+        let index = self.builder
+            .index(name)
+            .unwrap_or_else(|| panic!("No gbuffer attachment named '{}'", name));
+        &self.images[index]
+    }
+}
+
+#[derive(Debug, Clone)]
 pub struct GBufferBuilder {
-    diffuse_usage: (ImageUsage, Format),
-    specular_usage: (ImageUsage, Format),
-    normals_usage: (ImageUsage, Format),
-    depth_usage: (ImageUsage, Format),
+    attachments: Vec<GBufferAttachment>,
+}
+
+impl Default for GBufferBuilder {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+fn transient_input_usage() -> ImageUsage {
+    ImageUsage {
+        transient_attachment: true,
+        input_attachment: true,
+        ..ImageUsage::none()
+    }
 }
 
 impl GBufferBuilder {
-    pub fn build_no_dims(&self, queue: Arc<Queue>) -> GBuffer {
+    pub fn new() -> Self {
+        Self {
+            attachments: Vec::new(),
+        }
+    }
+    // Replaces an attachment with the same name, otherwise adds it after the others
+    pub fn attachment<S: Into<String>>(mut self, name: S, format: Format, usage: ImageUsage, clear_value: ClearValue) -> Self {
+        let attachment = GBufferAttachment {
+            name: name.into(),
+            format,
+            usage,
+            clear_value,
+        };
+        match self.index(&attachment.name) {
+            Some(i) => self.attachments[i] = attachment,
+            None => self.attachments.push(attachment),
+        }
+        self
+    }
+    pub fn build_no_dims(&self, queue: Arc<Queue>) -> Result<GBuffer, RendererError> {
         self.build_with_dims(queue, [1, 1])
     }
-    #[inline]
-    pub fn build_with_dims(&self, queue: Arc<Queue>, dimensions: [u32; 2]) -> GBuffer {
-        GBuffer {
-            diffuse: AttachmentImage::with_usage(
-                queue.device().clone(),
-                dimensions,
-                self.diffuse_usage.1,
-                self.diffuse_usage.0,
-            ).unwrap(),
-            specular: AttachmentImage::with_usage(
-                queue.device().clone(),
-                dimensions,
-                self.specular_usage.1,
-                self.specular_usage.0,
-            ).unwrap(),
-            normal: AttachmentImage::with_usage(
-                queue.device().clone(),
-                dimensions,
-                self.normals_usage.1,
-                self.normals_usage.0,
-            ).unwrap(),
-            depth: AttachmentImage::with_usage(
-                queue.device().clone(),
-                dimensions,
-                self.depth_usage.1,
-                self.depth_usage.0,
-            ).unwrap(),
-            builder: *self,
+    pub fn build_with_dims(&self, queue: Arc<Queue>, dimensions: [u32; 2]) -> Result<GBuffer, RendererError> {
+        if dimensions[0] == 0 || dimensions[1] == 0 {
+            return Err(RendererError::InvalidDimensions(dimensions));
+        }
+        let mut images = Vec::with_capacity(self.attachments.len());
+        for a in &self.attachments {
+            let image = AttachmentImage::with_usage(queue.device().clone(), dimensions, a.format, a.usage)
+                .map_err(|err| RendererError::from_image_creation(err, a.format))?;
+            images.push(image);
         }
+        Ok(GBuffer {
+            images,
+            dims: dimensions,
+            builder: self.clone(),
+        })
     }
+    // Diffuse, specular, normals and depth, all transient input attachments
     pub fn new_default() -> Self {
-        let atch_usage = ImageUsage {
-            transient_attachment: true,
-            input_attachment: true,
-            ..ImageUsage::none()
-        };
-        Self {
-            diffuse_usage: (atch_usage, Format::A2B10G10R10UnormPack32),
-            specular_usage: (atch_usage, Format::R16Unorm),
-            normals_usage: (atch_usage, Format::R16G16B16A16Sfloat),
-            depth_usage: (atch_usage, Format::D16Unorm),
-        }
+        Self::new()
+            .attachment(DIFFUSE, Format::A2B10G10R10UnormPack32, transient_input_usage(), [0.0, 0.0, 0.0, 0.0].into())
+            .attachment(SPECULAR, Format::R16Unorm, transient_input_usage(), [0.0, 0.0, 0.0, 0.0].into())
+            .attachment(NORMALS, Format::R16G16B16A16Sfloat, transient_input_usage(), [0.0, 0.0, 0.0, 0.0].into())
+            .attachment(DEPTH, Format::D16Unorm, transient_input_usage(), 1.0f32.into())
+    }
+    pub fn new_default_with_stencil(depth_stencil_format: Format) -> Self {
+        let mut builder = Self::new_default();
+        builder.set_depth_format(depth_stencil_format);
+        builder
+    }
+    pub fn attachments(&self) -> &[GBufferAttachment] {
+        &self.attachments
+    }
+    pub fn index(&self, name: &str) -> Option<usize> {
+        self.attachments.iter().position(|a| a.name == name)
+    }
+    pub fn get(&self, name: &str) -> Option<&GBufferAttachment> {
+        self.attachments.iter().find(|a| a.name == name)
     }
-    pub fn set_diffuse_usage(&mut self, atch_usage: ImageUsage, format: Format) {
-        self.diffuse_usage = (atch_usage, format);
+    // The setters return false when there is no attachment called `name`
+    pub fn set_format(&mut self, name: &str, format: Format) -> bool {
+        self.modify(name, |a| a.format = format)
     }
-    pub fn set_specular_usage(&mut self, atch_usage: ImageUsage, format: Format) {
-        self.specular_usage = (atch_usage, format);
+    pub fn set_usage(&mut self, name: &str, usage: ImageUsage) -> bool {
+        self.modify(name, |a| a.usage = usage)
     }
-    pub fn set_normals_usage(&mut self, atch_usage: ImageUsage, format: Format) {
-        self.normals_usage = (atch_usage, format);
+    pub fn set_clear_value(&mut self, name: &str, clear_value: ClearValue) -> bool {
+        self.modify(name, |a| a.clear_value = clear_value)
     }
-    pub fn set_depth_usage(&mut self, atch_usage: ImageUsage, format: Format) {
-        self.depth_usage = (atch_usage, format);
+    // Adds the usage bits a render graph needs, transient is only kept if both want it
+    pub fn merge_usage(&mut self, name: &str, usage: ImageUsage) -> bool {
+        self.modify(name, |a| {
+            a.usage = ImageUsage {
+                transfer_source: a.usage.transfer_source || usage.transfer_source,
+                transfer_destination: a.usage.transfer_destination || usage.transfer_destination,
+                sampled: a.usage.sampled || usage.sampled,
+                storage: a.usage.storage || usage.storage,
+                color_attachment: a.usage.color_attachment || usage.color_attachment,
+                depth_stencil_attachment: a.usage.depth_stencil_attachment || usage.depth_stencil_attachment,
+                transient_attachment: a.usage.transient_attachment && usage.transient_attachment,
+                input_attachment: a.usage.input_attachment || usage.input_attachment,
+            }
+        })
     }
-}
\ No newline at end of file
+    // Use a format with a stencil component (such as `Format::D24UnormS8Uint`)
+    // with `deffered_lighting_stencil_graph`. The clear value follows the format
+    pub fn set_depth_format(&mut self, format: Format) {
+        self.set_format(DEPTH, format);
+        self.set_clear_value(DEPTH, DepthMode::Standard.clear_value_for(format));
+    }
+    pub fn depth_format(&self) -> Option<Format> {
+        self.get(DEPTH).map(|a| a.format)
+    }
+    fn modify<F: FnOnce(&mut GBufferAttachment)>(&mut self, name: &str, f: F) -> bool {
+        match self.attachments.iter_mut().find(|a| a.name == name) {
+            Some(attachment) => {
+                f(attachment);
+                true
+            }
+            None => false,
+        }
+    }
+}
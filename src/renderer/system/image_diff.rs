@@ -0,0 +1,198 @@
+use renderer::system::offscreen::CpuImage;
+
+// SSIM stabilising constants for a dynamic range of 1
+const C1: f32 = 0.01 * 0.01;
+const C2: f32 = 0.03 * 0.03;
+const SSIM_WINDOW: u32 = 8;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ImageDiff {
+    // Largest difference of any channel of any pixel
+    pub max_difference: f32,
+    // Pixels with a channel differing by more than the tolerance
+    pub mismatched_pixels: usize,
+    pub total_pixels: usize,
+    // Mean structural similarity of the luma over 8x8 windows, 1 for identical images
+    pub ssim: f32,
+}
+
+impl ImageDiff {
+    pub fn mismatched_fraction(&self) -> f32 {
+        self.mismatched_pixels as f32 / self.total_pixels.max(1) as f32
+    }
+}
+
+// None if the images have different dimensions
+pub fn compare(expected: &CpuImage, actual: &CpuImage, tolerance: f32) -> Option<ImageDiff> {
+    if expected.width != actual.width || expected.height != actual.height {
+        return None;
+    }
+    let mut max_difference: f32 = 0.0;
+    let mut mismatched_pixels = 0;
+    for (a, b) in expected.pixels.iter().zip(actual.pixels.iter()) {
+        let difference = (0..4).map(|c| (a[c] - b[c]).abs()).fold(0.0, f32::max);
+        max_difference = max_difference.max(difference);
+        if difference > tolerance {
+            mismatched_pixels += 1;
+        }
+    }
+    Some(ImageDiff {
+        max_difference,
+        mismatched_pixels,
+        total_pixels: expected.pixels.len(),
+        ssim: ssim(expected, actual),
+    })
+}
+
+fn luma(pixel: [f32; 4]) -> f32 {
+    0.2126 * pixel[0] + 0.7152 * pixel[1] + 0.0722 * pixel[2]
+}
+
+// Both images must have the same dimensions
+pub fn ssim(a: &CpuImage, b: &CpuImage) -> f32 {
+    let mut total = 0.0;
+    let mut windows = 0;
+    let mut y = 0;
+    while y < a.height {
+        let mut x = 0;
+        while x < a.width {
+            let x_end = (x + SSIM_WINDOW).min(a.width);
+            let y_end = (y + SSIM_WINDOW).min(a.height);
+            let count = ((x_end - x) * (y_end - y)) as f32;
+            let (mut mean_a, mut mean_b) = (0.0, 0.0);
+            for wy in y..y_end {
+                for wx in x..x_end {
+                    mean_a += luma(a.pixel(wx, wy));
+                    mean_b += luma(b.pixel(wx, wy));
+                }
+            }
+            mean_a /= count;
+            mean_b /= count;
+            let (mut var_a, mut var_b, mut covariance) = (0.0, 0.0, 0.0);
+            for wy in y..y_end {
+                for wx in x..x_end {
+                    let da = luma(a.pixel(wx, wy)) - mean_a;
+                    let db = luma(b.pixel(wx, wy)) - mean_b;
+                    var_a += da * da;
+                    var_b += db * db;
+                    covariance += da * db;
+                }
+            }
+            var_a /= count;
+            var_b /= count;
+            covariance /= count;
+            total += ((2.0 * mean_a * mean_b + C1) * (2.0 * covariance + C2))
+                / ((mean_a * mean_a + mean_b * mean_b + C1) * (var_a + var_b + C2));
+            windows += 1;
+            x += SSIM_WINDOW;
+        }
+        y += SSIM_WINDOW;
+    }
+    if windows == 0 {
+        1.0
+    } else {
+        total / windows as f32
+    }
+}
+
+// Absolute per channel differences scaled by `gain` so small errors are visible,
+// with alpha set to 1. Both images must have the same dimensions
+pub fn diff_image(a: &CpuImage, b: &CpuImage, gain: f32) -> CpuImage {
+    let mut diff = CpuImage::new(a.width, a.height);
+    for (out, (pa, pb)) in diff.pixels.iter_mut().zip(a.pixels.iter().zip(b.pixels.iter())) {
+        *out = [
+            ((pa[0] - pb[0]).abs() * gain).min(1.0),
+            ((pa[1] - pb[1]).abs() * gain).min(1.0),
+            ((pa[2] - pb[2]).abs() * gain).min(1.0),
+            1.0,
+        ];
+    }
+    diff
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // The thresholds tests/golden.rs applies
+    const TOLERANCE: f32 = 3.0 / 255.0;
+    const MAX_MISMATCHED_FRACTION: f32 = 0.002;
+    const MIN_SSIM: f32 = 0.98;
+
+    fn image<F: Fn(u32, u32) -> [f32; 4]>(width: u32, height: u32, pixel: F) -> CpuImage {
+        let mut image = CpuImage::new(width, height);
+        for y in 0..height {
+            for x in 0..width {
+                image.set_pixel(x, y, pixel(x, y));
+            }
+        }
+        image
+    }
+    // Smooth stripes with some colour, sized so the right and bottom SSIM windows are partial
+    fn pattern(offset: u32) -> CpuImage {
+        image(30, 20, |x, y| {
+            let v = 0.5 + 0.4 * ((x + offset) as f32 * 0.7).sin() * (y as f32 * 0.5).cos();
+            [v, v * 0.8, 1.0 - v, 1.0]
+        })
+    }
+    fn passes(diff: &ImageDiff) -> bool {
+        diff.mismatched_fraction() <= MAX_MISMATCHED_FRACTION && diff.ssim >= MIN_SSIM
+    }
+
+    #[test]
+    fn identical_images_match() {
+        let diff = compare(&pattern(0), &pattern(0), TOLERANCE).unwrap();
+        assert_eq!(diff.max_difference, 0.0);
+        assert_eq!(diff.mismatched_pixels, 0);
+        assert_eq!(diff.total_pixels, 600);
+        assert!((diff.ssim - 1.0).abs() < 1e-6, "ssim {}", diff.ssim);
+        assert!(passes(&diff));
+    }
+
+    #[test]
+    fn flat_images_match() {
+        let flat = image(16, 16, |_, _| [0.25, 0.5, 0.75, 1.0]);
+        let diff = compare(&flat, &flat, 0.0).unwrap();
+        assert!((diff.ssim - 1.0).abs() < 1e-6, "ssim {}", diff.ssim);
+        assert!(passes(&diff));
+    }
+
+    #[test]
+    fn shifted_image_fails() {
+        let diff = compare(&pattern(0), &pattern(2), TOLERANCE).unwrap();
+        assert!(diff.ssim < MIN_SSIM, "ssim {}", diff.ssim);
+        assert!(diff.mismatched_fraction() > 0.5);
+        assert!(!passes(&diff));
+    }
+
+    #[test]
+    fn tinted_image_fails() {
+        let expected = pattern(0);
+        let mut tinted = expected.clone();
+        for p in tinted.pixels.iter_mut() {
+            p[0] = (p[0] + 0.1).min(1.0);
+        }
+        let diff = compare(&expected, &tinted, TOLERANCE).unwrap();
+        assert!((diff.max_difference - 0.1).abs() < 1e-5);
+        assert_eq!(diff.mismatched_pixels, diff.total_pixels);
+        assert!(!passes(&diff));
+    }
+
+    #[test]
+    fn differences_within_the_tolerance_pass() {
+        let expected = pattern(0);
+        let mut rounded = expected.clone();
+        for p in rounded.pixels.iter_mut() {
+            p[1] += 2.0 / 255.0;
+        }
+        let diff = compare(&expected, &rounded, TOLERANCE).unwrap();
+        assert_eq!(diff.mismatched_pixels, 0);
+        assert!(passes(&diff), "{:?}", diff);
+    }
+
+    #[test]
+    fn size_mismatch_is_none() {
+        assert_eq!(compare(&pattern(0), &CpuImage::new(20, 30), TOLERANCE), None);
+        assert_eq!(compare(&pattern(0), &CpuImage::new(30, 19), TOLERANCE), None);
+    }
+}
@@ -0,0 +1,612 @@
+use math;
+use math::{Mat4, Point, Quaternion, Vec3};
+use nalgebra::Unit;
+use renderer::system::camera_uniform::{mat4_to_array, CameraBuffer};
+use renderer::system::error::RendererError;
+use renderer::system::gbuffer::{GBuffer, DEPTH, DIFFUSE, NORMALS, SPECULAR};
+use renderer::system::lighting_system::additive_blend;
+use renderer::system::render_system::DepthMode;
+use std::f32::consts::PI;
+use std::sync::Arc;
+use vulkano::{buffer::{BufferUsage, CpuAccessibleBuffer},
+              command_buffer::{AutoCommandBuffer, AutoCommandBufferBuilder, DynamicState},
+              descriptor::descriptor_set::{DescriptorSet, PersistentDescriptorSet},
+              device::Queue,
+              framebuffer::{RenderPassAbstract, Subpass},
+              pipeline::{blend::{AttachmentBlend, BlendFactor, BlendOp},
+                         depth_stencil::{Compare, DepthBounds, DepthStencil, Stencil, StencilOp},
+                         GraphicsPipeline, GraphicsPipelineAbstract}};
+
+const SPHERE_SEGMENTS: usize = 16;
+const SPHERE_RINGS: usize = 12;
+const CONE_SEGMENTS: usize = 16;
+// Marks point lights in the shader, spot light cone cosines are always above it
+const NO_CONE: f32 = -2.0;
+// The cone mesh turns inside out at a right angle, wider spot lights are clamped to this
+const MAX_SPOT_ANGLE: f32 = PI * 0.5 - 0.01;
+
+#[derive(Debug, Clone, Copy)]
+pub struct PointLight {
+    pub position: Point,
+    pub radius: f32,
+    pub color: [f32; 3],
+}
+
+// The light fades from full strength inside `inner_angle` to nothing at
+// `outer_angle`, both measured from `direction` in radians. `outer_angle` must
+// be below a right angle and is clamped just short of it, `inner_angle` is clamped
+// to `outer_angle`. `direction` does not need to be normalised but must not be zero
+#[derive(Debug, Clone, Copy)]
+pub struct SpotLight {
+    pub position: Point,
+    pub direction: Vec3,
+    pub range: f32,
+    pub inner_angle: f32,
+    pub outer_angle: f32,
+    pub color: [f32; 3],
+}
+
+#[derive(Debug, Clone)]
+pub struct VolumeVertex {
+    pub position: [f32; 3],
+}
+impl_vertex!(VolumeVertex, position);
+
+// Flips any triangle whose winding does not face away from `inside`, so every
+// face is counter clockwise when seen from outside the mesh
+fn push_outward(vertices: &mut Vec<VolumeVertex>, a: Vec3, b: Vec3, c: Vec3, inside: Vec3) {
+    let normal = math::cross(b - a, c - a);
+    let (b, c) = if normal.dot(&((a + b + c) / 3.0 - inside)) < 0.0 {
+        (c, b)
+    } else {
+        (b, c)
+    };
+    for v in [a, b, c].iter() {
+        vertices.push(VolumeVertex {
+            position: [v.x, v.y, v.z],
+        });
+    }
+}
+
+// A unit sphere scaled so its flat faces still enclose the real sphere
+fn sphere_mesh() -> Vec<VolumeVertex> {
+    let scale = 1.0 / ((PI / SPHERE_SEGMENTS as f32).cos() * (PI / SPHERE_RINGS as f32).cos());
+    let point = |ring: usize, segment: usize| {
+        let theta = PI * ring as f32 / SPHERE_RINGS as f32;
+        let phi = 2.0 * PI * segment as f32 / SPHERE_SEGMENTS as f32;
+        Vec3::new(theta.sin() * phi.cos(), theta.cos(), theta.sin() * phi.sin()) * scale
+    };
+    let mut vertices = Vec::new();
+    for ring in 0..SPHERE_RINGS {
+        for segment in 0..SPHERE_SEGMENTS {
+            let a = point(ring, segment);
+            let b = point(ring + 1, segment);
+            let c = point(ring + 1, segment + 1);
+            let d = point(ring, segment + 1);
+            if ring != 0 {
+                push_outward(&mut vertices, a, b, d, Vec3::zeros());
+            }
+            if ring != SPHERE_RINGS - 1 {
+                push_outward(&mut vertices, b, c, d, Vec3::zeros());
+            }
+        }
+    }
+    vertices
+}
+
+// A cone with its apex at the origin opening along +z, length 1 and base radius 1,
+// with the base widened so its flat sides enclose the real cone
+fn cone_mesh() -> Vec<VolumeVertex> {
+    let scale = 1.0 / (PI / CONE_SEGMENTS as f32).cos();
+    let base = |segment: usize| {
+        let phi = 2.0 * PI * segment as f32 / CONE_SEGMENTS as f32;
+        Vec3::new(phi.cos() * scale, phi.sin() * scale, 1.0)
+    };
+    let apex = Vec3::zeros();
+    let center = Vec3::new(0.0, 0.0, 1.0);
+    let inside = Vec3::new(0.0, 0.0, 0.6);
+    let mut vertices = Vec::new();
+    for segment in 0..CONE_SEGMENTS {
+        let a = base(segment);
+        let b = base(segment + 1);
+        push_outward(&mut vertices, apex, a, b, inside);
+        push_outward(&mut vertices, center, b, a, inside);
+    }
+    vertices
+}
+
+// Draws point and spot lights as meshes bounding their area of effect in the
+// lighting stage of `deffered_lighting_stencil_graph`. For each light the
+// volume is first drawn without colour, counting depth test failures of back faces
+// up and front faces down in the stencil buffer so only gbuffer pixels inside the
+// volume are left non zero. The back faces are then drawn with the stencil test,
+// shading those pixels and resetting the stencil for the next light
+pub struct LightVolumeSystem {
+    queue: Arc<Queue>,
+    sphere: Arc<CpuAccessibleBuffer<[VolumeVertex]>>,
+    cone: Arc<CpuAccessibleBuffer<[VolumeVertex]>>,
+    stencil_pipeline: Arc<GraphicsPipelineAbstract + Send + Sync>,
+    light_pipeline: Arc<GraphicsPipelineAbstract + Send + Sync>,
+}
+
+impl LightVolumeSystem {
+    pub fn new<R>(queue: Arc<Queue>, subpass: Subpass<R>, depth_mode: DepthMode) -> Result<Self, RendererError>
+    where
+        R: RenderPassAbstract + Clone + Send + Sync + 'static,
+    {
+        let vs = volume_vs::Shader::load(queue.device().clone()).map_err(RendererError::ShaderLoad)?;
+        let stencil_fs = stencil_fs::Shader::load(queue.device().clone()).map_err(RendererError::ShaderLoad)?;
+        let light_fs = light_fs::Shader::load(queue.device().clone()).map_err(RendererError::ShaderLoad)?;
+
+        let mark = |depth_fail_op: StencilOp| Stencil {
+            compare: Compare::Always,
+            pass_op: StencilOp::Keep,
+            fail_op: StencilOp::Keep,
+            depth_fail_op,
+            compare_mask: Some(0xff),
+            write_mask: Some(0xff),
+            reference: Some(0),
+        };
+        let stencil_pipeline = Arc::new(GraphicsPipeline::start()
+            .vertex_input_single_buffer::<VolumeVertex>()
+            .vertex_shader(vs.main_entry_point(), ())
+            .triangle_list()
+            .viewports_dynamic_scissors_irrelevant(1)
+            .fragment_shader(stencil_fs.main_entry_point(), ())
+            .cull_mode_disabled()
+            .blend_collective(AttachmentBlend {
+                enabled: false,
+                color_op: BlendOp::Add,
+                color_source: BlendFactor::One,
+                color_destination: BlendFactor::Zero,
+                alpha_op: BlendOp::Add,
+                alpha_source: BlendFactor::One,
+                alpha_destination: BlendFactor::Zero,
+                mask_red: false,
+                mask_green: false,
+                mask_blue: false,
+                mask_alpha: false,
+            })
+            .depth_stencil(DepthStencil {
+                depth_write: false,
+                depth_compare: depth_mode.compare_op(),
+                depth_bounds_test: DepthBounds::Disabled,
+                stencil_front: mark(StencilOp::DecrementAndWrap),
+                stencil_back: mark(StencilOp::IncrementAndWrap),
+            })
+            .render_pass(subpass.clone())
+            .build(queue.device().clone())?) as Arc<_>;
+
+        let shade = Stencil {
+            compare: Compare::NotEqual,
+            pass_op: StencilOp::Zero,
+            fail_op: StencilOp::Keep,
+            depth_fail_op: StencilOp::Keep,
+            compare_mask: Some(0xff),
+            write_mask: Some(0xff),
+            reference: Some(0),
+        };
+        let light_pipeline = Arc::new(GraphicsPipeline::start()
+            .vertex_input_single_buffer::<VolumeVertex>()
+            .vertex_shader(vs.main_entry_point(), ())
+            .triangle_list()
+            .viewports_dynamic_scissors_irrelevant(1)
+            .fragment_shader(light_fs.main_entry_point(), ())
+            .cull_mode_front()
+            .blend_collective(additive_blend())
+            .depth_stencil(DepthStencil {
+                depth_write: false,
+                depth_compare: Compare::Always,
+                depth_bounds_test: DepthBounds::Disabled,
+                stencil_front: shade.clone(),
+                stencil_back: shade,
+            })
+            .render_pass(subpass)
+            .build(queue.device().clone())?) as Arc<_>;
+
+        let (sphere, cone) = {
+            let buffer = |vertices: Vec<VolumeVertex>| {
+                CpuAccessibleBuffer::from_iter(queue.device().clone(), BufferUsage::all(), vertices.into_iter())
+            };
+            (buffer(sphere_mesh())?, buffer(cone_mesh())?)
+        };
+        Ok(Self {
+            sphere,
+            cone,
+            queue,
+            stencil_pipeline,
+            light_pipeline,
+        })
+    }
+    fn camera_set(
+        pipeline: &Arc<GraphicsPipelineAbstract + Send + Sync>,
+        camera: CameraBuffer,
+    ) -> Result<Arc<DescriptorSet + Send + Sync>, RendererError> {
+        Ok(Arc::new(
+            PersistentDescriptorSet::start(pipeline.clone(), 0)
+                .add_buffer(camera)?
+                .build()?,
+        ))
+    }
+    // Records every light into one secondary command buffer, lights are drawn in order
+    pub fn draw(
+        &self,
+        dynamic_state: DynamicState,
+        gbuffer: &GBuffer,
+        camera: CameraBuffer,
+        point_lights: &[PointLight],
+        spot_lights: &[SpotLight],
+    ) -> Result<AutoCommandBuffer, RendererError> {
+        let stencil_camera = Self::camera_set(&self.stencil_pipeline, camera.clone())?;
+        let light_camera = Self::camera_set(&self.light_pipeline, camera)?;
+        let gbuffer_set = Arc::new(
+            PersistentDescriptorSet::start(self.light_pipeline.clone(), 1)
+                .add_image(gbuffer.require(DIFFUSE)?)?
+                .add_image(gbuffer.require(SPECULAR)?)?
+                .add_image(gbuffer.require(NORMALS)?)?
+                .add_image(gbuffer.require(DEPTH)?)?
+                .build()?,
+        ) as Arc<DescriptorSet + Send + Sync>;
+
+        let mut builder = AutoCommandBufferBuilder::secondary_graphics(
+            self.queue.device().clone(),
+            self.queue.family(),
+            self.light_pipeline.clone().subpass(),
+        )?;
+
+        let lights = point_lights
+            .iter()
+            .map(|light| (self.sphere.clone(), point_push_constants(light)))
+            .chain(
+                spot_lights
+                    .iter()
+                    .map(|light| (self.cone.clone(), spot_push_constants(light))),
+            );
+        for (mesh, push_constants) in lights {
+            builder = builder
+                .draw(
+                    self.stencil_pipeline.clone(),
+                    dynamic_state.clone(),
+                    mesh.clone(),
+                    stencil_camera.clone(),
+                    push_constants,
+                )?
+                .draw(
+                    self.light_pipeline.clone(),
+                    dynamic_state.clone(),
+                    mesh,
+                    (light_camera.clone(), gbuffer_set.clone()),
+                    push_constants,
+                )?;
+        }
+        Ok(builder.build()?)
+    }
+}
+
+fn point_push_constants(light: &PointLight) -> volume_vs::ty::PushConstants {
+    let model = Mat4::new_translation(&light.position.coords) * Mat4::new_scaling(light.radius);
+    volume_vs::ty::PushConstants {
+        model: mat4_to_array(&model),
+        color_outer: [light.color[0], light.color[1], light.color[2], NO_CONE],
+        position_radius: [light.position.x, light.position.y, light.position.z, light.radius],
+        direction_inner: [0.0, 0.0, 0.0, NO_CONE],
+    }
+}
+
+fn spot_push_constants(light: &SpotLight) -> volume_vs::ty::PushConstants {
+    let length = light.direction.norm();
+    assert!(length > 0.0, "spot light direction must not be zero");
+    let direction = light.direction / length;
+    let outer = light.outer_angle.min(MAX_SPOT_ANGLE);
+    let inner = light.inner_angle.min(outer);
+    // The cone mesh opens along +z
+    let rotation = Quaternion::rotation_between(&Vec3::z(), &direction).unwrap_or_else(|| {
+        Quaternion::from_axis_angle(&Unit::new_normalize(Vec3::x()), PI)
+    });
+    let radius = light.range * outer.tan();
+    let model = Mat4::new_translation(&light.position.coords) * rotation.to_homogeneous()
+        * Mat4::new_nonuniform_scaling(&Vec3::new(radius, radius, light.range));
+    volume_vs::ty::PushConstants {
+        model: mat4_to_array(&model),
+        color_outer: [light.color[0], light.color[1], light.color[2], outer.cos()],
+        position_radius: [light.position.x, light.position.y, light.position.z, light.range],
+        direction_inner: [direction.x, direction.y, direction.z, inner.cos()],
+    }
+}
+
+mod volume_vs {
+    #[derive(VulkanoShader)]
+    #[allow(dead_code)]
+    #[ty = "vertex"]
+    #[src = "
+#version 450
+layout(location = 0) in vec3 position;
+layout(location = 0) out vec4 v_clip;
+
+layout(set = 0, binding = 0) uniform CameraData {
+    mat4 view;
+    mat4 projection;
+    mat4 view_projection;
+    mat4 inverse_view;
+    mat4 inverse_projection;
+    mat4 inverse_view_projection;
+    vec4 position;
+} camera;
+
+layout(push_constant) uniform PushConstants {
+    mat4 model;
+    vec4 color_outer;
+    vec4 position_radius;
+    vec4 direction_inner;
+} light;
+
+void main() {
+    gl_Position = camera.view_projection * light.model * vec4(position, 1.0);
+    v_clip = gl_Position;
+}
+"]
+    struct Dummy;
+}
+
+mod stencil_fs {
+    #[derive(VulkanoShader)]
+    #[allow(dead_code)]
+    #[ty = "fragment"]
+    #[src = "
+#version 450
+layout(location = 0) out vec4 f_color;
+
+void main() {
+    f_color = vec4(0.0);
+}
+"]
+    struct Dummy;
+}
+
+mod light_fs {
+    #[derive(VulkanoShader)]
+    #[allow(dead_code)]
+    #[ty = "fragment"]
+    #[src = "
+#version 450
+layout(location = 0) in vec4 v_clip;
+
+layout(set = 0, binding = 0) uniform CameraData {
+    mat4 view;
+    mat4 projection;
+    mat4 view_projection;
+    mat4 inverse_view;
+    mat4 inverse_projection;
+    mat4 inverse_view_projection;
+    vec4 position;
+} camera;
+
+layout(input_attachment_index = 0, set = 1, binding = 0) uniform subpassInput u_diffuse;
+layout(input_attachment_index = 1, set = 1, binding = 1) uniform subpassInput u_specular;
+layout(input_attachment_index = 2, set = 1, binding = 2) uniform subpassInput u_normals;
+layout(input_attachment_index = 3, set = 1, binding = 3) uniform subpassInput u_depth;
+
+// color_outer.w and direction_inner.w hold the cosines of the outer and inner
+// cone angles, both below -1 for point lights
+layout(push_constant) uniform PushConstants {
+    mat4 model;
+    vec4 color_outer;
+    vec4 position_radius;
+    vec4 direction_inner;
+} light;
+
+layout(location = 0) out vec4 f_color;
+
+const float SHININESS = 32.0;
+
+void main() {
+    vec2 ndc = v_clip.xy / v_clip.w;
+    vec4 world = camera.inverse_view_projection * vec4(ndc, subpassLoad(u_depth).x, 1.0);
+    vec3 position = world.xyz / world.w;
+    // Unlit pixels write black instead of discarding, a discarded fragment would
+    // skip the stencil reset and leave its count for the next light
+    f_color = vec4(0.0);
+    // Nothing was drawn where the normal is still the zero it was cleared to
+    vec3 packed_normal = subpassLoad(u_normals).xyz;
+    if (dot(packed_normal, packed_normal) < 1e-8) {
+        return;
+    }
+    vec3 normal = normalize(packed_normal);
+    vec3 diffuse = subpassLoad(u_diffuse).rgb;
+    float specular = subpassLoad(u_specular).x;
+
+    vec3 offset = light.position_radius.xyz - position;
+    float distance = length(offset);
+    float radius = light.position_radius.w;
+    float window = clamp(1.0 - pow(distance / radius, 4.0), 0.0, 1.0);
+    float attenuation = window * window / (distance * distance + 1.0);
+    vec3 to_light = offset / distance;
+
+    float inner = light.direction_inner.w;
+    if (inner > -1.5) {
+        float outer = light.color_outer.w;
+        float angle = dot(-to_light, normalize(light.direction_inner.xyz));
+        attenuation *= smoothstep(outer, inner, angle);
+    }
+    if (attenuation <= 0.0) {
+        return;
+    }
+
+    vec3 to_eye = normalize(camera.position.xyz - position);
+    vec3 halfway = normalize(to_light + to_eye);
+    float lambert = max(dot(normal, to_light), 0.0);
+    float highlight = lambert > 0.0 ? pow(max(dot(normal, halfway), 0.0), SHININESS) : 0.0;
+
+    vec3 color = light.color_outer.rgb * attenuation;
+    f_color = vec4((diffuse * lambert + specular * highlight) * color, 1.0);
+}
+"]
+    struct Dummy;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+
+    // A small linear congruential generator so the points are the same every run
+    struct Lcg(u32);
+
+    impl Lcg {
+        fn next(&mut self) -> f32 {
+            self.0 = self.0.wrapping_mul(1_664_525).wrapping_add(1_013_904_223);
+            (self.0 >> 8) as f32 / (1 << 24) as f32
+        }
+        fn range(&mut self, min: f32, max: f32) -> f32 {
+            min + self.next() * (max - min)
+        }
+    }
+
+    fn triangles(vertices: &[VolumeVertex]) -> Vec<[Vec3; 3]> {
+        let v = |vertex: &VolumeVertex| Vec3::new(vertex.position[0], vertex.position[1], vertex.position[2]);
+        vertices
+            .chunks(3)
+            .map(|t| [v(&t[0]), v(&t[1]), v(&t[2])])
+            .collect()
+    }
+    // Every directed edge has to be matched by exactly one edge running the other
+    // way for the mesh to be closed with consistent winding
+    fn assert_closed(triangles: &[[Vec3; 3]]) {
+        let key = |v: Vec3| ((v.x * 1e4).round() as i32, (v.y * 1e4).round() as i32, (v.z * 1e4).round() as i32);
+        let mut edges = HashMap::new();
+        for t in triangles {
+            for i in 0..3 {
+                *edges.entry((key(t[i]), key(t[(i + 1) % 3]))).or_insert(0) += 1;
+            }
+        }
+        for (&(a, b), &count) in edges.iter() {
+            assert_eq!(count, 1, "edge {:?} -> {:?} is used {} times", a, b, count);
+            assert_eq!(edges.get(&(b, a)), Some(&1), "edge {:?} -> {:?} has no reverse", a, b);
+        }
+    }
+    // Positive when the faces are counter clockwise seen from outside
+    fn signed_volume(triangles: &[[Vec3; 3]]) -> f32 {
+        triangles
+            .iter()
+            .map(|t| t[0].dot(&math::cross(t[1], t[2])) / 6.0)
+            .sum()
+    }
+    fn assert_encloses(triangles: &[[Vec3; 3]], points: &[Vec3]) {
+        for t in triangles {
+            let normal = math::cross(t[1] - t[0], t[2] - t[0]).normalize();
+            for p in points {
+                assert!(
+                    normal.dot(&(p - t[0])) <= 1e-5,
+                    "{:?} is outside the face {:?}",
+                    p,
+                    t
+                );
+            }
+        }
+    }
+    fn transform(model: &[[f32; 4]; 4], p: Vec3) -> Vec3 {
+        let column = |c: usize| Vec3::new(model[c][0], model[c][1], model[c][2]);
+        column(0) * p.x + column(1) * p.y + column(2) * p.z + column(3)
+    }
+    fn assert_near(a: Vec3, b: Vec3) {
+        assert!((a - b).norm() < 1e-4, "{:?} != {:?}", a, b);
+    }
+    fn spot(direction: Vec3, outer_angle: f32) -> SpotLight {
+        SpotLight {
+            position: Point::new(1.0, 2.0, 3.0),
+            direction,
+            range: 4.0,
+            inner_angle: outer_angle * 0.5,
+            outer_angle,
+            color: [1.0, 1.0, 1.0],
+        }
+    }
+    // The rim of the cone's far end in model space, where the real cone's radius is 1
+    fn rim(count: usize) -> Vec<Vec3> {
+        (0..count)
+            .map(|i| {
+                let phi = 2.0 * PI * i as f32 / count as f32;
+                Vec3::new(phi.cos(), phi.sin(), 1.0)
+            })
+            .collect()
+    }
+
+    #[test]
+    fn sphere_mesh_encloses_the_unit_sphere() {
+        let triangles = triangles(&sphere_mesh());
+        assert_closed(&triangles);
+        assert!(signed_volume(&triangles) > 4.0 / 3.0 * PI);
+
+        let mut rng = Lcg(3);
+        let mut points = vec![Vec3::y(), -Vec3::y()];
+        for _ in 0..2000 {
+            let theta = rng.range(0.0, PI);
+            let phi = rng.range(0.0, 2.0 * PI);
+            points.push(Vec3::new(theta.sin() * phi.cos(), theta.cos(), theta.sin() * phi.sin()));
+        }
+        assert_encloses(&triangles, &points);
+    }
+
+    #[test]
+    fn cone_mesh_encloses_the_unit_cone() {
+        let triangles = triangles(&cone_mesh());
+        assert_closed(&triangles);
+        assert!(signed_volume(&triangles) > PI / 3.0);
+
+        // The cone is convex so its apex and rim are enough
+        let mut points = rim(1000);
+        points.push(Vec3::zeros());
+        assert_encloses(&triangles, &points);
+    }
+
+    #[test]
+    fn spot_light_facing_down_z_turns_the_cone_around() {
+        let light = spot(Vec3::new(0.0, 0.0, -2.0), PI / 4.0);
+        let constants = spot_push_constants(&light);
+        let position = light.position.coords;
+
+        assert_near(transform(&constants.model, Vec3::zeros()), position);
+        assert_near(transform(&constants.model, Vec3::z()), position + Vec3::new(0.0, 0.0, -4.0));
+        assert_eq!(constants.direction_inner[..3], [0.0, 0.0, -1.0]);
+        assert_eq!(constants.direction_inner[3], (PI / 8.0).cos());
+        assert_eq!(constants.color_outer[3], (PI / 4.0).cos());
+    }
+
+    #[test]
+    fn spot_light_radius_is_range_times_tan_outer() {
+        let direction = Vec3::new(1.0, -2.0, 0.5);
+        let light = spot(direction, 0.6);
+        let constants = spot_push_constants(&light);
+        let axis_end = light.position.coords + direction.normalize() * light.range;
+        assert_near(transform(&constants.model, Vec3::z()), axis_end);
+
+        for p in rim(12) {
+            let offset = transform(&constants.model, p) - axis_end;
+            assert!((offset.norm() - light.range * 0.6f32.tan()).abs() < 1e-4);
+            assert!(offset.dot(&direction).abs() < 1e-4);
+        }
+    }
+
+    #[test]
+    fn wide_spot_lights_are_clamped_below_a_right_angle() {
+        let light = SpotLight {
+            inner_angle: PI * 0.7,
+            ..spot(Vec3::x(), PI * 0.75)
+        };
+        let constants = spot_push_constants(&light);
+
+        assert_eq!(constants.color_outer[3], MAX_SPOT_ANGLE.cos());
+        assert_eq!(constants.direction_inner[3], MAX_SPOT_ANGLE.cos());
+        // Still opening along the direction rather than turned inside out
+        let axis_end = transform(&constants.model, Vec3::z());
+        assert_near(axis_end, light.position.coords + Vec3::x() * light.range);
+        let radius = (transform(&constants.model, Vec3::new(1.0, 0.0, 1.0)) - axis_end).norm();
+        assert!(radius.is_finite() && radius > light.range);
+    }
+
+    #[test]
+    #[should_panic(expected = "spot light direction must not be zero")]
+    fn zero_spot_light_direction_panics() {
+        spot_push_constants(&spot(Vec3::zeros(), PI / 4.0));
+    }
+}
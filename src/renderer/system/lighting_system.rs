@@ -0,0 +1,413 @@
+use math::{Point, Vec3};
+use renderer::system::camera_uniform::CameraBuffer;
+use renderer::system::error::RendererError;
+use renderer::system::gbuffer::{GBuffer, DEPTH, DIFFUSE, NORMALS, SPECULAR};
+use std::sync::Arc;
+use vulkano::{buffer::{BufferUsage, CpuAccessibleBuffer},
+              command_buffer::{AutoCommandBuffer, AutoCommandBufferBuilder, DynamicState},
+              descriptor::descriptor_set::{DescriptorSet, PersistentDescriptorSet},
+              device::Queue,
+              framebuffer::{RenderPassAbstract, Subpass},
+              pipeline::{blend::{AttachmentBlend, BlendFactor, BlendOp},
+                         GraphicsPipeline, GraphicsPipelineAbstract}};
+
+// The lighting systems draw into the lighting stage of `deffered_lighting_graph`,
+// reading the gbuffer through input attachments and adding their contribution to
+// the final colour. World positions are rebuilt from depth with the camera's
+// inverse view projection, so the camera must use Vulkan clip space
+
+#[derive(Debug, Clone)]
+pub struct LightingVertex {
+    pub position: [f32; 2],
+}
+impl_vertex!(LightingVertex, position);
+
+// A single triangle covering the whole screen
+pub fn fullscreen_triangle(queue: &Arc<Queue>) -> Result<Arc<CpuAccessibleBuffer<[LightingVertex]>>, RendererError> {
+    Ok(CpuAccessibleBuffer::from_iter(
+        queue.device().clone(),
+        BufferUsage::all(),
+        [
+            LightingVertex { position: [-1.0, -1.0] },
+            LightingVertex { position: [-1.0, 3.0] },
+            LightingVertex { position: [3.0, -1.0] },
+        ].iter()
+            .cloned(),
+    )?)
+}
+
+pub fn additive_blend() -> AttachmentBlend {
+    AttachmentBlend {
+        enabled: true,
+        color_op: BlendOp::Add,
+        color_source: BlendFactor::One,
+        color_destination: BlendFactor::One,
+        alpha_op: BlendOp::Max,
+        alpha_source: BlendFactor::One,
+        alpha_destination: BlendFactor::One,
+        mask_red: true,
+        mask_green: true,
+        mask_blue: true,
+        mask_alpha: true,
+    }
+}
+
+// Binds the diffuse, specular, normal and depth attachments to set 0
+fn gbuffer_set(
+    pipeline: &Arc<GraphicsPipelineAbstract + Send + Sync>,
+    gbuffer: &GBuffer,
+) -> Result<Arc<DescriptorSet + Send + Sync>, RendererError> {
+    Ok(Arc::new(
+        PersistentDescriptorSet::start(pipeline.clone(), 0)
+            .add_image(gbuffer.require(DIFFUSE)?)?
+            .add_image(gbuffer.require(SPECULAR)?)?
+            .add_image(gbuffer.require(NORMALS)?)?
+            .add_image(gbuffer.require(DEPTH)?)?
+            .build()?,
+    ))
+}
+
+fn camera_set(
+    pipeline: &Arc<GraphicsPipelineAbstract + Send + Sync>,
+    camera: CameraBuffer,
+) -> Result<Arc<DescriptorSet + Send + Sync>, RendererError> {
+    Ok(Arc::new(
+        PersistentDescriptorSet::start(pipeline.clone(), 1)
+            .add_buffer(camera)?
+            .build()?,
+    ))
+}
+
+fn secondary_builder(
+    queue: &Arc<Queue>,
+    pipeline: &Arc<GraphicsPipelineAbstract + Send + Sync>,
+) -> Result<AutoCommandBufferBuilder, RendererError> {
+    Ok(AutoCommandBufferBuilder::secondary_graphics(
+        queue.device().clone(),
+        queue.family(),
+        pipeline.clone().subpass(),
+    )?)
+}
+
+// Multiplies the diffuse colour by a constant light
+pub struct AmbientLightingSystem {
+    queue: Arc<Queue>,
+    vertex_buffer: Arc<CpuAccessibleBuffer<[LightingVertex]>>,
+    pipeline: Arc<GraphicsPipelineAbstract + Send + Sync>,
+}
+
+impl AmbientLightingSystem {
+    pub fn new<R>(queue: Arc<Queue>, subpass: Subpass<R>) -> Result<Self, RendererError>
+    where
+        R: RenderPassAbstract + Send + Sync + 'static,
+    {
+        let vs = lighting_vs::Shader::load(queue.device().clone()).map_err(RendererError::ShaderLoad)?;
+        let fs = ambient_fs::Shader::load(queue.device().clone()).map_err(RendererError::ShaderLoad)?;
+        let pipeline = Arc::new(GraphicsPipeline::start()
+            .vertex_input_single_buffer::<LightingVertex>()
+            .vertex_shader(vs.main_entry_point(), ())
+            .triangle_list()
+            .viewports_dynamic_scissors_irrelevant(1)
+            .fragment_shader(fs.main_entry_point(), ())
+            .blend_collective(additive_blend())
+            .render_pass(subpass)
+            .build(queue.device().clone())?) as Arc<_>;
+
+        Ok(Self {
+            vertex_buffer: fullscreen_triangle(&queue)?,
+            queue,
+            pipeline,
+        })
+    }
+    pub fn draw(&self, dynamic_state: DynamicState, gbuffer: &GBuffer, color: [f32; 3]) -> Result<AutoCommandBuffer, RendererError> {
+        let set = Arc::new(
+            PersistentDescriptorSet::start(self.pipeline.clone(), 0)
+                .add_image(gbuffer.require(DIFFUSE)?)?
+                .build()?,
+        );
+        let push_constants = ambient_fs::ty::PushConstants {
+            color: [color[0], color[1], color[2], 1.0],
+        };
+        Ok(secondary_builder(&self.queue, &self.pipeline)?
+            .draw(
+                self.pipeline.clone(),
+                dynamic_state,
+                self.vertex_buffer.clone(),
+                set,
+                push_constants,
+            )?
+            .build()?)
+    }
+}
+
+// A light infinitely far away shining along `direction`
+pub struct DirectionalLightingSystem {
+    queue: Arc<Queue>,
+    vertex_buffer: Arc<CpuAccessibleBuffer<[LightingVertex]>>,
+    pipeline: Arc<GraphicsPipelineAbstract + Send + Sync>,
+}
+
+impl DirectionalLightingSystem {
+    pub fn new<R>(queue: Arc<Queue>, subpass: Subpass<R>) -> Result<Self, RendererError>
+    where
+        R: RenderPassAbstract + Send + Sync + 'static,
+    {
+        let vs = lighting_vs::Shader::load(queue.device().clone()).map_err(RendererError::ShaderLoad)?;
+        let fs = directional_fs::Shader::load(queue.device().clone()).map_err(RendererError::ShaderLoad)?;
+        let pipeline = Arc::new(GraphicsPipeline::start()
+            .vertex_input_single_buffer::<LightingVertex>()
+            .vertex_shader(vs.main_entry_point(), ())
+            .triangle_list()
+            .viewports_dynamic_scissors_irrelevant(1)
+            .fragment_shader(fs.main_entry_point(), ())
+            .blend_collective(additive_blend())
+            .render_pass(subpass)
+            .build(queue.device().clone())?) as Arc<_>;
+
+        Ok(Self {
+            vertex_buffer: fullscreen_triangle(&queue)?,
+            queue,
+            pipeline,
+        })
+    }
+    pub fn draw(
+        &self,
+        dynamic_state: DynamicState,
+        gbuffer: &GBuffer,
+        camera: CameraBuffer,
+        direction: Vec3,
+        color: [f32; 3],
+    ) -> Result<AutoCommandBuffer, RendererError> {
+        let direction = direction.normalize();
+        let push_constants = directional_fs::ty::PushConstants {
+            color: [color[0], color[1], color[2], 1.0],
+            direction: [direction.x, direction.y, direction.z, 0.0],
+        };
+        Ok(secondary_builder(&self.queue, &self.pipeline)?
+            .draw(
+                self.pipeline.clone(),
+                dynamic_state,
+                self.vertex_buffer.clone(),
+                (gbuffer_set(&self.pipeline, gbuffer)?, camera_set(&self.pipeline, camera)?),
+                push_constants,
+            )?
+            .build()?)
+    }
+}
+
+// A light at `position` fading out to nothing at `radius`
+pub struct PointLightingSystem {
+    queue: Arc<Queue>,
+    vertex_buffer: Arc<CpuAccessibleBuffer<[LightingVertex]>>,
+    pipeline: Arc<GraphicsPipelineAbstract + Send + Sync>,
+}
+
+impl PointLightingSystem {
+    pub fn new<R>(queue: Arc<Queue>, subpass: Subpass<R>) -> Result<Self, RendererError>
+    where
+        R: RenderPassAbstract + Send + Sync + 'static,
+    {
+        let vs = lighting_vs::Shader::load(queue.device().clone()).map_err(RendererError::ShaderLoad)?;
+        let fs = point_fs::Shader::load(queue.device().clone()).map_err(RendererError::ShaderLoad)?;
+        let pipeline = Arc::new(GraphicsPipeline::start()
+            .vertex_input_single_buffer::<LightingVertex>()
+            .vertex_shader(vs.main_entry_point(), ())
+            .triangle_list()
+            .viewports_dynamic_scissors_irrelevant(1)
+            .fragment_shader(fs.main_entry_point(), ())
+            .blend_collective(additive_blend())
+            .render_pass(subpass)
+            .build(queue.device().clone())?) as Arc<_>;
+
+        Ok(Self {
+            vertex_buffer: fullscreen_triangle(&queue)?,
+            queue,
+            pipeline,
+        })
+    }
+    pub fn draw(
+        &self,
+        dynamic_state: DynamicState,
+        gbuffer: &GBuffer,
+        camera: CameraBuffer,
+        position: Point,
+        radius: f32,
+        color: [f32; 3],
+    ) -> Result<AutoCommandBuffer, RendererError> {
+        let push_constants = point_fs::ty::PushConstants {
+            color: [color[0], color[1], color[2], 1.0],
+            position_radius: [position.x, position.y, position.z, radius],
+        };
+        Ok(secondary_builder(&self.queue, &self.pipeline)?
+            .draw(
+                self.pipeline.clone(),
+                dynamic_state,
+                self.vertex_buffer.clone(),
+                (gbuffer_set(&self.pipeline, gbuffer)?, camera_set(&self.pipeline, camera)?),
+                push_constants,
+            )?
+            .build()?)
+    }
+}
+
+mod lighting_vs {
+    #[derive(VulkanoShader)]
+    #[allow(dead_code)]
+    #[ty = "vertex"]
+    #[src = "
+#version 450
+layout(location = 0) in vec2 position;
+layout(location = 0) out vec2 v_ndc;
+
+void main() {
+    v_ndc = position;
+    gl_Position = vec4(position, 0.0, 1.0);
+}
+"]
+    struct Dummy;
+}
+
+mod ambient_fs {
+    #[derive(VulkanoShader)]
+    #[allow(dead_code)]
+    #[ty = "fragment"]
+    #[src = "
+#version 450
+layout(input_attachment_index = 0, set = 0, binding = 0) uniform subpassInput u_diffuse;
+
+layout(push_constant) uniform PushConstants {
+    vec4 color;
+} push_constants;
+
+layout(location = 0) in vec2 v_ndc;
+layout(location = 0) out vec4 f_color;
+
+void main() {
+    vec3 diffuse = subpassLoad(u_diffuse).rgb;
+    f_color = vec4(diffuse * push_constants.color.rgb, 1.0);
+}
+"]
+    struct Dummy;
+}
+
+mod directional_fs {
+    #[derive(VulkanoShader)]
+    #[allow(dead_code)]
+    #[ty = "fragment"]
+    #[src = "
+#version 450
+layout(input_attachment_index = 0, set = 0, binding = 0) uniform subpassInput u_diffuse;
+layout(input_attachment_index = 1, set = 0, binding = 1) uniform subpassInput u_specular;
+layout(input_attachment_index = 2, set = 0, binding = 2) uniform subpassInput u_normals;
+layout(input_attachment_index = 3, set = 0, binding = 3) uniform subpassInput u_depth;
+
+layout(set = 1, binding = 0) uniform CameraData {
+    mat4 view;
+    mat4 projection;
+    mat4 view_projection;
+    mat4 inverse_view;
+    mat4 inverse_projection;
+    mat4 inverse_view_projection;
+    vec4 position;
+} camera;
+
+layout(push_constant) uniform PushConstants {
+    vec4 color;
+    vec4 direction;
+} push_constants;
+
+layout(location = 0) in vec2 v_ndc;
+layout(location = 0) out vec4 f_color;
+
+const float SHININESS = 32.0;
+
+void main() {
+    vec4 world = camera.inverse_view_projection * vec4(v_ndc, subpassLoad(u_depth).x, 1.0);
+    vec3 position = world.xyz / world.w;
+    // Nothing was drawn where the normal is still the zero it was cleared to
+    vec3 packed_normal = subpassLoad(u_normals).xyz;
+    if (dot(packed_normal, packed_normal) < 1e-8) {
+        discard;
+    }
+    vec3 normal = normalize(packed_normal);
+    vec3 diffuse = subpassLoad(u_diffuse).rgb;
+    float specular = subpassLoad(u_specular).x;
+
+    vec3 to_light = -normalize(push_constants.direction.xyz);
+    vec3 to_eye = normalize(camera.position.xyz - position);
+    vec3 halfway = normalize(to_light + to_eye);
+    float lambert = max(dot(normal, to_light), 0.0);
+    float highlight = lambert > 0.0 ? pow(max(dot(normal, halfway), 0.0), SHININESS) : 0.0;
+
+    vec3 light = push_constants.color.rgb;
+    f_color = vec4((diffuse * lambert + specular * highlight) * light, 1.0);
+}
+"]
+    struct Dummy;
+}
+
+mod point_fs {
+    #[derive(VulkanoShader)]
+    #[allow(dead_code)]
+    #[ty = "fragment"]
+    #[src = "
+#version 450
+layout(input_attachment_index = 0, set = 0, binding = 0) uniform subpassInput u_diffuse;
+layout(input_attachment_index = 1, set = 0, binding = 1) uniform subpassInput u_specular;
+layout(input_attachment_index = 2, set = 0, binding = 2) uniform subpassInput u_normals;
+layout(input_attachment_index = 3, set = 0, binding = 3) uniform subpassInput u_depth;
+
+layout(set = 1, binding = 0) uniform CameraData {
+    mat4 view;
+    mat4 projection;
+    mat4 view_projection;
+    mat4 inverse_view;
+    mat4 inverse_projection;
+    mat4 inverse_view_projection;
+    vec4 position;
+} camera;
+
+layout(push_constant) uniform PushConstants {
+    vec4 color;
+    vec4 position_radius;
+} push_constants;
+
+layout(location = 0) in vec2 v_ndc;
+layout(location = 0) out vec4 f_color;
+
+const float SHININESS = 32.0;
+
+void main() {
+    vec4 world = camera.inverse_view_projection * vec4(v_ndc, subpassLoad(u_depth).x, 1.0);
+    vec3 position = world.xyz / world.w;
+    // Nothing was drawn where the normal is still the zero it was cleared to
+    vec3 packed_normal = subpassLoad(u_normals).xyz;
+    if (dot(packed_normal, packed_normal) < 1e-8) {
+        discard;
+    }
+    vec3 normal = normalize(packed_normal);
+    vec3 diffuse = subpassLoad(u_diffuse).rgb;
+    float specular = subpassLoad(u_specular).x;
+
+    vec3 offset = push_constants.position_radius.xyz - position;
+    float distance = length(offset);
+    float radius = push_constants.position_radius.w;
+    // Inverse square falloff windowed to reach exactly zero at the radius
+    float window = clamp(1.0 - pow(distance / radius, 4.0), 0.0, 1.0);
+    float attenuation = window * window / (distance * distance + 1.0);
+    if (attenuation <= 0.0) {
+        discard;
+    }
+
+    vec3 to_light = offset / distance;
+    vec3 to_eye = normalize(camera.position.xyz - position);
+    vec3 halfway = normalize(to_light + to_eye);
+    float lambert = max(dot(normal, to_light), 0.0);
+    float highlight = lambert > 0.0 ? pow(max(dot(normal, halfway), 0.0), SHININESS) : 0.0;
+
+    vec3 light = push_constants.color.rgb * attenuation;
+    f_color = vec4((diffuse * lambert + specular * highlight) * light, 1.0);
+}
+"]
+    struct Dummy;
+}
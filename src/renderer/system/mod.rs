@@ -1,4 +1,20 @@
 
 pub mod render_system;
+pub mod render_graph;
+pub mod error;
 pub mod gbuffer;
-pub mod drawing_system;
\ No newline at end of file
+pub mod drawing_system;
+pub mod camera_uniform;
+pub mod lighting_system;
+pub mod light_volume_system;
+pub mod compute_system;
+pub mod cluster;
+pub mod offscreen;
+pub mod image_diff;
+pub mod presenter;
+pub mod device;
+pub mod recovery;
+pub mod frames;
+pub mod parallel;
+pub mod profiler;
+pub mod trace;
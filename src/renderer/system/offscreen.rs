@@ -0,0 +1,335 @@
+use png;
+use renderer::system::device::{DeviceSelector, InstanceBuilder};
+use renderer::system::error::RendererError;
+use renderer::system::gbuffer::GBuffer;
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+use std::path::Path;
+use std::sync::Arc;
+use vulkano::{buffer::{BufferUsage, CpuAccessibleBuffer},
+              command_buffer::AutoCommandBufferBuilder,
+              device::Queue,
+              format::Format,
+              image::{AttachmentImage, ImageAccess, ImageUsage},
+              sync::GpuFuture};
+
+// Creates a device and graphics queue without any surface extensions, for rendering
+// on machines without a window system such as CI runners using lavapipe. Returns
+// None when there is no Vulkan driver or no device with a graphics queue
+pub fn headless_queue() -> Option<Arc<Queue>> {
+    let instance = InstanceBuilder::new().build().ok()?;
+    let selected = DeviceSelector::new().select(&instance.instance).ok()?;
+    Some(selected.queues.graphics)
+}
+
+// A color image to pass to `RenderSystem::frame` in place of a swapchain image
+pub struct OffscreenTarget {
+    queue: Arc<Queue>,
+    image: Arc<AttachmentImage>,
+    format: Format,
+}
+
+impl OffscreenTarget {
+    pub fn new(queue: Arc<Queue>, dimensions: [u32; 2], format: Format) -> Result<Self, RendererError> {
+        if dimensions[0] == 0 || dimensions[1] == 0 {
+            return Err(RendererError::InvalidDimensions(dimensions));
+        }
+        let usage = ImageUsage {
+            color_attachment: true,
+            transfer_source: true,
+            ..ImageUsage::none()
+        };
+        let image = AttachmentImage::with_usage(queue.device().clone(), dimensions, format, usage)
+            .map_err(|err| RendererError::from_image_creation(err, format))?;
+        Ok(Self {
+            queue,
+            image,
+            format,
+        })
+    }
+    pub fn image(&self) -> Arc<AttachmentImage> {
+        self.image.clone()
+    }
+    pub fn format(&self) -> Format {
+        self.format
+    }
+    pub fn dims(&self) -> [u32; 2] {
+        ImageAccess::dimensions(&self.image).width_height()
+    }
+    // Left unchanged if the new image can not be created
+    pub fn resize(&mut self, dimensions: [u32; 2]) -> Result<(), RendererError> {
+        *self = Self::new(self.queue.clone(), dimensions, self.format)?;
+        Ok(())
+    }
+    // Waits for `before_future`, usually the future `Frame` finishes with, then
+    // copies the final color into host memory
+    pub fn read<F: GpuFuture + 'static>(&self, before_future: F) -> Result<CpuImage, RendererError> {
+        read_image(&self.queue, before_future, self.image.clone(), self.format)
+    }
+    // Reads back a gbuffer attachment, which must have been marked stored in the
+    // render graph with `RenderGraph::set_stored`
+    pub fn read_attachment<F: GpuFuture + 'static>(
+        &self,
+        before_future: F,
+        gbuffer: &GBuffer,
+        name: &str,
+    ) -> Result<CpuImage, RendererError> {
+        let missing = || RendererError::AttachmentMismatch(name.to_string());
+        let image = gbuffer.attachment(name).ok_or_else(missing)?;
+        let format = gbuffer.builder().get(name).ok_or_else(missing)?.format;
+        read_image(&self.queue, before_future, image, format)
+    }
+}
+
+// Bytes per pixel of the formats `CpuImage` can decode
+pub fn format_size(format: Format) -> Option<usize> {
+    match format {
+        Format::R8G8B8A8Unorm | Format::R8G8B8A8Srgb | Format::B8G8R8A8Unorm | Format::B8G8R8A8Srgb => Some(4),
+        Format::A2B10G10R10UnormPack32 | Format::R32Sfloat | Format::D32Sfloat => Some(4),
+        Format::R16G16B16A16Sfloat => Some(8),
+        Format::R32G32B32A32Sfloat => Some(16),
+        Format::R16Unorm | Format::D16Unorm => Some(2),
+        _ => None,
+    }
+}
+
+fn read_image<F, I>(queue: &Arc<Queue>, before_future: F, image: Arc<I>, format: Format) -> Result<CpuImage, RendererError>
+where
+    F: GpuFuture + 'static,
+    I: ImageAccess + Send + Sync + 'static,
+{
+    let dims = ImageAccess::dimensions(&image).width_height();
+    let size = format_size(format).ok_or(RendererError::UnsupportedFormat(format))?;
+    let buffer = CpuAccessibleBuffer::from_iter(
+        queue.device().clone(),
+        BufferUsage::all(),
+        (0..dims[0] as usize * dims[1] as usize * size).map(|_| 0u8),
+    )?;
+    let command_buffer = AutoCommandBufferBuilder::primary_one_time_submit(queue.device().clone(), queue.family())?
+        .copy_image_to_buffer(image, buffer.clone())?
+        .build()?;
+    before_future
+        .then_execute(queue.clone(), command_buffer)?
+        .then_signal_fence_and_flush()?
+        .wait(None)?;
+    let bytes = buffer.read()?;
+    CpuImage::from_bytes(format, dims, &bytes).ok_or(RendererError::UnsupportedFormat(format))
+}
+
+fn half_to_f32(half: u16) -> f32 {
+    let sign = if half & 0x8000 != 0 { -1.0 } else { 1.0 };
+    let exponent = ((half >> 10) & 0x1f) as i32;
+    let mantissa = (half & 0x3ff) as f32;
+    match exponent {
+        0 => sign * mantissa * 2f32.powi(-24),
+        31 if mantissa == 0.0 => sign * ::std::f32::INFINITY,
+        31 => ::std::f32::NAN,
+        _ => sign * (1.0 + mantissa / 1024.0) * 2f32.powi(exponent - 15),
+    }
+}
+
+// Decoded RGBA pixels in rows from the top. Single channel formats are put in
+// red, srgb formats are left encoded
+#[derive(Debug, Clone, PartialEq)]
+pub struct CpuImage {
+    pub width: u32,
+    pub height: u32,
+    pub pixels: Vec<[f32; 4]>,
+}
+
+impl CpuImage {
+    pub fn new(width: u32, height: u32) -> Self {
+        Self {
+            width,
+            height,
+            pixels: vec![[0.0; 4]; width as usize * height as usize],
+        }
+    }
+    pub fn from_bytes(format: Format, dims: [u32; 2], bytes: &[u8]) -> Option<Self> {
+        let size = format_size(format)?;
+        if bytes.len() < dims[0] as usize * dims[1] as usize * size {
+            return None;
+        }
+        let u16_at = |b: &[u8], i: usize| b[i] as u16 | (b[i + 1] as u16) << 8;
+        let u32_at = |b: &[u8], i: usize| u16_at(b, i) as u32 | (u16_at(b, i + 2) as u32) << 16;
+        let f32_at = |b: &[u8], i: usize| f32::from_bits(u32_at(b, i));
+        let pixels = bytes
+            .chunks(size)
+            .take(dims[0] as usize * dims[1] as usize)
+            .map(|p| match format {
+                Format::R8G8B8A8Unorm | Format::R8G8B8A8Srgb => [
+                    p[0] as f32 / 255.0,
+                    p[1] as f32 / 255.0,
+                    p[2] as f32 / 255.0,
+                    p[3] as f32 / 255.0,
+                ],
+                Format::B8G8R8A8Unorm | Format::B8G8R8A8Srgb => [
+                    p[2] as f32 / 255.0,
+                    p[1] as f32 / 255.0,
+                    p[0] as f32 / 255.0,
+                    p[3] as f32 / 255.0,
+                ],
+                Format::A2B10G10R10UnormPack32 => {
+                    let v = u32_at(p, 0);
+                    [
+                        (v & 0x3ff) as f32 / 1023.0,
+                        (v >> 10 & 0x3ff) as f32 / 1023.0,
+                        (v >> 20 & 0x3ff) as f32 / 1023.0,
+                        (v >> 30) as f32 / 3.0,
+                    ]
+                }
+                Format::R16G16B16A16Sfloat => [
+                    half_to_f32(u16_at(p, 0)),
+                    half_to_f32(u16_at(p, 2)),
+                    half_to_f32(u16_at(p, 4)),
+                    half_to_f32(u16_at(p, 6)),
+                ],
+                Format::R32G32B32A32Sfloat => [f32_at(p, 0), f32_at(p, 4), f32_at(p, 8), f32_at(p, 12)],
+                Format::R32Sfloat | Format::D32Sfloat => [f32_at(p, 0), 0.0, 0.0, 1.0],
+                _ => [u16_at(p, 0) as f32 / 65535.0, 0.0, 0.0, 1.0],
+            })
+            .collect();
+        Some(Self {
+            width: dims[0],
+            height: dims[1],
+            pixels,
+        })
+    }
+    pub fn pixel(&self, x: u32, y: u32) -> [f32; 4] {
+        self.pixels[(y * self.width + x) as usize]
+    }
+    pub fn set_pixel(&mut self, x: u32, y: u32, value: [f32; 4]) {
+        self.pixels[(y * self.width + x) as usize] = value;
+    }
+    // Clamped to [0, 1] without any transfer function
+    pub fn to_rgba8(&self) -> Vec<u8> {
+        let mut bytes = Vec::with_capacity(self.pixels.len() * 4);
+        for pixel in &self.pixels {
+            for &c in pixel.iter() {
+                bytes.push((c.max(0.0).min(1.0) * 255.0).round() as u8);
+            }
+        }
+        bytes
+    }
+    pub fn write_png<P: AsRef<Path>>(&self, path: P) -> io::Result<()> {
+        let file = BufWriter::new(File::create(path)?);
+        let mut encoder = png::Encoder::new(file, self.width, self.height);
+        encoder.set(png::ColorType::RGBA).set(png::BitDepth::Eight);
+        let mut writer = encoder
+            .write_header()
+            .map_err(|err| io::Error::new(io::ErrorKind::Other, err))?;
+        writer
+            .write_image_data(&self.to_rgba8())
+            .map_err(|err| io::Error::new(io::ErrorKind::Other, err))
+    }
+    pub fn read_png<P: AsRef<Path>>(path: P) -> io::Result<Self> {
+        let decoder = png::Decoder::new(File::open(path)?);
+        let (info, mut reader) = decoder
+            .read_info()
+            .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))?;
+        let mut bytes = vec![0; info.buffer_size()];
+        reader
+            .next_frame(&mut bytes)
+            .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))?;
+        let channels = match info.color_type {
+            png::ColorType::RGBA => 4,
+            png::ColorType::RGB => 3,
+            _ => return Err(io::Error::new(io::ErrorKind::InvalidData, "only 8 bit RGB and RGBA pngs are supported")),
+        };
+        if info.bit_depth != png::BitDepth::Eight {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "only 8 bit RGB and RGBA pngs are supported"));
+        }
+        let pixels = bytes
+            .chunks(channels)
+            .map(|p| {
+                let alpha = if channels == 4 { p[3] } else { 255 };
+                [
+                    p[0] as f32 / 255.0,
+                    p[1] as f32 / 255.0,
+                    p[2] as f32 / 255.0,
+                    alpha as f32 / 255.0,
+                ]
+            })
+            .collect();
+        Ok(Self {
+            width: info.width,
+            height: info.height,
+            pixels,
+        })
+    }
+    // An uncompressed scanline OpenEXR file with 32 bit float RGBA channels, keeping
+    // values outside of [0, 1]
+    pub fn write_exr<P: AsRef<Path>>(&self, path: P) -> io::Result<()> {
+        // The data window can not describe an empty image
+        if self.width == 0 || self.height == 0 {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "can not write an empty image"));
+        }
+        let mut file = BufWriter::new(File::create(path)?);
+        let mut header = Vec::new();
+        header.extend_from_slice(&[0x76, 0x2f, 0x31, 0x01]);
+        header.extend_from_slice(&u32_bytes(2));
+
+        // Channels must be sorted by name, this is also the order they are stored in
+        let channels = ["A", "B", "G", "R"];
+        let mut chlist = Vec::new();
+        for name in channels.iter() {
+            chlist.extend_from_slice(name.as_bytes());
+            chlist.push(0);
+            // FLOAT pixel type, pLinear and reserved bytes, x and y sampling
+            chlist.extend_from_slice(&u32_bytes(2));
+            chlist.extend_from_slice(&[0, 0, 0, 0]);
+            chlist.extend_from_slice(&u32_bytes(1));
+            chlist.extend_from_slice(&u32_bytes(1));
+        }
+        chlist.push(0);
+        let mut window = Vec::new();
+        for &v in [0, 0, self.width - 1, self.height - 1].iter() {
+            window.extend_from_slice(&u32_bytes(v));
+        }
+        exr_attribute(&mut header, "channels", "chlist", &chlist);
+        exr_attribute(&mut header, "compression", "compression", &[0]);
+        exr_attribute(&mut header, "dataWindow", "box2i", &window);
+        exr_attribute(&mut header, "displayWindow", "box2i", &window);
+        exr_attribute(&mut header, "lineOrder", "lineOrder", &[0]);
+        exr_attribute(&mut header, "pixelAspectRatio", "float", &u32_bytes(1f32.to_bits()));
+        exr_attribute(&mut header, "screenWindowCenter", "v2f", &[0; 8]);
+        exr_attribute(&mut header, "screenWindowWidth", "float", &u32_bytes(1f32.to_bits()));
+        header.push(0);
+
+        // One scanline per chunk, each is its y coordinate, its size then the channels
+        let line_size = self.width as u64 * 4 * channels.len() as u64;
+        let chunks_start = header.len() as u64 + self.height as u64 * 8;
+        file.write_all(&header)?;
+        for y in 0..self.height as u64 {
+            file.write_all(&u64_bytes(chunks_start + y * (line_size + 8)))?;
+        }
+        for y in 0..self.height {
+            file.write_all(&u32_bytes(y))?;
+            file.write_all(&u32_bytes(line_size as u32))?;
+            for &channel in [3, 2, 1, 0].iter() {
+                for x in 0..self.width {
+                    file.write_all(&u32_bytes(self.pixel(x, y)[channel].to_bits()))?;
+                }
+            }
+        }
+        file.flush()
+    }
+}
+
+fn u32_bytes(v: u32) -> [u8; 4] {
+    [v as u8, (v >> 8) as u8, (v >> 16) as u8, (v >> 24) as u8]
+}
+
+fn u64_bytes(v: u64) -> [u8; 8] {
+    let (low, high) = (u32_bytes(v as u32), u32_bytes((v >> 32) as u32));
+    [low[0], low[1], low[2], low[3], high[0], high[1], high[2], high[3]]
+}
+
+fn exr_attribute(header: &mut Vec<u8>, name: &str, ty: &str, value: &[u8]) {
+    header.extend_from_slice(name.as_bytes());
+    header.push(0);
+    header.extend_from_slice(ty.as_bytes());
+    header.push(0);
+    header.extend_from_slice(&u32_bytes(value.len() as u32));
+    header.extend_from_slice(value);
+}
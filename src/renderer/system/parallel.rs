@@ -0,0 +1,70 @@
+use rayon::{self, prelude::*};
+use renderer::system::error::RendererError;
+use renderer::system::render_system::Pass;
+use vulkano::command_buffer::{AutoCommandBuffer, AutoCommandBufferBuilder, DynamicState};
+
+// Records a draw list into several secondary command buffers at once on rayon's
+// thread pool, then executes them on the pass in the order of the list. The
+// result is the same as recording everything into one buffer, whatever the
+// number of jobs
+#[derive(Debug, Clone, Copy)]
+pub struct ParallelRecorder {
+    jobs: usize,
+}
+
+impl Default for ParallelRecorder {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl ParallelRecorder {
+    // One job per thread of the pool
+    pub fn new() -> Self {
+        Self::with_jobs(rayon::current_num_threads())
+    }
+    pub fn with_jobs(jobs: usize) -> Self {
+        Self { jobs: jobs.max(1) }
+    }
+    pub fn jobs(&self) -> usize {
+        self.jobs
+    }
+
+    // Splits `items` into at most `jobs` contiguous chunks and calls `record` for
+    // each on a worker thread, with a secondary builder for the pass's subpass and
+    // the pass's dynamic state. Returns the number of command buffers executed,
+    // which is zero for an empty list
+    pub fn record<T, F>(&self, pass: &mut Pass, items: &[T], record: F) -> Result<usize, RendererError>
+    where
+        T: Sync,
+        F: Fn(AutoCommandBufferBuilder, &DynamicState, &[T]) -> Result<AutoCommandBufferBuilder, RendererError>
+            + Sync,
+    {
+        if items.is_empty() {
+            return Ok(0);
+        }
+        let chunk_size = (items.len() + self.jobs - 1) / self.jobs;
+        let queue = pass.queue().clone();
+        let subpass = pass.subpass();
+        let dynamic_state = pass.dynamic_state();
+        // Builders are tied to the thread that created them, only the finished
+        // command buffers cross back
+        let command_buffers: Result<Vec<AutoCommandBuffer>, RendererError> = items
+            .par_chunks(chunk_size)
+            .map(|chunk| -> Result<AutoCommandBuffer, RendererError> {
+                let builder = AutoCommandBufferBuilder::secondary_graphics(
+                    queue.device().clone(),
+                    queue.family(),
+                    subpass.clone(),
+                )?;
+                Ok(record(builder, &dynamic_state, chunk)?.build()?)
+            })
+            .collect();
+        let command_buffers = command_buffers?;
+        let count = command_buffers.len();
+        for command_buffer in command_buffers {
+            pass.execute(command_buffer)?;
+        }
+        Ok(count)
+    }
+}
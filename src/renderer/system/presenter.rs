@@ -0,0 +1,247 @@
+use renderer::system::error::RendererError;
+use renderer::system::frames::FramesInFlight;
+use std::sync::Arc;
+use vulkano::{device::Queue,
+              format::Format,
+              image::{ImageUsage, SwapchainImage},
+              swapchain::{self, AcquireError, ColorSpace, PresentMode, Surface, SurfaceTransform, Swapchain,
+                          SwapchainCreationError},
+              sync::{now, FlushError, GpuFuture}};
+use winit::{Event, Window, WindowEvent};
+
+// Surface formats tried in order before falling back to whatever comes first
+const PREFERRED_FORMATS: [Format; 4] = [
+    Format::B8G8R8A8Srgb,
+    Format::R8G8B8A8Srgb,
+    Format::B8G8R8A8Unorm,
+    Format::R8G8B8A8Unorm,
+];
+
+// An image acquired from the swapchain, hand `image` and `future` to
+// `RenderSystem::frame` and the finished future back to `Presenter::present`
+pub struct AcquiredImage {
+    pub index: usize,
+    pub image: Arc<SwapchainImage<Window>>,
+    pub future: Box<GpuFuture>,
+}
+
+// Owns the swapchain of a window surface, recreating it when the window is resized
+// or the swapchain goes out of date. The format never changes after creation so a
+// render graph built with `format()` stays valid
+pub struct Presenter {
+    queue: Arc<Queue>,
+    surface: Arc<Surface<Window>>,
+    swapchain: Arc<Swapchain<Window>>,
+    images: Vec<Arc<SwapchainImage<Window>>>,
+    present_mode: PresentMode,
+    needs_recreate: bool,
+    previous_frame: Option<Box<GpuFuture>>,
+}
+
+impl Presenter {
+    // Falls back to FIFO, which is always supported, if `present_mode` is not
+    pub fn new(
+        queue: Arc<Queue>,
+        surface: Arc<Surface<Window>>,
+        present_mode: PresentMode,
+    ) -> Result<Self, RendererError> {
+        let (swapchain, images, present_mode) = create_swapchain(&queue, &surface, present_mode)?;
+        Ok(Self {
+            previous_frame: Some(Box::new(now(queue.device().clone()))),
+            queue,
+            surface,
+            swapchain,
+            images,
+            present_mode,
+            needs_recreate: false,
+        })
+    }
+    // Moves onto a new window surface after `RendererError::SurfaceLost`. The format
+    // may differ from before, in which case the render graph has to be rebuilt.
+    // After a lost device create a new presenter instead, see `DeviceRecovery`
+    pub fn replace_surface(&mut self, surface: Arc<Surface<Window>>) -> Result<(), RendererError> {
+        *self = Self::new(self.queue.clone(), surface, self.present_mode)?;
+        Ok(())
+    }
+    pub fn surface(&self) -> &Arc<Surface<Window>> {
+        &self.surface
+    }
+    // The output format to build the render graph with
+    pub fn format(&self) -> Format {
+        self.swapchain.format()
+    }
+    pub fn dimensions(&self) -> [u32; 2] {
+        self.swapchain.dimensions()
+    }
+    pub fn present_mode(&self) -> PresentMode {
+        self.present_mode
+    }
+    pub fn images(&self) -> &[Arc<SwapchainImage<Window>>] {
+        &self.images
+    }
+    // The swapchain is recreated before the next image is acquired
+    pub fn resize(&mut self) {
+        self.needs_recreate = true;
+    }
+    // Marks the swapchain for recreation on window resizes
+    pub fn handle_event(&mut self, event: &Event) {
+        if let Event::WindowEvent {
+            event: WindowEvent::Resized(..),
+            ..
+        } = *event
+        {
+            self.resize();
+        }
+    }
+    // Returns None when the swapchain could not provide an image this frame, in
+    // which case it will be recreated and the frame should be skipped
+    pub fn acquire(&mut self) -> Result<Option<AcquiredImage>, RendererError> {
+        if let Some(previous_frame) = self.previous_frame.as_mut() {
+            previous_frame.cleanup_finished();
+        }
+        if self.needs_recreate && !self.recreate()? {
+            return Ok(None);
+        }
+        match swapchain::acquire_next_image(self.swapchain.clone(), None) {
+            Ok((index, acquire_future)) => {
+                let previous_frame = self.previous_frame
+                    .take()
+                    .unwrap_or_else(|| Box::new(now(self.queue.device().clone())));
+                Ok(Some(AcquiredImage {
+                    index,
+                    image: self.images[index].clone(),
+                    future: Box::new(previous_frame.join(acquire_future)),
+                }))
+            }
+            Err(AcquireError::OutOfDate) => {
+                self.needs_recreate = true;
+                Ok(None)
+            }
+            Err(err) => Err(err.into()),
+        }
+    }
+    // Presents image `index` once `render_future` completes. The presenter stays
+    // usable after an error other than a lost device
+    pub fn present<F: GpuFuture + 'static>(&mut self, index: usize, render_future: F) -> Result<(), RendererError> {
+        let future = render_future
+            .then_swapchain_present(self.queue.clone(), self.swapchain.clone(), index)
+            .then_signal_fence_and_flush();
+        match future {
+            Ok(future) => {
+                self.previous_frame = Some(Box::new(future));
+                Ok(())
+            }
+            Err(FlushError::OutOfDate) => {
+                self.needs_recreate = true;
+                self.previous_frame = Some(Box::new(now(self.queue.device().clone())));
+                Ok(())
+            }
+            Err(err) => {
+                self.previous_frame = Some(Box::new(now(self.queue.device().clone())));
+                Err(err.into())
+            }
+        }
+    }
+    // Like `present` but the frame's fence belongs to `frames`, which then limits
+    // how far the CPU gets ahead instead of the presenter. The next acquire still
+    // starts after this frame on the GPU
+    pub fn present_in_flight<F>(
+        &mut self,
+        index: usize,
+        render_future: F,
+        frames: &mut FramesInFlight,
+    ) -> Result<(), RendererError>
+    where
+        F: GpuFuture + 'static,
+    {
+        let future = render_future.then_swapchain_present(self.queue.clone(), self.swapchain.clone(), index);
+        let result = frames.end(future);
+        self.previous_frame = Some(frames.previous_frame(self.queue.device().clone()));
+        match result {
+            Ok(()) => Ok(()),
+            Err(FlushError::OutOfDate) => {
+                self.needs_recreate = true;
+                Ok(())
+            }
+            Err(err) => Err(err.into()),
+        }
+    }
+    // Returns false if the window currently has no valid size, such as while minimised
+    fn recreate(&mut self) -> Result<bool, RendererError> {
+        match self.swapchain.recreate_with_dimension(window_dimensions(&self.surface)) {
+            Ok((swapchain, images)) => {
+                self.swapchain = swapchain;
+                self.images = images;
+                self.needs_recreate = false;
+                Ok(true)
+            }
+            Err(SwapchainCreationError::UnsupportedDimensions) => Ok(false),
+            Err(err) => Err(err.into()),
+        }
+    }
+}
+
+fn create_swapchain(
+    queue: &Arc<Queue>,
+    surface: &Arc<Surface<Window>>,
+    present_mode: PresentMode,
+) -> Result<(Arc<Swapchain<Window>>, Vec<Arc<SwapchainImage<Window>>>, PresentMode), RendererError> {
+    let capabilities = surface
+        .capabilities(queue.device().physical_device())
+        .map_err(|_| SwapchainCreationError::SurfaceLost)?;
+    let present_mode = if capabilities.present_modes.supports(present_mode) {
+        present_mode
+    } else {
+        PresentMode::Fifo
+    };
+    let format = choose_format(&capabilities.supported_formats).ok_or(SwapchainCreationError::UnsupportedFormat)?;
+    let dimensions = capabilities
+        .current_extent
+        .unwrap_or_else(|| window_dimensions(surface));
+    // One more than the minimum so acquiring does not wait on the driver
+    let image_count = match capabilities.max_image_count {
+        Some(max) => (capabilities.min_image_count + 1).min(max),
+        None => capabilities.min_image_count + 1,
+    };
+    let alpha = capabilities
+        .supported_composite_alpha
+        .iter()
+        .next()
+        .ok_or(SwapchainCreationError::UnsupportedCompositeAlpha)?;
+    let (swapchain, images) = Swapchain::new(
+        queue.device().clone(),
+        surface.clone(),
+        image_count,
+        format,
+        dimensions,
+        1,
+        ImageUsage::color_attachment(),
+        queue,
+        SurfaceTransform::Identity,
+        alpha,
+        present_mode,
+        true,
+        None,
+    )?;
+    Ok((swapchain, images, present_mode))
+}
+
+pub fn choose_format(supported: &[(Format, ColorSpace)]) -> Option<Format> {
+    PREFERRED_FORMATS
+        .iter()
+        .cloned()
+        .find(|format| {
+            supported
+                .iter()
+                .any(|&(f, space)| f == *format && space == ColorSpace::SrgbNonLinear)
+        })
+        .or_else(|| supported.first().map(|&(format, _)| format))
+}
+
+fn window_dimensions(surface: &Surface<Window>) -> [u32; 2] {
+    surface
+        .window()
+        .get_inner_size()
+        .map(|(width, height)| [width, height])
+        .unwrap_or([0, 0])
+}
@@ -0,0 +1,624 @@
+use renderer::system::error::RendererError;
+use std::collections::HashMap;
+use std::fmt;
+use std::ptr;
+use std::sync::{Arc, Mutex};
+use std::thread::{self, ThreadId};
+use std::time::{Duration, Instant};
+use vulkano::{buffer::{BufferAccess, BufferUsage, CpuAccessibleBuffer},
+              command_buffer::{pool::{standard::{StandardCommandPoolAlloc, StandardCommandPoolBuilder}, CommandPool},
+                               sys::{Flags, Kind, KindOcclusionQuery, KindSecondaryRenderPass, UnsafeCommandBuffer,
+                                     UnsafeCommandBufferBuilder},
+                               CommandBuffer, CommandBufferExecError},
+              device::{Device, DeviceOwned, Queue},
+              framebuffer::{FramebufferAbstract, RenderPassAbstract, Subpass},
+              image::{ImageAccess, ImageLayout},
+              query::{QueryPipelineStatisticFlags, QueryType, UnsafeQueryPool},
+              sync::{AccessCheckError, AccessFlagBits, GpuFuture, PipelineStages},
+              VulkanObject};
+use vk;
+
+// Times the stages of every frame on the GPU, along with any scopes opened inside
+// them with `Pass::scope`. Set it on a `RenderSystem` and each frame writes a
+// timestamp query at the start and end of every scope. Queries are only read back
+// `latency` frames later, when the GPU is long done with them, so reports lag the
+// frame being recorded
+pub struct GpuProfiler {
+    queue: Arc<Queue>,
+    // Nanoseconds per timestamp tick
+    period: f64,
+    // The bits of a timestamp the queue family writes, the rest are undefined
+    mask: u64,
+    max_scopes: u32,
+    frames: Vec<QueryFrame>,
+    slot: usize,
+    frame: u64,
+    recording: bool,
+    // Indices into the scopes of the frame being recorded, `None` for scopes that
+    // did not fit in the query pool
+    open: Vec<Option<usize>>,
+    report: Option<TimingReport>,
+}
+
+struct QueryFrame {
+    pool: Arc<UnsafeQueryPool>,
+    results: Arc<CpuAccessibleBuffer<[u64]>>,
+    scopes: Vec<ScopeQueries>,
+    frame: u64,
+    recorded: Instant,
+    // Submitted and not yet read back
+    pending: bool,
+    overflowed: bool,
+}
+
+// The end query directly follows the start query
+struct ScopeQueries {
+    name: String,
+    depth: usize,
+    start: u32,
+    closed: bool,
+}
+
+impl GpuProfiler {
+    // `latency` should be at least one more than the number of frames in flight.
+    // Every frame can time up to `max_scopes` stages and scopes, later ones are
+    // left out of its report
+    pub fn new(queue: Arc<Queue>, latency: usize, max_scopes: u32) -> Result<Self, RendererError> {
+        assert!(latency > 0, "Need at least one frame of latency");
+        let period = {
+            let limits = queue.device().physical_device().limits();
+            if limits.timestamp_compute_and_graphics() == 0 {
+                return Err(RendererError::TimestampsUnsupported);
+            }
+            limits.timestamp_period() as f64
+        };
+        let mask = match timestamp_valid_bits(&queue) {
+            0 => return Err(RendererError::TimestampsUnsupported),
+            bits if bits >= 64 => !0,
+            bits => (1 << bits) - 1,
+        };
+        let mut frames = Vec::with_capacity(latency);
+        for _ in 0..latency {
+            let pool = UnsafeQueryPool::new(queue.device().clone(), QueryType::Timestamp, max_scopes * 2)?;
+            let results = CpuAccessibleBuffer::from_iter(
+                queue.device().clone(),
+                BufferUsage::transfer_destination(),
+                (0..max_scopes * 2).map(|_| 0u64),
+            )?;
+            frames.push(QueryFrame {
+                pool: Arc::new(pool),
+                results,
+                scopes: Vec::new(),
+                frame: 0,
+                recorded: Instant::now(),
+                pending: false,
+                overflowed: false,
+            });
+        }
+        Ok(Self {
+            queue,
+            period,
+            mask,
+            max_scopes,
+            frames,
+            slot: 0,
+            frame: 0,
+            recording: false,
+            open: Vec::new(),
+            report: None,
+        })
+    }
+    // A profiler with the same settings on another queue, used when the render
+    // system moves to a new device
+    pub fn recreate(&self, queue: Arc<Queue>) -> Result<Self, RendererError> {
+        Self::new(queue, self.latency(), self.max_scopes)
+    }
+    pub fn latency(&self) -> usize {
+        self.frames.len()
+    }
+    pub fn max_scopes(&self) -> u32 {
+        self.max_scopes
+    }
+    // The number of frames submitted so far
+    pub fn frame_number(&self) -> u64 {
+        self.frame
+    }
+    // The report of the most recent frame that has been read back
+    pub fn report(&self) -> Option<&TimingReport> {
+        self.report.as_ref()
+    }
+    pub fn take_report(&mut self) -> Option<TimingReport> {
+        self.report.take()
+    }
+
+    // Reads back every submitted frame the GPU has finished, oldest first. Frames
+    // are otherwise only read when their queries are reused, so call this after
+    // waiting on the last frames to get their reports
+    pub fn resolve(&mut self) -> Option<&TimingReport> {
+        let mut slots: Vec<usize> = (0..self.frames.len()).filter(|&s| self.frames[s].pending).collect();
+        slots.sort_by_key(|&s| self.frames[s].frame);
+        for slot in slots {
+            self.resolve_slot(slot);
+        }
+        self.report.as_ref()
+    }
+
+    // Called by `RenderSystem::frame`, the returned command buffer resets the
+    // queries of the new frame and must run before it. If the GPU still holds the
+    // results of the last frame to use these queries that frame is not reported
+    pub fn begin_frame(&mut self) -> Result<QueryCommandBuffer, RendererError> {
+        let slot = self.slot;
+        self.resolve_slot(slot);
+        self.recording = true;
+        self.open.clear();
+        let frame = &mut self.frames[slot];
+        frame.scopes.clear();
+        frame.frame = self.frame;
+        frame.recorded = Instant::now();
+        frame.pending = false;
+        frame.overflowed = false;
+        QueryCommandBuffer::primary(&self.queue, frame.pool.clone(), None, |builder, pool| unsafe {
+            builder.reset_query_pool(pool.queries_range(0, pool.num_slots()).unwrap());
+        })
+    }
+
+    // Called by `Frame` at the start of a stage or a scope inside a subpass. The
+    // returned command buffer writes the start timestamp and must be executed in
+    // the subpass, `None` when the frame ran out of queries
+    pub fn begin_scope(
+        &mut self,
+        name: &str,
+        subpass: &Subpass<Arc<RenderPassAbstract + Send + Sync>>,
+        framebuffer: &Arc<FramebufferAbstract + Send + Sync>,
+    ) -> Result<Option<QueryCommandBuffer>, RendererError> {
+        assert!(self.recording, "GpuProfiler::begin_scope called outside of a frame");
+        let frame = &mut self.frames[self.slot];
+        let start = frame.scopes.len() as u32 * 2;
+        if start + 2 > frame.pool.num_slots() {
+            frame.overflowed = true;
+            self.open.push(None);
+            return Ok(None);
+        }
+        self.open.push(Some(frame.scopes.len()));
+        frame.scopes.push(ScopeQueries {
+            name: name.to_string(),
+            depth: self.open.len() - 1,
+            start,
+            closed: false,
+        });
+        QueryCommandBuffer::timestamp(&self.queue, frame.pool.clone(), start, subpass, framebuffer).map(Some)
+    }
+
+    // Closes the innermost open scope, the returned command buffer writes its end
+    // timestamp
+    pub fn end_scope(
+        &mut self,
+        subpass: &Subpass<Arc<RenderPassAbstract + Send + Sync>>,
+        framebuffer: &Arc<FramebufferAbstract + Send + Sync>,
+    ) -> Result<Option<QueryCommandBuffer>, RendererError> {
+        let index = match self.open.pop() {
+            Some(Some(index)) => index,
+            Some(None) => return Ok(None),
+            None => panic!("GpuProfiler::end_scope called without an open scope"),
+        };
+        let frame = &mut self.frames[self.slot];
+        frame.scopes[index].closed = true;
+        let end = frame.scopes[index].start + 1;
+        QueryCommandBuffer::timestamp(&self.queue, frame.pool.clone(), end, subpass, framebuffer).map(Some)
+    }
+
+    // Called by `Frame` once the render pass has ended, the returned command buffer
+    // copies the timestamps to a buffer to be read back later and must run after
+    // the frame
+    pub fn end_frame(&mut self) -> Result<QueryCommandBuffer, RendererError> {
+        assert!(self.recording, "GpuProfiler::end_frame called outside of a frame");
+        let slot = self.slot;
+        self.recording = false;
+        self.frame += 1;
+        self.slot = (self.slot + 1) % self.frames.len();
+        let frame = &mut self.frames[slot];
+        frame.pending = true;
+        let used = frame.scopes.len() as u32 * 2;
+        let results = frame.results.clone();
+        QueryCommandBuffer::primary(
+            &self.queue,
+            frame.pool.clone(),
+            Some(results.clone()),
+            |builder, pool| unsafe {
+                if used > 0 {
+                    copy_timestamps(builder, pool, used, &results);
+                }
+            },
+        )
+    }
+
+    // False when the GPU still holds the results
+    fn resolve_slot(&mut self, slot: usize) -> bool {
+        let report = {
+            let frame = &self.frames[slot];
+            if !frame.pending {
+                return true;
+            }
+            let results = match frame.results.read() {
+                Ok(results) => results,
+                Err(_) => return false,
+            };
+            if frame.overflowed {
+                warn!(
+                    "Frame {} opened more than {} profiler scopes, the rest are not timed",
+                    frame.frame, self.max_scopes
+                );
+            }
+            frame.report(&results, self.period, self.mask)
+        };
+        self.frames[slot].pending = false;
+        if self.report.as_ref().map_or(true, |r| r.frame < report.frame) {
+            self.report = Some(report);
+        }
+        true
+    }
+}
+
+// The number of valid bits in the timestamps written by the queue's family, 0 if
+// it can not write them. vulkano 0.9 does not expose it
+fn timestamp_valid_bits(queue: &Queue) -> u32 {
+    let physical = queue.device().physical_device();
+    let vk = physical.instance().pointers();
+    let mut count = 0;
+    let mut families: Vec<vk::QueueFamilyProperties> = Vec::new();
+    unsafe {
+        vk.GetPhysicalDeviceQueueFamilyProperties(physical.internal_object(), &mut count, ptr::null_mut());
+        families.reserve(count as usize);
+        vk.GetPhysicalDeviceQueueFamilyProperties(physical.internal_object(), &mut count, families.as_mut_ptr());
+        families.set_len(count as usize);
+    }
+    families
+        .get(queue.family().id() as usize)
+        .map_or(0, |family| family.timestampValidBits)
+}
+
+// `UnsafeCommandBufferBuilder::copy_query_pool_results` copies 32 bit results
+// without waiting for them, which truncates timestamps and can leave stale values
+// in the buffer, so the command is recorded directly
+unsafe fn copy_timestamps(
+    builder: &UnsafeCommandBufferBuilder<StandardCommandPoolBuilder>,
+    pool: &UnsafeQueryPool,
+    count: u32,
+    results: &CpuAccessibleBuffer<[u64]>,
+) {
+    let destination = results.inner();
+    let vk = pool.device().pointers();
+    vk.CmdCopyQueryPoolResults(
+        builder.internal_object(),
+        pool.internal_object(),
+        0,
+        count,
+        destination.buffer.internal_object(),
+        destination.offset as vk::DeviceSize,
+        8,
+        vk::QUERY_RESULT_64_BIT | vk::QUERY_RESULT_WAIT_BIT,
+    );
+}
+
+impl QueryFrame {
+    // Timestamps only have the bits in `mask` and wrap at its width, differences
+    // between them do not as long as a frame takes less than a full wrap
+    fn report(&self, results: &[u64], period: f64, mask: u64) -> TimingReport {
+        let first = self.scopes.first().map_or(0, |s| results[s.start as usize] & mask);
+        let scopes = self.scopes
+            .iter()
+            .filter(|s| s.closed)
+            .map(|s| {
+                let start = results[s.start as usize] & mask;
+                let end = results[s.start as usize + 1] & mask;
+                TimedScope {
+                    name: s.name.clone(),
+                    depth: s.depth,
+                    start: (start.wrapping_sub(first) & mask) as f64 * period,
+                    duration: (end.wrapping_sub(start) & mask) as f64 * period,
+                }
+            })
+            .collect();
+        TimingReport {
+            frame: self.frame,
+            recorded: self.recorded,
+            scopes,
+        }
+    }
+}
+
+// A command buffer recorded with vulkano's unsafe builder, for the query commands
+// `AutoCommandBufferBuilder` lacks. The only resource it reports to the future
+// system is the buffer the results are copied to, so it can not be read on the CPU
+// until the copy has finished
+pub struct QueryCommandBuffer {
+    inner: UnsafeCommandBuffer<StandardCommandPoolAlloc>,
+    // Kept alive until the commands have run
+    pool: Arc<UnsafeQueryPool>,
+    results: Option<Arc<CpuAccessibleBuffer<[u64]>>>,
+}
+
+impl QueryCommandBuffer {
+    fn primary<C>(
+        queue: &Arc<Queue>,
+        pool: Arc<UnsafeQueryPool>,
+        results: Option<Arc<CpuAccessibleBuffer<[u64]>>>,
+        commands: C,
+    ) -> Result<Self, RendererError>
+    where
+        C: FnOnce(&mut UnsafeCommandBufferBuilder<StandardCommandPoolBuilder>, &UnsafeQueryPool),
+    {
+        let inner = Self::record(queue, Kind::primary(), false, |builder| commands(builder, &pool))?;
+        Ok(Self { inner, pool, results })
+    }
+
+    // Writes a timestamp once everything recorded before it in the subpass is done
+    fn timestamp(
+        queue: &Arc<Queue>,
+        pool: Arc<UnsafeQueryPool>,
+        index: u32,
+        subpass: &Subpass<Arc<RenderPassAbstract + Send + Sync>>,
+        framebuffer: &Arc<FramebufferAbstract + Send + Sync>,
+    ) -> Result<Self, RendererError> {
+        let kind = Kind::Secondary {
+            render_pass: Some(KindSecondaryRenderPass {
+                subpass: subpass.clone(),
+                framebuffer: Some(framebuffer.clone()),
+            }),
+            occlusion_query: KindOcclusionQuery::Forbidden,
+            query_statistics_flags: QueryPipelineStatisticFlags::none(),
+        };
+        let inner = Self::record(queue, kind, true, |builder| unsafe {
+            let stages = PipelineStages {
+                bottom_of_pipe: true,
+                ..PipelineStages::none()
+            };
+            builder.write_timestamp(pool.query(index).unwrap(), stages);
+        })?;
+        Ok(Self {
+            inner,
+            pool,
+            results: None,
+        })
+    }
+
+    fn record<R, F, C>(
+        queue: &Arc<Queue>,
+        kind: Kind<R, F>,
+        secondary: bool,
+        commands: C,
+    ) -> Result<UnsafeCommandBuffer<StandardCommandPoolAlloc>, RendererError>
+    where
+        R: RenderPassAbstract,
+        F: FramebufferAbstract,
+        C: FnOnce(&mut UnsafeCommandBufferBuilder<StandardCommandPoolBuilder>),
+    {
+        let pool = Device::standard_command_pool(queue.device(), queue.family());
+        let alloc = pool.alloc(secondary, 1)?.next().unwrap();
+        unsafe {
+            let mut builder = UnsafeCommandBufferBuilder::new(alloc, kind, Flags::OneTimeSubmit)?;
+            commands(&mut builder);
+            Ok(builder.build()?)
+        }
+    }
+}
+
+unsafe impl DeviceOwned for QueryCommandBuffer {
+    fn device(&self) -> &Arc<Device> {
+        self.pool.device()
+    }
+}
+
+unsafe impl CommandBuffer for QueryCommandBuffer {
+    type PoolAlloc = StandardCommandPoolAlloc;
+
+    fn inner(&self) -> &UnsafeCommandBuffer<StandardCommandPoolAlloc> {
+        &self.inner
+    }
+    fn lock_submit(&self, _: &GpuFuture, queue: &Queue) -> Result<(), CommandBufferExecError> {
+        if let Some(ref results) = self.results {
+            results
+                .try_gpu_lock(true, queue)
+                .map_err(|error| CommandBufferExecError::AccessError {
+                    error,
+                    command_name: "vkCmdCopyQueryPoolResults".into(),
+                    command_param: "destination".into(),
+                    command_offset: 0,
+                })?;
+        }
+        Ok(())
+    }
+    unsafe fn unlock(&self) {
+        if let Some(ref results) = self.results {
+            results.unlock();
+        }
+    }
+    fn check_buffer_access(
+        &self,
+        buffer: &BufferAccess,
+        _: bool,
+        _: &Queue,
+    ) -> Result<Option<(PipelineStages, AccessFlagBits)>, AccessCheckError> {
+        match self.results {
+            Some(ref results) if results.conflicts_buffer(buffer) => Ok(Some((
+                PipelineStages {
+                    transfer: true,
+                    ..PipelineStages::none()
+                },
+                AccessFlagBits {
+                    transfer_write: true,
+                    ..AccessFlagBits::none()
+                },
+            ))),
+            _ => Err(AccessCheckError::Unknown),
+        }
+    }
+    fn check_image_access(
+        &self,
+        _: &ImageAccess,
+        _: ImageLayout,
+        _: bool,
+        _: &Queue,
+    ) -> Result<Option<(PipelineStages, AccessFlagBits)>, AccessCheckError> {
+        Err(AccessCheckError::Unknown)
+    }
+}
+
+// A stage of the render graph or a scope inside one. Times are in nanoseconds
+#[derive(Debug, Clone, PartialEq)]
+pub struct TimedScope {
+    pub name: String,
+    // 0 for a stage, 1 for a scope directly inside a stage and so on
+    pub depth: usize,
+    // From the start of the first stage of the frame
+    pub start: f64,
+    pub duration: f64,
+}
+
+impl TimedScope {
+    pub fn end(&self) -> f64 {
+        self.start + self.duration
+    }
+}
+
+// The GPU times of one frame, scopes are in the order they were opened
+#[derive(Debug, Clone)]
+pub struct TimingReport {
+    pub frame: u64,
+    // When the CPU started recording the frame, used to line the GPU times up
+    // with CPU scopes in a trace
+    pub recorded: Instant,
+    pub scopes: Vec<TimedScope>,
+}
+
+impl TimingReport {
+    // The stages of the render graph, without the scopes inside them
+    pub fn stages(&self) -> Vec<&TimedScope> {
+        self.scopes.iter().filter(|s| s.depth == 0).collect()
+    }
+    pub fn stage(&self, name: &str) -> Option<&TimedScope> {
+        self.scopes.iter().find(|s| s.depth == 0 && s.name == name)
+    }
+    // The first scope with `name` at any depth
+    pub fn scope(&self, name: &str) -> Option<&TimedScope> {
+        self.scopes.iter().find(|s| s.name == name)
+    }
+    // From the start of the first stage to the end of the last
+    pub fn total(&self) -> f64 {
+        self.stages().iter().map(|s| s.end()).fold(0.0, f64::max)
+    }
+}
+
+// One line per scope, indented by depth, in milliseconds
+impl fmt::Display for TimingReport {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "frame {}: {:.3} ms", self.frame, self.total() / 1e6)?;
+        for scope in &self.scopes {
+            writeln!(
+                f,
+                "{:indent$}{} {:.3} ms",
+                "",
+                scope.name,
+                scope.duration / 1e6,
+                indent = (scope.depth + 1) * 2
+            )?;
+        }
+        Ok(())
+    }
+}
+
+// Times scopes on the CPU from any number of threads. A scope is recorded when
+// the guard returned by `scope` is dropped
+pub struct CpuProfiler {
+    origin: Instant,
+    state: Mutex<CpuState>,
+}
+
+#[derive(Default)]
+struct CpuState {
+    records: Vec<CpuRecord>,
+    // Small ids in the order threads were first seen, with their current depth
+    threads: HashMap<ThreadId, (usize, usize)>,
+}
+
+// Times are from the profiler's origin
+#[derive(Debug, Clone, PartialEq)]
+pub struct CpuRecord {
+    pub name: String,
+    pub thread: usize,
+    pub depth: usize,
+    pub start: Duration,
+    pub duration: Duration,
+}
+
+impl CpuProfiler {
+    pub fn new() -> Self {
+        Self {
+            origin: Instant::now(),
+            state: Mutex::new(CpuState::default()),
+        }
+    }
+    // When the profiler was created, every record is relative to it
+    pub fn origin(&self) -> Instant {
+        self.origin
+    }
+    pub fn scope(&self, name: &str) -> CpuScope {
+        let thread = thread::current().id();
+        let (thread_index, depth) = {
+            let mut state = self.state.lock().unwrap();
+            let next = state.threads.len();
+            let entry = state.threads.entry(thread).or_insert((next, 0));
+            entry.1 += 1;
+            (entry.0, entry.1 - 1)
+        };
+        CpuScope {
+            profiler: self,
+            name: name.to_string(),
+            thread,
+            thread_index,
+            depth,
+            start: Instant::now(),
+        }
+    }
+    // Finished scopes in the order they ended
+    pub fn records(&self) -> Vec<CpuRecord> {
+        self.state.lock().unwrap().records.clone()
+    }
+    // Takes the finished scopes, leaving scopes that are still open running
+    pub fn drain(&self) -> Vec<CpuRecord> {
+        let mut state = self.state.lock().unwrap();
+        state.records.drain(..).collect()
+    }
+}
+
+impl Default for CpuProfiler {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+pub struct CpuScope<'a> {
+    profiler: &'a CpuProfiler,
+    name: String,
+    thread: ThreadId,
+    thread_index: usize,
+    depth: usize,
+    start: Instant,
+}
+
+impl<'a> Drop for CpuScope<'a> {
+    fn drop(&mut self) {
+        let duration = self.start.elapsed();
+        let mut state = self.profiler.state.lock().unwrap();
+        if let Some(entry) = state.threads.get_mut(&self.thread) {
+            entry.1 -= 1;
+        }
+        state.records.push(CpuRecord {
+            name: ::std::mem::replace(&mut self.name, String::new()),
+            thread: self.thread_index,
+            depth: self.depth,
+            start: self.start.duration_since(self.profiler.origin),
+            duration,
+        });
+    }
+}
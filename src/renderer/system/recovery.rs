@@ -0,0 +1,153 @@
+use renderer::system::device::{DeviceSelector, RendererInstance};
+use renderer::system::error::RendererError;
+use renderer::system::gbuffer::GBufferBuilder;
+use renderer::system::render_graph::RenderGraph;
+use renderer::system::render_system::RenderSystem;
+use std::sync::Arc;
+use vulkano::{device::Queue, swapchain::Surface};
+use winit::Window;
+
+// Where `DeviceRecovery` gets its devices from. Called once on creation and again
+// every time the device is lost
+pub trait DeviceSource {
+    fn create_queue(&mut self) -> Result<Arc<Queue>, RendererError>;
+}
+
+// Selects a device without presentation support from an existing instance
+pub struct HeadlessSource {
+    instance: RendererInstance,
+    selector: DeviceSelector,
+}
+
+impl HeadlessSource {
+    pub fn new(instance: RendererInstance, selector: DeviceSelector) -> Self {
+        Self { instance, selector }
+    }
+}
+
+impl DeviceSource for HeadlessSource {
+    fn create_queue(&mut self) -> Result<Arc<Queue>, RendererError> {
+        Ok(self.selector.select(&self.instance.instance)?.queues.graphics)
+    }
+}
+
+// Selects a device that can present to `surface`. The surface belongs to the
+// instance and outlives any device created from it
+pub struct SurfaceSource {
+    instance: RendererInstance,
+    surface: Arc<Surface<Window>>,
+    selector: DeviceSelector,
+}
+
+impl SurfaceSource {
+    pub fn new(instance: RendererInstance, surface: Arc<Surface<Window>>, selector: DeviceSelector) -> Self {
+        Self {
+            instance,
+            surface,
+            selector,
+        }
+    }
+    pub fn surface(&self) -> &Arc<Surface<Window>> {
+        &self.surface
+    }
+}
+
+impl DeviceSource for SurfaceSource {
+    fn create_queue(&mut self) -> Result<Arc<Queue>, RendererError> {
+        Ok(self.selector
+            .select_for_surface(&self.instance.instance, &self.surface)?
+            .queues
+            .graphics)
+    }
+}
+
+// Owns a `RenderSystem` along with everything built from its device, and rebuilds
+// all of it on a new device when a frame fails with `RendererError::DeviceLost`.
+//
+// `P` holds whatever else needs the device, such as the draw and lighting systems,
+// an offscreen target or a `Presenter`. It is only ever created by the recreate
+// hook, which is run again after every loss once the render system has moved to
+// the new device. Buffers the application made from the old device must not be
+// used after a loss, keep them in `P` or recreate them from `queue()`
+pub struct DeviceRecovery<P> {
+    source: Box<DeviceSource>,
+    render_system: RenderSystem,
+    recreate: Box<FnMut(&RenderSystem) -> Result<P, RendererError>>,
+    // None between dropping the old resources and the hook succeeding
+    resources: Option<P>,
+    recoveries: usize,
+}
+
+impl<P> DeviceRecovery<P> {
+    pub fn new<S, F>(
+        mut source: S,
+        graph: RenderGraph,
+        gbuffer: GBufferBuilder,
+        mut recreate: F,
+    ) -> Result<Self, RendererError>
+    where
+        S: DeviceSource + 'static,
+        F: FnMut(&RenderSystem) -> Result<P, RendererError> + 'static,
+    {
+        let queue = source.create_queue()?;
+        let gbuffer = gbuffer.build_no_dims(queue.clone())?;
+        let render_system = RenderSystem::new(queue, graph, gbuffer)?;
+        let resources = recreate(&render_system)?;
+        Ok(Self {
+            source: Box::new(source),
+            render_system,
+            recreate: Box::new(recreate),
+            resources: Some(resources),
+            recoveries: 0,
+        })
+    }
+    pub fn queue(&self) -> &Arc<Queue> {
+        self.render_system.queue()
+    }
+    pub fn render_system(&self) -> &RenderSystem {
+        &self.render_system
+    }
+    // None if the last recovery failed part way, the next `frame` tries again
+    pub fn resources(&self) -> Option<&P> {
+        self.resources.as_ref()
+    }
+    pub fn resources_mut(&mut self) -> Option<&mut P> {
+        self.resources.as_mut()
+    }
+    // How many times the device has been recreated
+    pub fn recoveries(&self) -> usize {
+        self.recoveries
+    }
+
+    // Runs `record` with the render system and resources. If it fails because the
+    // device was lost everything is recreated and `record` runs once more, a frame
+    // is never lost to a single reset. Other errors are returned as they are
+    pub fn frame<F, R>(&mut self, mut record: F) -> Result<R, RendererError>
+    where
+        F: FnMut(&mut RenderSystem, &mut P) -> Result<R, RendererError>,
+    {
+        if self.resources.is_none() {
+            self.recover()?;
+        }
+        match record(&mut self.render_system, self.resources.as_mut().unwrap()) {
+            Err(ref err) if err.is_device_lost() => {}
+            result => return result,
+        }
+        warn!("Device lost, recreating the renderer");
+        self.recover()?;
+        record(&mut self.render_system, self.resources.as_mut().unwrap())
+    }
+
+    // Recreates the device, render pass, gbuffer and resources. Also call this after
+    // a device loss noticed outside of `frame`, such as while presenting
+    pub fn recover(&mut self) -> Result<(), RendererError> {
+        // Dropped first so the old device can go away, and so a presenter can hand
+        // its surface to a new swapchain
+        self.resources = None;
+        let queue = self.source.create_queue()?;
+        self.render_system.recreate(queue)?;
+        self.resources = Some((self.recreate)(&self.render_system)?);
+        self.recoveries += 1;
+        Ok(())
+    }
+}
@@ -0,0 +1,831 @@
+use renderer::system::gbuffer::GBufferBuilder;
+use std::error::Error;
+use std::fmt;
+use std::sync::Arc;
+use vulkano::{device::Device,
+              format::{ClearValue, Format, FormatTy},
+              framebuffer::{LayoutAttachmentDescription, LayoutPassDependencyDescription,
+                            LayoutPassDescription, LoadOp, RenderPassAbstract,
+                            RenderPassCreationError, RenderPassDesc, RenderPassDescClearValues,
+                            StoreOp},
+              image::{ImageLayout, ImageUsage},
+              sync::{AccessFlagBits, PipelineStages}};
+
+#[derive(Debug)]
+pub enum RenderGraphError {
+    DuplicateName(String),
+    UnknownAttachment { pass: String, attachment: String },
+    // An input attachment that no earlier pass writes to
+    ReadBeforeWrite { pass: String, attachment: String },
+    // An attachment used in a way its format does not allow, or twice within one pass
+    InvalidUsage { pass: String, attachment: String },
+    UnusedAttachment(String),
+    // Graphs must have exactly one output, the image handed to `RenderSystem::frame`
+    OutputCount(usize),
+    NoPasses,
+    RenderPass(RenderPassCreationError),
+}
+
+impl fmt::Display for RenderGraphError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            RenderGraphError::DuplicateName(ref name) => write!(f, "'{}' is declared more than once", name),
+            RenderGraphError::UnknownAttachment { ref pass, ref attachment } => {
+                write!(f, "pass '{}' uses undeclared attachment '{}'", pass, attachment)
+            }
+            RenderGraphError::ReadBeforeWrite { ref pass, ref attachment } => {
+                write!(f, "pass '{}' reads '{}' before any pass writes it", pass, attachment)
+            }
+            RenderGraphError::InvalidUsage { ref pass, ref attachment } => {
+                write!(f, "pass '{}' cannot use '{}' that way", pass, attachment)
+            }
+            RenderGraphError::UnusedAttachment(ref name) => write!(f, "attachment '{}' is never used", name),
+            RenderGraphError::OutputCount(count) => write!(f, "expected one output attachment, found {}", count),
+            RenderGraphError::NoPasses => write!(f, "render graph has no passes"),
+            RenderGraphError::RenderPass(ref err) => write!(f, "failed to create render pass: {}", err),
+        }
+    }
+}
+
+impl Error for RenderGraphError {
+    fn description(&self) -> &str {
+        match *self {
+            RenderGraphError::DuplicateName(_) => "duplicate name in render graph",
+            RenderGraphError::UnknownAttachment { .. } => "unknown attachment in render graph",
+            RenderGraphError::ReadBeforeWrite { .. } => "attachment read before written",
+            RenderGraphError::InvalidUsage { .. } => "invalid attachment usage",
+            RenderGraphError::UnusedAttachment(_) => "unused attachment in render graph",
+            RenderGraphError::OutputCount(_) => "render graph needs one output",
+            RenderGraphError::NoPasses => "render graph has no passes",
+            RenderGraphError::RenderPass(_) => "failed to create render pass",
+        }
+    }
+}
+
+impl From<RenderPassCreationError> for RenderGraphError {
+    fn from(err: RenderPassCreationError) -> Self {
+        RenderGraphError::RenderPass(err)
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct GraphAttachment {
+    pub name: String,
+    pub format: Format,
+    pub clear_value: ClearValue,
+    pub output: bool,
+    // Stored attachments keep their contents after the render pass so they can be
+    // copied out, the output always is. Everything else is transient
+    pub stored: bool,
+}
+
+// A pass declares the attachments it writes (color and depth stencil) and reads
+// (input). The depth stencil attachment may also be read as an input, it is then
+// kept in the general layout for the pass
+#[derive(Debug, Clone)]
+pub struct GraphPass {
+    name: String,
+    color: Vec<String>,
+    depth_stencil: Option<String>,
+    input: Vec<String>,
+}
+
+impl GraphPass {
+    pub fn new<S: Into<String>>(name: S) -> Self {
+        Self {
+            name: name.into(),
+            color: Vec::new(),
+            depth_stencil: None,
+            input: Vec::new(),
+        }
+    }
+    pub fn color<S: Into<String>>(mut self, attachment: S) -> Self {
+        self.color.push(attachment.into());
+        self
+    }
+    pub fn depth_stencil<S: Into<String>>(mut self, attachment: S) -> Self {
+        self.depth_stencil = Some(attachment.into());
+        self
+    }
+    pub fn input<S: Into<String>>(mut self, attachment: S) -> Self {
+        self.input.push(attachment.into());
+        self
+    }
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+}
+
+pub struct RenderGraphBuilder {
+    attachments: Vec<GraphAttachment>,
+    passes: Vec<GraphPass>,
+}
+
+impl RenderGraphBuilder {
+    pub fn attachment<S: Into<String>>(mut self, name: S, format: Format, clear_value: ClearValue) -> Self {
+        self.attachments.push(GraphAttachment {
+            name: name.into(),
+            format,
+            clear_value,
+            output: false,
+            stored: false,
+        });
+        self
+    }
+    pub fn output<S: Into<String>>(mut self, name: S, format: Format, clear_value: ClearValue) -> Self {
+        self.attachments.push(GraphAttachment {
+            name: name.into(),
+            format,
+            clear_value,
+            output: true,
+            stored: true,
+        });
+        self
+    }
+    // Declares every attachment of the gbuffer, in the gbuffer's order
+    pub fn gbuffer(mut self, gbuffer: &GBufferBuilder) -> Self {
+        for attachment in gbuffer.attachments() {
+            self = self.attachment(attachment.name.clone(), attachment.format, attachment.clear_value);
+        }
+        self
+    }
+    // Passes run in the order they are added
+    pub fn pass(mut self, pass: GraphPass) -> Self {
+        self.passes.push(pass);
+        self
+    }
+    pub fn build(self) -> Result<RenderGraph, RenderGraphError> {
+        RenderGraph::compile(self.attachments, self.passes)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Access {
+    Color,
+    DepthStencil,
+    Input,
+    // Depth stencil attachment that is also read as an input
+    DepthStencilInput,
+}
+
+impl Access {
+    fn writes(self) -> bool {
+        self != Access::Input
+    }
+    fn layout(self) -> ImageLayout {
+        match self {
+            Access::Color => ImageLayout::ColorAttachmentOptimal,
+            Access::DepthStencil => ImageLayout::DepthStencilAttachmentOptimal,
+            Access::Input => ImageLayout::ShaderReadOnlyOptimal,
+            Access::DepthStencilInput => ImageLayout::General,
+        }
+    }
+    fn stages(self) -> PipelineStages {
+        let depth = PipelineStages {
+            early_fragment_tests: true,
+            late_fragment_tests: true,
+            ..PipelineStages::none()
+        };
+        match self {
+            Access::Color => PipelineStages {
+                color_attachment_output: true,
+                ..PipelineStages::none()
+            },
+            Access::DepthStencil => depth,
+            Access::Input => PipelineStages {
+                fragment_shader: true,
+                ..PipelineStages::none()
+            },
+            Access::DepthStencilInput => PipelineStages {
+                fragment_shader: true,
+                ..depth
+            },
+        }
+    }
+    fn access(self) -> AccessFlagBits {
+        let depth = AccessFlagBits {
+            depth_stencil_attachment_read: true,
+            depth_stencil_attachment_write: true,
+            ..AccessFlagBits::none()
+        };
+        match self {
+            Access::Color => AccessFlagBits {
+                color_attachment_read: true,
+                color_attachment_write: true,
+                ..AccessFlagBits::none()
+            },
+            Access::DepthStencil => depth,
+            Access::Input => AccessFlagBits {
+                input_attachment_read: true,
+                ..AccessFlagBits::none()
+            },
+            Access::DepthStencilInput => AccessFlagBits {
+                input_attachment_read: true,
+                ..depth
+            },
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+struct CompiledPass {
+    name: String,
+    color: Vec<usize>,
+    depth_stencil: Option<usize>,
+    input: Vec<usize>,
+    // Attachment index to its access in this pass
+    accesses: Vec<(usize, Access)>,
+    preserve: Vec<usize>,
+}
+
+impl CompiledPass {
+    fn access_of(&self, attachment: usize) -> Option<Access> {
+        self.accesses
+            .iter()
+            .find(|&&(a, _)| a == attachment)
+            .map(|&(_, access)| access)
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Dependency {
+    source: usize,
+    destination: usize,
+    source_stages: PipelineStages,
+    destination_stages: PipelineStages,
+    source_access: AccessFlagBits,
+    destination_access: AccessFlagBits,
+}
+
+// A compiled graph. Attachments keep their declaration order, which is the order
+// images are added to the framebuffer and clear values are given in. Layouts,
+// image usages and subpass dependencies are all derived from how passes use each
+// attachment, and the graph is its own `RenderPassDesc`
+#[derive(Debug, Clone)]
+pub struct RenderGraph {
+    attachments: Vec<GraphAttachment>,
+    passes: Vec<CompiledPass>,
+    dependencies: Vec<Dependency>,
+}
+
+fn is_depth(format: Format) -> bool {
+    match format.ty() {
+        FormatTy::Depth | FormatTy::Stencil | FormatTy::DepthStencil => true,
+        _ => false,
+    }
+}
+
+fn has_stencil(format: Format) -> bool {
+    match format.ty() {
+        FormatTy::Stencil | FormatTy::DepthStencil => true,
+        _ => false,
+    }
+}
+
+fn union_stages(a: PipelineStages, b: PipelineStages) -> PipelineStages {
+    PipelineStages {
+        color_attachment_output: a.color_attachment_output || b.color_attachment_output,
+        early_fragment_tests: a.early_fragment_tests || b.early_fragment_tests,
+        late_fragment_tests: a.late_fragment_tests || b.late_fragment_tests,
+        fragment_shader: a.fragment_shader || b.fragment_shader,
+        ..PipelineStages::none()
+    }
+}
+
+fn union_access(a: AccessFlagBits, b: AccessFlagBits) -> AccessFlagBits {
+    AccessFlagBits {
+        color_attachment_read: a.color_attachment_read || b.color_attachment_read,
+        color_attachment_write: a.color_attachment_write || b.color_attachment_write,
+        depth_stencil_attachment_read: a.depth_stencil_attachment_read || b.depth_stencil_attachment_read,
+        depth_stencil_attachment_write: a.depth_stencil_attachment_write || b.depth_stencil_attachment_write,
+        input_attachment_read: a.input_attachment_read || b.input_attachment_read,
+        ..AccessFlagBits::none()
+    }
+}
+
+impl RenderGraph {
+    pub fn start() -> RenderGraphBuilder {
+        RenderGraphBuilder {
+            attachments: Vec::new(),
+            passes: Vec::new(),
+        }
+    }
+
+    fn compile(attachments: Vec<GraphAttachment>, passes: Vec<GraphPass>) -> Result<Self, RenderGraphError> {
+        if passes.is_empty() {
+            return Err(RenderGraphError::NoPasses);
+        }
+        let outputs = attachments.iter().filter(|a| a.output).count();
+        if outputs != 1 {
+            return Err(RenderGraphError::OutputCount(outputs));
+        }
+        for (i, attachment) in attachments.iter().enumerate() {
+            if attachments[..i].iter().any(|a| a.name == attachment.name) {
+                return Err(RenderGraphError::DuplicateName(attachment.name.clone()));
+            }
+        }
+        for (i, pass) in passes.iter().enumerate() {
+            if passes[..i].iter().any(|p| p.name == pass.name) {
+                return Err(RenderGraphError::DuplicateName(pass.name.clone()));
+            }
+        }
+
+        let mut compiled: Vec<CompiledPass> = Vec::with_capacity(passes.len());
+        for pass in &passes {
+            let lookup = |name: &String| {
+                attachments
+                    .iter()
+                    .position(|a| &a.name == name)
+                    .ok_or_else(|| RenderGraphError::UnknownAttachment {
+                        pass: pass.name.clone(),
+                        attachment: name.clone(),
+                    })
+            };
+            let invalid = |attachment: usize| RenderGraphError::InvalidUsage {
+                pass: pass.name.clone(),
+                attachment: attachments[attachment].name.clone(),
+            };
+
+            let mut accesses: Vec<(usize, Access)> = Vec::new();
+            let mut color = Vec::with_capacity(pass.color.len());
+            for name in &pass.color {
+                let index = lookup(name)?;
+                if is_depth(attachments[index].format) || accesses.iter().any(|&(a, _)| a == index) {
+                    return Err(invalid(index));
+                }
+                accesses.push((index, Access::Color));
+                color.push(index);
+            }
+            let depth_stencil = match pass.depth_stencil {
+                Some(ref name) => {
+                    let index = lookup(name)?;
+                    if !is_depth(attachments[index].format) || accesses.iter().any(|&(a, _)| a == index) {
+                        return Err(invalid(index));
+                    }
+                    accesses.push((index, Access::DepthStencil));
+                    Some(index)
+                }
+                None => None,
+            };
+            let mut input = Vec::with_capacity(pass.input.len());
+            for name in &pass.input {
+                let index = lookup(name)?;
+                let written = compiled.iter().any(|p| {
+                    p.access_of(index).map(|access| access.writes()).unwrap_or(false)
+                });
+                if !written {
+                    return Err(RenderGraphError::ReadBeforeWrite {
+                        pass: pass.name.clone(),
+                        attachment: name.clone(),
+                    });
+                }
+                match accesses.iter().position(|&(a, _)| a == index) {
+                    Some(i) if accesses[i].1 == Access::DepthStencil => accesses[i].1 = Access::DepthStencilInput,
+                    Some(_) => return Err(invalid(index)),
+                    None => accesses.push((index, Access::Input)),
+                }
+                input.push(index);
+            }
+            compiled.push(CompiledPass {
+                name: pass.name.clone(),
+                color,
+                depth_stencil,
+                input,
+                accesses,
+                preserve: Vec::new(),
+            });
+        }
+
+        for (index, attachment) in attachments.iter().enumerate() {
+            let users: Vec<usize> = (0..compiled.len())
+                .filter(|&p| compiled[p].access_of(index).is_some())
+                .collect();
+            let (first, last) = match (users.first(), users.last()) {
+                (Some(&first), Some(&last)) => (first, last),
+                _ => return Err(RenderGraphError::UnusedAttachment(attachment.name.clone())),
+            };
+            for pass in first + 1..last {
+                if compiled[pass].access_of(index).is_none() {
+                    compiled[pass].preserve.push(index);
+                }
+            }
+        }
+
+        // Each use of an attachment depends on the previous use, unless both only read it
+        let mut dependencies: Vec<Dependency> = Vec::new();
+        for index in 0..attachments.len() {
+            let mut previous: Option<(usize, Access)> = None;
+            for (pass, compiled_pass) in compiled.iter().enumerate() {
+                let access = match compiled_pass.access_of(index) {
+                    Some(access) => access,
+                    None => continue,
+                };
+                if let Some((source, source_use)) = previous {
+                    if source_use.writes() || access.writes() {
+                        match dependencies
+                            .iter()
+                            .position(|d| d.source == source && d.destination == pass)
+                        {
+                            Some(i) => {
+                                let d = &mut dependencies[i];
+                                d.source_stages = union_stages(d.source_stages, source_use.stages());
+                                d.destination_stages = union_stages(d.destination_stages, access.stages());
+                                d.source_access = union_access(d.source_access, source_use.access());
+                                d.destination_access = union_access(d.destination_access, access.access());
+                            }
+                            None => dependencies.push(Dependency {
+                                source,
+                                destination: pass,
+                                source_stages: source_use.stages(),
+                                destination_stages: access.stages(),
+                                source_access: source_use.access(),
+                                destination_access: access.access(),
+                            }),
+                        }
+                    }
+                }
+                previous = Some((pass, access));
+            }
+        }
+
+        Ok(Self {
+            attachments,
+            passes: compiled,
+            dependencies,
+        })
+    }
+
+    pub fn render_pass(&self, device: Arc<Device>) -> Result<Arc<RenderPassAbstract + Send + Sync>, RenderGraphError> {
+        Ok(Arc::new(self.clone().build_render_pass(device)?))
+    }
+    pub fn attachments(&self) -> &[GraphAttachment] {
+        &self.attachments
+    }
+    pub fn attachment_index(&self, name: &str) -> Option<usize> {
+        self.attachments.iter().position(|a| a.name == name)
+    }
+    // The attachment the final image is bound to
+    pub fn output(&self) -> &GraphAttachment {
+        self.attachments.iter().find(|a| a.output).unwrap()
+    }
+    pub fn num_passes(&self) -> usize {
+        self.passes.len()
+    }
+    pub fn pass_name(&self, index: usize) -> Option<&str> {
+        self.passes.get(index).map(|p| p.name.as_str())
+    }
+    pub fn pass_index(&self, name: &str) -> Option<usize> {
+        self.passes.iter().position(|p| p.name == name)
+    }
+    // In attachment order, as `begin_render_pass` expects them
+    pub fn clear_values(&self) -> Vec<ClearValue> {
+        self.attachments.iter().map(|a| a.clear_value).collect()
+    }
+    // `RenderSystem` clears every attachment, gbuffer ones included, with these
+    pub fn set_clear_value(&mut self, name: &str, clear_value: ClearValue) -> Result<(), RenderGraphError> {
+        match self.attachments.iter_mut().find(|a| a.name == name) {
+            Some(attachment) => {
+                attachment.clear_value = clear_value;
+                Ok(())
+            }
+            None => Err(RenderGraphError::UnknownAttachment {
+                pass: String::new(),
+                attachment: name.to_string(),
+            }),
+        }
+    }
+    // Stored attachments can be read back with `OffscreenTarget::read_attachment`,
+    // set this before handing the graph to `RenderSystem::new`
+    pub fn set_stored(&mut self, name: &str, stored: bool) -> Result<(), RenderGraphError> {
+        match self.attachments.iter_mut().find(|a| a.name == name && !a.output) {
+            Some(attachment) => {
+                attachment.stored = stored;
+                Ok(())
+            }
+            None => Err(RenderGraphError::UnknownAttachment {
+                pass: String::new(),
+                attachment: name.to_string(),
+            }),
+        }
+    }
+    // The usage images bound to an attachment need, derived from every pass that uses it
+    pub fn image_usage(&self, name: &str) -> Option<ImageUsage> {
+        let index = self.attachment_index(name)?;
+        let attachment = &self.attachments[index];
+        let mut usage = ImageUsage {
+            transient_attachment: !attachment.stored,
+            transfer_source: attachment.stored && !attachment.output,
+            ..ImageUsage::none()
+        };
+        for pass in &self.passes {
+            match pass.access_of(index) {
+                Some(Access::Color) => usage.color_attachment = true,
+                Some(Access::DepthStencil) => usage.depth_stencil_attachment = true,
+                Some(Access::Input) => usage.input_attachment = true,
+                Some(Access::DepthStencilInput) => {
+                    usage.depth_stencil_attachment = true;
+                    usage.input_attachment = true;
+                }
+                None => {}
+            }
+        }
+        Some(usage)
+    }
+}
+
+unsafe impl RenderPassDesc for RenderGraph {
+    fn num_attachments(&self) -> usize {
+        self.attachments.len()
+    }
+    fn attachment_desc(&self, num: usize) -> Option<LayoutAttachmentDescription> {
+        let attachment = self.attachments.get(num)?;
+        // Stored gbuffer attachments end in the layout their images are expected to be in
+        let final_layout = if attachment.stored && !attachment.output {
+            if is_depth(attachment.format) {
+                ImageLayout::DepthStencilAttachmentOptimal
+            } else {
+                ImageLayout::ColorAttachmentOptimal
+            }
+        } else {
+            self.passes
+                .iter()
+                .rev()
+                .filter_map(|p| p.access_of(num))
+                .next()
+                .map(|access| access.layout())
+                .unwrap_or(ImageLayout::General)
+        };
+        let store = if attachment.stored {
+            StoreOp::Store
+        } else {
+            StoreOp::DontCare
+        };
+        let stencil_load = if has_stencil(attachment.format) {
+            LoadOp::Clear
+        } else {
+            LoadOp::DontCare
+        };
+        Some(LayoutAttachmentDescription {
+            format: attachment.format,
+            samples: 1,
+            load: LoadOp::Clear,
+            store,
+            stencil_load,
+            stencil_store: StoreOp::DontCare,
+            initial_layout: ImageLayout::Undefined,
+            final_layout,
+        })
+    }
+    fn num_subpasses(&self) -> usize {
+        self.passes.len()
+    }
+    fn subpass_desc(&self, num: usize) -> Option<LayoutPassDescription> {
+        let pass = self.passes.get(num)?;
+        let with_layout = |a: &usize| (*a, pass.access_of(*a).unwrap().layout());
+        Some(LayoutPassDescription {
+            color_attachments: pass.color.iter().map(&with_layout).collect(),
+            depth_stencil: pass.depth_stencil.as_ref().map(&with_layout),
+            input_attachments: pass.input.iter().map(&with_layout).collect(),
+            resolve_attachments: vec![],
+            preserve_attachments: pass.preserve.clone(),
+        })
+    }
+    fn num_dependencies(&self) -> usize {
+        self.dependencies.len()
+    }
+    fn dependency_desc(&self, num: usize) -> Option<LayoutPassDependencyDescription> {
+        let dependency = self.dependencies.get(num)?;
+        Some(LayoutPassDependencyDescription {
+            source_subpass: dependency.source,
+            destination_subpass: dependency.destination,
+            source_stages: dependency.source_stages,
+            destination_stages: dependency.destination_stages,
+            source_access: dependency.source_access,
+            destination_access: dependency.destination_access,
+            by_region: true,
+        })
+    }
+}
+
+unsafe impl RenderPassDescClearValues<Vec<ClearValue>> for RenderGraph {
+    fn convert_clear_values(&self, values: Vec<ClearValue>) -> Box<Iterator<Item = ClearValue>> {
+        Box::new(values.into_iter())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn clear() -> ClearValue {
+        [0.0, 0.0, 0.0, 0.0].into()
+    }
+
+    // Geometry writes albedo and depth, lighting reads both into the output
+    fn deferred() -> RenderGraphBuilder {
+        RenderGraph::start()
+            .output("final", Format::R8G8B8A8Unorm, clear())
+            .attachment("albedo", Format::R8G8B8A8Unorm, clear())
+            .attachment("depth", Format::D16Unorm, 1.0f32.into())
+            .pass(GraphPass::new("geometry").color("albedo").depth_stencil("depth"))
+            .pass(GraphPass::new("lighting").color("final").input("albedo").input("depth"))
+    }
+
+    #[test]
+    fn dependency_from_writes_to_input_reads() {
+        let graph = deferred().build().unwrap();
+        assert_eq!(graph.num_subpasses(), 2);
+        // Both attachments are merged into a single dependency
+        assert_eq!(graph.num_dependencies(), 1);
+        let dependency = graph.dependency_desc(0).unwrap();
+        assert_eq!((dependency.source_subpass, dependency.destination_subpass), (0, 1));
+        assert!(dependency.by_region);
+        let source = dependency.source_stages;
+        assert!(source.color_attachment_output && source.early_fragment_tests && source.late_fragment_tests);
+        assert!(!source.fragment_shader);
+        let destination = dependency.destination_stages;
+        assert!(destination.fragment_shader && !destination.color_attachment_output);
+        assert!(dependency.source_access.color_attachment_write);
+        assert!(dependency.source_access.depth_stencil_attachment_write);
+        assert!(dependency.destination_access.input_attachment_read);
+        assert!(!dependency.destination_access.color_attachment_write);
+        assert!(graph.dependency_desc(1).is_none());
+    }
+
+    #[test]
+    fn reads_of_a_read_need_no_dependency() {
+        let graph = deferred()
+            .pass(GraphPass::new("post").color("final").input("albedo"))
+            .build()
+            .unwrap();
+        let pairs: Vec<(usize, usize)> = (0..graph.num_dependencies())
+            .map(|i| graph.dependency_desc(i).unwrap())
+            .map(|d| (d.source_subpass, d.destination_subpass))
+            .collect();
+        // final is written by lighting and post, albedo is only read after geometry so
+        // post adds no dependency on lighting or geometry for it
+        assert_eq!(pairs, vec![(1, 2), (0, 1)]);
+        let post = graph.dependency_desc(0).unwrap();
+        assert!(post.source_access.color_attachment_write && post.destination_access.color_attachment_write);
+        assert!(!post.destination_access.input_attachment_read);
+    }
+
+    #[test]
+    fn attachment_layouts_follow_their_last_use() {
+        let graph = deferred().build().unwrap();
+        let final_layout = |name| graph.attachment_desc(graph.attachment_index(name).unwrap()).unwrap().final_layout;
+        assert_eq!(final_layout("final"), ImageLayout::ColorAttachmentOptimal);
+        assert_eq!(final_layout("albedo"), ImageLayout::ShaderReadOnlyOptimal);
+        assert_eq!(final_layout("depth"), ImageLayout::ShaderReadOnlyOptimal);
+        for i in 0..graph.num_attachments() {
+            let desc = graph.attachment_desc(i).unwrap();
+            assert_eq!(desc.initial_layout, ImageLayout::Undefined);
+            assert_eq!(desc.load, LoadOp::Clear);
+        }
+
+        let geometry = graph.subpass_desc(0).unwrap();
+        assert_eq!(geometry.color_attachments, vec![(1, ImageLayout::ColorAttachmentOptimal)]);
+        assert_eq!(geometry.depth_stencil, Some((2, ImageLayout::DepthStencilAttachmentOptimal)));
+        assert!(geometry.input_attachments.is_empty());
+        let lighting = graph.subpass_desc(1).unwrap();
+        assert_eq!(lighting.color_attachments, vec![(0, ImageLayout::ColorAttachmentOptimal)]);
+        assert_eq!(lighting.depth_stencil, None);
+        assert_eq!(
+            lighting.input_attachments,
+            vec![(1, ImageLayout::ShaderReadOnlyOptimal), (2, ImageLayout::ShaderReadOnlyOptimal)]
+        );
+    }
+
+    #[test]
+    fn depth_bound_and_read_in_one_pass_is_general() {
+        let graph = RenderGraph::start()
+            .output("final", Format::R8G8B8A8Unorm, clear())
+            .attachment("depth", Format::D24Unorm_S8Uint, ClearValue::DepthStencil((1.0, 0)))
+            .pass(GraphPass::new("geometry").color("final").depth_stencil("depth"))
+            .pass(GraphPass::new("lighting").color("final").depth_stencil("depth").input("depth"))
+            .build()
+            .unwrap();
+        let lighting = graph.subpass_desc(1).unwrap();
+        assert_eq!(lighting.depth_stencil, Some((1, ImageLayout::General)));
+        assert_eq!(lighting.input_attachments, vec![(1, ImageLayout::General)]);
+        let depth = graph.attachment_desc(1).unwrap();
+        assert_eq!(depth.final_layout, ImageLayout::General);
+        assert_eq!(depth.stencil_load, LoadOp::Clear);
+        let usage = graph.image_usage("depth").unwrap();
+        assert!(usage.depth_stencil_attachment && usage.input_attachment);
+        let dependency = graph.dependency_desc(0).unwrap();
+        assert!(dependency.destination_stages.fragment_shader && dependency.destination_stages.late_fragment_tests);
+    }
+
+    #[test]
+    fn preserves_attachments_skipped_between_uses() {
+        let graph = RenderGraph::start()
+            .output("final", Format::R8G8B8A8Unorm, clear())
+            .attachment("albedo", Format::R8G8B8A8Unorm, clear())
+            .attachment("scratch", Format::R8G8B8A8Unorm, clear())
+            .pass(GraphPass::new("geometry").color("albedo"))
+            .pass(GraphPass::new("blur").color("scratch").input("albedo"))
+            .pass(GraphPass::new("resolve").color("final").input("scratch"))
+            .pass(GraphPass::new("composite").color("final").input("albedo"))
+            .build()
+            .unwrap();
+        assert!(graph.subpass_desc(0).unwrap().preserve_attachments.is_empty());
+        // albedo is used by blur and composite but not by resolve in between
+        assert_eq!(graph.subpass_desc(2).unwrap().preserve_attachments, vec![1]);
+    }
+
+    #[test]
+    fn set_stored_keeps_contents() {
+        let mut graph = deferred().build().unwrap();
+        let albedo = graph.attachment_index("albedo").unwrap();
+        assert_eq!(graph.attachment_desc(albedo).unwrap().store, StoreOp::DontCare);
+        let usage = graph.image_usage("albedo").unwrap();
+        assert!(usage.transient_attachment && !usage.transfer_source);
+
+        graph.set_stored("albedo", true).unwrap();
+        graph.set_stored("depth", true).unwrap();
+        let desc = graph.attachment_desc(albedo).unwrap();
+        assert_eq!(desc.store, StoreOp::Store);
+        assert_eq!(desc.final_layout, ImageLayout::ColorAttachmentOptimal);
+        let depth = graph.attachment_desc(graph.attachment_index("depth").unwrap()).unwrap();
+        assert_eq!(depth.final_layout, ImageLayout::DepthStencilAttachmentOptimal);
+        let usage = graph.image_usage("albedo").unwrap();
+        assert!(!usage.transient_attachment && usage.transfer_source);
+        assert!(usage.color_attachment && usage.input_attachment);
+
+        graph.set_stored("albedo", false).unwrap();
+        assert_eq!(graph.attachment_desc(albedo).unwrap().store, StoreOp::DontCare);
+        // The output is always stored
+        match graph.set_stored("final", false) {
+            Err(RenderGraphError::UnknownAttachment { ref attachment, .. }) => assert_eq!(attachment, "final"),
+            other => panic!("expected UnknownAttachment, got {:?}", other),
+        }
+        assert!(graph.set_stored("missing", true).is_err());
+        assert_eq!(graph.attachment_desc(0).unwrap().store, StoreOp::Store);
+    }
+
+    #[test]
+    fn duplicate_names_are_rejected() {
+        let result = deferred().attachment("albedo", Format::R8G8B8A8Unorm, clear()).build();
+        match result {
+            Err(RenderGraphError::DuplicateName(ref name)) => assert_eq!(name, "albedo"),
+            other => panic!("expected DuplicateName, got {:?}", other),
+        }
+        let result = deferred().pass(GraphPass::new("lighting").color("final")).build();
+        match result {
+            Err(RenderGraphError::DuplicateName(ref name)) => assert_eq!(name, "lighting"),
+            other => panic!("expected DuplicateName, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn unknown_attachments_are_rejected() {
+        let result = deferred().pass(GraphPass::new("post").color("final").input("bloom")).build();
+        match result {
+            Err(RenderGraphError::UnknownAttachment { ref pass, ref attachment }) => {
+                assert_eq!((pass.as_str(), attachment.as_str()), ("post", "bloom"))
+            }
+            other => panic!("expected UnknownAttachment, got {:?}", other),
+        }
+        let mut graph = deferred().build().unwrap();
+        assert!(graph.set_clear_value("bloom", clear()).is_err());
+        assert!(graph.image_usage("bloom").is_none());
+    }
+
+    #[test]
+    fn invalid_graphs_are_rejected() {
+        let read_first = RenderGraph::start()
+            .output("final", Format::R8G8B8A8Unorm, clear())
+            .attachment("albedo", Format::R8G8B8A8Unorm, clear())
+            .pass(GraphPass::new("lighting").color("final").input("albedo"))
+            .build();
+        match read_first {
+            Err(RenderGraphError::ReadBeforeWrite { ref attachment, .. }) => assert_eq!(attachment, "albedo"),
+            other => panic!("expected ReadBeforeWrite, got {:?}", other),
+        }
+        let depth_as_color = deferred().pass(GraphPass::new("post").color("depth")).build();
+        match depth_as_color {
+            Err(RenderGraphError::InvalidUsage { ref attachment, .. }) => assert_eq!(attachment, "depth"),
+            other => panic!("expected InvalidUsage, got {:?}", other),
+        }
+        let color_and_input = deferred().pass(GraphPass::new("post").color("albedo").input("albedo")).build();
+        match color_and_input {
+            Err(RenderGraphError::InvalidUsage { ref attachment, .. }) => assert_eq!(attachment, "albedo"),
+            other => panic!("expected InvalidUsage, got {:?}", other),
+        }
+        let unused = deferred().attachment("bloom", Format::R8G8B8A8Unorm, clear()).build();
+        match unused {
+            Err(RenderGraphError::UnusedAttachment(ref name)) => assert_eq!(name, "bloom"),
+            other => panic!("expected UnusedAttachment, got {:?}", other),
+        }
+        let two_outputs = deferred().output("second", Format::R8G8B8A8Unorm, clear()).build();
+        match two_outputs {
+            Err(RenderGraphError::OutputCount(2)) => {}
+            other => panic!("expected OutputCount, got {:?}", other),
+        }
+        match RenderGraph::start().output("final", Format::R8G8B8A8Unorm, clear()).build() {
+            Err(RenderGraphError::NoPasses) => {}
+            other => panic!("expected NoPasses, got {:?}", other),
+        }
+    }
+}
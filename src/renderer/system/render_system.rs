@@ -1,31 +1,172 @@
-use renderer::system::gbuffer::GBuffer;
+use renderer::system::error::RendererError;
+use renderer::system::gbuffer::{GBuffer, GBufferBuilder, DEPTH, DIFFUSE, NORMALS, SPECULAR};
+use renderer::system::profiler::{GpuProfiler, QueryCommandBuffer};
+use renderer::system::render_graph::{GraphPass, RenderGraph};
+use std::collections::HashMap;
 use std::sync::Arc;
 use vulkano::{command_buffer::{AutoCommandBufferBuilder, CommandBuffer, DynamicState},
               device::Queue,
-              format::Format,
+              format::{ClearValue, Format, FormatTy},
               framebuffer::{Framebuffer, FramebufferAbstract, RenderPassAbstract, Subpass},
-              image::{AttachmentImage, ImageAccess, ImageUsage, ImageViewAccess},
-              pipeline::viewport::Viewport,
+              image::{ImageAccess, ImageViewAccess},
+              pipeline::{depth_stencil::Compare, viewport::Viewport},
               sync::GpuFuture};
 
+// How depth values are laid out in the depth buffer. Reversed depth pairs with
+// `math::ReverseZPerspective` and a floating point depth format
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DepthMode {
+    Standard,
+    Reversed,
+}
+
+impl DepthMode {
+    // The value the depth attachment is cleared to, the furthest possible depth
+    pub fn clear_value(&self) -> ClearValue {
+        match *self {
+            DepthMode::Standard => 1.0f32.into(),
+            DepthMode::Reversed => 0.0f32.into(),
+        }
+    }
+    // Used instead of `clear_value` for formats with a stencil component, the stencil is cleared to 0
+    pub fn depth_stencil_clear_value(&self) -> ClearValue {
+        match *self {
+            DepthMode::Standard => ClearValue::DepthStencil((1.0, 0)),
+            DepthMode::Reversed => ClearValue::DepthStencil((0.0, 0)),
+        }
+    }
+    // Picks between `clear_value` and `depth_stencil_clear_value` from the format
+    pub fn clear_value_for(&self, format: Format) -> ClearValue {
+        match format.ty() {
+            FormatTy::DepthStencil => self.depth_stencil_clear_value(),
+            _ => self.clear_value(),
+        }
+    }
+    pub fn is_depth_format(format: Format) -> bool {
+        match format.ty() {
+            FormatTy::Depth | FormatTy::DepthStencil => true,
+            _ => false,
+        }
+    }
+    // The comparison that passes for fragments closer to the camera
+    pub fn compare_op(&self) -> Compare {
+        match *self {
+            DepthMode::Standard => Compare::Less,
+            DepthMode::Reversed => Compare::Greater,
+        }
+    }
+}
+
+pub const GEOMETRY_STAGE: &str = "geometry";
+pub const LIGHTING_STAGE: &str = "lighting";
+
+// More output images than any swapchain has, the cache is emptied past this
+const MAX_CACHED_FRAMEBUFFERS: usize = 8;
+
 pub struct RenderSystem {
     queue: Arc<Queue>,
+    graph: RenderGraph,
     render_pass: Arc<RenderPassAbstract + Send + Sync>,
     gbuffer: GBuffer,
+    depth_mode: DepthMode,
+    // Keyed by the output image, only valid for the current gbuffer images
+    framebuffers: HashMap<u64, Arc<FramebufferAbstract + Send + Sync>>,
+    profiler: Option<GpuProfiler>,
 }
 
 impl RenderSystem {
-    pub fn new(
-        queue: Arc<Queue>,
-        render_pass: Arc<RenderPassAbstract + Send + Sync>,
-        gbuffer: GBuffer,
-    ) -> Self {
-        Self {
+    // Every attachment of `graph` other than the output must be in `gbuffer` with the
+    // same format. Gbuffer images are rebuilt if they lack a usage the graph needs.
+    // Attachments are cleared with the graph's clear values, the gbuffer's are
+    // overwritten with them
+    pub fn new(queue: Arc<Queue>, graph: RenderGraph, mut gbuffer: GBuffer) -> Result<Self, RendererError> {
+        let mut builder = gbuffer.builder().clone();
+        for attachment in graph.attachments().iter().filter(|a| !a.output) {
+            match builder.get(&attachment.name) {
+                Some(a) if a.format == attachment.format => {}
+                _ => return Err(RendererError::AttachmentMismatch(attachment.name.clone())),
+            }
+            builder.merge_usage(&attachment.name, graph.image_usage(&attachment.name).unwrap());
+            builder.set_clear_value(&attachment.name, attachment.clear_value);
+        }
+        gbuffer.rebuild_with_builder(queue.clone(), builder)?;
+        let render_pass = graph.render_pass(queue.device().clone())?;
+        Ok(Self {
             queue,
+            graph,
             render_pass,
             gbuffer,
+            depth_mode: DepthMode::Standard,
+            framebuffers: HashMap::new(),
+            profiler: None,
+        })
+    }
+    // Also changes the clear value of every depth attachment in the graph and gbuffer
+    pub fn set_depth_mode(&mut self, depth_mode: DepthMode) {
+        self.depth_mode = depth_mode;
+        let depth_attachments: Vec<(String, Format)> = self.graph
+            .attachments()
+            .iter()
+            .filter(|a| DepthMode::is_depth_format(a.format))
+            .map(|a| (a.name.clone(), a.format))
+            .collect();
+        for (name, format) in depth_attachments {
+            // Every graph attachment is known to both
+            self.set_clear_value(&name, depth_mode.clear_value_for(format))
+                .unwrap();
         }
     }
+    pub fn depth_mode(&self) -> DepthMode {
+        self.depth_mode
+    }
+    // Changes what an attachment of the graph, the output included, is cleared to
+    // from the next frame on
+    pub fn set_clear_value(&mut self, name: &str, clear_value: ClearValue) -> Result<(), RendererError> {
+        self.graph.set_clear_value(name, clear_value)?;
+        self.gbuffer.set_clear_value(name, clear_value);
+        Ok(())
+    }
+    // Moves the render system onto a new device after the old one was lost. The
+    // render pass and gbuffer images are recreated with the same formats, usages,
+    // clear values and dimensions, anything built from the old subpasses must be
+    // recreated as well. Left unchanged if any of it fails
+    pub fn recreate(&mut self, queue: Arc<Queue>) -> Result<(), RendererError> {
+        let render_pass = self.graph.render_pass(queue.device().clone())?;
+        let gbuffer = self.gbuffer.builder().build_with_dims(queue.clone(), self.gbuffer.dims())?;
+        let profiler = match self.profiler {
+            Some(ref profiler) => Some(profiler.recreate(queue.clone())?),
+            None => None,
+        };
+        self.render_pass = render_pass;
+        self.gbuffer = gbuffer;
+        self.profiler = profiler;
+        self.queue = queue;
+        self.framebuffers.clear();
+        Ok(())
+    }
+    pub fn queue(&self) -> &Arc<Queue> {
+        &self.queue
+    }
+    // Times every frame on the GPU from now on, returns the profiler that was set
+    // before. Its reports are read from `profiler`
+    pub fn set_profiler(&mut self, profiler: Option<GpuProfiler>) -> Option<GpuProfiler> {
+        ::std::mem::replace(&mut self.profiler, profiler)
+    }
+    pub fn profiler(&self) -> Option<&GpuProfiler> {
+        self.profiler.as_ref()
+    }
+    pub fn profiler_mut(&mut self) -> Option<&mut GpuProfiler> {
+        self.profiler.as_mut()
+    }
+    pub fn graph(&self) -> &RenderGraph {
+        &self.graph
+    }
+    pub fn gbuffer(&self) -> &GBuffer {
+        &self.gbuffer
+    }
+    pub fn render_pass(&self) -> &Arc<RenderPassAbstract + Send + Sync> {
+        &self.render_pass
+    }
     pub fn get_subpass(
         &self,
         index: u32,
@@ -35,168 +176,209 @@ impl RenderSystem {
         }
         None
     }
-    pub fn frame<F, I>(&mut self, before_future: F, final_image: I) -> Frame
+    pub fn subpass(&self, stage: &str) -> Option<Subpass<Arc<RenderPassAbstract + Send + Sync>>> {
+        self.graph
+            .pass_index(stage)
+            .and_then(|index| self.get_subpass(index as u32))
+    }
+    // Framebuffers are cached per output image, so cycling through the images of a
+    // swapchain only creates them on the first frames. Resizing the output rebuilds
+    // the gbuffer and starts the cache over. With a profiler set, the frame waits
+    // on `before_future` and then resets its timestamp queries
+    pub fn frame<F, I>(&mut self, before_future: F, final_image: I) -> Result<Frame, RendererError>
     where
         F: GpuFuture + 'static,
         I: ImageAccess + ImageViewAccess + Clone + Send + Sync + 'static,
     {
         let img_dims = ImageAccess::dimensions(&final_image).width_height();
         if self.gbuffer.dims() != img_dims {
-            self.gbuffer.rebuild_with_dims(self.queue.clone(), img_dims);
+            self.gbuffer.rebuild_with_dims(self.queue.clone(), img_dims)?;
+            self.framebuffers.clear();
+        }
+        let key = final_image.conflict_key();
+        let cached = self.framebuffers.get(&key).cloned();
+        let framebuffer = match cached {
+            Some(framebuffer) => framebuffer,
+            None => {
+                let framebuffer = self.build_framebuffer(final_image)?;
+                if self.framebuffers.len() >= MAX_CACHED_FRAMEBUFFERS {
+                    self.framebuffers.clear();
+                }
+                self.framebuffers.insert(key, framebuffer.clone());
+                framebuffer
+            }
+        };
+        let clear_values = self.graph.clear_values();
+        let mut before_future: Box<GpuFuture> = Box::new(before_future);
+        if let Some(ref mut profiler) = self.profiler {
+            before_future = Box::new(before_future.then_execute(self.queue.clone(), profiler.begin_frame()?)?);
         }
-        let framebuffer = Arc::new(
-            Framebuffer::start(self.render_pass.clone())
-                .add(final_image.clone())
-                .unwrap()
-                .add(self.gbuffer.diffuse.clone())
-                .unwrap()
-                .add(self.gbuffer.specular.clone())
-                .unwrap()
-                .add(self.gbuffer.normal.clone())
-                .unwrap()
-                .add(self.gbuffer.depth.clone())
-                .unwrap()
-                .build()
-                .unwrap(),
-        );
         let command_buffer = Some(
             AutoCommandBufferBuilder::primary_one_time_submit(
                 self.queue.device().clone(),
                 self.queue.family(),
-            ).unwrap()
-                .begin_render_pass(
-                    framebuffer.clone(),
-                    true,
-                    vec![
-                        [0.0, 0.0, 0.0, 0.0].into(),
-                        0.0f32.into(),
-                        [0.0, 0.0, 0.0, 0.0].into(),
-                        [0.0, 0.0, 0.0, 0.0].into(),
-                        1.0f32.into(),
-                    ],
-                )
-                .unwrap(),
+            )?
+                .begin_render_pass(framebuffer.clone(), true, clear_values)?,
         );
-        Frame {
+        Ok(Frame {
             render_system: self,
-            before_main_cb_future: Some(Box::new(before_future)),
+            before_main_cb_future: Some(before_future),
             framebuffer,
-            number_of_stages: self.render_pass.num_subpasses() as u8,
             stage: 0,
             command_buffer,
+        })
+    }
+    // Drops the cached framebuffers along with the output images they hold, for
+    // when a swapchain is replaced by one with the same dimensions
+    pub fn clear_framebuffer_cache(&mut self) {
+        self.framebuffers.clear();
+    }
+    pub fn cached_framebuffers(&self) -> usize {
+        self.framebuffers.len()
+    }
+    fn build_framebuffer<I>(&self, final_image: I) -> Result<Arc<FramebufferAbstract + Send + Sync>, RendererError>
+    where
+        I: ImageAccess + ImageViewAccess + Clone + Send + Sync + 'static,
+    {
+        let mut framebuffer = Framebuffer::start(self.render_pass.clone()).boxed();
+        for attachment in self.graph.attachments() {
+            framebuffer = if attachment.output {
+                framebuffer.add(final_image.clone())?.boxed()
+            } else {
+                framebuffer.add(self.gbuffer.require(&attachment.name)?)?.boxed()
+            };
         }
+        Ok(Arc::new(framebuffer.build()?))
     }
 }
 
-pub fn deffered_lighting_render_pass(
-    queue: Arc<Queue>,
+// The gbuffer must have the attachments of `GBufferBuilder::new_default`, any
+// others are left out of the graph
+pub fn deffered_lighting_graph(final_output_format: Format, gbuffer: &GBufferBuilder) -> Result<RenderGraph, RendererError> {
+    deffered_graph(final_output_format, gbuffer, false)
+}
+
+// The same attachments and passes as `deffered_lighting_graph` but the depth
+// attachment must have a stencil component and stays bound as the depth stencil
+// attachment during the lighting pass, in the general layout so it can also be
+// read as an input attachment. Light volumes use it to mark the pixels they
+// cover, see `LightVolumeSystem`
+pub fn deffered_lighting_stencil_graph(
     final_output_format: Format,
-) -> Arc<RenderPassAbstract + Send + Sync> {
-    let render_pass = Arc::new(
-        ordered_passes_renderpass!(queue.device().clone(),
-            attachments: {
-                // The image that will contain the final rendering (in this example the swapchain
-                // image, but it could be another image).
-                final_color: {
-                    load: Clear,
-                    store: Store,
-                    format: final_output_format,
-                    samples: 1,
-                },
-                // Will be bound to `self.diffuse_buffer`.
-                diffuse: {
-                    load: Clear,
-                    store: DontCare,
-                    format: Format::A2B10G10R10UnormPack32,
-                    samples: 1,
-                },
-                specular: {
-                    load: Clear,
-                    store: DontCare,
-                    format: Format::R16Unorm,
-                    samples: 1,
-                },
-                // Will be bound to `self.normals_buffer`.
-                normals: {
-                    load: Clear,
-                    store: DontCare,
-                    format: Format::R16G16B16A16Sfloat,
-                    samples: 1,
-                },
-                // Will be bound to `self.depth_buffer`.
-                depth: {
-                    load: Clear,
-                    store: DontCare,
-                    format: Format::D16Unorm,
-                    samples: 1,
-                }
-            },
-            passes: [
-                // Write to the diffuse, normals and depth attachments.
-                {
-                    color: [diffuse, specular, normals],
-                    depth_stencil: {depth},
-                    input: []
-                },
-                // Apply lighting by reading these three attachments and writing to `final_color`.
-                {
-                    color: [final_color],
-                    depth_stencil: {},
-                    input: [diffuse, specular, normals, depth]
-                }
-            ]
-        ).unwrap(),
-    );
-    return render_pass;
+    gbuffer: &GBufferBuilder,
+) -> Result<RenderGraph, RendererError> {
+    deffered_graph(final_output_format, gbuffer, true)
+}
+
+fn deffered_graph(
+    final_output_format: Format,
+    gbuffer: &GBufferBuilder,
+    depth_in_lighting: bool,
+) -> Result<RenderGraph, RendererError> {
+    let mut lighting = GraphPass::new(LIGHTING_STAGE)
+        .color("final")
+        .input(DIFFUSE)
+        .input(SPECULAR)
+        .input(NORMALS)
+        .input(DEPTH);
+    if depth_in_lighting {
+        lighting = lighting.depth_stencil(DEPTH);
+    }
+    let mut graph = RenderGraph::start().output("final", final_output_format, [0.0, 0.0, 0.0, 0.0].into());
+    for name in [DIFFUSE, SPECULAR, NORMALS, DEPTH].iter() {
+        let attachment = gbuffer
+            .get(name)
+            .ok_or_else(|| RendererError::AttachmentMismatch(name.to_string()))?;
+        graph = graph.attachment(*name, attachment.format, attachment.clear_value);
+    }
+    let graph = graph
+        .pass(GraphPass::new(GEOMETRY_STAGE)
+            .color(DIFFUSE)
+            .color(SPECULAR)
+            .color(NORMALS)
+            .depth_stencil(DEPTH))
+        .pass(lighting)
+        .build()?;
+    Ok(graph)
 }
 
 // Want to expose the command buffer at each stage
 pub struct Frame<'a> {
-    render_system: &'a RenderSystem,
-    number_of_stages: u8,
-    stage: u8,
+    render_system: &'a mut RenderSystem,
+    stage: usize,
     before_main_cb_future: Option<Box<GpuFuture>>,
     framebuffer: Arc<FramebufferAbstract + Send + Sync>,
     command_buffer: Option<AutoCommandBufferBuilder>,
 }
 
-// returns the RenderPass for the next subpass and the index of the current subpass
+// Walks the stages of the render graph in order, returns `Finished` after the
+// last stage and `None` once the frame has been submitted. A frame that returned
+// an error can not continue and should be dropped
 impl<'a> Frame<'a> {
-    pub fn next_pass<'f>(&'f mut self) -> (Option<RenderPass<'f, 'a>>, u8) {
-        match {
-            let current = self.stage;
-            self.stage += 1;
-            current
-        } {
-            n if n == 0 => (Some(RenderPass::SubPass(Pass { frame: self })), n),
-            n if n < self.number_of_stages => {
-                self.command_buffer = Some(
-                    self.command_buffer
-                        .take()
-                        .unwrap()
-                        .next_subpass(true)
-                        .unwrap(),
-                );
-                (Some(RenderPass::SubPass(Pass { frame: self })), n)
+    pub fn next_pass<'f>(&'f mut self) -> Result<Option<RenderPass<'f, 'a>>, RendererError> {
+        let number_of_stages = self.render_system.graph.num_passes();
+        let current = self.stage;
+        self.stage += 1;
+        match current {
+            0 => {
+                self.begin_stage(0)?;
+                Ok(Some(RenderPass::SubPass(Pass { frame: self, stage: 0 })))
             }
-            n if n == self.number_of_stages => {
-                let command_buffer = self.command_buffer
-                    .take()
-                    .unwrap()
-                    .end_render_pass()
-                    .unwrap()
-                    .build()
-                    .unwrap();
-                let after_main_cb = self.before_main_cb_future
+            n if n < number_of_stages => {
+                self.end_scope(n - 1)?;
+                self.command_buffer = Some(self.take_command_buffer()?.next_subpass(true)?);
+                self.begin_stage(n)?;
+                Ok(Some(RenderPass::SubPass(Pass { frame: self, stage: n })))
+            }
+            n if n == number_of_stages => {
+                self.end_scope(n - 1)?;
+                let command_buffer = self.take_command_buffer()?.end_render_pass()?.build()?;
+                let queue = self.render_system.queue.clone();
+                let mut after_main_cb: Box<GpuFuture> = Box::new(self.before_main_cb_future
                     .take()
-                    .unwrap()
-                    .then_execute(self.render_system.queue.clone(), command_buffer)
-                    .unwrap();
-                return (Some(RenderPass::Finished(Box::new(after_main_cb))), n);
+                    .ok_or(RendererError::FrameAborted)?
+                    .then_execute(queue.clone(), command_buffer)?);
+                if let Some(ref mut profiler) = self.render_system.profiler {
+                    after_main_cb = Box::new(after_main_cb.then_execute(queue, profiler.end_frame()?)?);
+                }
+                Ok(Some(RenderPass::Finished(after_main_cb)))
             }
-            n => (None, n),
+            _ => Ok(None),
         }
     }
+    fn take_command_buffer(&mut self) -> Result<AutoCommandBufferBuilder, RendererError> {
+        self.command_buffer.take().ok_or(RendererError::FrameAborted)
+    }
+    // Stages are timed as scopes named after the stage
+    fn begin_stage(&mut self, stage: usize) -> Result<(), RendererError> {
+        let name = self.render_system.graph.pass_name(stage).unwrap().to_string();
+        self.begin_scope(&name, stage)
+    }
+    fn begin_scope(&mut self, name: &str, stage: usize) -> Result<(), RendererError> {
+        let subpass = self.render_system.get_subpass(stage as u32).unwrap();
+        let command_buffer = match self.render_system.profiler {
+            Some(ref mut profiler) => profiler.begin_scope(name, &subpass, &self.framebuffer)?,
+            None => None,
+        };
+        self.execute_query(command_buffer)
+    }
+    fn end_scope(&mut self, stage: usize) -> Result<(), RendererError> {
+        let subpass = self.render_system.get_subpass(stage as u32).unwrap();
+        let command_buffer = match self.render_system.profiler {
+            Some(ref mut profiler) => profiler.end_scope(&subpass, &self.framebuffer)?,
+            None => None,
+        };
+        self.execute_query(command_buffer)
+    }
+    fn execute_query(&mut self, command_buffer: Option<QueryCommandBuffer>) -> Result<(), RendererError> {
+        if let Some(command_buffer) = command_buffer {
+            let builder = self.take_command_buffer()?;
+            unsafe {
+                self.command_buffer = Some(builder.execute_commands(command_buffer)?);
+            }
+        }
+        Ok(())
+    }
 }
 
 pub enum RenderPass<'f, 's: 'f> {
@@ -206,24 +388,50 @@ pub enum RenderPass<'f, 's: 'f> {
 
 pub struct Pass<'f, 's: 'f> {
     frame: &'f mut Frame<'s>,
+    stage: usize,
 }
 
 impl<'f, 's: 'f> Pass<'f, 's> {
     #[inline]
-    pub fn execute<C>(&mut self, command_buffer: C)
+    pub fn execute<C>(&mut self, command_buffer: C) -> Result<(), RendererError>
     where
         C: CommandBuffer + Send + Sync + 'static,
     {
+        let builder = self.frame.take_command_buffer()?;
         unsafe {
-            self.frame.command_buffer = Some(
-                self.frame
-                    .command_buffer
-                    .take()
-                    .unwrap()
-                    .execute_commands(command_buffer)
-                    .unwrap(),
-            );
+            self.frame.command_buffer = Some(builder.execute_commands(command_buffer)?);
         }
+        Ok(())
+    }
+    // Times everything `scope` records on the GPU when the render system has a
+    // profiler, the scope shows up in its reports nested inside this stage
+    pub fn scope<R, F>(&mut self, name: &str, scope: F) -> Result<R, RendererError>
+    where
+        F: FnOnce(&mut Self) -> Result<R, RendererError>,
+    {
+        self.frame.begin_scope(name, self.stage)?;
+        // Closed even when `scope` fails, so the scopes opened after it still nest
+        let result = scope(self);
+        self.frame.end_scope(self.stage)?;
+        result
+    }
+    // The name the render graph gives this stage
+    pub fn stage(&self) -> &str {
+        self.frame.render_system.graph.pass_name(self.stage).unwrap()
+    }
+    pub fn stage_index(&self) -> usize {
+        self.stage
+    }
+    // Secondary command buffers passed to `execute` must be created for this subpass
+    pub fn subpass(&self) -> Subpass<Arc<RenderPassAbstract + Send + Sync>> {
+        self.frame.render_system.get_subpass(self.stage as u32).unwrap()
+    }
+    pub fn queue(&self) -> &Arc<Queue> {
+        &self.frame.render_system.queue
+    }
+    // The gbuffer images bound to this frame, read by the lighting systems
+    pub fn gbuffer(&self) -> &GBuffer {
+        &self.frame.render_system.gbuffer
     }
     pub fn viewport_dimensions(&self) -> [u32; 2] {
         let dims = self.frame.framebuffer.dimensions();
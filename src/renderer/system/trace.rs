@@ -0,0 +1,128 @@
+use renderer::system::profiler::{CpuRecord, TimingReport};
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+use std::path::Path;
+use std::time::{Duration, Instant};
+
+const CPU_PROCESS: u32 = 1;
+const GPU_PROCESS: u32 = 2;
+
+// Collects CPU scopes and GPU timing reports into the trace event format read by
+// chrome://tracing and Perfetto. CPU threads and GPU frames are shown as separate
+// processes, GPU frames start at the time the CPU began recording them since the
+// two clocks can not be compared directly
+pub struct ChromeTrace {
+    origin: Instant,
+    events: Vec<TraceEvent>,
+}
+
+struct TraceEvent {
+    name: String,
+    category: &'static str,
+    pid: u32,
+    tid: usize,
+    // Microseconds from the origin
+    start: f64,
+    duration: f64,
+}
+
+impl ChromeTrace {
+    // Events are placed relative to `origin`, usually `CpuProfiler::origin`
+    pub fn new(origin: Instant) -> Self {
+        Self {
+            origin,
+            events: Vec::new(),
+        }
+    }
+    pub fn len(&self) -> usize {
+        self.events.len()
+    }
+    pub fn is_empty(&self) -> bool {
+        self.events.is_empty()
+    }
+    // Records must come from a `CpuProfiler` with the same origin
+    pub fn add_cpu(&mut self, records: &[CpuRecord]) {
+        for record in records {
+            self.events.push(TraceEvent {
+                name: record.name.clone(),
+                category: "cpu",
+                pid: CPU_PROCESS,
+                tid: record.thread,
+                start: micros(record.start),
+                duration: micros(record.duration),
+            });
+        }
+    }
+    pub fn add_gpu(&mut self, report: &TimingReport) {
+        let offset = if report.recorded > self.origin {
+            micros(report.recorded.duration_since(self.origin))
+        } else {
+            0.0
+        };
+        for scope in &report.scopes {
+            self.events.push(TraceEvent {
+                name: scope.name.clone(),
+                category: "gpu",
+                pid: GPU_PROCESS,
+                tid: 0,
+                start: offset + scope.start / 1e3,
+                duration: scope.duration / 1e3,
+            });
+        }
+    }
+
+    pub fn write<W: Write>(&self, mut writer: W) -> io::Result<()> {
+        write!(writer, "{{\"traceEvents\":[")?;
+        write!(
+            writer,
+            "{{\"name\":\"process_name\",\"ph\":\"M\",\"pid\":{},\"args\":{{\"name\":\"CPU\"}}}},",
+            CPU_PROCESS
+        )?;
+        write!(
+            writer,
+            "{{\"name\":\"process_name\",\"ph\":\"M\",\"pid\":{},\"args\":{{\"name\":\"GPU\"}}}}",
+            GPU_PROCESS
+        )?;
+        for event in &self.events {
+            write!(
+                writer,
+                ",{{\"name\":\"{}\",\"cat\":\"{}\",\"ph\":\"X\",\"ts\":{:.3},\"dur\":{:.3},\"pid\":{},\"tid\":{}}}",
+                escape(&event.name),
+                event.category,
+                event.start,
+                event.duration,
+                event.pid,
+                event.tid
+            )?;
+        }
+        write!(writer, "],\"displayTimeUnit\":\"ms\"}}")
+    }
+    pub fn to_json(&self) -> String {
+        let mut bytes = Vec::new();
+        self.write(&mut bytes).unwrap();
+        String::from_utf8(bytes).unwrap()
+    }
+    pub fn save<P: AsRef<Path>>(&self, path: P) -> io::Result<()> {
+        let mut writer = BufWriter::new(File::create(path)?);
+        self.write(&mut writer)?;
+        writer.flush()
+    }
+}
+
+fn micros(duration: Duration) -> f64 {
+    duration.as_secs() as f64 * 1e6 + f64::from(duration.subsec_nanos()) / 1e3
+}
+
+fn escape(name: &str) -> String {
+    let mut escaped = String::with_capacity(name.len());
+    for c in name.chars() {
+        match c {
+            '"' => escaped.push_str("\\\""),
+            '\\' => escaped.push_str("\\\\"),
+            '\n' => escaped.push_str("\\n"),
+            c if (c as u32) < 0x20 => escaped.push_str(&format!("\\u{:04x}", c as u32)),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
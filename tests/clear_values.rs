@@ -0,0 +1,77 @@
+// Checks gbuffer attachments are cleared with the render graph's clear values by
+// reading a stored attachment back after an empty frame. Skips when there is no
+// Vulkan driver
+extern crate vulkan_renderer;
+extern crate vulkano;
+
+use vulkan_renderer::renderer::system::gbuffer::{GBufferBuilder, DEPTH, DIFFUSE};
+use vulkan_renderer::renderer::system::offscreen::{headless_queue, CpuImage, OffscreenTarget};
+use vulkan_renderer::renderer::system::render_system::{deffered_lighting_graph, DepthMode, RenderPass,
+                                                       RenderSystem};
+use vulkano::format::{ClearValue, Format};
+use vulkano::sync::now;
+
+const FORMAT: Format = Format::R8G8B8A8Unorm;
+
+fn render_diffuse(render_system: &mut RenderSystem, target: &OffscreenTarget) -> CpuImage {
+    let device = render_system.queue().device().clone();
+    let mut finished = None;
+    {
+        let mut frame = render_system.frame(now(device), target.image()).unwrap();
+        while let Some(pass) = frame.next_pass().unwrap() {
+            if let RenderPass::Finished(future) = pass {
+                finished = Some(future);
+            }
+        }
+    }
+    target
+        .read_attachment(finished.unwrap(), render_system.gbuffer(), DIFFUSE)
+        .unwrap()
+}
+
+fn assert_cleared_to(image: &CpuImage, color: [f32; 4]) {
+    for pixel in &image.pixels {
+        for channel in 0..4 {
+            // 10 bits per colour channel
+            assert!((pixel[channel] - color[channel]).abs() < 2e-3, "{:?} != {:?}", pixel, color);
+        }
+    }
+}
+
+#[test]
+fn gbuffer_is_cleared_with_the_graph_values() {
+    let queue = match headless_queue() {
+        Some(queue) => queue,
+        None => {
+            eprintln!("skipping, no Vulkan driver available");
+            return;
+        }
+    };
+    let gbuffer = GBufferBuilder::new_default();
+    let mut graph = deffered_lighting_graph(FORMAT, &gbuffer).unwrap();
+    graph.set_stored(DIFFUSE, true).unwrap();
+    graph.set_clear_value(DIFFUSE, [0.25, 0.5, 0.75, 1.0].into()).unwrap();
+    let mut render_system =
+        RenderSystem::new(queue.clone(), graph, gbuffer.build_no_dims(queue.clone()).unwrap()).unwrap();
+    let target = OffscreenTarget::new(queue.clone(), [16, 16], FORMAT).unwrap();
+
+    // The gbuffer takes the graph's value over its own
+    match render_system.gbuffer().clear_value(DIFFUSE) {
+        Some(ClearValue::Float(color)) => assert_eq!(color, [0.25, 0.5, 0.75, 1.0]),
+        other => panic!("unexpected clear value {:?}", other),
+    }
+    assert_cleared_to(&render_diffuse(&mut render_system, &target), [0.25, 0.5, 0.75, 1.0]);
+
+    render_system
+        .set_clear_value(DIFFUSE, [1.0, 0.0, 0.5, 0.0].into())
+        .unwrap();
+    assert_cleared_to(&render_diffuse(&mut render_system, &target), [1.0, 0.0, 0.5, 0.0]);
+    assert!(render_system.set_clear_value("missing", [0.0; 4].into()).is_err());
+
+    render_system.set_depth_mode(DepthMode::Reversed);
+    let depth = render_system.graph().attachment_index(DEPTH).unwrap();
+    match render_system.graph().clear_values()[depth] {
+        ClearValue::Depth(depth) => assert_eq!(depth, 0.0),
+        other => panic!("unexpected depth clear value {:?}", other),
+    }
+}
@@ -0,0 +1,202 @@
+// Drives `DeviceRecovery` through simulated device losses. Vulkan gives no way to
+// reset a device on demand, so `SimulatedLoss` makes frames fail the way a reset
+// would and the tests check everything is rebuilt on a fresh device. Skips when
+// there is no Vulkan driver
+extern crate vulkan_renderer;
+extern crate vulkano;
+
+use std::cell::Cell;
+use std::rc::Rc;
+use std::sync::Arc;
+use vulkan_renderer::renderer::system::device::{DeviceSelector, InstanceBuilder};
+use vulkan_renderer::renderer::system::drawing_system::DrawSystem;
+use vulkan_renderer::renderer::system::error::RendererError;
+use vulkan_renderer::renderer::system::gbuffer::GBufferBuilder;
+use vulkan_renderer::renderer::system::lighting_system::AmbientLightingSystem;
+use vulkan_renderer::renderer::system::offscreen::{headless_queue, OffscreenTarget};
+use vulkan_renderer::renderer::system::recovery::{DeviceRecovery, DeviceSource, HeadlessSource};
+use vulkan_renderer::renderer::system::render_system::{deffered_lighting_graph, RenderPass, RenderSystem,
+                                                       GEOMETRY_STAGE, LIGHTING_STAGE};
+use vulkano::device::{Device, Queue};
+use vulkano::format::Format;
+use vulkano::sync::now;
+
+const FORMAT: Format = Format::R8G8B8A8Unorm;
+const DIMS: [u32; 2] = [32, 32];
+
+// Counts the devices handed out
+struct CountingSource {
+    inner: HeadlessSource,
+    created: Rc<Cell<usize>>,
+}
+
+impl DeviceSource for CountingSource {
+    fn create_queue(&mut self) -> Result<Arc<Queue>, RendererError> {
+        self.created.set(self.created.get() + 1);
+        self.inner.create_queue()
+    }
+}
+
+// Stands in for the driver, reporting the device as lost for the next `losses` checks
+struct SimulatedLoss {
+    losses: Cell<usize>,
+}
+
+impl SimulatedLoss {
+    fn new(losses: usize) -> Self {
+        Self {
+            losses: Cell::new(losses),
+        }
+    }
+    fn check(&self) -> Result<(), RendererError> {
+        match self.losses.get() {
+            0 => Ok(()),
+            n => {
+                self.losses.set(n - 1);
+                Err(RendererError::DeviceLost)
+            }
+        }
+    }
+}
+
+struct Resources {
+    draw: DrawSystem,
+    ambient: AmbientLightingSystem,
+    target: OffscreenTarget,
+    device: Arc<Device>,
+}
+
+fn create_resources(render_system: &RenderSystem) -> Result<Resources, RendererError> {
+    let queue = render_system.queue().clone();
+    let geometry = render_system.subpass(GEOMETRY_STAGE).unwrap();
+    let lighting = render_system.subpass(LIGHTING_STAGE).unwrap();
+    Ok(Resources {
+        draw: DrawSystem::new_geometry_draw(queue.clone(), geometry)?,
+        ambient: AmbientLightingSystem::new(queue.clone(), lighting)?,
+        target: OffscreenTarget::new(queue.clone(), DIMS, FORMAT)?,
+        device: queue.device().clone(),
+    })
+}
+
+// None when there is no driver to test with
+fn new_recovery(created: Rc<Cell<usize>>, hook_runs: Rc<Cell<usize>>) -> Option<DeviceRecovery<Resources>> {
+    headless_queue()?;
+    let source = CountingSource {
+        inner: HeadlessSource::new(InstanceBuilder::new().build().unwrap(), DeviceSelector::new()),
+        created,
+    };
+    let gbuffer = GBufferBuilder::new_default();
+    let graph = deffered_lighting_graph(FORMAT, &gbuffer).unwrap();
+    let recovery = DeviceRecovery::new(source, graph, gbuffer, move |render_system| {
+        hook_runs.set(hook_runs.get() + 1);
+        create_resources(render_system)
+    });
+    Some(recovery.unwrap())
+}
+
+// Clears the gbuffer, adds ambient light and reads the result back
+fn render(render_system: &mut RenderSystem, resources: &mut Resources) -> Result<[f32; 4], RendererError> {
+    let queue = render_system.queue().clone();
+    let mut finished = None;
+    {
+        let mut frame = render_system.frame(now(queue.device().clone()), resources.target.image())?;
+        while let Some(pass) = frame.next_pass()? {
+            match pass {
+                RenderPass::SubPass(mut pass) => {
+                    if pass.stage() == GEOMETRY_STAGE {
+                        let builder = resources.draw.command_buffer_builder()?;
+                        pass.execute(builder.build()?)?;
+                    } else {
+                        let command_buffer = resources
+                            .ambient
+                            .draw(pass.dynamic_state(), pass.gbuffer(), [0.5, 0.5, 0.5])?;
+                        pass.execute(command_buffer)?;
+                    }
+                }
+                RenderPass::Finished(future) => finished = Some(future),
+            }
+        }
+    }
+    let image = resources.target.read(finished.unwrap())?;
+    Ok(image.pixel(0, 0))
+}
+
+#[test]
+fn recreates_everything_after_a_loss() {
+    let created = Rc::new(Cell::new(0));
+    let hook_runs = Rc::new(Cell::new(0));
+    let mut recovery = match new_recovery(created.clone(), hook_runs.clone()) {
+        Some(recovery) => recovery,
+        None => {
+            eprintln!("skipping, no Vulkan driver available");
+            return;
+        }
+    };
+    let old_device = recovery.queue().device().clone();
+    let loss = SimulatedLoss::new(1);
+    let pixel = recovery
+        .frame(|render_system, resources| {
+            loss.check()?;
+            render(render_system, resources)
+        })
+        .unwrap();
+
+    assert_eq!(recovery.recoveries(), 1);
+    assert_eq!(created.get(), 2);
+    assert_eq!(hook_runs.get(), 2);
+    assert!(!Arc::ptr_eq(&old_device, recovery.queue().device()));
+    let resources = recovery.resources().unwrap();
+    assert!(Arc::ptr_eq(&resources.device, recovery.queue().device()));
+    assert_eq!(resources.target.dims(), DIMS);
+    assert_eq!(recovery.render_system().gbuffer().dims(), DIMS);
+    // Nothing was drawn so the ambient light lands on the black diffuse clear value
+    assert_eq!(pixel, [0.0, 0.0, 0.0, 1.0]);
+
+    // Rendering carries on as normal on the new device
+    recovery.frame(render).unwrap();
+    assert_eq!(recovery.recoveries(), 1);
+}
+
+#[test]
+fn gives_up_when_the_new_device_is_lost_too() {
+    let created = Rc::new(Cell::new(0));
+    let hook_runs = Rc::new(Cell::new(0));
+    let mut recovery = match new_recovery(created.clone(), hook_runs.clone()) {
+        Some(recovery) => recovery,
+        None => {
+            eprintln!("skipping, no Vulkan driver available");
+            return;
+        }
+    };
+    let loss = SimulatedLoss::new(2);
+    match recovery.frame(|_, _| loss.check()) {
+        Err(RendererError::DeviceLost) => {}
+        result => panic!("expected a lost device, got {:?}", result),
+    }
+    assert_eq!(recovery.recoveries(), 1);
+    assert_eq!(created.get(), 2);
+
+    // The next frame runs on the device created by the last recovery
+    recovery.frame(|_, _| loss.check()).unwrap();
+    assert_eq!(recovery.recoveries(), 1);
+}
+
+#[test]
+fn other_errors_are_returned_without_recovering() {
+    let created = Rc::new(Cell::new(0));
+    let hook_runs = Rc::new(Cell::new(0));
+    let mut recovery = match new_recovery(created.clone(), hook_runs.clone()) {
+        Some(recovery) => recovery,
+        None => {
+            eprintln!("skipping, no Vulkan driver available");
+            return;
+        }
+    };
+    match recovery.frame(|_, _| -> Result<(), RendererError> { Err(RendererError::FrameAborted) }) {
+        Err(RendererError::FrameAborted) => {}
+        result => panic!("expected an aborted frame, got {:?}", result),
+    }
+    assert_eq!(recovery.recoveries(), 0);
+    assert_eq!(created.get(), 1);
+    assert_eq!(hook_runs.get(), 1);
+}
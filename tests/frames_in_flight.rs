@@ -0,0 +1,153 @@
+// Records frames ahead of the GPU with `FramesInFlight`, alternating between two
+// output images the way a swapchain does, each frame ordered after the previous
+// one since they share the gbuffer. Skips when there is no Vulkan driver
+extern crate vulkan_renderer;
+extern crate vulkano;
+
+use vulkan_renderer::renderer::system::frames::{FramesInFlight, UniformRing};
+use vulkan_renderer::renderer::system::gbuffer::{GBufferBuilder, DIFFUSE};
+use vulkan_renderer::renderer::system::lighting_system::AmbientLightingSystem;
+use vulkan_renderer::renderer::system::offscreen::{headless_queue, OffscreenTarget};
+use vulkan_renderer::renderer::system::render_system::{deffered_lighting_graph, RenderPass, RenderSystem,
+                                                       LIGHTING_STAGE};
+use vulkano::format::Format;
+use vulkano::sync::now;
+
+const FORMAT: Format = Format::R8G8B8A8Unorm;
+const FRAMES_IN_FLIGHT: usize = 2;
+// Enough fullscreen draws at this size that the GPU is usually still on the first
+// frame when the CPU has submitted the second
+const HEAVY_DIMS: [u32; 2] = [2048, 2048];
+const HEAVY_DRAWS: usize = 64;
+
+#[test]
+fn frames_cycle_through_slots_and_cached_framebuffers() {
+    let queue = match headless_queue() {
+        Some(queue) => queue,
+        None => {
+            eprintln!("skipping, no Vulkan driver available");
+            return;
+        }
+    };
+    let gbuffer = GBufferBuilder::new_default();
+    let graph = deffered_lighting_graph(FORMAT, &gbuffer).unwrap();
+    let mut render_system =
+        RenderSystem::new(queue.clone(), graph, gbuffer.build_no_dims(queue.clone()).unwrap()).unwrap();
+    let targets = [
+        OffscreenTarget::new(queue.clone(), [64, 48], FORMAT).unwrap(),
+        OffscreenTarget::new(queue.clone(), [64, 48], FORMAT).unwrap(),
+    ];
+    let mut frames = FramesInFlight::new(FRAMES_IN_FLIGHT);
+    let ring = UniformRing::new(queue.device().clone(), &frames, [0.0f32; 4]).unwrap();
+
+    for i in 0..6 {
+        let slot = frames.begin().unwrap();
+        assert_eq!(slot, i % FRAMES_IN_FLIGHT);
+        // The GPU may still be on the previous frame, but never on this slot
+        ring.write(slot, [i as f32; 4]).unwrap();
+
+        let mut finished = None;
+        {
+            let target = &targets[i % targets.len()];
+            let mut frame = render_system
+                .frame(frames.previous_frame(queue.device().clone()), target.image())
+                .unwrap();
+            while let Some(pass) = frame.next_pass().unwrap() {
+                if let RenderPass::Finished(future) = pass {
+                    finished = Some(future);
+                }
+            }
+        }
+        frames.end(finished.unwrap()).unwrap();
+    }
+    frames.wait_idle().unwrap();
+
+    assert_eq!(frames.frame_number(), 6);
+    assert_eq!(render_system.cached_framebuffers(), targets.len());
+    assert_eq!(*ring.get(0).read().unwrap(), [4.0; 4]);
+    assert_eq!(*ring.get(1).read().unwrap(), [5.0; 4]);
+
+    // A new output size rebuilds the gbuffer, which the cached framebuffers refer to
+    let resized = OffscreenTarget::new(queue.clone(), [32, 32], FORMAT).unwrap();
+    frames.begin().unwrap();
+    let mut finished = None;
+    {
+        let mut frame = render_system
+            .frame(frames.previous_frame(queue.device().clone()), resized.image())
+            .unwrap();
+        while let Some(pass) = frame.next_pass().unwrap() {
+            if let RenderPass::Finished(future) = pass {
+                finished = Some(future);
+            }
+        }
+    }
+    frames.end(finished.unwrap()).unwrap();
+    frames.wait_idle().unwrap();
+    assert_eq!(render_system.cached_framebuffers(), 1);
+}
+
+#[test]
+fn next_frame_is_submitted_while_the_previous_runs() {
+    let queue = match headless_queue() {
+        Some(queue) => queue,
+        None => {
+            eprintln!("skipping, no Vulkan driver available");
+            return;
+        }
+    };
+    let gbuffer = GBufferBuilder::new_default();
+    let mut graph = deffered_lighting_graph(FORMAT, &gbuffer).unwrap();
+    // Without geometry the ambient light is added onto the cleared diffuse colour
+    graph.set_clear_value(DIFFUSE, [1.0, 1.0, 1.0, 1.0].into()).unwrap();
+    let mut render_system =
+        RenderSystem::new(queue.clone(), graph, gbuffer.build_no_dims(queue.clone()).unwrap()).unwrap();
+    let ambient = AmbientLightingSystem::new(queue.clone(), render_system.subpass(LIGHTING_STAGE).unwrap()).unwrap();
+    let targets = [
+        OffscreenTarget::new(queue.clone(), HEAVY_DIMS, FORMAT).unwrap(),
+        OffscreenTarget::new(queue.clone(), HEAVY_DIMS, FORMAT).unwrap(),
+    ];
+    // The first frame adds a little light many times, the second a lot once
+    let draws = [(HEAVY_DRAWS, 1.0 / 128.0), (1, 0.25)];
+
+    let mut frames = FramesInFlight::new(FRAMES_IN_FLIGHT);
+    for (target, &(count, light)) in targets.iter().zip(draws.iter()) {
+        frames.begin().unwrap();
+        let mut finished = None;
+        {
+            let mut frame = render_system
+                .frame(frames.previous_frame(queue.device().clone()), target.image())
+                .unwrap();
+            while let Some(pass) = frame.next_pass().unwrap() {
+                match pass {
+                    RenderPass::SubPass(mut pass) => {
+                        if pass.stage() == LIGHTING_STAGE {
+                            for _ in 0..count {
+                                let command_buffer = ambient
+                                    .draw(pass.dynamic_state(), pass.gbuffer(), [light; 3])
+                                    .unwrap();
+                                pass.execute(command_buffer).unwrap();
+                            }
+                        }
+                    }
+                    RenderPass::Finished(future) => finished = Some(future),
+                }
+            }
+        }
+        // Both frames use the same gbuffer images, the second is only accepted
+        // because it is ordered after the first
+        frames.end(finished.unwrap()).unwrap();
+    }
+    // Whether the first frame is still running depends on the driver
+    assert!(frames.in_flight() <= 2);
+    frames.wait_idle().unwrap();
+    assert_eq!(frames.in_flight(), 0);
+
+    // 64 adds of 2/255 and a single 0.25
+    for (target, &expected) in targets.iter().zip([128.0 / 255.0, 0.25].iter()) {
+        let image = target.read(now(queue.device().clone())).unwrap();
+        for &(x, y) in [(0, 0), (1024, 1024), (2047, 2047)].iter() {
+            let pixel = image.pixel(x, y);
+            assert!((pixel[0] - expected).abs() < 0.01, "{:?} at {},{}", pixel, x, y);
+        }
+    }
+}
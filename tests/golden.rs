@@ -0,0 +1,275 @@
+// Renders canonical scenes through `RenderSystem` and compares them with the
+// reference images in tests/golden. Runs on any Vulkan driver including software
+// ones such as lavapipe, and skips when there is none.
+//
+// Regenerate the references with `UPDATE_GOLDEN=1 cargo test --test golden` after
+// an intended change. Failing tests write the actual and diff images to
+// target/golden for inspection
+extern crate nalgebra;
+extern crate vulkan_renderer;
+extern crate vulkano;
+
+use nalgebra::{Point3, Vector3};
+use std::env;
+use std::fs;
+use std::path::PathBuf;
+use std::sync::Arc;
+use vulkan_renderer::camera::Camera;
+use vulkan_renderer::math::Perspective;
+use vulkan_renderer::renderer::system::camera_uniform::{CameraUniformPool, ModelTransform};
+use vulkan_renderer::renderer::system::drawing_system::{DrawSystem, Vertex};
+use vulkan_renderer::renderer::system::gbuffer::GBufferBuilder;
+use vulkan_renderer::renderer::system::image_diff;
+use vulkan_renderer::renderer::system::lighting_system::{AmbientLightingSystem, DirectionalLightingSystem,
+                                                         PointLightingSystem};
+use vulkan_renderer::renderer::system::offscreen::{headless_queue, CpuImage, OffscreenTarget};
+use vulkan_renderer::renderer::system::render_system::{deffered_lighting_graph, RenderPass, RenderSystem,
+                                                       GEOMETRY_STAGE, LIGHTING_STAGE};
+use vulkano::buffer::{BufferUsage, CpuAccessibleBuffer};
+use vulkano::device::Queue;
+use vulkano::format::Format;
+use vulkano::sync::now;
+
+const WIDTH: u32 = 160;
+const HEIGHT: u32 = 120;
+// Software and hardware drivers round differently, allow a couple of steps per channel
+const PIXEL_TOLERANCE: f32 = 3.0 / 255.0;
+const MAX_MISMATCHED_FRACTION: f32 = 0.002;
+const MIN_SSIM: f32 = 0.98;
+
+struct Scene {
+    name: &'static str,
+    ambient: [f32; 3],
+    directional: Option<(Vector3<f32>, [f32; 3])>,
+    point: Option<(Point3<f32>, f32, [f32; 3])>,
+}
+
+fn vertex(position: [f32; 3], normal: [f32; 3], colour: [f32; 3]) -> Vertex {
+    Vertex {
+        position,
+        normal,
+        colour,
+        specular: 0.5,
+    }
+}
+
+// A floor quad with a unit cube standing on it
+fn scene_vertices() -> Vec<Vertex> {
+    let floor = [0.6, 0.6, 0.6];
+    let mut vertices = vec![
+        vertex([-4.0, 0.0, -4.0], [0.0, 1.0, 0.0], floor),
+        vertex([-4.0, 0.0, 4.0], [0.0, 1.0, 0.0], floor),
+        vertex([4.0, 0.0, 4.0], [0.0, 1.0, 0.0], floor),
+        vertex([-4.0, 0.0, -4.0], [0.0, 1.0, 0.0], floor),
+        vertex([4.0, 0.0, 4.0], [0.0, 1.0, 0.0], floor),
+        vertex([4.0, 0.0, -4.0], [0.0, 1.0, 0.0], floor),
+    ];
+    let cube = [0.8, 0.3, 0.2];
+    let faces: [([f32; 3], [f32; 3], [f32; 3]); 6] = [
+        ([1.0, 0.0, 0.0], [0.0, 1.0, 0.0], [0.0, 0.0, 1.0]),
+        ([-1.0, 0.0, 0.0], [0.0, 0.0, 1.0], [0.0, 1.0, 0.0]),
+        ([0.0, 1.0, 0.0], [0.0, 0.0, 1.0], [1.0, 0.0, 0.0]),
+        ([0.0, -1.0, 0.0], [1.0, 0.0, 0.0], [0.0, 0.0, 1.0]),
+        ([0.0, 0.0, 1.0], [1.0, 0.0, 0.0], [0.0, 1.0, 0.0]),
+        ([0.0, 0.0, -1.0], [0.0, 1.0, 0.0], [1.0, 0.0, 0.0]),
+    ];
+    for &(n, u, v) in faces.iter() {
+        let corner = |su: f32, sv: f32| {
+            [
+                (n[0] + u[0] * su + v[0] * sv) * 0.5,
+                (n[1] + u[1] * su + v[1] * sv) * 0.5 + 0.5,
+                (n[2] + u[2] * su + v[2] * sv) * 0.5,
+            ]
+        };
+        for &(su, sv) in [(-1.0, -1.0), (1.0, -1.0), (1.0, 1.0), (-1.0, -1.0), (1.0, 1.0), (-1.0, 1.0)].iter() {
+            vertices.push(vertex(corner(su, sv), n, cube));
+        }
+    }
+    vertices
+}
+
+fn render(queue: Arc<Queue>, scene: &Scene) -> CpuImage {
+    let target = OffscreenTarget::new(queue.clone(), [WIDTH, HEIGHT], Format::R8G8B8A8Unorm).unwrap();
+    let gbuffer = GBufferBuilder::new_default();
+    let graph = deffered_lighting_graph(target.format(), &gbuffer).unwrap();
+    let mut render_system =
+        RenderSystem::new(queue.clone(), graph, gbuffer.build_no_dims(queue.clone()).unwrap()).unwrap();
+
+    let geometry_subpass = render_system.subpass(GEOMETRY_STAGE).unwrap();
+    let lighting_subpass = render_system.subpass(LIGHTING_STAGE).unwrap();
+    let draw_system = DrawSystem::new_geometry_draw(queue.clone(), geometry_subpass).unwrap();
+    let ambient = AmbientLightingSystem::new(queue.clone(), lighting_subpass.clone()).unwrap();
+    let directional = DirectionalLightingSystem::new(queue.clone(), lighting_subpass.clone()).unwrap();
+    let point = PointLightingSystem::new(queue.clone(), lighting_subpass).unwrap();
+
+    let camera = Camera::new(
+        Point3::new(3.0, 2.5, 4.0),
+        Point3::new(0.0, 0.5, 0.0),
+        Vector3::new(0.0, 1.0, 0.0),
+        Perspective::new(WIDTH as f32 / HEIGHT as f32, 1.0, 0.1, 100.0),
+    );
+    let camera_pool = CameraUniformPool::new(queue.device().clone());
+    let vertices = CpuAccessibleBuffer::from_iter(
+        queue.device().clone(),
+        BufferUsage::all(),
+        scene_vertices().into_iter(),
+    ).unwrap();
+
+    let mut finished = None;
+    {
+        let mut frame = render_system
+            .frame(now(queue.device().clone()), target.image())
+            .unwrap();
+        loop {
+            match frame.next_pass().unwrap() {
+                Some(RenderPass::SubPass(mut pass)) => {
+                    let stage = pass.stage().to_string();
+                    let dynamic_state = pass.dynamic_state();
+                    if stage == GEOMETRY_STAGE {
+                        let camera_set = draw_system.camera_set(camera_pool.next(&camera)).unwrap();
+                        let builder = draw_system
+                            .draw(
+                                draw_system.command_buffer_builder().unwrap(),
+                                dynamic_state,
+                                vertices.clone(),
+                                camera_set,
+                                ModelTransform::identity(),
+                            )
+                            .unwrap();
+                        pass.execute(builder.build().unwrap()).unwrap();
+                    } else if stage == LIGHTING_STAGE {
+                        let command_buffer = ambient
+                            .draw(dynamic_state.clone(), pass.gbuffer(), scene.ambient)
+                            .unwrap();
+                        pass.execute(command_buffer).unwrap();
+                        if let Some((direction, color)) = scene.directional {
+                            let command_buffer = directional.draw(
+                                dynamic_state.clone(),
+                                pass.gbuffer(),
+                                camera_pool.next(&camera),
+                                direction,
+                                color,
+                            ).unwrap();
+                            pass.execute(command_buffer).unwrap();
+                        }
+                        if let Some((position, radius, color)) = scene.point {
+                            let command_buffer = point.draw(
+                                dynamic_state,
+                                pass.gbuffer(),
+                                camera_pool.next(&camera),
+                                position,
+                                radius,
+                                color,
+                            ).unwrap();
+                            pass.execute(command_buffer).unwrap();
+                        }
+                    }
+                }
+                Some(RenderPass::Finished(future)) => finished = Some(future),
+                None => break,
+            }
+        }
+    }
+    target.read(finished.unwrap()).unwrap()
+}
+
+fn golden_dir() -> PathBuf {
+    PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("tests").join("golden")
+}
+
+fn output_dir() -> PathBuf {
+    PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("target").join("golden")
+}
+
+fn check_golden(name: &str, actual: &CpuImage) {
+    let reference_path = golden_dir().join(format!("{}.png", name));
+    if env::var_os("UPDATE_GOLDEN").is_some() {
+        fs::create_dir_all(golden_dir()).unwrap();
+        actual.write_png(&reference_path).unwrap();
+        return;
+    }
+    let expected = CpuImage::read_png(&reference_path).unwrap_or_else(|err| {
+        panic!(
+            "missing reference {}: {}, run with UPDATE_GOLDEN=1 on lavapipe to create it, see tests/golden/README.md",
+            reference_path.display(),
+            err
+        )
+    });
+    // Compare what was written to disk so both sides are quantised the same way
+    let actual = CpuImage {
+        width: actual.width,
+        height: actual.height,
+        pixels: actual
+            .to_rgba8()
+            .chunks(4)
+            .map(|p| [p[0] as f32 / 255.0, p[1] as f32 / 255.0, p[2] as f32 / 255.0, p[3] as f32 / 255.0])
+            .collect(),
+    };
+    let diff = image_diff::compare(&expected, &actual, PIXEL_TOLERANCE).unwrap_or_else(|| {
+        panic!(
+            "{}: expected {}x{}, rendered {}x{}",
+            name, expected.width, expected.height, actual.width, actual.height
+        )
+    });
+    if diff.mismatched_fraction() > MAX_MISMATCHED_FRACTION || diff.ssim < MIN_SSIM {
+        fs::create_dir_all(output_dir()).unwrap();
+        let actual_path = output_dir().join(format!("{}.actual.png", name));
+        let diff_path = output_dir().join(format!("{}.diff.png", name));
+        actual.write_png(&actual_path).unwrap();
+        image_diff::diff_image(&expected, &actual, 8.0)
+            .write_png(&diff_path)
+            .unwrap();
+        panic!(
+            "{} differs from its reference: {} of {} pixels over tolerance, max difference {}, ssim {}. See {} and {}",
+            name,
+            diff.mismatched_pixels,
+            diff.total_pixels,
+            diff.max_difference,
+            diff.ssim,
+            actual_path.display(),
+            diff_path.display()
+        );
+    }
+}
+
+fn run_scene(scene: Scene) {
+    let queue = match headless_queue() {
+        Some(queue) => queue,
+        None => {
+            eprintln!("skipping {}, no Vulkan driver available", scene.name);
+            return;
+        }
+    };
+    let image = render(queue, &scene);
+    check_golden(scene.name, &image);
+}
+
+#[test]
+fn ambient_only() {
+    run_scene(Scene {
+        name: "ambient_only",
+        ambient: [0.5, 0.5, 0.5],
+        directional: None,
+        point: None,
+    });
+}
+
+#[test]
+fn directional_light() {
+    run_scene(Scene {
+        name: "directional_light",
+        ambient: [0.1, 0.1, 0.1],
+        directional: Some((Vector3::new(-0.4, -1.0, -0.3), [0.9, 0.9, 0.8])),
+        point: None,
+    });
+}
+
+#[test]
+fn point_light() {
+    run_scene(Scene {
+        name: "point_light",
+        ambient: [0.05, 0.05, 0.05],
+        directional: None,
+        point: Some((Point3::new(1.5, 1.5, 1.0), 5.0, [1.0, 0.8, 0.6])),
+    });
+}
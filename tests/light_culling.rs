@@ -0,0 +1,148 @@
+// Runs the light culling compute pass for two frames in flight, reads the cluster
+// buffers of both back and compares them with `assign_lights`. Skips when there is
+// no Vulkan driver
+extern crate vulkan_renderer;
+extern crate vulkano;
+
+use std::sync::Arc;
+use vulkan_renderer::camera::Camera;
+use vulkan_renderer::math::{self, ClipSpace, Mat4, Perspective, Point, Vec3};
+use vulkan_renderer::renderer::system::camera_uniform::CameraUniformPool;
+use vulkan_renderer::renderer::system::cluster::{assign_lights, ClusterAssignment, ClusterGrid, ClusterLight,
+                                                 LightCullingSystem, MAX_LIGHTS_PER_CLUSTER};
+use vulkan_renderer::renderer::system::frames::FramesInFlight;
+use vulkan_renderer::renderer::system::offscreen::headless_queue;
+use vulkano::buffer::{BufferUsage, CpuAccessibleBuffer, DeviceLocalBuffer, TypedBufferAccess};
+use vulkano::command_buffer::AutoCommandBufferBuilder;
+use vulkano::device::Queue;
+use vulkano::sync::{now, GpuFuture};
+
+const FRAMES_IN_FLIGHT: usize = 2;
+
+fn read_back(queue: &Arc<Queue>, buffer: Arc<DeviceLocalBuffer<[u32]>>) -> Vec<u32> {
+    let cpu_buffer = CpuAccessibleBuffer::from_iter(
+        queue.device().clone(),
+        BufferUsage::all(),
+        (0..buffer.len()).map(|_| 0u32),
+    ).unwrap();
+    let command_buffer = AutoCommandBufferBuilder::primary_one_time_submit(queue.device().clone(), queue.family())
+        .unwrap()
+        .copy_buffer(buffer, cpu_buffer.clone())
+        .unwrap()
+        .build()
+        .unwrap();
+    now(queue.device().clone())
+        .then_execute(queue.clone(), command_buffer)
+        .unwrap()
+        .then_signal_fence_and_flush()
+        .unwrap()
+        .wait(None)
+        .unwrap();
+    let values = cpu_buffer.read().unwrap().to_vec();
+    values
+}
+
+// A loose grid of lights in front of the camera, shifted per frame
+fn lights(frame: usize) -> Vec<ClusterLight> {
+    let mut lights = Vec::new();
+    for i in 0..48 {
+        let (x, y, z) = ((i % 4) as f32, ((i / 4) % 3) as f32, (i / 12) as f32);
+        let position = Point::new(x * 6.0 - 9.0 + frame as f32, y * 5.0 - 5.0, -4.0 - z * 9.0);
+        lights.push(ClusterLight::new(position, 2.0 + (i % 5) as f32, [1.0, 1.0, 1.0]));
+    }
+    lights
+}
+
+// Distance from a view space light to the view space bounds of a cluster
+fn distance_to_cluster(grid: &ClusterGrid, camera: &Camera<Perspective>, light: &ClusterLight, cluster: usize) -> f32 {
+    let inverse_projection = camera.projection_matrix().try_inverse().unwrap_or_else(Mat4::identity);
+    let (near, far) = camera.depth_range();
+    let x = cluster as u32 % grid.tiles_x;
+    let y = (cluster as u32 / grid.tiles_x) % grid.tiles_y;
+    let slice = cluster as u32 / (grid.tiles_x * grid.tiles_y);
+    let ndc_depths = (near, (near + far) * 0.5);
+    let (min, max) = grid.cluster_bounds(&inverse_projection, ndc_depths, grid.last_slice_far(camera), x, y, slice);
+    let p = light.position_radius;
+    let center = math::transform_point(&camera.view_matrix(), &Point::new(p[0], p[1], p[2]));
+    let mut distance = 0.0f32;
+    for axis in 0..3 {
+        let d = center[axis] - center[axis].max(min[axis]).min(max[axis]);
+        distance += d * d;
+    }
+    distance.sqrt()
+}
+
+#[test]
+fn gpu_culling_matches_assign_lights() {
+    let queue = match headless_queue() {
+        Some(queue) => queue,
+        None => {
+            eprintln!("skipping, no Vulkan driver available");
+            return;
+        }
+    };
+    let grid = ClusterGrid::new(8, 6, 12, 0.5, 60.0);
+    let mut camera: Camera<Perspective> = Camera::new(
+        Point::new(0.0, 0.0, 0.0),
+        Point::new(0.0, 0.0, -1.0),
+        Vec3::new(0.0, 1.0, 0.0),
+        Perspective::new(4.0 / 3.0, 1.0, 0.5, 60.0),
+    );
+    camera.set_clip_space(ClipSpace::Vulkan);
+    let camera_pool = CameraUniformPool::new(queue.device().clone());
+    let mut frames = FramesInFlight::new(FRAMES_IN_FLIGHT);
+    let culling = LightCullingSystem::new(queue.clone(), grid, &frames).unwrap();
+
+    let mut culled = Vec::new();
+    for frame in 0..FRAMES_IN_FLIGHT {
+        let slot = frames.begin().unwrap();
+        let lights = lights(frame);
+        let (command_buffer, buffers) = culling
+            .cull(slot, &camera, camera_pool.next(&camera), &lights)
+            .unwrap();
+        let future = now(queue.device().clone())
+            .then_execute(queue.clone(), command_buffer)
+            .unwrap();
+        frames.end(future).unwrap();
+        culled.push((lights, buffers));
+    }
+    frames.wait_idle().unwrap();
+    // Every slot has its own buffers, the second frame did not overwrite the first
+    assert!(!Arc::ptr_eq(&culled[0].1.counts, &culled[1].1.counts));
+    assert!(!Arc::ptr_eq(&culled[0].1.indices, &culled[1].1.indices));
+
+    for &(ref lights, ref buffers) in culled.iter() {
+        let expected = assign_lights(&grid, &camera, lights);
+        let gpu = ClusterAssignment {
+            counts: read_back(&queue, buffers.counts.clone()),
+            indices: read_back(&queue, buffers.indices.clone()),
+        };
+        assert_eq!(gpu.counts.len(), grid.cluster_count());
+        assert_eq!(gpu.indices.len(), grid.cluster_count() * MAX_LIGHTS_PER_CLUSTER);
+
+        let mut assigned = 0;
+        for cluster in 0..grid.cluster_count() {
+            let (gpu_lights, cpu_lights) = (gpu.lights(cluster), expected.lights(cluster));
+            assigned += cpu_lights.len();
+            if gpu_lights == cpu_lights {
+                continue;
+            }
+            // The GPU and CPU may round a light that just touches a cluster differently
+            let missing = cpu_lights.iter().filter(|&&i| !gpu_lights.contains(&i));
+            for &i in gpu_lights.iter().filter(|&&i| !cpu_lights.contains(&i)).chain(missing) {
+                let light = &lights[i as usize];
+                let radius = light.position_radius[3];
+                let distance = distance_to_cluster(&grid, &camera, light, cluster);
+                assert!(
+                    (distance - radius).abs() < radius * 1e-3,
+                    "cluster {} light {}: gpu {:?}, cpu {:?}",
+                    cluster,
+                    i,
+                    gpu_lights,
+                    cpu_lights
+                );
+            }
+        }
+        assert!(assigned > 0);
+    }
+}
@@ -0,0 +1,250 @@
+// Draws point and spot lights with `LightVolumeSystem` onto a floor seen from
+// above. Each light has a colour channel of its own, so the tests can check that a
+// light only reaches the floor inside its volume and that lights drawn together
+// give the same image as each drawn alone, which fails if a stencil count is left
+// over from one light to the next. Skips when there is no Vulkan driver
+extern crate vulkan_renderer;
+extern crate vulkano;
+
+use std::sync::Arc;
+use vulkan_renderer::camera::Camera;
+use vulkan_renderer::math::{Perspective, Point, Vec3};
+use vulkan_renderer::renderer::system::camera_uniform::{CameraUniformPool, ModelTransform};
+use vulkan_renderer::renderer::system::drawing_system::{DrawSystem, Vertex};
+use vulkan_renderer::renderer::system::gbuffer::GBufferBuilder;
+use vulkan_renderer::renderer::system::light_volume_system::{LightVolumeSystem, PointLight, SpotLight};
+use vulkan_renderer::renderer::system::offscreen::{headless_queue, CpuImage, OffscreenTarget};
+use vulkan_renderer::renderer::system::render_system::{deffered_lighting_stencil_graph, RenderPass, RenderSystem,
+                                                       GEOMETRY_STAGE, LIGHTING_STAGE};
+use vulkano::buffer::{BufferUsage, CpuAccessibleBuffer};
+use vulkano::device::Queue;
+use vulkano::format::Format;
+use vulkano::sync::now;
+
+const SIZE: u32 = 128;
+// Lights are blended into an 8 bit target, allow one rounding step per light
+const TOLERANCE: f32 = 1.5 / 255.0;
+// A pixel covers about 0.09 of the floor, leave more than that around each volume
+const MARGIN: f32 = 0.15;
+
+fn camera() -> Camera<Perspective> {
+    Camera::new(
+        Point::new(0.0, 10.0, 0.0),
+        Point::origin(),
+        Vec3::new(0.0, 0.0, -1.0),
+        Perspective::new(1.0, 1.0, 0.1, 100.0),
+    )
+}
+
+fn red_point() -> PointLight {
+    PointLight {
+        position: Point::new(-2.5, 0.5, 0.0),
+        radius: 2.0,
+        color: [1.0, 0.0, 0.0],
+    }
+}
+
+// Overlaps the red light on screen and on the floor
+fn blue_point() -> PointLight {
+    PointLight {
+        position: Point::new(-1.0, 0.5, 1.0),
+        radius: 2.0,
+        color: [0.0, 0.0, 1.0],
+    }
+}
+
+fn green_spot() -> SpotLight {
+    SpotLight {
+        position: Point::new(2.5, 1.0, 0.0),
+        direction: Vec3::new(0.0, -1.0, 0.0),
+        range: 3.0,
+        inner_angle: 0.3,
+        outer_angle: 0.6,
+        color: [0.0, 1.0, 0.0],
+    }
+}
+
+// A white floor, larger than the camera can see so every pixel lands on it
+fn floor_vertices() -> Vec<Vertex> {
+    let corner = |x: f32, z: f32| Vertex {
+        position: [x, 0.0, z],
+        normal: [0.0, 1.0, 0.0],
+        colour: [1.0, 1.0, 1.0],
+        specular: 0.0,
+    };
+    vec![
+        corner(-8.0, -8.0),
+        corner(-8.0, 8.0),
+        corner(8.0, 8.0),
+        corner(-8.0, -8.0),
+        corner(8.0, 8.0),
+        corner(8.0, -8.0),
+    ]
+}
+
+// The first depth stencil format the device can render to
+fn stencil_format(queue: &Arc<Queue>) -> Format {
+    let physical = queue.device().physical_device();
+    *[Format::D24Unorm_S8Uint, Format::D32Sfloat_S8Uint, Format::D16Unorm_S8Uint]
+        .iter()
+        .find(|format| format.properties(physical).optimal_tiling_features.depth_stencil_attachment)
+        .expect("no depth stencil format")
+}
+
+fn render(queue: Arc<Queue>, point_lights: &[PointLight], spot_lights: &[SpotLight]) -> CpuImage {
+    let target = OffscreenTarget::new(queue.clone(), [SIZE, SIZE], Format::R8G8B8A8Unorm).unwrap();
+    let gbuffer = GBufferBuilder::new_default_with_stencil(stencil_format(&queue));
+    let graph = deffered_lighting_stencil_graph(target.format(), &gbuffer).unwrap();
+    let mut render_system =
+        RenderSystem::new(queue.clone(), graph, gbuffer.build_no_dims(queue.clone()).unwrap()).unwrap();
+
+    let geometry_subpass = render_system.subpass(GEOMETRY_STAGE).unwrap();
+    let lighting_subpass = render_system.subpass(LIGHTING_STAGE).unwrap();
+    let draw_system = DrawSystem::new_geometry_draw(queue.clone(), geometry_subpass).unwrap();
+    let light_volumes = LightVolumeSystem::new(queue.clone(), lighting_subpass, render_system.depth_mode()).unwrap();
+
+    let camera = camera();
+    let camera_pool = CameraUniformPool::new(queue.device().clone());
+    let vertices = CpuAccessibleBuffer::from_iter(
+        queue.device().clone(),
+        BufferUsage::all(),
+        floor_vertices().into_iter(),
+    ).unwrap();
+
+    let mut finished = None;
+    {
+        let mut frame = render_system
+            .frame(now(queue.device().clone()), target.image())
+            .unwrap();
+        loop {
+            match frame.next_pass().unwrap() {
+                Some(RenderPass::SubPass(mut pass)) => {
+                    let stage = pass.stage().to_string();
+                    let dynamic_state = pass.dynamic_state();
+                    if stage == GEOMETRY_STAGE {
+                        let camera_set = draw_system.camera_set(camera_pool.next(&camera)).unwrap();
+                        let builder = draw_system
+                            .draw(
+                                draw_system.command_buffer_builder().unwrap(),
+                                dynamic_state,
+                                vertices.clone(),
+                                camera_set,
+                                ModelTransform::identity(),
+                            )
+                            .unwrap();
+                        pass.execute(builder.build().unwrap()).unwrap();
+                    } else if stage == LIGHTING_STAGE {
+                        let command_buffer = light_volumes
+                            .draw(
+                                dynamic_state,
+                                pass.gbuffer(),
+                                camera_pool.next(&camera),
+                                point_lights,
+                                spot_lights,
+                            )
+                            .unwrap();
+                        pass.execute(command_buffer).unwrap();
+                    }
+                }
+                Some(RenderPass::Finished(future)) => finished = Some(future),
+                None => break,
+            }
+        }
+    }
+    target.read(finished.unwrap()).unwrap()
+}
+
+// Where the centre of a pixel lands on the floor
+fn floor_point(camera: &Camera<Perspective>, x: u32, y: u32) -> Point {
+    let ray = camera.pixel_to_world_space([x as f32 + 0.5, y as f32 + 0.5], [SIZE, SIZE]);
+    ray.point_at(-ray.origin().y / ray.dir().y)
+}
+
+fn pixel_of(camera: &Camera<Perspective>, point: Point) -> (u32, u32) {
+    let pixel = camera.world_to_screen(point, [SIZE, SIZE]).pixel;
+    (pixel[0] as u32, pixel[1] as u32)
+}
+
+fn outside_point(light: &PointLight, p: Point) -> bool {
+    (p - light.position).norm() > light.radius + MARGIN
+}
+
+fn outside_spot(light: &SpotLight, p: Point) -> bool {
+    let offset = p - light.position;
+    let angle = offset.normalize().dot(&light.direction.normalize()).acos();
+    offset.norm() > light.range + MARGIN || angle > light.outer_angle + MARGIN / offset.norm()
+}
+
+#[test]
+fn lights_only_reach_pixels_inside_their_volume() {
+    let queue = match headless_queue() {
+        Some(queue) => queue,
+        None => {
+            eprintln!("skipping, no Vulkan driver available");
+            return;
+        }
+    };
+    let (red, blue, green) = (red_point(), blue_point(), green_spot());
+    let image = render(queue, &[red, blue], &[green]);
+    let camera = camera();
+
+    for y in 0..SIZE {
+        for x in 0..SIZE {
+            let p = floor_point(&camera, x, y);
+            let pixel = image.pixel(x, y);
+            if outside_point(&red, p) {
+                assert_eq!(pixel[0], 0.0, "red light reached {:?} at pixel {}, {}", p, x, y);
+            }
+            if outside_spot(&green, p) {
+                assert_eq!(pixel[1], 0.0, "green light reached {:?} at pixel {}, {}", p, x, y);
+            }
+            if outside_point(&blue, p) {
+                assert_eq!(pixel[2], 0.0, "blue light reached {:?} at pixel {}, {}", p, x, y);
+            }
+        }
+    }
+
+    let below = |position: Point| pixel_of(&camera, Point::new(position.x, 0.0, position.z));
+    let (x, y) = below(red.position);
+    assert!(image.pixel(x, y)[0] > 0.5, "red light missing: {:?}", image.pixel(x, y));
+    let (x, y) = below(green.position);
+    assert!(image.pixel(x, y)[1] > 0.3, "green light missing: {:?}", image.pixel(x, y));
+    let (x, y) = below(blue.position);
+    assert!(image.pixel(x, y)[2] > 0.5, "blue light missing: {:?}", image.pixel(x, y));
+}
+
+#[test]
+fn lights_drawn_together_add_up_to_each_drawn_alone() {
+    let queue = match headless_queue() {
+        Some(queue) => queue,
+        None => {
+            eprintln!("skipping, no Vulkan driver available");
+            return;
+        }
+    };
+    let (red, blue, green) = (red_point(), blue_point(), green_spot());
+    let together = render(queue.clone(), &[red, blue], &[green]);
+    let alone = [
+        render(queue.clone(), &[red], &[]),
+        render(queue.clone(), &[], &[green]),
+        render(queue, &[blue], &[]),
+    ];
+
+    for y in 0..SIZE {
+        for x in 0..SIZE {
+            for channel in 0..3 {
+                let expected = alone[channel].pixel(x, y)[channel];
+                let actual = together.pixel(x, y)[channel];
+                assert!(
+                    (expected - actual).abs() <= TOLERANCE,
+                    "channel {} at pixel {}, {}: {} alone, {} together",
+                    channel,
+                    x,
+                    y,
+                    expected,
+                    actual
+                );
+            }
+        }
+    }
+}
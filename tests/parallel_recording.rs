@@ -0,0 +1,146 @@
+// Draws a grid of overlapping quads through `ParallelRecorder` with different job
+// counts. Draw order decides which quad ends up on top, so the images only match
+// if the command buffers are executed in list order. Skips when there is no
+// Vulkan driver
+extern crate nalgebra;
+extern crate vulkan_renderer;
+extern crate vulkano;
+
+use nalgebra::{Point3, Vector3};
+use std::sync::Arc;
+use vulkan_renderer::camera::Camera;
+use vulkan_renderer::math::{Mat4, Perspective};
+use vulkan_renderer::renderer::system::camera_uniform::{CameraUniformPool, ModelTransform};
+use vulkan_renderer::renderer::system::drawing_system::{DrawSystem, Vertex};
+use vulkan_renderer::renderer::system::gbuffer::GBufferBuilder;
+use vulkan_renderer::renderer::system::lighting_system::AmbientLightingSystem;
+use vulkan_renderer::renderer::system::offscreen::{headless_queue, CpuImage, OffscreenTarget};
+use vulkan_renderer::renderer::system::parallel::ParallelRecorder;
+use vulkan_renderer::renderer::system::render_system::{deffered_lighting_graph, RenderPass, RenderSystem,
+                                                       GEOMETRY_STAGE, LIGHTING_STAGE};
+use vulkano::buffer::{BufferUsage, CpuAccessibleBuffer};
+use vulkano::device::Queue;
+use vulkano::format::Format;
+use vulkano::sync::now;
+
+const FORMAT: Format = Format::R8G8B8A8Unorm;
+
+fn quad(colour: [f32; 3]) -> Vec<Vertex> {
+    let vertex = |x: f32, y: f32| Vertex {
+        position: [x, y, 0.0],
+        normal: [0.0, 0.0, 1.0],
+        colour,
+        specular: 0.0,
+    };
+    vec![
+        vertex(-0.6, -0.6),
+        vertex(0.6, -0.6),
+        vertex(0.6, 0.6),
+        vertex(-0.6, -0.6),
+        vertex(0.6, 0.6),
+        vertex(-0.6, 0.6),
+    ]
+}
+
+// Every quad sits at the same depth so later draws fail the depth test where
+// they overlap earlier ones
+fn transforms() -> Vec<ModelTransform> {
+    let mut transforms = Vec::new();
+    for y in 0..8 {
+        for x in 0..8 {
+            let offset = Vector3::new(x as f32 * 0.5 - 1.75, y as f32 * 0.5 - 1.75, 0.0);
+            transforms.push(ModelTransform::from_matrix(&Mat4::new_translation(&offset)));
+        }
+    }
+    transforms
+}
+
+fn render(queue: Arc<Queue>, jobs: usize) -> (CpuImage, usize) {
+    let target = OffscreenTarget::new(queue.clone(), [96, 96], FORMAT).unwrap();
+    let gbuffer = GBufferBuilder::new_default();
+    let graph = deffered_lighting_graph(FORMAT, &gbuffer).unwrap();
+    let mut render_system =
+        RenderSystem::new(queue.clone(), graph, gbuffer.build_no_dims(queue.clone()).unwrap()).unwrap();
+    let draw_system =
+        DrawSystem::new_geometry_draw(queue.clone(), render_system.subpass(GEOMETRY_STAGE).unwrap()).unwrap();
+    let ambient =
+        AmbientLightingSystem::new(queue.clone(), render_system.subpass(LIGHTING_STAGE).unwrap()).unwrap();
+
+    let camera = Camera::new(
+        Point3::new(0.0, 0.0, 5.0),
+        Point3::new(0.0, 0.0, 0.0),
+        Vector3::new(0.0, 1.0, 0.0),
+        Perspective::new(1.0, 1.0, 0.1, 100.0),
+    );
+    let camera_pool = CameraUniformPool::new(queue.device().clone());
+    let camera_set = draw_system.camera_set(camera_pool.next(&camera)).unwrap();
+    // Alternating colours so neighbouring quads can be told apart
+    let quads: Vec<_> = [[0.9, 0.2, 0.2], [0.2, 0.9, 0.2], [0.2, 0.2, 0.9]]
+        .iter()
+        .map(|&colour| {
+            CpuAccessibleBuffer::from_iter(queue.device().clone(), BufferUsage::all(), quad(colour).into_iter())
+                .unwrap()
+        })
+        .collect();
+    let items: Vec<(usize, ModelTransform)> = transforms()
+        .into_iter()
+        .enumerate()
+        .map(|(i, transform)| (i % quads.len(), transform))
+        .collect();
+
+    let recorder = ParallelRecorder::with_jobs(jobs);
+    let mut command_buffers = 0;
+    let mut finished = None;
+    {
+        let mut frame = render_system
+            .frame(now(queue.device().clone()), target.image())
+            .unwrap();
+        while let Some(pass) = frame.next_pass().unwrap() {
+            match pass {
+                RenderPass::SubPass(mut pass) => {
+                    if pass.stage() == GEOMETRY_STAGE {
+                        command_buffers = recorder
+                            .record(&mut pass, &items, |mut builder, dynamic_state, chunk| {
+                                for &(mesh, transform) in chunk {
+                                    builder = draw_system.draw(
+                                        builder,
+                                        dynamic_state.clone(),
+                                        quads[mesh].clone(),
+                                        camera_set.clone(),
+                                        transform,
+                                    )?;
+                                }
+                                Ok(builder)
+                            })
+                            .unwrap();
+                    } else {
+                        let command_buffer = ambient
+                            .draw(pass.dynamic_state(), pass.gbuffer(), [1.0, 1.0, 1.0])
+                            .unwrap();
+                        pass.execute(command_buffer).unwrap();
+                    }
+                }
+                RenderPass::Finished(future) => finished = Some(future),
+            }
+        }
+    }
+    (target.read(finished.unwrap()).unwrap(), command_buffers)
+}
+
+#[test]
+fn job_count_does_not_change_the_image() {
+    let queue = match headless_queue() {
+        Some(queue) => queue,
+        None => {
+            eprintln!("skipping, no Vulkan driver available");
+            return;
+        }
+    };
+    let (serial, serial_buffers) = render(queue.clone(), 1);
+    assert_eq!(serial_buffers, 1);
+    for &jobs in [2, 3, 8, 100].iter() {
+        let (parallel, buffers) = render(queue.clone(), jobs);
+        assert_eq!(buffers, jobs.min(64));
+        assert!(parallel == serial, "{} jobs rendered a different image", jobs);
+    }
+}
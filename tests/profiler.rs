@@ -0,0 +1,167 @@
+// Times frames with `GpuProfiler` and CPU scopes with `CpuProfiler`, and exports
+// both as a Chrome trace. The GPU test skips when there is no Vulkan driver or the
+// device can not write timestamps
+extern crate vulkan_renderer;
+extern crate vulkano;
+
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
+use vulkan_renderer::renderer::system::error::RendererError;
+use vulkan_renderer::renderer::system::frames::FramesInFlight;
+use vulkan_renderer::renderer::system::gbuffer::GBufferBuilder;
+use vulkan_renderer::renderer::system::lighting_system::AmbientLightingSystem;
+use vulkan_renderer::renderer::system::offscreen::{headless_queue, OffscreenTarget};
+use vulkan_renderer::renderer::system::profiler::{CpuProfiler, GpuProfiler, TimedScope, TimingReport};
+use vulkan_renderer::renderer::system::render_system::{deffered_lighting_graph, RenderPass, RenderSystem,
+                                                       GEOMETRY_STAGE, LIGHTING_STAGE};
+use vulkan_renderer::renderer::system::trace::ChromeTrace;
+use vulkano::format::Format;
+
+const FORMAT: Format = Format::R8G8B8A8Unorm;
+const FRAMES_IN_FLIGHT: usize = 2;
+const FRAMES: u64 = 5;
+
+#[test]
+fn gpu_profiler_times_stages_and_scopes() {
+    let queue = match headless_queue() {
+        Some(queue) => queue,
+        None => {
+            eprintln!("skipping, no Vulkan driver available");
+            return;
+        }
+    };
+    let profiler = match GpuProfiler::new(queue.clone(), FRAMES_IN_FLIGHT + 1, 16) {
+        Ok(profiler) => profiler,
+        Err(RendererError::TimestampsUnsupported) => {
+            eprintln!("skipping, the device can not write timestamps");
+            return;
+        }
+        Err(err) => panic!("{}", err),
+    };
+    let gbuffer = GBufferBuilder::new_default();
+    let graph = deffered_lighting_graph(FORMAT, &gbuffer).unwrap();
+    let mut render_system =
+        RenderSystem::new(queue.clone(), graph, gbuffer.build_no_dims(queue.clone()).unwrap()).unwrap();
+    assert!(render_system.set_profiler(Some(profiler)).is_none());
+    let ambient = AmbientLightingSystem::new(queue.clone(), render_system.subpass(LIGHTING_STAGE).unwrap()).unwrap();
+    let target = OffscreenTarget::new(queue.clone(), [64, 64], FORMAT).unwrap();
+
+    let mut frames = FramesInFlight::new(FRAMES_IN_FLIGHT);
+    for _ in 0..FRAMES {
+        frames.begin().unwrap();
+        let mut finished = None;
+        {
+            let mut frame = render_system
+                .frame(frames.previous_frame(queue.device().clone()), target.image())
+                .unwrap();
+            while let Some(pass) = frame.next_pass().unwrap() {
+                match pass {
+                    RenderPass::SubPass(mut pass) => {
+                        if pass.stage() == LIGHTING_STAGE {
+                            pass.scope("ambient", |pass| {
+                                let command_buffer =
+                                    ambient.draw(pass.dynamic_state(), pass.gbuffer(), [1.0, 1.0, 1.0])?;
+                                pass.execute(command_buffer)
+                            }).unwrap();
+                        }
+                    }
+                    RenderPass::Finished(future) => finished = Some(future),
+                }
+            }
+        }
+        frames.end(finished.unwrap()).unwrap();
+    }
+    frames.wait_idle().unwrap();
+
+    let profiler = render_system.profiler_mut().unwrap();
+    assert_eq!(profiler.frame_number(), FRAMES);
+    let report = profiler.resolve().unwrap().clone();
+    assert_eq!(report.frame, FRAMES - 1);
+    let names: Vec<(&str, usize)> = report.scopes.iter().map(|s| (s.name.as_str(), s.depth)).collect();
+    assert_eq!(names, vec![(GEOMETRY_STAGE, 0), (LIGHTING_STAGE, 0), ("ambient", 1)]);
+
+    let geometry = report.stage(GEOMETRY_STAGE).unwrap();
+    let lighting = report.stage(LIGHTING_STAGE).unwrap();
+    let scope = report.scope("ambient").unwrap();
+    assert_eq!(geometry.start, 0.0);
+    // Nothing is drawn in the geometry stage, its timestamps can land on the same tick
+    assert!(geometry.duration >= 0.0 && lighting.duration > 0.0);
+    assert!(lighting.start >= geometry.end());
+    // The end timestamps can land on the same tick
+    assert!(scope.start >= lighting.start && scope.end() <= lighting.end() + 1e-3);
+    assert_eq!(report.total(), lighting.end());
+}
+
+#[test]
+fn cpu_scopes_nest_per_thread() {
+    let profiler = Arc::new(CpuProfiler::new());
+    {
+        let _frame = profiler.scope("frame");
+        {
+            let _record = profiler.scope("record");
+            thread::sleep(Duration::from_millis(2));
+        }
+        let worker = profiler.clone();
+        let handle = thread::spawn(move || {
+            let _job = worker.scope("job");
+        });
+        handle.join().unwrap();
+    }
+
+    let records = profiler.records();
+    let names: Vec<(&str, usize, usize)> = records
+        .iter()
+        .map(|r| (r.name.as_str(), r.thread, r.depth))
+        .collect();
+    // In the order the scopes ended
+    assert_eq!(names, vec![("record", 0, 1), ("job", 1, 0), ("frame", 0, 0)]);
+    let (record, frame) = (&records[0], &records[2]);
+    assert!(record.duration >= Duration::from_millis(2));
+    assert!(record.start >= frame.start);
+    assert!(record.start + record.duration <= frame.start + frame.duration);
+
+    assert_eq!(profiler.drain().len(), 3);
+    assert!(profiler.records().is_empty());
+}
+
+#[test]
+fn chrome_trace_has_an_event_per_scope() {
+    let origin = Instant::now();
+    let report = TimingReport {
+        frame: 0,
+        recorded: origin + Duration::from_millis(1),
+        scopes: vec![
+            TimedScope {
+                name: GEOMETRY_STAGE.to_string(),
+                depth: 0,
+                start: 0.0,
+                duration: 2000.0,
+            },
+            TimedScope {
+                name: "lights \"spot\"".to_string(),
+                depth: 1,
+                start: 2500.0,
+                duration: 500.0,
+            },
+        ],
+    };
+    let profiler = CpuProfiler::new();
+    drop(profiler.scope("record"));
+
+    let mut trace = ChromeTrace::new(profiler.origin());
+    trace.add_cpu(&profiler.records());
+    let mut gpu_trace = ChromeTrace::new(origin);
+    gpu_trace.add_gpu(&report);
+    assert_eq!(trace.len(), 1);
+    assert_eq!(gpu_trace.len(), 2);
+
+    let json = gpu_trace.to_json();
+    assert!(json.starts_with("{\"traceEvents\":["));
+    assert!(json.ends_with("],\"displayTimeUnit\":\"ms\"}"));
+    assert!(json.contains(
+        "{\"name\":\"geometry\",\"cat\":\"gpu\",\"ph\":\"X\",\"ts\":1000.000,\"dur\":2.000,\"pid\":2,\"tid\":0}"
+    ));
+    assert!(json.contains("\"name\":\"lights \\\"spot\\\"\",\"cat\":\"gpu\",\"ph\":\"X\",\"ts\":1002.500"));
+    assert!(trace.to_json().contains("\"name\":\"record\",\"cat\":\"cpu\""));
+}
@@ -0,0 +1,135 @@
+// Feeds the renderer bad input and checks it comes back as a `RendererError`
+// rather than a panic. The tests that need a device skip when there is no Vulkan
+// driver, the others always run
+extern crate vulkan_renderer;
+extern crate vulkano;
+
+use std::env;
+use std::io;
+use vulkan_renderer::renderer::system::error::RendererError;
+use vulkan_renderer::renderer::system::gbuffer::{GBufferBuilder, DIFFUSE, SPECULAR};
+use vulkan_renderer::renderer::system::offscreen::{headless_queue, CpuImage, OffscreenTarget};
+use vulkan_renderer::renderer::system::render_graph::{GraphPass, RenderGraph, RenderGraphError};
+use vulkan_renderer::renderer::system::render_system::{deffered_lighting_graph, RenderSystem};
+use vulkano::format::Format;
+use vulkano::image::ImageUsage;
+
+// No driver reports a 64 bit per channel format as renderable
+const UNSUPPORTED_FORMAT: Format = Format::R64G64B64A64Sfloat;
+
+macro_rules! queue_or_skip {
+    () => {
+        match headless_queue() {
+            Some(queue) => queue,
+            None => {
+                eprintln!("skipping, no Vulkan driver available");
+                return;
+            }
+        }
+    };
+}
+
+#[test]
+fn graph_missing_gbuffer_attachment() {
+    let gbuffer = GBufferBuilder::new().attachment(
+        DIFFUSE,
+        Format::R8G8B8A8Unorm,
+        ImageUsage::none(),
+        [0.0, 0.0, 0.0, 0.0].into(),
+    );
+    match deffered_lighting_graph(Format::R8G8B8A8Unorm, &gbuffer) {
+        Err(RendererError::AttachmentMismatch(ref name)) if name == SPECULAR => {}
+        Err(err) => panic!("unexpected error {}", err),
+        Ok(_) => panic!("graph built without its gbuffer attachments"),
+    }
+}
+
+#[test]
+fn graph_reading_before_writing() {
+    let graph = RenderGraph::start()
+        .output("final", Format::R8G8B8A8Unorm, [0.0, 0.0, 0.0, 0.0].into())
+        .attachment("albedo", Format::R8G8B8A8Unorm, [0.0, 0.0, 0.0, 0.0].into())
+        .pass(GraphPass::new("lighting").color("final").input("albedo"))
+        .build();
+    match graph {
+        Err(RenderGraphError::ReadBeforeWrite { ref attachment, .. }) if attachment == "albedo" => {}
+        Err(err) => panic!("unexpected error {}", err),
+        Ok(_) => panic!("graph read an attachment nothing writes"),
+    }
+}
+
+#[test]
+fn zero_size_gbuffer() {
+    let queue = queue_or_skip!();
+    match GBufferBuilder::new_default().build_with_dims(queue, [0, 64]) {
+        Err(RendererError::InvalidDimensions([0, 64])) => {}
+        Err(err) => panic!("unexpected error {}", err),
+        Ok(_) => panic!("built a zero sized gbuffer"),
+    }
+}
+
+#[test]
+fn unsupported_gbuffer_format() {
+    let queue = queue_or_skip!();
+    let mut builder = GBufferBuilder::new_default();
+    builder.set_format(DIFFUSE, UNSUPPORTED_FORMAT);
+    match builder.build_with_dims(queue, [64, 64]) {
+        Err(RendererError::UnsupportedFormat(UNSUPPORTED_FORMAT)) => {}
+        Err(err) => panic!("unexpected error {}", err),
+        Ok(_) => panic!("built a gbuffer with an unsupported format"),
+    }
+}
+
+#[test]
+fn gbuffer_not_matching_graph() {
+    let queue = queue_or_skip!();
+    let graph = deffered_lighting_graph(Format::R8G8B8A8Unorm, &GBufferBuilder::new_default()).unwrap();
+    let mut builder = GBufferBuilder::new_default();
+    builder.set_format(DIFFUSE, Format::R8G8B8A8Unorm);
+    let gbuffer = builder.build_no_dims(queue.clone()).unwrap();
+    match RenderSystem::new(queue, graph, gbuffer) {
+        Err(RendererError::AttachmentMismatch(ref name)) if name == DIFFUSE => {}
+        Err(err) => panic!("unexpected error {}", err),
+        Ok(_) => panic!("render system accepted a gbuffer in the wrong format"),
+    }
+}
+
+#[test]
+fn zero_size_offscreen_target() {
+    let queue = queue_or_skip!();
+    match OffscreenTarget::new(queue, [128, 0], Format::R8G8B8A8Unorm) {
+        Err(RendererError::InvalidDimensions([128, 0])) => {}
+        Err(err) => panic!("unexpected error {}", err),
+        Ok(_) => panic!("created a zero sized target"),
+    }
+}
+
+#[test]
+fn unsupported_offscreen_format() {
+    let queue = queue_or_skip!();
+    match OffscreenTarget::new(queue, [64, 64], UNSUPPORTED_FORMAT) {
+        Err(RendererError::UnsupportedFormat(UNSUPPORTED_FORMAT)) => {}
+        Err(err) => panic!("unexpected error {}", err),
+        Ok(_) => panic!("created a target with an unsupported format"),
+    }
+}
+
+#[test]
+fn empty_image_to_exr() {
+    let path = env::temp_dir().join("vulkan_renderer_empty.exr");
+    for &(width, height) in [(0, 4), (4, 0), (0, 0)].iter() {
+        match CpuImage::new(width, height).write_exr(&path) {
+            Err(ref err) if err.kind() == io::ErrorKind::InvalidInput => {}
+            Err(err) => panic!("unexpected error {}", err),
+            Ok(_) => panic!("wrote an empty {}x{} image", width, height),
+        }
+    }
+    assert!(!path.exists());
+}
+
+#[test]
+fn short_image_bytes() {
+    assert!(CpuImage::from_bytes(Format::R8G8B8A8Unorm, [2, 2], &[0; 15]).is_none());
+    assert!(CpuImage::from_bytes(UNSUPPORTED_FORMAT, [1, 1], &[0; 32]).is_none());
+    assert!(CpuImage::from_bytes(Format::R8G8B8A8Unorm, [2, 2], &[0; 16]).is_some());
+}